@@ -0,0 +1,120 @@
+// Counts heap allocations made by the front end on a large generated
+// file with long identifiers, and by the IR passes on call-heavy code.
+// Run with `cargo bench --bench alloc`.
+
+extern crate r9cc;
+
+use r9cc::gen_ir::gen_ir;
+use r9cc::parse::parse;
+use r9cc::preprocess::Preprocessor;
+use r9cc::regalloc::alloc_regs;
+use r9cc::sema::sema;
+use r9cc::token::{tokenize, TokenStream};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const FUNCS: usize = 500;
+const CALLERS: usize = 100;
+const CALLS_PER_FUNC: usize = 100;
+
+fn source() -> String {
+    let mut s = String::new();
+    for i in 0..FUNCS {
+        s.push_str(&format!(
+            "int a_rather_long_generated_function_name_{}(int first_parameter_with_long_name, int second_parameter_with_long_name) {{\n",
+            i
+        ));
+        s.push_str("  int a_local_variable_with_a_long_name = first_parameter_with_long_name;\n");
+        s.push_str("  a_local_variable_with_a_long_name = a_local_variable_with_a_long_name + second_parameter_with_long_name;\n");
+        if i == 0 {
+            s.push_str("  return a_local_variable_with_a_long_name;\n");
+        } else {
+            s.push_str(&format!(
+                "  return a_rather_long_generated_function_name_{}(a_local_variable_with_a_long_name, second_parameter_with_long_name);\n",
+                i - 1
+            ));
+        }
+        s.push_str("}\n");
+    }
+    s
+}
+
+// CALLERS * CALLS_PER_FUNC call instructions in total.
+fn call_source() -> String {
+    let mut s = String::from("int callee(int x, int y) { return x + y; }\n");
+    for i in 0..CALLERS {
+        s.push_str(&format!("int caller_{}(int a) {{\n", i));
+        for _ in 0..CALLS_PER_FUNC {
+            s.push_str("  a = callee(a, 1);\n");
+        }
+        s.push_str("  return a;\n}\n");
+    }
+    s
+}
+
+fn write_source(name: &str, source: String) -> String {
+    let path = std::env::temp_dir().join(name);
+    fs::write(&path, source).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn main() {
+    let path = write_source("r9cc-bench-alloc.c", source());
+
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let tokens = tokenize(path.clone(), &mut Preprocessor::new()).unwrap();
+    let after_tokenize = ALLOCS.load(Ordering::Relaxed);
+
+    // The parser pulls its own tokens, so the front end runs again,
+    // tokenizing included.
+    let before_parse = ALLOCS.load(Ordering::Relaxed);
+    let mut ctx = Preprocessor::new();
+    let nodes = parse(TokenStream::open(path, &mut ctx).unwrap()).unwrap();
+    let prog = sema(nodes).unwrap();
+    let fns = gen_ir(prog.nodes, &prog.noreturn, false);
+    let after = ALLOCS.load(Ordering::Relaxed);
+
+    println!("functions:       {}", fns.len());
+    println!("tokens:          {}", tokens.len());
+    println!("allocs tokenize: {}", after_tokenize - before);
+    println!("allocs total:    {}", after - before_parse);
+
+    let path = write_source("r9cc-bench-calls.c", call_source());
+    let mut ctx = Preprocessor::new();
+    let prog = sema(parse(TokenStream::open(path, &mut ctx).unwrap()).unwrap()).unwrap();
+
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let mut fns = gen_ir(prog.nodes, &prog.noreturn, false);
+    let after_gen_ir = ALLOCS.load(Ordering::Relaxed);
+    alloc_regs(&mut fns);
+    let after = ALLOCS.load(Ordering::Relaxed);
+
+    println!("call sites:      {}", CALLERS * CALLS_PER_FUNC);
+    println!("allocs gen_ir:   {}", after_gen_ir - before);
+    println!("allocs regalloc: {}", after - after_gen_ir);
+}
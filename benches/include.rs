@@ -0,0 +1,47 @@
+// Measures preprocessing a file that includes a large generated header,
+// at two sizes. Every line of the header calls a macro whose expansion
+// is longer than the call, so the rest of the header is read again after
+// it; the time per line should not grow with the size of the header.
+// Run with `cargo bench --bench include`.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::tokenize;
+
+use std::fs;
+use std::time::Instant;
+
+const SIZES: [usize; 2] = [10_000, 40_000];
+
+fn header(lines: usize) -> String {
+    let mut s = String::from("#define PAIR(a, b) ((a) + (b) * 2 + (a) * (b))\n");
+    for i in 0..lines {
+        s.push_str(&format!("int value_{} = PAIR({}, {});\n", i, i, i + 1));
+    }
+    s
+}
+
+fn main() {
+    let dir = std::env::temp_dir();
+    for &lines in &SIZES {
+        let name = format!("r9cc-bench-include-{}.h", lines);
+        fs::write(dir.join(&name), header(lines)).unwrap();
+        let path = dir.join(format!("r9cc-bench-include-{}.c", lines));
+        fs::write(
+            &path,
+            format!("#include \"{}\"\nint main() {{ return 0; }}\n", name),
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let tokens =
+            tokenize(path.to_str().unwrap().to_string(), &mut Preprocessor::new()).unwrap();
+        let elapsed = start.elapsed();
+
+        println!("header lines:    {}", lines);
+        println!("tokens:          {}", tokens.len());
+        println!("time:            {:?}", elapsed);
+        println!("per line:        {:?}", elapsed / lines as u32);
+    }
+}
@@ -0,0 +1,86 @@
+// Measures the most heap memory in use at once while preprocessing a
+// large translation unit: a file that includes a generated header of
+// about 1 MB fifty times over. Reading all its tokens into a vector
+// holds every one of them; pulling them one at a time, the way the
+// parser does, holds only the file being read.
+// Run with `cargo bench --bench memory`.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::{tokenize, TokenStream};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Peak;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Peak {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(live, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Peak = Peak;
+
+const HEADER_LINES: usize = 20_000;
+const INCLUDES: usize = 50;
+
+fn header() -> String {
+    let mut s = String::new();
+    for i in 0..HEADER_LINES {
+        s.push_str(&format!(
+            "int generated_declaration_{}(int first, int second); /* padding */\n",
+            i
+        ));
+    }
+    s
+}
+
+// The most memory in use above what was in use before `f` ran.
+fn peak<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let base = LIVE.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let res = f();
+    (res, PEAK.load(Ordering::Relaxed) - base)
+}
+
+fn main() {
+    let dir = std::env::temp_dir();
+    let header = header();
+    fs::write(dir.join("r9cc-bench-memory.h"), &header).unwrap();
+    let main = "#include \"r9cc-bench-memory.h\"\n".repeat(INCLUDES);
+    let path = dir.join("r9cc-bench-memory.c");
+    fs::write(&path, main).unwrap();
+    let path = path.to_str().unwrap().to_string();
+
+    let (n, pulled) = peak(|| {
+        let mut ctx = Preprocessor::new();
+        let mut n = 0;
+        for t in TokenStream::open(path.clone(), &mut ctx).unwrap() {
+            t.unwrap();
+            n += 1;
+        }
+        n
+    });
+    let (tokens, collected) = peak(|| tokenize(path, &mut Preprocessor::new()).unwrap());
+    assert_eq!(n, tokens.len());
+
+    let mb = |n: usize| n as f64 / (1 << 20) as f64;
+    println!("input:           {:.1} MB", mb(header.len() * INCLUDES));
+    println!("tokens:          {}", n);
+    println!("peak pulled:     {:.1} MB", mb(pulled));
+    println!("peak collected:  {:.1} MB", mb(collected));
+}
@@ -0,0 +1,77 @@
+// Measures tokenizing and preprocessing a generated header of about
+// 10k lines that defines and expands many macros.
+// Run with `cargo bench --bench preprocess`.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::tokenize;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const GROUPS: usize = 2000;
+
+// Five lines per group.
+fn header() -> String {
+    let mut s = String::new();
+    for i in 0..GROUPS {
+        s.push_str(&format!("#define CONSTANT_VALUE_{} {}\n", i, i));
+        s.push_str(&format!(
+            "#define ADD_TWO_VALUES_{}(first_value, second_value) ((first_value) + (second_value) + CONSTANT_VALUE_{})\n",
+            i, i
+        ));
+        s.push_str(&format!(
+            "int declared_function_number_{}(int first_argument, int second_argument);\n",
+            i
+        ));
+        s.push_str(&format!("int global_variable_number_{};\n", i));
+        s.push_str(&format!(
+            "int use_{}() {{ return ADD_TWO_VALUES_{}(global_variable_number_{}, CONSTANT_VALUE_{}); }}\n",
+            i, i, i, i
+        ));
+    }
+    s
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("r9cc-bench-preprocess.h");
+    fs::write(&path, header()).unwrap();
+    let path = path.to_str().unwrap().to_string();
+
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let tokens = tokenize(path, &mut Preprocessor::new()).unwrap();
+    let elapsed = start.elapsed();
+    let after = ALLOCS.load(Ordering::Relaxed);
+
+    println!("lines:           {}", GROUPS * 5);
+    println!("tokens:          {}", tokens.len());
+    println!("allocs:          {}", after - before);
+    println!("time:            {:?}", elapsed);
+}
@@ -0,0 +1,28 @@
+// Sets R9CC_VERSION for version(): the package version, followed by
+// the git commit it was built from when there is one.
+
+use std::fs;
+use std::process::Command;
+
+fn main() {
+    let mut version = env!("CARGO_PKG_VERSION").to_string();
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string());
+    if let Some(hash) = hash {
+        version = format!("{} ({})", version, hash);
+    }
+    println!("cargo:rustc-env=R9CC_VERSION={}", version);
+
+    // Build again after a commit, which moves the branch HEAD names.
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Ok(head) = fs::read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(branch) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", branch);
+        }
+    }
+}
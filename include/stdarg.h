@@ -0,0 +1,19 @@
+// Bundled with r9cc. va_list has the x86-64 layout, so it can be passed
+// on to functions like vprintf. The va_* macros name builtins of r9cc;
+// va_arg reads integers, pointers and doubles, but not structs.
+#ifndef __STDARG_H
+#define __STDARG_H
+
+typedef struct {
+  int gp_offset;
+  int fp_offset;
+  void *overflow_arg_area;
+  void *reg_save_area;
+} va_list[1];
+
+#define va_start(ap, last) __builtin_va_start(ap, last)
+#define va_arg(ap, type) __builtin_va_arg(ap, type)
+#define va_copy(dest, src) __builtin_va_copy(dest, src)
+#define va_end(ap) __builtin_va_end(ap)
+
+#endif
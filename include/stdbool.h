@@ -0,0 +1,10 @@
+// Bundled with r9cc. There is no _Bool yet, so bool is an int.
+#ifndef __STDBOOL_H
+#define __STDBOOL_H
+
+#define bool int
+#define true 1
+#define false 0
+#define __bool_true_false_are_defined 1
+
+#endif
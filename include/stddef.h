@@ -0,0 +1,14 @@
+// Bundled with r9cc, for systems without a compiler's own <stddef.h>.
+// There is no unsigned type yet, so size_t is signed.
+#ifndef __STDDEF_H
+#define __STDDEF_H
+
+#define NULL ((void *)0)
+
+typedef long size_t;
+typedef long ptrdiff_t;
+typedef int wchar_t;
+
+#define offsetof(type, member) ((size_t)&((type *)0)->member)
+
+#endif
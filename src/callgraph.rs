@@ -0,0 +1,112 @@
+// Call graph report
+//
+// Printed by the driver with --dump-callgraph. It reads the IR right
+// after generation, so the callees of a function are exactly the names
+// of its Call ops, and calls through memcpy/memset lowering or through
+// a function pointer don't show.
+
+use crate::gen_ir::{Function, IROp};
+use crate::symbol::Symbol;
+
+use std::collections::HashMap;
+
+pub struct Node {
+    pub name: Symbol,
+    pub callees: Vec<Symbol>, // in order of first call, without repeats
+    pub insns: usize,
+    pub stacksize: usize,
+    pub recursive: bool,
+}
+
+pub fn callgraph(fns: &[Function]) -> Vec<Node> {
+    let mut nodes: Vec<Node> = fns
+        .iter()
+        .map(|f| {
+            let mut callees = vec![];
+            let mut insns = 0;
+            for ir in &f.ir {
+                match ir {
+                    IROp::Kill { .. } | IROp::Nop => continue,
+                    IROp::Call {
+                        name, ptr: None, ..
+                    } if !callees.contains(name) => callees.push(*name),
+                    _ => (),
+                }
+                insns += 1;
+            }
+            Node {
+                name: f.name,
+                callees,
+                insns,
+                stacksize: f.stacksize,
+                recursive: false,
+            }
+        })
+        .collect();
+
+    let index: HashMap<Symbol, usize> =
+        nodes.iter().enumerate().map(|(i, n)| (n.name, i)).collect();
+    for i in 0..nodes.len() {
+        nodes[i].recursive = reaches(&nodes, &index, i);
+    }
+    nodes
+}
+
+// Whether nodes[from] can call itself, directly or through other
+// functions defined in this file.
+fn reaches(nodes: &[Node], index: &HashMap<Symbol, usize>, from: usize) -> bool {
+    let mut visited = vec![false; nodes.len()];
+    let mut stack = vec![from];
+    while let Some(i) = stack.pop() {
+        for callee in &nodes[i].callees {
+            let j = match index.get(callee) {
+                Some(j) => *j,
+                None => continue,
+            };
+            if j == from {
+                return true;
+            }
+            if !visited[j] {
+                visited[j] = true;
+                stack.push(j);
+            }
+        }
+    }
+    false
+}
+
+pub fn print_text(nodes: &[Node]) {
+    eprintln!(
+        "{:<24} {:>8} {:>8} {:<9} callees",
+        "function", "insns", "stack", "recursive"
+    );
+    for n in nodes {
+        let callees: Vec<String> = n.callees.iter().map(|c| c.to_string()).collect();
+        let line = format!(
+            "{:<24} {:>8} {:>8} {:<9} {}",
+            n.name,
+            n.insns,
+            n.stacksize,
+            if n.recursive { "yes" } else { "no" },
+            callees.join(", ")
+        );
+        eprintln!("{}", line.trim_end());
+    }
+}
+
+pub fn print_dot(nodes: &[Node]) {
+    eprintln!("digraph callgraph {{");
+    for n in nodes {
+        let style = if n.recursive { ", style=bold" } else { "" };
+        eprintln!(
+            "  \"{}\" [label=\"{}\\n{} insns, stack {}\"{}];",
+            n.name, n.name, n.insns, n.stacksize, style
+        );
+    }
+    for n in nodes {
+        for callee in &n.callees {
+            eprintln!("  \"{}\" -> \"{}\";", n.name, callee);
+        }
+    }
+    eprintln!("}}");
+}
@@ -0,0 +1,136 @@
+// Diagnostics
+//
+// Most errors still stop the compiler on the spot. The ones a phase
+// can recover from are reported here instead and counted, so that the
+// driver can stop after the phase and summarize. Once the error limit
+// is reached, `too_many_errors` tells the phase to give up early.
+//
+// compile_many captures diagnostics instead of printing them: errors
+// come back as its results, and warnings as a list with each output.
+//
+// The error limit and the warnings turned off are set for the whole
+// process. Counts and captured warnings are kept per thread, each
+// compilation running on one, so that threads can compile at once.
+
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+struct Settings {
+    max_errors: usize,     // 0 means no limit
+    disabled: Vec<String>, // warnings turned off with -Wno-<name>
+}
+
+#[derive(Default)]
+struct Counts {
+    errors: usize,
+    warnings: usize,
+    captured: Option<Vec<String>>, // warnings kept while capturing
+}
+
+lazy_static! {
+    static ref SETTINGS: Mutex<Settings> = Mutex::new(Settings {
+        max_errors: 20,
+        disabled: vec![],
+    });
+}
+
+thread_local! {
+    static COUNTS: RefCell<Counts> = RefCell::new(Counts::default());
+}
+
+fn max_errors() -> usize {
+    SETTINGS.lock().unwrap().max_errors
+}
+
+pub fn set_max_errors(n: usize) {
+    SETTINGS.lock().unwrap().max_errors = n;
+}
+
+pub fn error(msg: &str) {
+    let max_errors = max_errors();
+    COUNTS.with(|c| {
+        let mut c = c.borrow_mut();
+        if max_errors != 0 && c.errors >= max_errors {
+            return;
+        }
+        if c.captured.is_none() {
+            eprintln!("{}", msg);
+        }
+        c.errors += 1;
+        if c.errors == max_errors && c.captured.is_none() {
+            eprintln!("too many errors, stopping");
+        }
+    })
+}
+
+pub fn warning(msg: &str) {
+    let msg = format!("warning: {}", msg);
+    COUNTS.with(|c| {
+        let mut c = c.borrow_mut();
+        match c.captured {
+            Some(ref mut kept) => kept.push(msg),
+            None => eprintln!("{}", msg),
+        }
+        c.warnings += 1;
+    })
+}
+
+// Keeps diagnostics from being printed until end_capture.
+pub fn capture() {
+    COUNTS.with(|c| c.borrow_mut().captured = Some(vec![]));
+}
+
+// Prints diagnostics again, and returns the warnings kept since capture.
+pub fn end_capture() -> Vec<String> {
+    COUNTS.with(|c| c.borrow_mut().captured.take().unwrap_or_default())
+}
+
+// Starts counting again for the next compilation. Which warnings are
+// off and the error limit stay.
+pub fn reset_counts() {
+    COUNTS.with(|c| {
+        let mut c = c.borrow_mut();
+        c.errors = 0;
+        c.warnings = 0;
+    })
+}
+
+pub fn disable_warning(name: &str) {
+    SETTINGS.lock().unwrap().disabled.push(name.to_string());
+}
+
+// A warning that can be turned off by name, e.g. -Wno-parentheses.
+pub fn warning_named(name: &str, msg: &str) {
+    if SETTINGS.lock().unwrap().disabled.iter().any(|n| n == name) {
+        return;
+    }
+    warning(&format!("{} [-W{}]", msg, name));
+}
+
+pub fn error_count() -> usize {
+    COUNTS.with(|c| c.borrow().errors)
+}
+
+pub fn warning_count() -> usize {
+    COUNTS.with(|c| c.borrow().warnings)
+}
+
+pub fn too_many_errors() -> bool {
+    let max_errors = max_errors();
+    max_errors != 0 && error_count() >= max_errors
+}
+
+// e.g. "3 errors, 2 warnings generated."
+pub fn summary() -> Option<String> {
+    let plural = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+    match (error_count(), warning_count()) {
+        (0, 0) => None,
+        (0, w) => Some(format!("{} generated.", plural(w, "warning"))),
+        (e, 0) => Some(format!("{} generated.", plural(e, "error"))),
+        (e, w) => Some(format!(
+            "{}, {} generated.",
+            plural(e, "error"),
+            plural(w, "warning")
+        )),
+    }
+}
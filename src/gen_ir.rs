@@ -11,556 +11,1365 @@
 // > in a later pass.
 
 use crate::parse::{Node, NodeType};
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::symbol::Symbol;
+use crate::util::roundup;
+use crate::{Ctype, Scope, TokenType, Type, REGS_N};
 
-use std::sync::Mutex;
+use std::collections::HashSet;
+use std::mem;
 
-lazy_static! {
-    static ref NUM_REGS: Mutex<usize> = Mutex::new(0);
-    static ref NLABEL: Mutex<usize> = Mutex::new(1);
-    static ref RETURN_LABEL: Mutex<usize> = Mutex::new(0);
-    static ref RETURN_REG: Mutex<usize> = Mutex::new(0);
-    static ref BREAK_LABEL: Mutex<usize> = Mutex::new(0);
-    static ref CODE: Mutex<Vec<IR>> = Mutex::new(vec![]);
+// The state of turning one translation unit into IR.
+#[derive(Default)]
+struct IrGen {
+    num_regs: usize,
+    nlabel: usize,
+    return_label: Label,
+    return_reg: Reg,
+    break_label: Label,
+    addr_taken: bool,
+    zero_locals: bool,
+    stacksize: usize,
+    named_args: (usize, usize, usize),
+    code: Vec<IROp>,
+    noreturn: HashSet<Symbol>,
 }
 
-fn add(op: IROp, lhs: Option<usize>, rhs: Option<usize>) {
-    let ir = IR::new(op, lhs, rhs);
-    CODE.lock().unwrap().push(ir.clone());
+impl IrGen {
+    fn add(&mut self, op: IROp) {
+        self.code.push(op);
+    }
+
+    fn new_reg(&mut self) -> Reg {
+        let r = self.num_regs;
+        self.num_regs += 1;
+        r
+    }
+
+    fn new_label(&mut self) -> Label {
+        let x = self.nlabel;
+        self.nlabel += 1;
+        x
+    }
+
+    // Makes room for 8 bytes below the current function's locals.
+    fn new_slot(&mut self) -> usize {
+        self.stacksize = roundup(self.stacksize, 8) + 8;
+        self.stacksize
+    }
 }
 
-#[derive(Clone, Debug)]
-pub enum IRType {
-    Noarg,
-    Reg,
-    Imm,
-    Mem,
-    Jmp,
-    Label,
-    LabelAddr,
-    RegReg,
-    RegImm,
-    StoreArg,
-    RegLabel,
-    Call,
+// A register number: virtual before regalloc, physical after it.
+pub type Reg = usize;
+pub type Label = usize;
+
+// Where a call finds an argument: in a register, or in a frame slot
+// when the call has more arguments than there are registers to hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg {
+    Reg(Reg),
+    Slot(usize),
 }
 
 #[derive(Clone, Debug)]
 pub struct Function {
-    pub name: String,
-    pub ir: Vec<IR>,
+    pub name: Symbol,
+    pub ir: Vec<IROp>,
     pub stacksize: usize,
+
+    // Facts the backend uses to decide how much of a frame to set up.
+    pub has_calls: bool,  // calls a function, including memcpy/memset
+    pub addr_taken: bool, // the address of a local escapes into a register
+
+    // The size of a floating point return value, 0 for other types.
+    pub float_ret: u8,
+
+    pub is_static: bool, // not visible to other files
+
+    pub spills: usize, // spill slots the register allocator used
 }
 
 impl Function {
-    fn new(name: String, ir: Vec<IR>, stacksize: usize) -> Self {
+    pub(crate) fn new(
+        name: Symbol,
+        ir: Vec<IROp>,
+        stacksize: usize,
+        addr_taken: bool,
+        float_ret: u8,
+    ) -> Self {
+        let has_calls = ir.iter().any(|ir| {
+            matches!(
+                ir,
+                IROp::Call { .. } | IROp::Memcpy { .. } | IROp::Memset { .. }
+            )
+        });
         Function {
             name,
             ir,
             stacksize,
+            has_calls,
+            addr_taken,
+            float_ret,
+            is_static: false,
+            spills: 0,
         }
     }
 }
 
+// Two-operand instructions store their result in `dst`.
+//
+// Floating point values live in the same registers as integers, as the
+// bits of a double. The F ops work on those bits.
+//
+// EQ, NE, LE and LT compare whole registers and set `dst` to 0 or 1.
+// LE and LT compare as signed numbers, or as unsigned ones if `signed`
+// is false, as addresses are. Div, Mod and SHR take the same flag. If and Unless jump when the low `size` bytes of
+// `cond` are nonzero or zero, so that an int whose upper half is left
+// over from 64-bit arithmetic tests the same as its low 32 bits.
 #[derive(Debug, Clone, PartialEq)]
 pub enum IROp {
-    Add,
-    AddImm,
-    Sub,
-    SubImm,
-    Mul,
-    MulImm,
-    Div,
-    Imm,
-    Bprel,
-    Mov,
-    Return,
-    Call(String, usize, [usize; 6]),
-    Label,
-    LabelAddr(String),
-    EQ,
-    NE,
-    LE,
-    LT,
-    AND,
-    OR,
-    XOR,
-    SHL,
-    SHR,
-    Mod,
-    Neg,
-    Jmp,
-    If,
-    Unless,
-    Load(u8),
-    Store(u8),
-    StoreArg(u8),
-    Kill,
+    Add {
+        dst: Reg,
+        src: Reg,
+    },
+    AddImm {
+        dst: Reg,
+        imm: i32,
+    },
+    Sub {
+        dst: Reg,
+        src: Reg,
+    },
+    SubImm {
+        dst: Reg,
+        imm: i32,
+    },
+    Mul {
+        dst: Reg,
+        src: Reg,
+    },
+    MulImm {
+        dst: Reg,
+        imm: i32,
+    },
+    Div {
+        dst: Reg,
+        src: Reg,
+        signed: bool,
+    },
+    Mod {
+        dst: Reg,
+        src: Reg,
+        signed: bool,
+    },
+    Imm {
+        dst: Reg,
+        imm: i64,
+    },
+    Bprel {
+        dst: Reg,
+        offset: usize,
+    },
+    Mov {
+        dst: Reg,
+        src: Reg,
+    },
+    Return {
+        src: Reg,
+    },
+    Call {
+        dst: Reg,
+        name: Symbol,
+        ptr: Option<Reg>, // called through this register instead of `name`
+        args: Vec<Arg>,
+        float_args: Vec<u8>, // sizes of floating point arguments, 0 for others
+        float_ret: u8,
+        noreturn: bool,
+    },
+    Label(Label),
+    LabelAddr {
+        dst: Reg,
+        name: Symbol,
+    },
+    EQ {
+        dst: Reg,
+        src: Reg,
+    },
+    NE {
+        dst: Reg,
+        src: Reg,
+    },
+    LE {
+        dst: Reg,
+        src: Reg,
+        signed: bool,
+    },
+    LT {
+        dst: Reg,
+        src: Reg,
+        signed: bool,
+    },
+    AND {
+        dst: Reg,
+        src: Reg,
+    },
+    OR {
+        dst: Reg,
+        src: Reg,
+    },
+    XOR {
+        dst: Reg,
+        src: Reg,
+    },
+    SHL {
+        dst: Reg,
+        src: Reg,
+    },
+    SHR {
+        dst: Reg,
+        src: Reg,
+        signed: bool,
+    },
+    Neg {
+        dst: Reg,
+    },
+    // Extends the low `size` bytes of `dst` to the whole register, the
+    // way a load of that size would, or with zeros if not `signed`.
+    Extend {
+        dst: Reg,
+        size: u8,
+        signed: bool,
+    },
+    FAdd {
+        dst: Reg,
+        src: Reg,
+    },
+    FSub {
+        dst: Reg,
+        src: Reg,
+    },
+    FMul {
+        dst: Reg,
+        src: Reg,
+    },
+    FDiv {
+        dst: Reg,
+        src: Reg,
+    },
+    FEQ {
+        dst: Reg,
+        src: Reg,
+    },
+    FNE {
+        dst: Reg,
+        src: Reg,
+    },
+    FLE {
+        dst: Reg,
+        src: Reg,
+    },
+    FLT {
+        dst: Reg,
+        src: Reg,
+    },
+    FNeg {
+        dst: Reg,
+    },
+    I2F {
+        dst: Reg,
+    },
+    F2I {
+        dst: Reg,
+    },
+    FLoad {
+        size: u8,
+        dst: Reg,
+        addr: Reg,
+    },
+    FStore {
+        size: u8,
+        addr: Reg,
+        src: Reg,
+    },
+    FStoreArg {
+        size: u8,
+        offset: usize,
+        arg: usize,
+    },
+    Jmp(Label),
+    If {
+        size: u8,
+        cond: Reg,
+        label: Label,
+    },
+    Unless {
+        size: u8,
+        cond: Reg,
+        label: Label,
+    },
+    Load {
+        size: u8,
+        dst: Reg,
+        addr: Reg,
+    },
+    Store {
+        size: u8,
+        addr: Reg,
+        src: Reg,
+    },
+    StoreArg {
+        size: u8,
+        offset: usize,
+        arg: usize,
+    }, // arg: index of the argument register
+    StoreStackArg {
+        size: u8,
+        offset: usize,
+        index: usize, // among the arguments passed on the stack
+    },
+    // Added by the register allocator for a register it spilled.
+    StoreSpill {
+        slot: usize,
+        src: Reg,
+    },
+    LoadSpill {
+        dst: Reg,
+        slot: usize,
+    },
+    // Points the va_list at `ap` past the named parameters: `gp` and
+    // `fp` bytes into the register save area, `stack` arguments into
+    // the ones passed on the stack.
+    VaStart {
+        ap: Reg,
+        gp: usize,
+        fp: usize,
+        stack: usize,
+    },
+    Kill(Reg),
     Nop,
+    Line {
+        line: usize,
+        text: String,
+    },
+    Memcpy {
+        dst: Reg,
+        src: Reg,
+        size: usize,
+    },
+    Memset {
+        dst: Reg,
+        value: u8,
+        size: usize,
+    },
 }
 
-impl From<NodeType> for IROp {
-    fn from(node_type: NodeType) -> Self {
-        match node_type {
-            NodeType::BinOp(op, _, _) => Self::from(op),
-            e => panic!("cannot convert: {:?}", e),
+impl IROp {
+    // Calls `f` on every register operand, destination first.
+    pub fn for_each_reg<F: FnMut(&mut Reg)>(&mut self, mut f: F) {
+        use self::IROp::*;
+        match self {
+            Add { dst, src }
+            | Sub { dst, src }
+            | Mul { dst, src }
+            | Div { dst, src, .. }
+            | Mod { dst, src, .. }
+            | Mov { dst, src }
+            | EQ { dst, src }
+            | NE { dst, src }
+            | LE { dst, src, .. }
+            | LT { dst, src, .. }
+            | AND { dst, src }
+            | OR { dst, src }
+            | XOR { dst, src }
+            | SHL { dst, src }
+            | SHR { dst, src, .. }
+            | FAdd { dst, src }
+            | FSub { dst, src }
+            | FMul { dst, src }
+            | FDiv { dst, src }
+            | FEQ { dst, src }
+            | FNE { dst, src }
+            | FLE { dst, src }
+            | FLT { dst, src }
+            | Load { dst, addr: src, .. }
+            | Store { addr: dst, src, .. }
+            | FLoad { dst, addr: src, .. }
+            | FStore { addr: dst, src, .. }
+            | Memcpy { dst, src, .. } => {
+                f(dst);
+                f(src);
+            }
+            AddImm { dst, .. }
+            | SubImm { dst, .. }
+            | MulImm { dst, .. }
+            | Imm { dst, .. }
+            | Bprel { dst, .. }
+            | LabelAddr { dst, .. }
+            | Neg { dst }
+            | StoreSpill { src: dst, .. }
+            | LoadSpill { dst, .. }
+            | Extend { dst, .. }
+            | FNeg { dst }
+            | I2F { dst }
+            | F2I { dst }
+            | Return { src: dst }
+            | If { cond: dst, .. }
+            | Unless { cond: dst, .. }
+            | Memset { dst, .. }
+            | VaStart { ap: dst, .. }
+            | Kill(dst) => f(dst),
+            Call { dst, ptr, args, .. } => {
+                f(dst);
+                if let Some(ptr) = ptr {
+                    f(ptr);
+                }
+                for arg in args {
+                    if let Arg::Reg(r) = arg {
+                        f(r);
+                    }
+                }
+            }
+            Label(_)
+            | Jmp(_)
+            | StoreArg { .. }
+            | FStoreArg { .. }
+            | StoreStackArg { .. }
+            | Nop
+            | Line { .. } => (),
         }
     }
 }
 
-impl From<TokenType> for IROp {
-    fn from(token_type: TokenType) -> Self {
-        match token_type {
-            TokenType::Plus => IROp::Add,
-            TokenType::Minus => IROp::Sub,
-            TokenType::Mul => IROp::Mul,
-            TokenType::Div => IROp::Div,
-            TokenType::LeftAngleBracket | TokenType::RightAngleBracket => IROp::LT,
-            e => panic!("cannot convert: {:?}", e),
-        }
+// How many integer and floating point arguments are passed in registers.
+// The rest go on the stack, in order.
+pub const INT_ARG_REGS: usize = 6;
+pub const FLOAT_ARG_REGS: usize = 8;
+
+// How many bytes If and Unless test of a value of type `ty`.
+fn cond_size(ty: &Type) -> u8 {
+    if ty.is_float() {
+        8
+    } else {
+        ty.size as u8
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct IR {
-    pub op: IROp,
-    pub lhs: Option<usize>,
-    pub rhs: Option<usize>,
+fn float_size(ty: &Type) -> u8 {
+    if ty.is_float() {
+        ty.size as u8
+    } else {
+        0
+    }
 }
 
-impl IR {
-    fn new(op: IROp, lhs: Option<usize>, rhs: Option<usize>) -> Self {
-        Self { op, lhs, rhs }
+// Does the lvalue live in the current stack frame?
+fn is_local(node: &Node) -> bool {
+    match node.op {
+        NodeType::Lvar(_) => true,
+        NodeType::Dot(ref expr, _, _) => is_local(expr),
+        _ => false,
     }
 }
 
-fn kill(r: Option<usize>) {
-    add(IROp::Kill, r, None);
+// Maps a binary or compound assignment operator on operands of type
+// `ty` to its instruction. Comparisons of pointers and unsigned integers
+// are unsigned.
+fn binop(op: &TokenType, dst: Reg, src: Reg, ty: &Type) -> IROp {
+    use self::TokenType::*;
+    let signed = !ty.unsigned;
+    if ty.is_float() {
+        return match op {
+            Plus | AddEQ => IROp::FAdd { dst, src },
+            Minus | SubEQ => IROp::FSub { dst, src },
+            Mul | MulEQ => IROp::FMul { dst, src },
+            Div | DivEQ => IROp::FDiv { dst, src },
+            EQ => IROp::FEQ { dst, src },
+            NE => IROp::FNE { dst, src },
+            LE => IROp::FLE { dst, src },
+            LeftAngleBracket | RightAngleBracket => IROp::FLT { dst, src },
+            e => panic!("unexpected floating point op: {:?}", e),
+        };
+    }
+    match op {
+        Plus | AddEQ => IROp::Add { dst, src },
+        Minus | SubEQ => IROp::Sub { dst, src },
+        Mul | MulEQ => IROp::Mul { dst, src },
+        Div | DivEQ => IROp::Div { dst, src, signed },
+        Mod | ModEQ => IROp::Mod { dst, src, signed },
+        EQ => IROp::EQ { dst, src },
+        NE => IROp::NE { dst, src },
+        LE => IROp::LE {
+            dst,
+            src,
+            signed: signed && !matches!(ty.ty, Ctype::Ptr(_)),
+        },
+        LeftAngleBracket | RightAngleBracket => IROp::LT {
+            dst,
+            src,
+            signed: signed && !matches!(ty.ty, Ctype::Ptr(_)),
+        },
+        And | BitandEQ => IROp::AND { dst, src },
+        VerticalBar | BitorEQ => IROp::OR { dst, src },
+        Hat | XorEQ => IROp::XOR { dst, src },
+        SHL | ShlEQ => IROp::SHL { dst, src },
+        SHR | ShrEQ => IROp::SHR { dst, src, signed },
+        e => panic!("unexpected op: {:?}", e),
+    }
 }
 
-fn label(x: Option<usize>) {
-    add(IROp::Label, x, None);
-}
+// The register save area holds the six integer argument registers, 8
+// bytes each, then the eight xmm registers, 16 bytes each.
+const GP_SAVE: usize = 8 * INT_ARG_REGS;
+const FP_SAVE: usize = GP_SAVE + 16 * FLOAT_ARG_REGS;
 
-fn jmp(x: Option<usize>) {
-    add(IROp::Jmp, x, None);
-}
+impl IrGen {
+    fn kill(&mut self, r: Reg) {
+        self.add(IROp::Kill(r));
+    }
 
-fn load(ty: &Type, dst: Option<usize>, src: Option<usize>) {
-    add(IROp::Load(ty.size as u8), dst, src);
-}
+    fn label(&mut self, x: Label) {
+        self.add(IROp::Label(x));
+    }
 
-fn store(ty: &Type, dst: Option<usize>, src: Option<usize>) {
-    add(IROp::Store(ty.size as u8), dst, src);
-}
+    fn jmp(&mut self, x: Label) {
+        self.add(IROp::Jmp(x));
+    }
 
-fn store_arg(ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
-    add(IROp::StoreArg(ty.size as u8), bpoff, argreg);
-}
+    fn load(&mut self, ty: &Type, dst: Reg, addr: Reg) {
+        let size = ty.size as u8;
+        if ty.is_float() {
+            self.add(IROp::FLoad { size, dst, addr });
+        } else {
+            self.add(IROp::Load { size, dst, addr });
+        }
+    }
 
-// Quoted from 9cc
-// > In C, all expressions that can be written on the left-hand side of
-// > the '=' operator must have an address in memory. In other words, if
-// > you can apply the '&' operator to take an address of some
-// > expression E, you can assign E to a new value.
-//
-// > Other expressions, such as `1+2`, cannot be written on the lhs of
-// > '=', since they are just temporary values that don't have an address.
-//
-// > The stuff that can be written on the lhs of '=' is called lvalue.
-// > Other values are called rvalue. An lvalue is essentially an address.
-//
-// > When lvalues appear on the rvalue context, they are converted to
-// > rvalues by loading their values from their addresses. You can think
-// > '&' as an operator that suppresses such automatic lvalue-to-rvalue
-// > conversion.
-//
-// > This function evaluates a given node as an lvalue.
+    fn store(&mut self, ty: &Type, addr: Reg, src: Reg) {
+        let size = ty.size as u8;
+        if ty.is_float() {
+            self.add(IROp::FStore { size, addr, src });
+        } else {
+            self.add(IROp::Store { size, addr, src });
+        }
+    }
 
-fn gen_lval(node: Box<Node>) -> Option<usize> {
-    match node.op {
-        NodeType::Deref(expr) => gen_expr(expr),
-        NodeType::Dot(ref expr, _, ref offset) => {
-            let r = gen_lval(expr.clone());
-            add(IROp::AddImm, r, Some(*offset));
-            r
+    // Replaces the address in `r` with the value there. A struct is
+    // handled through its address, so that stays.
+    fn load_value(&mut self, ty: &Type, r: Reg) {
+        if !matches!(ty.ty, Ctype::Struct(_)) {
+            self.load(ty, r, r);
         }
-        NodeType::Lvar(Scope::Local(offset)) => {
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Bprel, r, Some(offset));
-            r
+    }
+
+    // `arg` counts integer and floating point parameters separately, as
+    // they arrive in different registers.
+    fn store_arg(&mut self, ty: &Type, offset: usize, arg: usize) {
+        let size = ty.size as u8;
+        if ty.is_float() {
+            self.add(IROp::FStoreArg { size, offset, arg });
+        } else {
+            self.add(IROp::StoreArg { size, offset, arg });
         }
-        NodeType::Gvar(name, _, _) => {
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::LabelAddr(name), r, None);
-            r
+    }
+
+    // Quoted from 9cc
+    // > In C, all expressions that can be written on the left-hand side of
+    // > the '=' operator must have an address in memory. In other words, if
+    // > you can apply the '&' operator to take an address of some
+    // > expression E, you can assign E to a new value.
+    //
+    // > Other expressions, such as `1+2`, cannot be written on the lhs of
+    // > '=', since they are just temporary values that don't have an address.
+    //
+    // > The stuff that can be written on the lhs of '=' is called lvalue.
+    // > Other values are called rvalue. An lvalue is essentially an address.
+    //
+    // > When lvalues appear on the rvalue context, they are converted to
+    // > rvalues by loading their values from their addresses. You can think
+    // > '&' as an operator that suppresses such automatic lvalue-to-rvalue
+    // > conversion.
+    //
+    // > This function evaluates a given node as an lvalue.
+
+    fn gen_lval(&mut self, node: Node) -> Reg {
+        match node.op {
+            NodeType::Deref(expr) => self.gen_expr(*expr),
+            NodeType::Dot(ref expr, _, ref offset) => {
+                let r = self.gen_lval(*expr.clone());
+                self.add(IROp::AddImm {
+                    dst: r,
+                    imm: *offset as i32,
+                });
+                r
+            }
+            NodeType::Lvar(Scope::Local(offset)) => {
+                let r = self.new_reg();
+                self.add(IROp::Bprel { dst: r, offset });
+                r
+            }
+            NodeType::Gvar(name, _, _) => {
+                let r = self.new_reg();
+                self.add(IROp::LabelAddr { dst: r, name });
+                r
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn gen_binop(ty: IROp, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
-    let r1 = gen_expr(lhs);
-    let r2 = gen_expr(rhs);
-    add(ty, r1, r2);
-    kill(r2);
-    r1
-}
+    // Sema has converted the operands to their common type when that is
+    // floating point or unsigned, so the left one tells signed from unsigned
+    // comparisons. A pointer may be compared with an integer, which compares
+    // as a pointer.
+    fn gen_binop(&mut self, op: &TokenType, lhs: Node, rhs: Node) -> Reg {
+        let ty = match rhs.ty.ty {
+            Ctype::Ptr(_) => rhs.ty.clone(),
+            _ => lhs.ty.clone(),
+        };
+        let r1 = self.gen_expr(lhs);
+        let r2 = self.gen_expr(rhs);
+        let op = binop(op, r1, r2, &ty);
+        let wraps = matches!(
+            op,
+            IROp::Add { .. } | IROp::Sub { .. } | IROp::Mul { .. } | IROp::SHL { .. }
+        );
+        self.add(op);
+        self.kill(r2);
+        if wraps {
+            self.clear_upper(&ty, r1);
+        }
+        r1
+    }
 
-fn get_inc_scale(ty: &Type) -> usize {
-    match ty.ty {
-        Ctype::Ptr(ref ptr_to) => ptr_to.size,
-        _ => 1,
+    // An unsigned int wraps around at 32 bits, where the 64-bit arithmetic
+    // it is done with does not.
+    fn clear_upper(&mut self, ty: &Type, r: Reg) {
+        if ty.unsigned && ty.size == 4 {
+            self.add(IROp::Extend {
+                dst: r,
+                size: 4,
+                signed: false,
+            });
+        }
     }
-}
 
-fn gen_pre_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
-    let addr = gen_lval(expr);
-    let val = *NUM_REGS.lock().unwrap();
-    *NUM_REGS.lock().unwrap() += 1;
-    load(ty, Some(val), addr);
-    add(
-        IROp::AddImm,
-        Some(val),
-        Some(num as usize * get_inc_scale(ty)),
-    );
-    store(ty, addr, Some(val));
-    kill(addr);
-    val as i32
-}
+    fn gen_pre_inc(&mut self, ty: &Type, expr: Node, num: i32) -> Reg {
+        let addr = self.gen_lval(expr);
+        let val = self.new_reg();
+        self.load(ty, val, addr);
+        if ty.is_float() {
+            let one = self.gen_fnum(num as f64);
+            self.add(IROp::FAdd { dst: val, src: one });
+            self.kill(one);
+        } else {
+            self.add(IROp::AddImm {
+                dst: val,
+                imm: num * ty.step() as i32,
+            });
+        }
+        self.store(ty, addr, val);
+        self.kill(addr);
+        val
+    }
 
-fn gen_post_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
-    let val = gen_pre_inc(ty, expr, num);
-    add(
-        IROp::SubImm,
-        Some(val as usize),
-        Some(num as usize * get_inc_scale(ty)),
-    );
-    val as i32
-}
+    fn gen_post_inc(&mut self, ty: &Type, expr: Node, num: i32) -> Reg {
+        let val = self.gen_pre_inc(ty, expr, num);
+        if ty.is_float() {
+            let one = self.gen_fnum(num as f64);
+            self.add(IROp::FSub { dst: val, src: one });
+            self.kill(one);
+        } else {
+            self.add(IROp::SubImm {
+                dst: val,
+                imm: num * ty.step() as i32,
+            });
+        }
+        val
+    }
 
-fn to_assign_op(op: &TokenType) -> IROp {
-    use self::TokenType::*;
-    match op {
-        MulEQ => IROp::Mul,
-        DivEQ => IROp::Div,
-        ModEQ => IROp::Mod,
-        AddEQ => IROp::Add,
-        SubEQ => IROp::Sub,
-        ShlEQ => IROp::SHL,
-        ShrEQ => IROp::SHR,
-        BitandEQ => IROp::AND,
-        XorEQ => IROp::XOR,
-        BitorEQ => IROp::OR,
-        e => panic!("unexpected op: {:?}", e),
+    fn gen_fnum(&mut self, val: f64) -> Reg {
+        let r = self.new_reg();
+        self.add(IROp::Imm {
+            dst: r,
+            imm: val.to_bits() as i64,
+        });
+        r
     }
-}
 
-fn gen_assign_op(op: &TokenType, ty: &Type, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
-    let src = gen_expr(rhs);
-    let dst = gen_lval(lhs);
-    let val = Some(*NUM_REGS.lock().unwrap());
-    *NUM_REGS.lock().unwrap() += 1;
-
-    load(ty, val, dst);
-    add(to_assign_op(op), val, src);
-    kill(src);
-    store(ty, dst, val);
-    kill(dst);
-    val
-}
+    fn gen_assign_op(&mut self, op: &TokenType, ty: &Type, lhs: Node, rhs: Node) -> Reg {
+        let src = self.gen_expr(rhs);
+        let dst = self.gen_lval(lhs);
+        let val = self.new_reg();
 
-fn gen_expr(node: Box<Node>) -> Option<usize> {
-    let node = *node;
-    match node.op {
-        NodeType::Num(val) => {
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Imm, r, Some(val as usize));
-            r
-        }
-        NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
-            let r = gen_lval(Box::new(node.clone()));
-            load(&node.ty, r, r);
-            r
-        }
-        NodeType::Call(name, args) => {
-            let mut args_ir: [usize; 6] = [0; 6];
-            for i in 0..args.len() {
-                args_ir[i] = gen_expr(Box::new(args[i].clone())).unwrap();
+        self.load(ty, val, dst);
+        self.add(binop(op, val, src, ty));
+        self.kill(src);
+        self.store(ty, dst, val);
+        self.kill(dst);
+        val
+    }
+
+    // __builtin_memcpy and __builtin_memset with a constant size become
+    // single IR ops. Anything else is left to the C library.
+    fn gen_builtin(&mut self, name: Symbol, args: &[Node]) -> Option<Reg> {
+        let size = match args {
+            [_, _, Node {
+                op: NodeType::Num(n),
+                ..
+            }] if *n >= 0 => *n as usize,
+            _ => return None,
+        };
+
+        match &*name.as_str() {
+            "__builtin_memcpy" => {
+                let dst = self.gen_expr(args[0].clone());
+                let src = self.gen_expr(args[1].clone());
+                self.add(IROp::Memcpy { dst, src, size });
+                self.kill(src);
+                Some(dst)
+            }
+            "__builtin_memset" => {
+                let value = match args[1].op {
+                    NodeType::Num(n) => n as u8,
+                    _ => return None,
+                };
+                let dst = self.gen_expr(args[0].clone());
+                self.add(IROp::Memset { dst, value, size });
+                Some(dst)
             }
+            _ => None,
+        }
+    }
 
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
+    // Reads the next argument of type `ty` from the va_list `ap` points to:
+    // from the register save area while its offset for the type's register
+    // class is short of the end, from the stack after that.
+    fn gen_va_arg(&mut self, ap: Node, ty: &Type) -> Reg {
+        let (field, end, step) = if ty.is_float() {
+            (4, FP_SAVE, 16)
+        } else {
+            (0, GP_SAVE, 8)
+        };
+        let on_stack = self.new_label();
+        let done = self.new_label();
+        let ap = self.gen_expr(ap);
 
-            add(IROp::Call(name, args.len(), args_ir), r, None);
+        let offset_addr = self.new_reg();
+        self.add(IROp::Mov {
+            dst: offset_addr,
+            src: ap,
+        });
+        self.add(IROp::AddImm {
+            dst: offset_addr,
+            imm: field,
+        });
+        let offset = self.new_reg();
+        self.add(IROp::Load {
+            size: 4,
+            dst: offset,
+            addr: offset_addr,
+        });
+        let cond = self.new_reg();
+        let limit = self.new_reg();
+        self.add(IROp::Mov {
+            dst: cond,
+            src: offset,
+        });
+        self.add(IROp::Imm {
+            dst: limit,
+            imm: end as i64,
+        });
+        self.add(IROp::LT {
+            dst: cond,
+            src: limit,
+            signed: true,
+        });
+        self.kill(limit);
+        self.add(IROp::Unless {
+            size: 8,
+            cond,
+            label: on_stack,
+        });
+        self.kill(cond);
 
-            for arg in args_ir.iter().take(args.len()) {
-                kill(Some(*arg));
+        // reg_save_area + offset, and offset moves on.
+        let addr = self.new_reg();
+        self.add(IROp::Mov { dst: addr, src: ap });
+        self.add(IROp::AddImm { dst: addr, imm: 16 });
+        self.add(IROp::Load {
+            size: 8,
+            dst: addr,
+            addr,
+        });
+        self.add(IROp::Add {
+            dst: addr,
+            src: offset,
+        });
+        self.add(IROp::AddImm {
+            dst: offset,
+            imm: step,
+        });
+        self.add(IROp::Store {
+            size: 4,
+            addr: offset_addr,
+            src: offset,
+        });
+        self.jmp(done);
+
+        // overflow_arg_area, which moves on by 8.
+        self.label(on_stack);
+        self.add(IROp::AddImm { dst: ap, imm: 8 });
+        self.add(IROp::Load {
+            size: 8,
+            dst: addr,
+            addr: ap,
+        });
+        let next = self.new_reg();
+        self.add(IROp::Mov {
+            dst: next,
+            src: addr,
+        });
+        self.add(IROp::AddImm { dst: next, imm: 8 });
+        self.add(IROp::Store {
+            size: 8,
+            addr: ap,
+            src: next,
+        });
+        self.kill(next);
+
+        self.label(done);
+        self.kill(offset);
+        self.kill(offset_addr);
+        self.kill(ap);
+        self.load(ty, addr, addr);
+        addr
+    }
+
+    // Arguments stay in registers until the call. If that would leave no
+    // register for the result, each one is stored to a frame slot instead
+    // as soon as it is computed.
+    fn gen_call(&mut self, name: Symbol, ptr: Option<Reg>, args: Vec<Node>, ty: &Type) -> Reg {
+        let spill = args.len() >= REGS_N;
+        let mut args_ir = vec![];
+        let mut float_args = vec![];
+        for arg in args {
+            float_args.push(float_size(&arg.ty));
+            let r = self.gen_expr(arg);
+            if !spill {
+                args_ir.push(Arg::Reg(r));
+                continue;
             }
-            r
-        }
-        NodeType::Addr(expr) => gen_lval(expr),
-        NodeType::Deref(expr) => {
-            let r = gen_expr(expr);
-            load(&node.ty, r, r);
-            r
+            let offset = self.new_slot();
+            let addr = self.new_reg();
+            self.add(IROp::Bprel { dst: addr, offset });
+            self.add(IROp::Store {
+                size: 8,
+                addr,
+                src: r,
+            });
+            self.kill(addr);
+            self.kill(r);
+            args_ir.push(Arg::Slot(offset));
         }
-        NodeType::StmtExpr(body) => {
-            let orig_label = *RETURN_LABEL.lock().unwrap();
-            let orig_reg = *RETURN_REG.lock().unwrap();
-            *RETURN_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
-            *NLABEL.lock().unwrap() += 1;
-            let r = *NUM_REGS.lock().unwrap();
-            *NUM_REGS.lock().unwrap() += 1;
-            *RETURN_REG.lock().unwrap() = r;
-
-            gen_stmt(*body);
-            label(Some(*RETURN_LABEL.lock().unwrap()));
-
-            *RETURN_LABEL.lock().unwrap() = orig_label;
-            *RETURN_REG.lock().unwrap() = orig_reg;
-            Some(r)
+
+        // A call through a pointer returns its value in the pointer's
+        // register, so that it needs no more registers than a direct call.
+        let r = ptr.unwrap_or_else(|| self.new_reg());
+        self.add(IROp::Call {
+            dst: r,
+            name,
+            ptr,
+            args: args_ir.clone(),
+            float_args,
+            float_ret: float_size(ty),
+            noreturn: ptr.is_none() && self.noreturn.contains(&name),
+        });
+
+        for arg in args_ir {
+            if let Arg::Reg(r) = arg {
+                self.kill(r);
+            }
         }
-        NodeType::BinOp(op, lhs, rhs) => {
-            use self::TokenType::*;
-            match op {
-                Equal => {
-                    let rhs = gen_expr(rhs);
-                    let lhs = gen_lval(lhs);
-                    store(&node.ty, lhs, rhs);
-                    kill(lhs);
-                    rhs
+        r
+    }
+
+    fn gen_expr(&mut self, node: Node) -> Reg {
+        match node.op {
+            NodeType::Num(val) => {
+                let r = self.new_reg();
+                self.add(IROp::Imm { dst: r, imm: val });
+                r
+            }
+            NodeType::FNum(val) => self.gen_fnum(val),
+            NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
+                let r = self.gen_lval(node.clone());
+                self.load_value(&node.ty, r);
+                r
+            }
+            NodeType::Call(name, args) => {
+                if let Some(r) = self.gen_builtin(name, &args) {
+                    return r;
                 }
-                Plus => gen_binop(IROp::Add, lhs, rhs),
-                Minus => gen_binop(IROp::Sub, lhs, rhs),
-                Logand => {
-                    let x = Some(*NLABEL.lock().unwrap());
-                    *NLABEL.lock().unwrap() += 1;
-
-                    let r1 = gen_expr(lhs);
-                    add(IROp::Unless, r1, x);
-                    let r2 = gen_expr(rhs);
-                    add(IROp::Mov, r1, r2);
-                    kill(r2);
-                    add(IROp::Unless, r1, x);
-                    add(IROp::Imm, r1, Some(1));
-                    label(x);
-                    r1
+                let name = match &*name.as_str() {
+                    "__builtin_memcpy" => Symbol::intern("memcpy"),
+                    "__builtin_memset" => Symbol::intern("memset"),
+                    _ => name,
+                };
+                self.gen_call(name, None, args, &node.ty)
+            }
+            NodeType::CallPtr(expr, args) => {
+                let ptr = self.gen_expr(*expr);
+                self.gen_call(Symbol::intern(""), Some(ptr), args, &node.ty)
+            }
+            NodeType::VaStart(ap) => {
+                let (gp, fp, stack) = self.named_args;
+                let ap = self.gen_expr(*ap);
+                self.add(IROp::VaStart { ap, gp, fp, stack });
+                ap
+            }
+            NodeType::VaArg(ap) => self.gen_va_arg(*ap, &node.ty),
+            NodeType::Addr(expr) => {
+                if is_local(&expr) {
+                    self.addr_taken = true;
                 }
-                Logor => {
-                    let x = Some(*NLABEL.lock().unwrap());
-                    *NLABEL.lock().unwrap() += 1;
-                    let y = Some(*NLABEL.lock().unwrap());
-                    *NLABEL.lock().unwrap() += 1;
-
-                    let r1 = gen_expr(lhs);
-                    add(IROp::Unless, r1, x);
-                    add(IROp::Imm, r1, Some(1));
-                    jmp(y);
-                    label(x);
-
-                    let r2 = gen_expr(rhs);
-                    add(IROp::Mov, r1, r2);
-                    kill(r2);
-                    add(IROp::Unless, r1, y);
-                    add(IROp::Imm, r1, Some(1));
-                    label(y);
-                    r1
+                self.gen_lval(*expr)
+            }
+            NodeType::Deref(expr) => {
+                let r = self.gen_expr(*expr);
+                self.load_value(&node.ty, r);
+                r
+            }
+            NodeType::StmtExpr(body) => {
+                let orig_label = self.return_label;
+                let orig_reg = self.return_reg;
+                self.return_label = self.new_label();
+                let r = self.new_reg();
+                self.return_reg = r;
+
+                self.gen_stmt(*body);
+                self.label(self.return_label);
+
+                self.return_label = orig_label;
+                self.return_reg = orig_reg;
+                r
+            }
+            NodeType::BinOp(op, lhs, rhs) => {
+                use self::TokenType::*;
+                match op {
+                    Equal if matches!(node.ty.ty, Ctype::Struct(_)) => {
+                        let src = self.gen_lval(*rhs);
+                        let dst = self.gen_lval(*lhs);
+                        self.add(IROp::Memcpy {
+                            dst,
+                            src,
+                            size: node.ty.size,
+                        });
+                        self.kill(dst);
+                        src
+                    }
+                    Equal => {
+                        let rhs = self.gen_expr(*rhs);
+                        let lhs = self.gen_lval(*lhs);
+                        self.store(&node.ty, lhs, rhs);
+                        self.kill(lhs);
+                        rhs
+                    }
+                    Logand => {
+                        let x = self.new_label();
+
+                        let size = cond_size(&lhs.ty);
+                        let r1 = self.gen_expr(*lhs);
+                        self.add(IROp::Unless {
+                            size,
+                            cond: r1,
+                            label: x,
+                        });
+                        let size = cond_size(&rhs.ty);
+                        let r2 = self.gen_expr(*rhs);
+                        self.add(IROp::Mov { dst: r1, src: r2 });
+                        self.kill(r2);
+                        self.add(IROp::Unless {
+                            size,
+                            cond: r1,
+                            label: x,
+                        });
+                        self.add(IROp::Imm { dst: r1, imm: 1 });
+                        self.label(x);
+                        r1
+                    }
+                    Logor => {
+                        let x = self.new_label();
+                        let y = self.new_label();
+
+                        let size = cond_size(&lhs.ty);
+                        let r1 = self.gen_expr(*lhs);
+                        self.add(IROp::If {
+                            size,
+                            cond: r1,
+                            label: x,
+                        });
+                        let size = cond_size(&rhs.ty);
+                        let r2 = self.gen_expr(*rhs);
+                        self.add(IROp::Mov { dst: r1, src: r2 });
+                        self.kill(r2);
+                        self.add(IROp::Unless {
+                            size,
+                            cond: r1,
+                            label: y,
+                        });
+                        self.label(x);
+                        self.add(IROp::Imm { dst: r1, imm: 1 });
+                        self.label(y);
+                        r1
+                    }
+                    MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ
+                    | BitorEQ => self.gen_assign_op(&op, &node.ty, *lhs, *rhs),
+                    Comma => {
+                        let r = self.gen_expr(*lhs);
+                        self.kill(r);
+                        self.gen_expr(*rhs)
+                    }
+                    _ => self.gen_binop(&op, *lhs, *rhs),
                 }
-                MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ
-                | BitorEQ => gen_assign_op(&op, &node.ty, lhs, rhs),
-                EQ => gen_binop(IROp::EQ, lhs, rhs),
-                NE => gen_binop(IROp::NE, lhs, rhs),
-                LE => gen_binop(IROp::LE, lhs, rhs),
-                And => gen_binop(IROp::AND, lhs, rhs),
-                VerticalBar => gen_binop(IROp::OR, lhs, rhs),
-                Hat => gen_binop(IROp::XOR, lhs, rhs),
-                SHL => gen_binop(IROp::SHL, lhs, rhs),
-                SHR => gen_binop(IROp::SHR, lhs, rhs),
-                Mod => gen_binop(IROp::Mod, lhs, rhs),
-                Comma => {
-                    kill(gen_expr(lhs));
-                    gen_expr(rhs)
+            }
+            NodeType::Neg(expr) => {
+                let float = expr.ty.is_float();
+                let r = self.gen_expr(*expr);
+                if float {
+                    self.add(IROp::FNeg { dst: r });
+                } else {
+                    self.add(IROp::Neg { dst: r });
+                    self.clear_upper(&node.ty, r);
                 }
-                _ => gen_binop(IROp::from(op), lhs, rhs),
+                r
             }
+            NodeType::Cast(expr) => {
+                let from_float = expr.ty.is_float();
+                let r = self.gen_expr(*expr);
+                // Registers hold every value extended to 64 bits, and float
+                // as double, so casts between floating point types and to a
+                // wider integer have nothing to do.
+                if node.ty.is_float() {
+                    if !from_float {
+                        self.add(IROp::I2F { dst: r });
+                    }
+                    return r;
+                }
+                if from_float {
+                    self.add(IROp::F2I { dst: r });
+                }
+                let size = node.ty.size as u8;
+                if 0 < size && size < 8 {
+                    self.add(IROp::Extend {
+                        dst: r,
+                        size,
+                        signed: !node.ty.unsigned,
+                    });
+                }
+                r
+            }
+            NodeType::PostInc(expr) => self.gen_post_inc(&node.ty, *expr, 1),
+            NodeType::PostDec(expr) => self.gen_post_inc(&node.ty, *expr, -1),
+            NodeType::Ternary(cond, then, els) => {
+                //      cond then els  then
+                // return 1 ? 3 : 5; => 3
+                let x = self.new_label();
+                let y = self.new_label();
+
+                // `cond ?: els` yields cond itself when it is true, made
+                // floating point if els is.
+                if let NodeType::Null = then.op {
+                    let size = cond_size(&cond.ty);
+                    let widen = node.ty.is_float() && !cond.ty.is_float();
+                    let r = self.gen_expr(*cond);
+                    self.add(IROp::If {
+                        size,
+                        cond: r,
+                        label: if widen { x } else { y },
+                    });
+                    let r3 = self.gen_expr(*els);
+                    self.add(IROp::Mov { dst: r, src: r3 });
+                    self.kill(r3);
+                    if widen {
+                        self.jmp(y);
+                        self.label(x);
+                        self.add(IROp::I2F { dst: r });
+                    }
+                    self.label(y);
+                    return r;
+                }
+
+                self.gen_branch(*cond, x, false);
+                let r = self.gen_expr(*then);
+                self.jmp(y);
+
+                self.label(x);
+                let r3 = self.gen_expr(*els);
+                self.add(IROp::Mov { dst: r, src: r3 });
+                self.kill(r3);
+                self.label(y);
+                r
+            }
+            NodeType::Exclamation(expr) => {
+                let ty = expr.ty.clone();
+                let lhs = self.gen_expr(*expr);
+                let rhs = self.new_reg();
+                self.add(IROp::Imm { dst: rhs, imm: 0 });
+                self.add(binop(&TokenType::EQ, lhs, rhs, &ty));
+                self.kill(rhs);
+                lhs
+            }
+            e => unreachable!("{:?}", e),
         }
-        NodeType::Neg(expr) => {
-            let r = gen_expr(expr);
-            add(IROp::Neg, r, None);
-            r
-        }
-        NodeType::PostInc(expr) => Some(gen_post_inc(&node.ty, expr, 1) as usize),
-        NodeType::PostDec(expr) => Some(gen_post_inc(&node.ty, expr, -1) as usize),
-        NodeType::Ternary(cond, then, els) => {
-            //      cond then els  then
-            // return 1 ? 3 : 5; => 3
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let y = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
-
-            add(IROp::Unless, r, x);
-            let r2 = gen_expr(then);
-            add(IROp::Mov, r, r2);
-            kill(r2);
-            jmp(y);
-
-            label(x);
-            let r3 = gen_expr(els);
-            add(IROp::Mov, r, r3);
-            kill(r3);
-            label(y);
-            r
-        }
-        NodeType::Exclamation(expr) => {
-            let lhs = gen_expr(expr);
-            let rhs = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Imm, rhs, Some(0));
-            add(IROp::EQ, lhs, rhs);
-            kill(rhs);
-            lhs
-        }
-        e => unreachable!("{:?}", e),
     }
-}
 
-fn gen_stmt(node: Node) {
-    match node.op {
-        NodeType::Null => return,
-        NodeType::Vardef(_, init_may, Scope::Local(offset)) => {
-            if let Some(init) = init_may {
-                let rhs = gen_expr(init);
-                let lhs = Some(*NUM_REGS.lock().unwrap());
-                *NUM_REGS.lock().unwrap() += 1;
-                add(IROp::Bprel, lhs, Some(offset));
-                store(&node.ty, lhs, rhs);
-                kill(lhs);
-                kill(rhs);
-            }
-            return;
-        }
-        NodeType::If(cond, then, els_may) => {
-            if let Some(els) = els_may {
-                let x = Some(*NLABEL.lock().unwrap());
-                *NLABEL.lock().unwrap() += 1;
-                let y = Some(*NLABEL.lock().unwrap());
-                *NLABEL.lock().unwrap() += 1;
-                let r = gen_expr(cond.clone());
-                add(IROp::Unless, r, x);
-                kill(r);
-                gen_stmt(*then.clone());
-                jmp(y);
-                label(x);
-                gen_stmt(*els);
-                label(y);
-                return;
-            }
-
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
-            add(IROp::Unless, r, x);
-            kill(r);
-            gen_stmt(*then);
-            label(x);
+    // Jumps to `dest` if `node` is nonzero (`when` is true) or zero (`when`
+    // is false). &&, || and ! are lowered straight into jumps, so a
+    // condition made of them never computes a 0 or 1 on the way.
+    fn gen_branch(&mut self, node: Node, dest: usize, when: bool) {
+        match node.op {
+            NodeType::BinOp(TokenType::Logand, lhs, rhs) => {
+                if when {
+                    let skip = self.new_label();
+                    self.gen_branch(*lhs, skip, false);
+                    self.gen_branch(*rhs, dest, true);
+                    self.label(skip);
+                } else {
+                    self.gen_branch(*lhs, dest, false);
+                    self.gen_branch(*rhs, dest, false);
+                }
+            }
+            NodeType::BinOp(TokenType::Logor, lhs, rhs) => {
+                if when {
+                    self.gen_branch(*lhs, dest, true);
+                    self.gen_branch(*rhs, dest, true);
+                } else {
+                    let skip = self.new_label();
+                    self.gen_branch(*lhs, skip, true);
+                    self.gen_branch(*rhs, dest, false);
+                    self.label(skip);
+                }
+            }
+            NodeType::Exclamation(expr) => self.gen_branch(*expr, dest, !when),
+            _ => {
+                let float = node.ty.is_float();
+                let size = cond_size(&node.ty);
+                let r = self.gen_expr(node);
+                // -0.0 is false too.
+                if float {
+                    let zero = self.new_reg();
+                    self.add(IROp::Imm { dst: zero, imm: 0 });
+                    self.add(IROp::FNE { dst: r, src: zero });
+                    self.kill(zero);
+                }
+                if when {
+                    self.add(IROp::If {
+                        size,
+                        cond: r,
+                        label: dest,
+                    });
+                } else {
+                    self.add(IROp::Unless {
+                        size,
+                        cond: r,
+                        label: dest,
+                    });
+                }
+                self.kill(r);
+            }
         }
-        NodeType::For(init, cond, inc, body) => {
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let y = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let orig = *BREAK_LABEL.lock().unwrap();
-            *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
-            *NLABEL.lock().unwrap() += 1;
-
-            gen_stmt(*init);
-            label(x);
-            if !cond.is_null() {
-                let r2 = gen_expr(cond);
-                add(IROp::Unless, r2, y);
-                kill(r2);
-            }
-            gen_stmt(*body);
-            if !inc.is_null() {
-                gen_stmt(*inc);
-            }
-            jmp(x);
-            label(y);
-            label(Some(*BREAK_LABEL.lock().unwrap()));
-            *BREAK_LABEL.lock().unwrap() = orig;
+    }
+
+    // Marks the start of a statement with its source line so that the
+    // backend can annotate the output.
+    fn line_marker(&mut self, node: &Node) {
+        if let Some(ref t) = node.token {
+            self.add(IROp::Line {
+                line: t.get_line_number(),
+                text: t.source_line(),
+            });
         }
-        NodeType::DoWhile(body, cond) => {
-            let x = Some(*NLABEL.lock().unwrap());
-            *NLABEL.lock().unwrap() += 1;
-            let orig = *BREAK_LABEL.lock().unwrap();
-            *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
-            *NLABEL.lock().unwrap() += 1;
-            label(x);
-            gen_stmt(*body);
-            let r = gen_expr(cond);
-            add(IROp::If, r, x);
-            kill(r);
-            label(Some(*BREAK_LABEL.lock().unwrap()));
-            *BREAK_LABEL.lock().unwrap() = orig;
+    }
+
+    fn gen_stmt(&mut self, node: Node) {
+        if !matches!(node.op, NodeType::Null | NodeType::CompStmt(_)) {
+            self.line_marker(&node);
         }
-        NodeType::Break => {
-            let break_label = *BREAK_LABEL.lock().unwrap();
-            if break_label == 0 {
-                panic!("stray 'break' statement");
+
+        match node.op {
+            NodeType::Null => (),
+            NodeType::Vardef(_, init_may, Scope::Local(offset)) => {
+                // --ftrivial-auto-var-init=zero
+                if self.zero_locals {
+                    let dst = self.new_reg();
+                    self.add(IROp::Bprel { dst, offset });
+                    self.add(IROp::Memset {
+                        dst,
+                        value: 0,
+                        size: node.ty.size,
+                    });
+                    self.kill(dst);
+                }
+                if let Some(init) = init_may {
+                    if let Ctype::Struct(_) = node.ty.ty {
+                        let src = self.gen_lval(*init);
+                        let dst = self.new_reg();
+                        self.add(IROp::Bprel { dst, offset });
+                        self.add(IROp::Memcpy {
+                            dst,
+                            src,
+                            size: node.ty.size,
+                        });
+                        self.kill(dst);
+                        self.kill(src);
+                        return;
+                    }
+
+                    let rhs = self.gen_expr(*init);
+                    let lhs = self.new_reg();
+                    self.add(IROp::Bprel { dst: lhs, offset });
+                    self.store(&node.ty, lhs, rhs);
+                    self.kill(lhs);
+                    self.kill(rhs);
+                }
             }
-            jmp(Some(break_label));
-        }
-        NodeType::Return(expr) => {
-            let r = gen_expr(expr);
+            NodeType::If(cond, then, els_may) => {
+                if let Some(els) = els_may {
+                    let x = self.new_label();
+                    let y = self.new_label();
+                    self.gen_branch(*cond, x, false);
+                    self.gen_stmt(*then);
+                    self.jmp(y);
+                    self.label(x);
+                    self.gen_stmt(*els);
+                    self.label(y);
+                    return;
+                }
 
-            // Statement expression (GNU extension)
-            if *RETURN_LABEL.lock().unwrap() != 0 {
-                add(IROp::Mov, Some(*RETURN_REG.lock().unwrap()), r);
-                kill(r);
-                jmp(Some(*RETURN_LABEL.lock().unwrap()));
-                return;
+                let x = self.new_label();
+                self.gen_branch(*cond, x, false);
+                self.gen_stmt(*then);
+                self.label(x);
             }
+            NodeType::For(init, cond, inc, body) => {
+                let x = self.new_label();
+                let y = self.new_label();
+                let orig = self.break_label;
+                self.break_label = self.new_label();
 
-            add(IROp::Return, r, None);
-            kill(r);
-        }
-        NodeType::ExprStmt(expr) => {
-            let r = gen_expr(expr);
-            kill(r);
-        }
-        NodeType::VecStmt(stmts) | NodeType::CompStmt(stmts) => {
-            for n in stmts {
-                gen_stmt(n);
+                self.gen_stmt(*init);
+                self.label(x);
+                if !cond.is_null() {
+                    self.gen_branch(*cond, y, false);
+                }
+                self.gen_stmt(*body);
+                if !inc.is_null() {
+                    self.gen_stmt(*inc);
+                }
+                self.jmp(x);
+                self.label(y);
+                self.label(self.break_label);
+                self.break_label = orig;
+            }
+            NodeType::DoWhile(body, cond) => {
+                let x = self.new_label();
+                let orig = self.break_label;
+                self.break_label = self.new_label();
+                self.label(x);
+                self.gen_stmt(*body);
+                self.gen_branch(*cond, x, true);
+                self.label(self.break_label);
+                self.break_label = orig;
+            }
+            NodeType::Break => {
+                // The parser allows `break` only in a loop.
+                debug_assert!(self.break_label != 0, "stray 'break' statement");
+                self.jmp(self.break_label);
+            }
+            NodeType::Return(expr) => {
+                let r = self.gen_expr(*expr);
+
+                // Statement expression (GNU extension)
+                if self.return_label != 0 {
+                    self.add(IROp::Mov {
+                        dst: self.return_reg,
+                        src: r,
+                    });
+                    self.kill(r);
+                    self.jmp(self.return_label);
+                    return;
+                }
+
+                self.add(IROp::Return { src: r });
+                self.kill(r);
+            }
+            NodeType::ExprStmt(expr) => {
+                let r = self.gen_expr(*expr);
+                self.kill(r);
+            }
+            NodeType::VecStmt(stmts) | NodeType::CompStmt(stmts) => {
+                for n in stmts {
+                    self.gen_stmt(n);
+                }
             }
+            e => panic!("unknown node: {:?}", e),
         }
-        e => panic!("unknown node: {:?}", e),
     }
 }
 
-pub fn gen_ir(nodes: Vec<Node>) -> Vec<Function> {
+// With `zero_locals`, every local variable is zeroed where it is
+// defined, before its initializer if it has one. Calls to the functions
+// in `noreturn` are marked as never returning.
+pub fn gen_ir(nodes: Vec<Node>, noreturn: &HashSet<Symbol>, zero_locals: bool) -> Vec<Function> {
+    // Label 0 stands for no label.
+    let mut g = IrGen {
+        nlabel: 1,
+        zero_locals,
+        noreturn: noreturn.clone(),
+        ..IrGen::default()
+    };
     let mut v = vec![];
     for node in nodes {
         match node.op {
-            NodeType::Func(name, args, body, stacksize) => {
-                *CODE.lock().unwrap() = vec![];
-                // *NUM_REGS.lock().unwrap() = 0;
+            NodeType::Func(name, args, body, stacksize, is_static) => {
+                // g.num_regs = 0;
+                g.addr_taken = false;
+                g.stacksize = stacksize;
 
-                for (i, arg) in args.iter().enumerate() {
+                let (mut ints, mut floats, mut stack) = (0, 0, 0);
+                for arg in &args {
                     if let NodeType::Vardef(_, _, Scope::Local(offset)) = arg.op {
-                        store_arg(&arg.ty, Some(offset), Some(i));
+                        let (n, max) = if arg.ty.is_float() {
+                            (&mut floats, FLOAT_ARG_REGS)
+                        } else {
+                            (&mut ints, INT_ARG_REGS)
+                        };
+                        if *n < max {
+                            g.store_arg(&arg.ty, offset, *n);
+                            *n += 1;
+                            continue;
+                        }
+                        g.add(IROp::StoreStackArg {
+                            size: arg.ty.size as u8,
+                            offset,
+                            index: stack,
+                        });
+                        stack += 1;
                     } else {
                         unreachable!();
                     }
                 }
-                gen_stmt(*body);
+                g.named_args = (8 * ints, GP_SAVE + 16 * floats, stack);
+                g.gen_stmt(*body);
 
-                v.push(Function::new(name, CODE.lock().unwrap().clone(), stacksize));
+                let code = mem::take(&mut g.code);
+                let addr_taken = g.addr_taken;
+                let stacksize = g.stacksize;
+                let float_ret = match node.ty.ty {
+                    Ctype::Func(ref returning, ..) => float_size(returning),
+                    _ => 0,
+                };
+                let mut f = Function::new(name, code, stacksize, addr_taken, float_ret);
+                f.is_static = is_static;
+                v.push(f);
             }
             NodeType::Vardef(_, _, _) => (),
             _ => panic!("parse error."),
@@ -1,4 +1,9 @@
-use crate::gen_ir::{Function, IROp, IR};
+use crate::gen_ir::{Arg, Function, IROp, Reg, FLOAT_ARG_REGS, INT_ARG_REGS};
+use crate::mangle;
+use crate::matches;
+use crate::stats::{AsmStats, FuncStats};
+use crate::symbol::Symbol;
+use crate::target::target;
 use crate::util::roundup;
 use crate::{Scope, Var, REGS_N};
 
@@ -6,7 +11,8 @@ const REGS: [&str; REGS_N] = ["r10", "r11", "rbx", "r12", "r13", "r14", "r15"];
 const REGS8: [&str; REGS_N] = ["r10b", "r11b", "bl", "r12b", "r13b", "r14b", "r15b"];
 const REGS32: [&str; REGS_N] = ["r10d", "r11d", "ebx", "r12d", "r13d", "r14d", "r15d"];
 
-use std::sync::Mutex;
+use std::fmt;
+use std::io::Write;
 
 // Quoted from 9cc
 // > This pass generates x86-64 assembly from IR.
@@ -15,12 +21,30 @@ const ARGREGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
 const ARGREGS8: [&str; 6] = ["dil", "sil", "dl", "cl", "r8b", "r9b"];
 const ARGREGS32: [&str; 6] = ["edi", "esi", "edx", "ecx", "r8d", "r9d"];
 
-lazy_static! {
-    static ref LABEL: Mutex<usize> = Mutex::new(0);
+// Output is collected line by line and printed once the whole
+// translation unit is done, so that it can also be measured.
+enum Asm {
+    Directive(String),
+    Label(String),
+    Insn(String),
+    Comment(String),
+    Ascii(String, usize), // escaped string, size in bytes
 }
 
-fn backslash_escape(s: String, len: usize) -> String {
+impl fmt::Display for Asm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Asm::Directive(s) => write!(f, "{}", s),
+            Asm::Label(s) => write!(f, "{}:", s),
+            Asm::Insn(s) | Asm::Comment(s) => write!(f, "\t{}", s),
+            Asm::Ascii(s, _) => write!(f, "\t.ascii \"{}\"", s),
+        }
+    }
+}
+
+fn backslash_escape(s: String, len: usize) -> (String, usize) {
     let mut sb = String::new();
+    let mut size = 0;
     for i in 0..len {
         if let Some(c) = s.chars().collect::<Vec<char>>().get(i) {
             // Issue: https://github.com/rust-lang/rfcs/issues/751
@@ -39,31 +63,151 @@ fn backslash_escape(s: String, len: usize) -> String {
                 sb.push('\\');
                 sb.push(esc);
             } else if c.is_ascii_graphic() || c == &' ' {
-                sb.push(c.clone());
+                sb.push(*c);
             } else {
-                sb.push_str(&format!("\\{:o}", *c as i8));
+                sb.push_str(&format!("\\{:03o}", *c as u8));
             }
+            size += 1;
             if i == len - 1 {
                 sb.push_str("\\000");
+                size += 1;
             }
         } else {
             sb.push_str("\\000");
+            size += 1;
         }
     }
-    sb
+    (sb, size)
 }
 
 macro_rules! emit{
-    ($fmt:expr) => (print!(concat!("\t", $fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => (print!(concat!("\t", $fmt, "\n"), $($arg)*));
+    ($o:expr, $fmt:expr) => ($o.push(Asm::Insn($fmt.to_string())));
+    ($o:expr, $fmt:expr, $($arg:tt)*) => ($o.push(Asm::Insn(format!($fmt, $($arg)*))));
+}
+
+// Computes through xmm0 and xmm1, as values stay in integer registers.
+fn emit_fbinop(o: &mut Vec<Asm>, insn: &'static str, dst: Reg, src: Reg) {
+    emit!(o, "movq xmm0, {}", REGS[dst]);
+    emit!(o, "movq xmm1, {}", REGS[src]);
+    emit!(o, "{} xmm0, xmm1", insn);
+    emit!(o, "movq {}, xmm0", REGS[dst]);
+}
+
+// Sets `dst` to the result of comparing `a` with `b`. An unordered
+// result (a NaN operand) sets the parity flag, which `parity` folds in
+// for == and !=.
+fn emit_fcmp(
+    o: &mut Vec<Asm>,
+    insn: &'static str,
+    parity: Option<(&'static str, &'static str)>,
+    dst: Reg,
+    a: Reg,
+    b: Reg,
+) {
+    emit!(o, "movq xmm0, {}", REGS[a]);
+    emit!(o, "movq xmm1, {}", REGS[b]);
+    emit!(o, "ucomisd xmm0, xmm1");
+    emit!(o, "{} {}", insn, REGS8[dst]);
+    if let Some((set, op)) = parity {
+        emit!(o, "{} al", set);
+        emit!(o, "{} {}, al", op, REGS8[dst]);
+    }
+    emit!(o, "movzb {}, {}", REGS[dst], REGS8[dst]);
 }
 
-fn emit_cmp(ir: IR, insn: &'static str) {
-    let lhs = ir.lhs.unwrap();
-    let rhs = ir.rhs.unwrap();
-    emit!("cmp {}, {}", REGS[lhs], REGS[rhs]);
-    emit!("{} {}", insn, REGS8[lhs]);
-    emit!("movzb {}, {}", REGS[lhs], REGS8[lhs]);
+fn emit_cmp(o: &mut Vec<Asm>, insn: &'static str, dst: Reg, src: Reg) {
+    emit!(o, "cmp {}, {}", REGS[dst], REGS[src]);
+    emit!(o, "{} {}", insn, REGS8[dst]);
+    emit!(o, "movzb {}, {}", REGS[dst], REGS8[dst]);
+}
+
+// Bulk copies and fills up to UNROLL_MAX bytes are done with plain
+// moves and up to REP_MAX bytes with a string instruction. Larger ones
+// call the C library.
+const UNROLL_MAX: usize = 32;
+const REP_MAX: usize = 256;
+
+// Splits `size` bytes into the widest moves through rax, calling
+// `f(offset, reg)` for each.
+fn unrolled<F: FnMut(usize, &str)>(size: usize, mut f: F) {
+    let mut off = 0;
+    while off < size {
+        let (n, r) = match size - off {
+            8..=usize::MAX => (8, "rax"),
+            4..=7 => (4, "eax"),
+            2..=3 => (2, "ax"),
+            _ => (1, "al"),
+        };
+        f(off, r);
+        off += n;
+    }
+}
+
+fn emit_memcpy(o: &mut Vec<Asm>, dst: Reg, src: Reg, size: usize, layout: &FrameLayout) {
+    if size <= UNROLL_MAX {
+        unrolled(size, |off, r| {
+            emit!(o, "mov {}, [{}+{}]", r, REGS[src], off);
+            emit!(o, "mov [{}+{}], {}", REGS[dst], off, r);
+        });
+    } else if size <= REP_MAX {
+        emit!(o, "mov rdi, {}", REGS[dst]);
+        emit!(o, "mov rsi, {}", REGS[src]);
+        emit!(o, "mov rcx, {}", size);
+        emit!(o, "rep movsb");
+    } else {
+        layout.save_for_call(o);
+        emit!(o, "mov rdi, {}", REGS[dst]);
+        emit!(o, "mov rsi, {}", REGS[src]);
+        emit!(o, "mov rdx, {}", size);
+        emit!(o, "call memcpy");
+        layout.restore_after_call(o);
+    }
+}
+
+fn emit_memset(o: &mut Vec<Asm>, dst: Reg, value: u8, size: usize, layout: &FrameLayout) {
+    if size <= UNROLL_MAX {
+        emit!(o, "mov rax, {}", u64::from(value) * 0x0101_0101_0101_0101);
+        unrolled(size, |off, r| {
+            emit!(o, "mov [{}+{}], {}", REGS[dst], off, r)
+        });
+    } else if size <= REP_MAX {
+        emit!(o, "mov rdi, {}", REGS[dst]);
+        emit!(o, "mov al, {}", value);
+        emit!(o, "mov rcx, {}", size);
+        emit!(o, "rep stosb");
+    } else {
+        layout.save_for_call(o);
+        emit!(o, "mov rdi, {}", REGS[dst]);
+        emit!(o, "mov esi, {}", value);
+        emit!(o, "mov rdx, {}", size);
+        emit!(o, "call memset");
+        layout.restore_after_call(o);
+    }
+}
+
+// Values live in registers extended to 64 bits: int is sign-extended,
+// unsigned int zero-extended, and plain char follows the target's
+// signedness.
+fn emit_extend(o: &mut Vec<Asm>, dst: Reg, size: u8, signed: bool) {
+    match size {
+        1 if signed => emit!(o, "movsx {}, {}", REGS[dst], REGS8[dst]),
+        1 => emit!(o, "movzb {}, {}", REGS[dst], REGS8[dst]),
+        4 if signed => emit!(o, "movsxd {}, {}", REGS[dst], REGS32[dst]),
+        4 => emit!(o, "mov {}, {}", REGS32[dst], REGS32[dst]),
+        _ => (),
+    }
+}
+
+// Leaves dst / src in rax and the remainder in rdx.
+fn emit_div(o: &mut Vec<Asm>, dst: Reg, src: Reg, signed: bool) {
+    emit!(o, "mov rax, {}", REGS[dst]);
+    if signed {
+        emit!(o, "cqo"); // rax -> rdx:rax
+        emit!(o, "idiv {}", REGS[src]);
+    } else {
+        emit!(o, "xor edx, edx");
+        emit!(o, "div {}", REGS[src]);
+    }
 }
 
 fn reg(r: usize, size: u8) -> &'static str {
@@ -84,145 +228,632 @@ fn argreg(r: usize, size: u8) -> &'static str {
     }
 }
 
-fn gen(f: Function) {
+// An IR instruction the backend cannot translate. Nothing is written
+// when this is returned, so a failed build leaves no partial assembly.
+#[derive(Debug)]
+pub struct CodegenError {
+    pub function: Symbol,
+    pub index: usize, // position of the instruction in the function's IR
+    pub ir: Box<IROp>,
+    pub msg: String,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cannot generate code for {}: IR #{} ({}): {}",
+            self.function,
+            self.index,
+            self.ir.to_string().trim(),
+            self.msg
+        )
+    }
+}
+
+// Rejects what the match in gen() has no instructions for, so that
+// emitting an instruction never fails halfway.
+fn check(ir: &mut IROp) -> Result<(), String> {
     use self::IROp::*;
-    let ret = format!(".Lend{}", *LABEL.lock().unwrap());
-    *LABEL.lock().unwrap() += 1;
-
-    println!(".text");
-    println!(".global {}", f.name);
-    println!("{}:", f.name);
-    emit!("push rbp");
-    emit!("mov rbp, rsp");
-    emit!("sub rsp, {}", roundup(f.stacksize, 16));
-    emit!("push r12");
-    emit!("push r13");
-    emit!("push r14");
-    emit!("push r15");
-
-    for ir in f.ir {
-        let lhs = ir.lhs.unwrap();
-        let rhs = ir.rhs.unwrap_or(0);
-        match ir.op {
-            Imm => emit!("mov {}, {}", REGS[lhs], rhs as i32),
-            Mov => emit!("mov {}, {}", REGS[lhs], REGS[rhs]),
-            Return => {
-                emit!("mov rax, {}", REGS[lhs]);
-                emit!("jmp {}", ret);
-            }
-            Call(name, nargs, args) => {
-                for i in 0..nargs {
-                    emit!("mov {}, {}", ARGREGS[i], REGS[args[i]]);
+    match *ir {
+        Load { size, .. }
+        | Store { size, .. }
+        | StoreArg { size, .. }
+        | If { size, .. }
+        | Unless { size, .. }
+            if ![1, 4, 8].contains(&size) =>
+        {
+            return Err(format!("unsupported operand size {}", size));
+        }
+        FLoad { size, .. } | FStore { size, .. } | FStoreArg { size, .. }
+            if size != 4 && size != 8 =>
+        {
+            return Err(format!("unsupported floating point size {}", size));
+        }
+        StoreStackArg { size, .. } if ![1, 4, 8].contains(&size) => {
+            return Err(format!("unsupported operand size {}", size));
+        }
+        StoreArg { arg, .. } if arg >= INT_ARG_REGS => {
+            return Err(format!("argument {} is not passed in a register", arg));
+        }
+        FStoreArg { arg, .. } if arg >= FLOAT_ARG_REGS => {
+            return Err(format!("argument {} is not passed in a register", arg));
+        }
+        _ => (),
+    }
+    let mut bad = None;
+    ir.for_each_reg(|r| {
+        if *r >= REGS_N && bad.is_none() {
+            bad = Some(*r);
+        }
+    });
+    match bad {
+        Some(r) => Err(format!("r{} is not a machine register", r)),
+        None => Ok(()),
+    }
+}
+
+// Where a call passes each argument: integer and floating point ones
+// take the next free register of their own kind, and once those run out,
+// the next 8 bytes on the stack.
+enum ArgPlace {
+    Int(usize),
+    Float(usize),
+    Stack(usize),
+}
+
+// `float_args` has the size of each floating point argument, and 0 for
+// the others.
+fn arg_places(float_args: &[u8]) -> Vec<ArgPlace> {
+    let (mut ints, mut floats, mut stack) = (0, 0, 0);
+    let mut next = |n: &mut usize, max, place: fn(usize) -> ArgPlace| {
+        if *n < max {
+            *n += 1;
+            return place(*n - 1);
+        }
+        stack += 1;
+        ArgPlace::Stack(stack - 1)
+    };
+    float_args
+        .iter()
+        .map(|&size| {
+            if size == 0 {
+                next(&mut ints, INT_ARG_REGS, ArgPlace::Int)
+            } else {
+                next(&mut floats, FLOAT_ARG_REGS, ArgPlace::Float)
+            }
+        })
+        .collect()
+}
+
+fn stack_args(float_args: &[u8]) -> usize {
+    arg_places(float_args)
+        .iter()
+        .filter(|place| matches!(place, ArgPlace::Stack(_)))
+        .count()
+}
+
+// Bytes below rsp that a leaf function may use without moving rsp.
+const RED_ZONE: usize = 128;
+
+// Registers a function must give back to its caller unchanged, and
+// registers a call may clobber while they hold our values.
+const CALLEE_SAVED: [Reg; 5] = [2, 3, 4, 5, 6]; // rbx, r12-r15
+const CALLER_SAVED: [Reg; 2] = [0, 1]; // r10, r11
+
+// A function's frame, laid out once before any code is emitted so that
+// no part of it can overlap another. Going down from the base: the
+// locals that sema placed and any argument slots gen_ir added, the
+// slots of registers the allocator spilled, slots for the callee-saved
+// registers the function uses, if it makes calls, slots for r10 and
+// r11 across them, and, if it calls va_start, the register save area
+// its argument registers are stored to on entry. Below that, at rsp, is room for the arguments
+// its calls pass on the stack. Both parts are rounded up to 16, which
+// keeps rsp aligned at every call.
+struct FrameLayout {
+    base: &'static str,
+    frame: bool,   // rbp is set up and rsp moved down by `size`
+    spills: usize, // offset of the spill slots
+    saved: Vec<(Reg, usize)>,
+    call_saved: Vec<(Reg, usize)>,
+    va_save: Option<usize>, // offset of the register save area
+    size: usize,
+}
+
+impl FrameLayout {
+    fn new(f: &mut Function, optimize: bool) -> Self {
+        let mut used = [false; REGS_N];
+        for ir in f.ir.iter_mut() {
+            ir.for_each_reg(|r| {
+                if *r < REGS_N {
+                    used[*r] = true;
                 }
-                emit!("push r10");
-                emit!("push r11");
-                emit!("mov rax, 0");
-                emit!("call {}", name);
-                emit!("pop r11");
-                emit!("pop r10");
-
-                emit!("mov {}, rax", REGS[lhs]);
-            }
-            Label => println!(".L{}:", lhs),
-            LabelAddr(name) => emit!("lea {}, {}", REGS[lhs], name),
-            Neg => emit!("neg {}", REGS[lhs]),
-            EQ => emit_cmp(ir, "sete"),
-            NE => emit_cmp(ir, "setne"),
-            LT => emit_cmp(ir, "setl"),
-            LE => emit_cmp(ir, "setle"),
-            AND => emit!("and {}, {}", REGS[lhs], REGS[rhs]),
-            OR => emit!("or {}, {}", REGS[lhs], REGS[rhs]),
-            XOR => emit!("xor {}, {}", REGS[lhs], REGS[rhs]),
-            SHL => {
-                emit!("mov cl, {}", REGS8[rhs]);
-                emit!("shl {}, cl", REGS[lhs]);
-            }
-            SHR => {
-                emit!("mov cl, {}", REGS8[rhs]);
-                emit!("shr {}, cl", REGS[lhs]);
-            }
-            Mod => {
-                /* Same meaning(?).
-                 * emit!("mov rdx, 0");
-                 * emit!("mov rax, {}", REGS[lhs]);
-                 */
-                emit!("mov rax, {}", REGS[lhs]);
-                emit!("cqo"); // rax -> rdx:rax
-                emit!("div {}", REGS[rhs]);
-                emit!("mov {}, rdx", REGS[lhs]);
-            }
-            Jmp => emit!("jmp .L{}", lhs),
-            If => {
-                emit!("cmp {}, 0", REGS[lhs]);
-                emit!("jne .L{}", rhs);
-            }
-            Unless => {
-                emit!("cmp {}, 0", REGS[lhs]);
-                emit!("je .L{}", rhs);
-            }
-            Load(size) => {
-                emit!("mov {}, [{}]", reg(lhs, size), REGS[rhs]);
-                if size == 1 {
-                    emit!("movzb {}, {}", REGS[lhs], REGS8[lhs]);
+            });
+        }
+
+        let spills = roundup(f.stacksize, 8);
+        let mut size = spills + 8 * f.spills;
+        let mut slots = |regs: &[Reg]| {
+            regs.iter()
+                .map(|&r| {
+                    size += 8;
+                    (r, size)
+                })
+                .collect::<Vec<_>>()
+        };
+        let saved_regs: Vec<_> = CALLEE_SAVED.iter().cloned().filter(|&r| used[r]).collect();
+        let saved = slots(&saved_regs);
+        let call_saved = if f.has_calls {
+            slots(&CALLER_SAVED)
+        } else {
+            vec![]
+        };
+        let va_save = if f.ir.iter().any(|ir| matches!(ir, IROp::VaStart { .. })) {
+            size += 8 * INT_ARG_REGS + 16 * FLOAT_ARG_REGS;
+            Some(size)
+        } else {
+            None
+        };
+        let outgoing =
+            f.ir.iter()
+                .filter_map(|ir| match ir {
+                    IROp::Call { float_args, .. } => Some(stack_args(float_args)),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+        let size = roundup(size, 16) + roundup(8 * outgoing, 16);
+
+        // With -O, a leaf function whose frame fits in the red zone
+        // keeps it below rsp and sets up no frame. A local whose address
+        // is taken, or arguments passed on the stack, which are found
+        // through rbp, force a frame, and so does va_start.
+        let stack_params =
+            f.ir.iter()
+                .any(|ir| matches!(ir, IROp::StoreStackArg { .. }));
+        let frame = !optimize
+            || f.has_calls
+            || f.addr_taken
+            || stack_params
+            || va_save.is_some()
+            || size > RED_ZONE;
+        FrameLayout {
+            base: if frame { "rbp" } else { "rsp" },
+            frame,
+            spills,
+            saved,
+            call_saved,
+            va_save,
+            size,
+        }
+    }
+
+    fn slot(&self, offset: usize) -> String {
+        format!("[{}-{}]", self.base, offset)
+    }
+
+    fn spill_slot(&self, slot: usize) -> String {
+        self.slot(self.spills + 8 * (slot + 1))
+    }
+
+    fn prologue(&self, o: &mut Vec<Asm>) {
+        if self.frame {
+            emit!(o, "push rbp");
+            emit!(o, "mov rbp, rsp");
+            if self.size > 0 {
+                emit!(o, "sub rsp, {}", self.size);
+            }
+        }
+        for &(r, offset) in &self.saved {
+            emit!(o, "mov {}, {}", self.slot(offset), REGS[r]);
+        }
+        if let Some(offset) = self.va_save {
+            for (i, r) in ARGREGS.iter().enumerate() {
+                emit!(o, "mov {}, {}", self.slot(offset - 8 * i), r);
+            }
+            for i in 0..FLOAT_ARG_REGS {
+                let offset = offset - 8 * INT_ARG_REGS - 16 * i;
+                emit!(o, "movsd {}, xmm{}", self.slot(offset), i);
+            }
+        }
+    }
+
+    fn epilogue(&self, o: &mut Vec<Asm>) {
+        for &(r, offset) in &self.saved {
+            emit!(o, "mov {}, {}", REGS[r], self.slot(offset));
+        }
+        if self.frame {
+            emit!(o, "mov rsp, rbp");
+            emit!(o, "pop rbp");
+        }
+        emit!(o, "ret");
+    }
+
+    // Brackets a call.
+    fn save_for_call(&self, o: &mut Vec<Asm>) {
+        for &(r, offset) in &self.call_saved {
+            emit!(o, "mov {}, {}", self.slot(offset), REGS[r]);
+        }
+    }
+
+    fn restore_after_call(&self, o: &mut Vec<Asm>) {
+        for &(r, offset) in &self.call_saved {
+            emit!(o, "mov {}, {}", REGS[r], self.slot(offset));
+        }
+    }
+}
+
+// Emits function number `index` of the unit onto `o`.
+fn gen(
+    o: &mut Vec<Asm>,
+    index: usize,
+    mut f: Function,
+    asm_comments: bool,
+    optimize: bool,
+) -> Result<FuncStats, CodegenError> {
+    use self::IROp::*;
+    let ptr = target().ptr_size as u8;
+    let char_is_signed = target().char_is_signed;
+    let ret = mangle::return_label(index);
+
+    let start = o.len();
+    o.push(Asm::Directive(".text".into()));
+    if !f.is_static {
+        o.push(Asm::Directive(format!(".global {}", f.name)));
+    }
+    o.push(Asm::Label(f.name.to_string()));
+
+    let layout = FrameLayout::new(&mut f, optimize);
+    let mut stats = FuncStats::new(f.name);
+    stats.calls = f.ir.iter().filter(|ir| emits_call(ir)).count();
+    stats.spills = f.spills;
+    let base = layout.base;
+    layout.prologue(o);
+
+    let float_ret = f.float_ret;
+    for (index, mut ir) in f.ir.into_iter().enumerate() {
+        if let Err(msg) = check(&mut ir) {
+            return Err(CodegenError {
+                function: f.name,
+                index,
+                ir: Box::new(ir),
+                msg,
+            });
+        }
+        match ir {
+            Imm { dst, imm } => {
+                if imm as i32 as i64 == imm {
+                    emit!(o, "mov {}, {}", REGS[dst], imm);
+                } else {
+                    emit!(o, "movabs {}, {}", REGS[dst], imm);
+                }
+            }
+            Mov { dst, src } => emit!(o, "mov {}, {}", REGS[dst], REGS[src]),
+            Return { src } => {
+                emit!(o, "mov rax, {}", REGS[src]);
+                emit!(o, "jmp {}", ret);
+            }
+            Call {
+                dst,
+                name,
+                ptr,
+                args,
+                float_args,
+                float_ret,
+                ..
+            } => {
+                // The arguments for the stack are stored first, through
+                // rax and xmm0, before those registers hold arguments.
+                let places = arg_places(&float_args);
+                let src = |arg: &Arg| match *arg {
+                    Arg::Reg(r) => REGS[r].to_string(),
+                    Arg::Slot(offset) => layout.slot(offset),
+                };
+                for ((arg, &size), place) in args.iter().zip(float_args.iter()).zip(&places) {
+                    if let ArgPlace::Stack(i) = *place {
+                        if size == 4 {
+                            emit!(o, "movq xmm0, {}", src(arg));
+                            emit!(o, "cvtsd2ss xmm0, xmm0");
+                            emit!(o, "movss [rsp+{}], xmm0", 8 * i);
+                        } else {
+                            emit!(o, "mov rax, {}", src(arg));
+                            emit!(o, "mov [rsp+{}], rax", 8 * i);
+                        }
+                    }
+                }
+                let mut floats = 0;
+                for ((arg, &size), place) in args.iter().zip(float_args.iter()).zip(&places) {
+                    match *place {
+                        ArgPlace::Int(i) => emit!(o, "mov {}, {}", ARGREGS[i], src(arg)),
+                        ArgPlace::Float(i) => {
+                            emit!(o, "movq xmm{}, {}", i, src(arg));
+                            if size == 4 {
+                                emit!(o, "cvtsd2ss xmm{}, xmm{}", i, i);
+                            }
+                            floats += 1;
+                        }
+                        ArgPlace::Stack(_) => (),
+                    }
+                }
+                layout.save_for_call(o);
+                // al tells a variadic callee how many xmm registers are used.
+                emit!(o, "mov rax, {}", floats);
+                match ptr {
+                    Some(ptr) => emit!(o, "call {}", REGS[ptr]),
+                    None => emit!(o, "call {}", name),
+                }
+                layout.restore_after_call(o);
+
+                match float_ret {
+                    0 => emit!(o, "mov {}, rax", REGS[dst]),
+                    4 => {
+                        emit!(o, "cvtss2sd xmm0, xmm0");
+                        emit!(o, "movq {}, xmm0", REGS[dst]);
+                    }
+                    _ => emit!(o, "movq {}, xmm0", REGS[dst]),
+                }
+            }
+            Label(x) => o.push(Asm::Label(mangle::label(x))),
+            LabelAddr { dst, name } => emit!(o, "lea {}, {}", reg(dst, ptr), name),
+            Neg { dst } => emit!(o, "neg {}", REGS[dst]),
+            Extend { dst, size, signed } => {
+                emit_extend(o, dst, size, signed && (size != 1 || char_is_signed))
+            }
+            FAdd { dst, src } => emit_fbinop(o, "addsd", dst, src),
+            FSub { dst, src } => emit_fbinop(o, "subsd", dst, src),
+            FMul { dst, src } => emit_fbinop(o, "mulsd", dst, src),
+            FDiv { dst, src } => emit_fbinop(o, "divsd", dst, src),
+            FEQ { dst, src } => emit_fcmp(o, "sete", Some(("setnp", "and")), dst, dst, src),
+            FNE { dst, src } => emit_fcmp(o, "setne", Some(("setp", "or")), dst, dst, src),
+            // a < b is b > a, which is false when either is NaN.
+            FLT { dst, src } => emit_fcmp(o, "seta", None, dst, src, dst),
+            FLE { dst, src } => emit_fcmp(o, "setae", None, dst, src, dst),
+            FNeg { dst } => emit!(o, "btc {}, 63", REGS[dst]),
+            I2F { dst } => {
+                emit!(o, "cvtsi2sd xmm0, {}", REGS[dst]);
+                emit!(o, "movq {}, xmm0", REGS[dst]);
+            }
+            F2I { dst } => {
+                emit!(o, "movq xmm0, {}", REGS[dst]);
+                emit!(o, "cvttsd2si {}, xmm0", REGS[dst]);
+            }
+            FLoad { size, dst, addr } => {
+                if size == 4 {
+                    emit!(o, "cvtss2sd xmm0, dword ptr [{}]", REGS[addr]);
+                    emit!(o, "movq {}, xmm0", REGS[dst]);
+                } else {
+                    emit!(o, "mov {}, [{}]", REGS[dst], REGS[addr]);
                 }
             }
-            Store(size) => emit!("mov [{}], {}", REGS[lhs], reg(rhs, size)),
-            StoreArg(size) => emit!("mov [rbp-{}], {}", lhs, argreg(rhs, size)),
-            Add => emit!("add {}, {}", REGS[lhs], REGS[rhs]),
-            AddImm => emit!("add {}, {}", REGS[lhs], rhs as i32),
-            Sub => emit!("sub {}, {}", REGS[lhs], REGS[rhs]),
-            SubImm => emit!("sub {}, {}", REGS[lhs], rhs as i32),
-            Bprel => emit!("lea {}, [rbp-{}]", REGS[lhs], rhs),
-            Mul => {
-                emit!("mov rax, {}", REGS[rhs]);
-                emit!("mul {}", REGS[lhs]);
-                emit!("mov {}, rax", REGS[lhs]);
-            }
-            MulImm => {
-                if rhs < 256 && rhs.count_ones() == 1 {
-                    emit!("shl {}, {}", REGS[lhs], rhs.trailing_zeros());
+            FStore { size, addr, src } => {
+                if size == 4 {
+                    emit!(o, "movq xmm0, {}", REGS[src]);
+                    emit!(o, "cvtsd2ss xmm0, xmm0");
+                    emit!(o, "movss dword ptr [{}], xmm0", REGS[addr]);
                 } else {
-                    emit!("mov rax, {}", rhs as i32);
-                    emit!("mul {}", REGS[lhs]);
-                    emit!("mov {}, rax", REGS[lhs]);
+                    emit!(o, "mov [{}], {}", REGS[addr], REGS[src]);
                 }
             }
-            Div => {
-                emit!("mov rax, {}", REGS[lhs]);
-                emit!("cqo");
-                emit!("div {}", REGS[rhs]);
-                emit!("mov {}, rax", REGS[lhs]);
+            FStoreArg { size, offset, arg } => {
+                let insn = if size == 4 { "movss" } else { "movsd" };
+                emit!(o, "{} [{}-{}], xmm{}", insn, base, offset, arg);
             }
-            Nop | Kill => (),
+            EQ { dst, src } => emit_cmp(o, "sete", dst, src),
+            NE { dst, src } => emit_cmp(o, "setne", dst, src),
+            LT { dst, src, signed } => emit_cmp(o, if signed { "setl" } else { "setb" }, dst, src),
+            LE { dst, src, signed } => {
+                emit_cmp(o, if signed { "setle" } else { "setbe" }, dst, src)
+            }
+            AND { dst, src } => emit!(o, "and {}, {}", REGS[dst], REGS[src]),
+            OR { dst, src } => emit!(o, "or {}, {}", REGS[dst], REGS[src]),
+            XOR { dst, src } => emit!(o, "xor {}, {}", REGS[dst], REGS[src]),
+            SHL { dst, src } => {
+                emit!(o, "mov cl, {}", REGS8[src]);
+                emit!(o, "shl {}, cl", REGS[dst]);
+            }
+            SHR { dst, src, signed } => {
+                emit!(o, "mov cl, {}", REGS8[src]);
+                emit!(
+                    o,
+                    "{} {}, cl",
+                    if signed { "sar" } else { "shr" },
+                    REGS[dst]
+                );
+            }
+            Mod { dst, src, signed } => {
+                emit_div(o, dst, src, signed);
+                emit!(o, "mov {}, rdx", REGS[dst]);
+            }
+            Jmp(x) => emit!(o, "jmp {}", mangle::label(x)),
+            If { size, cond, label } => {
+                emit!(o, "cmp {}, 0", reg(cond, size));
+                emit!(o, "jne {}", mangle::label(label));
+            }
+            Unless { size, cond, label } => {
+                emit!(o, "cmp {}, 0", reg(cond, size));
+                emit!(o, "je {}", mangle::label(label));
+            }
+            Load { size, dst, addr } => {
+                if size == 4 {
+                    emit!(o, "movsxd {}, dword ptr [{}]", REGS[dst], REGS[addr]);
+                } else {
+                    emit!(o, "mov {}, [{}]", reg(dst, size), REGS[addr]);
+                    emit_extend(o, dst, size, char_is_signed);
+                }
+            }
+            Store { size, addr, src } => emit!(o, "mov [{}], {}", REGS[addr], reg(src, size)),
+            StoreArg { size, offset, arg } => {
+                emit!(o, "mov [{}-{}], {}", base, offset, argreg(arg, size))
+            }
+            // Past the return address and the saved rbp.
+            StoreStackArg {
+                size,
+                offset,
+                index,
+            } => {
+                let rax = match size {
+                    1 => "al",
+                    4 => "eax",
+                    _ => "rax",
+                };
+                emit!(o, "mov {}, [rbp+{}]", rax, 16 + 8 * index);
+                emit!(o, "mov [{}-{}], {}", base, offset, rax);
+            }
+            VaStart { ap, gp, fp, stack } => {
+                let ap = REGS[ap];
+                emit!(o, "mov dword ptr [{}], {}", ap, gp);
+                emit!(o, "mov dword ptr [{}+4], {}", ap, fp);
+                emit!(o, "lea rax, [rbp+{}]", 16 + 8 * stack);
+                emit!(o, "mov [{}+8], rax", ap);
+                emit!(o, "lea rax, {}", layout.slot(layout.va_save.unwrap()));
+                emit!(o, "mov [{}+16], rax", ap);
+            }
+            Add { dst, src } => emit!(o, "add {}, {}", REGS[dst], REGS[src]),
+            AddImm { dst, imm } => emit!(o, "add {}, {}", REGS[dst], imm),
+            Sub { dst, src } => emit!(o, "sub {}, {}", REGS[dst], REGS[src]),
+            SubImm { dst, imm } => emit!(o, "sub {}, {}", REGS[dst], imm),
+            Bprel { dst, offset } => emit!(o, "lea {}, [{}-{}]", reg(dst, ptr), base, offset),
+            Mul { dst, src } => {
+                emit!(o, "mov rax, {}", REGS[src]);
+                emit!(o, "mul {}", REGS[dst]);
+                emit!(o, "mov {}, rax", REGS[dst]);
+            }
+            MulImm { dst, imm } => {
+                if 0 < imm && imm < 256 && imm.count_ones() == 1 {
+                    emit!(o, "shl {}, {}", REGS[dst], imm.trailing_zeros());
+                } else {
+                    emit!(o, "mov rax, {}", imm);
+                    emit!(o, "mul {}", REGS[dst]);
+                    emit!(o, "mov {}, rax", REGS[dst]);
+                }
+            }
+            Div { dst, src, signed } => {
+                emit_div(o, dst, src, signed);
+                emit!(o, "mov {}, rax", REGS[dst]);
+            }
+            Line { line, text } => {
+                if asm_comments {
+                    o.push(Asm::Comment(format!("# line {}: {}", line, text)));
+                }
+            }
+            Memcpy { dst, src, size } => emit_memcpy(o, dst, src, size, &layout),
+            Memset { dst, value, size } => emit_memset(o, dst, value, size, &layout),
+            StoreSpill { slot, src } => emit!(o, "mov {}, {}", layout.spill_slot(slot), REGS[src]),
+            LoadSpill { dst, slot } => emit!(o, "mov {}, {}", REGS[dst], layout.spill_slot(slot)),
+            Nop | Kill(_) => (),
+        }
+    }
+
+    o.push(Asm::Label(ret));
+    // Return sets rax; a floating point result goes in xmm0.
+    if float_ret != 0 {
+        emit!(o, "movq xmm0, rax");
+        if float_ret == 4 {
+            emit!(o, "cvtsd2ss xmm0, xmm0");
         }
     }
+    layout.epilogue(o);
+    stats.insns = count_insns(&o[start..]);
+    Ok(stats)
+}
+
+// Whether `ir` is emitted as a call: calls, and copies and fills too
+// large to do inline.
+fn emits_call(ir: &IROp) -> bool {
+    match *ir {
+        IROp::Call { .. } => true,
+        IROp::Memcpy { size, .. } | IROp::Memset { size, .. } => size > REP_MAX,
+        _ => false,
+    }
+}
 
-    println!("{}:", ret);
-    emit!("pop r15");
-    emit!("pop r14");
-    emit!("pop r13");
-    emit!("pop r12");
-    emit!("mov rsp, rbp");
-    emit!("pop rbp");
-    emit!("ret");
+// Counts the instructions among `lines`.
+fn count_insns(lines: &[Asm]) -> usize {
+    lines
+        .iter()
+        .filter(|line| matches!(line, Asm::Insn(_)))
+        .count()
 }
 
-pub fn gen_x86(globals: Vec<Var>, fns: Vec<Function>) {
-    println!(".intel_syntax noprefix");
-    println!(".data");
+pub fn gen_x86<W: Write>(
+    w: &mut W,
+    globals: Vec<Var>,
+    fns: Vec<Function>,
+    asm_comments: bool,
+    optimize: bool,
+) -> Result<AsmStats, CodegenError> {
+    let mut stats = AsmStats::default();
+    let o = &mut vec![];
+    o.push(Asm::Directive(".intel_syntax noprefix".into()));
+    o.push(Asm::Directive(".data".into()));
     for var in globals {
         if let Scope::Global(data, len, is_extern) = var.scope {
             if is_extern {
                 continue;
             }
-            println!("{}:", var.name);
-            emit!(".ascii \"{}\"", backslash_escape(data, len));
+            o.push(Asm::Label(var.name.to_string()));
+            let (s, size) = backslash_escape(data, len);
+            o.push(Asm::Ascii(s, size));
             continue;
         }
         unreachable!();
     }
 
-    for f in fns {
-        gen(f);
+    let assert_fail = Symbol::intern(mangle::ASSERT_FAIL);
+    let uses_assert = fns.iter().any(|f| {
+        f.ir.iter()
+            .any(|ir| matches!(ir, IROp::Call { name, .. } if *name == assert_fail))
+    });
+
+    for (index, f) in fns.into_iter().enumerate() {
+        stats.funcs.push(gen(o, index, f, asm_comments, optimize)?);
+    }
+
+    if uses_assert {
+        stats.funcs.push(emit_assert_fail(o));
+    }
+    o.push(Asm::Directive(format!(
+        ".ident \"r9cc {}\"",
+        crate::version()
+    )));
+
+    for line in o.drain(..) {
+        if let Asm::Ascii(_, size) = line {
+            stats.data_bytes += size;
+        }
+        writeln!(w, "{}", line).expect("failed to write assembly");
     }
+    Ok(stats)
+}
+
+// The runtime half of the predefined assert() macro:
+// __r9cc_assert_fail(expr, file, line) reports the failed expression
+// on stderr and exits with status 1. It is weak so that a program can
+// supply its own.
+fn emit_assert_fail(o: &mut Vec<Asm>) -> FuncStats {
+    let name = Symbol::intern(mangle::ASSERT_FAIL);
+    let fmt = "%s:%d: assertion failed: %s\n";
+    o.push(Asm::Directive(".data".into()));
+    o.push(Asm::Label(mangle::assert_fmt()));
+    let (s, size) = backslash_escape(fmt.into(), fmt.len() + 1);
+    o.push(Asm::Ascii(s, size));
+    let start = o.len();
+    o.push(Asm::Directive(".text".into()));
+    o.push(Asm::Directive(format!(".weak {}", name)));
+    o.push(Asm::Label(name.to_string()));
+    emit!(o, "push rbp");
+    emit!(o, "mov rbp, rsp");
+    emit!(o, "mov r8, rdi");
+    emit!(o, "mov rcx, rdx");
+    emit!(o, "mov rdx, rsi");
+    emit!(o, "lea rsi, {}", mangle::assert_fmt());
+    emit!(o, "mov rdi, QWORD PTR stderr[rip]");
+    emit!(o, "mov rax, 0");
+    emit!(o, "call fprintf");
+    emit!(o, "mov rdi, 1");
+    emit!(o, "call exit");
+    let mut stats = FuncStats::new(name);
+    stats.insns = count_insns(&o[start..]);
+    stats.calls = 2;
+    stats
 }
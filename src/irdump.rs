@@ -1,103 +1,113 @@
-use crate::gen_ir::{Function, IROp, IRType, IR};
+use crate::gen_ir::{Arg, Function, IROp, Reg};
+use crate::mangle;
+use crate::symbol::Symbol;
 
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Clone, Debug)]
-pub struct IRInfo {
-    name: &'static str,
-    pub ty: IRType,
-}
-
-impl IRInfo {
-    pub fn new(name: &'static str, ty: IRType) -> Self {
-        IRInfo { name, ty }
-    }
-}
-
-impl<'a> From<&'a IROp> for IRInfo {
-    fn from(op: &'a IROp) -> IRInfo {
+impl fmt::Display for IROp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::IROp::*;
-        match op {
-            Add => IRInfo::new("ADD", IRType::RegReg),
-            AddImm => IRInfo::new("ADD", IRType::RegImm),
-            Call(_, _, _) => IRInfo::new("CALL", IRType::Call),
-            Div => IRInfo::new("DIV", IRType::RegReg),
-            Imm => IRInfo::new("MOV", IRType::RegImm),
-            Jmp => IRInfo::new("JMP", IRType::Jmp),
-            Kill => IRInfo::new("KILL", IRType::Reg),
-            Label => IRInfo::new("", IRType::Label),
-            LabelAddr(_) => IRInfo::new("LABEL_ADDR", IRType::LabelAddr),
-            EQ => IRInfo::new("EQ", IRType::RegReg),
-            NE => IRInfo::new("NE", IRType::RegReg),
-            LE => IRInfo::new("LE", IRType::RegReg),
-            LT => IRInfo::new("LT", IRType::RegReg),
-            AND => IRInfo::new("AND", IRType::RegReg),
-            OR => IRInfo::new("OR", IRType::RegReg),
-            XOR => IRInfo::new("XOR", IRType::RegReg),
-            SHL => IRInfo::new("SHL", IRType::RegReg),
-            SHR => IRInfo::new("SHR", IRType::RegReg),
-            Mod => IRInfo::new("MOD", IRType::RegReg),
-            Neg => IRInfo::new("NEG", IRType::Reg),
-            Load(_) => IRInfo::new("LOAD", IRType::Mem),
-            Mov => IRInfo::new("MOV", IRType::RegReg),
-            Mul => IRInfo::new("MUL", IRType::RegReg),
-            MulImm => IRInfo::new("MUL", IRType::RegImm),
-            Nop => IRInfo::new("NOP", IRType::Noarg),
-            Return => IRInfo::new("RET", IRType::Reg),
-            Store(_) => IRInfo::new("STORE", IRType::Mem),
-            StoreArg(_) => IRInfo::new("STORE_ARG", IRType::StoreArg),
-            Sub => IRInfo::new("SUB", IRType::RegReg),
-            SubImm => IRInfo::new("SUB", IRType::RegImm),
-            Bprel => IRInfo::new("BPREL", IRType::RegImm),
-            If => IRInfo::new("IF", IRType::RegLabel),
-            Unless => IRInfo::new("UNLESS", IRType::RegLabel),
-        }
-    }
-}
 
-impl fmt::Display for IR {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::IRType::*;
-
-        let info = &IRInfo::from(&self.op);
-
-        let lhs = self.lhs.unwrap();
-        match info.ty {
-            Label => write!(f, ".L{}:", lhs),
-            LabelAddr => match self.op {
-                IROp::LabelAddr(ref name) => write!(f, "  {} r{}, {}", info.name, lhs, name),
-                _ => unreachable!(),
-            },
-            Imm => write!(f, "  {} {}", info.name, lhs),
-            Reg => write!(f, "  {} r{}", info.name, lhs),
-            Jmp => write!(f, "  {} .L{}", info.name, lhs),
-            RegReg => write!(f, "  {} r{}, r{}", info.name, lhs, self.rhs.unwrap()),
-            Mem | StoreArg => match self.op {
-                IROp::Load(ref size) | IROp::Store(ref size) => {
-                    write!(f, "  {}{} r{}, {}", info.name, size, lhs, self.rhs.unwrap())
-                }
-                IROp::StoreArg(ref size) => {
-                    write!(f, "  {}{} {}, {}", info.name, size, lhs, self.rhs.unwrap())
+        let reg_reg =
+            |f: &mut fmt::Formatter, name, dst, src| write!(f, "  {} r{}, r{}", name, dst, src);
+        let reg_imm =
+            |f: &mut fmt::Formatter, name, dst, imm| write!(f, "  {} r{}, {}", name, dst, imm);
+
+        match *self {
+            Add { dst, src } => reg_reg(f, "ADD", dst, src),
+            Sub { dst, src } => reg_reg(f, "SUB", dst, src),
+            Mul { dst, src } => reg_reg(f, "MUL", dst, src),
+            Div { dst, src, signed } => reg_reg(f, if signed { "DIV" } else { "DIVU" }, dst, src),
+            Mod { dst, src, signed } => reg_reg(f, if signed { "MOD" } else { "MODU" }, dst, src),
+            Mov { dst, src } => reg_reg(f, "MOV", dst, src),
+            EQ { dst, src } => reg_reg(f, "EQ", dst, src),
+            NE { dst, src } => reg_reg(f, "NE", dst, src),
+            LE { dst, src, signed } => reg_reg(f, if signed { "LE" } else { "LEU" }, dst, src),
+            LT { dst, src, signed } => reg_reg(f, if signed { "LT" } else { "LTU" }, dst, src),
+            AND { dst, src } => reg_reg(f, "AND", dst, src),
+            OR { dst, src } => reg_reg(f, "OR", dst, src),
+            XOR { dst, src } => reg_reg(f, "XOR", dst, src),
+            SHL { dst, src } => reg_reg(f, "SHL", dst, src),
+            SHR { dst, src, signed } => reg_reg(f, if signed { "SHR" } else { "SHRU" }, dst, src),
+            FAdd { dst, src } => reg_reg(f, "FADD", dst, src),
+            FSub { dst, src } => reg_reg(f, "FSUB", dst, src),
+            FMul { dst, src } => reg_reg(f, "FMUL", dst, src),
+            FDiv { dst, src } => reg_reg(f, "FDIV", dst, src),
+            FEQ { dst, src } => reg_reg(f, "FEQ", dst, src),
+            FNE { dst, src } => reg_reg(f, "FNE", dst, src),
+            FLE { dst, src } => reg_reg(f, "FLE", dst, src),
+            FLT { dst, src } => reg_reg(f, "FLT", dst, src),
+            AddImm { dst, imm } => reg_imm(f, "ADD", dst, imm as i64),
+            SubImm { dst, imm } => reg_imm(f, "SUB", dst, imm as i64),
+            MulImm { dst, imm } => reg_imm(f, "MUL", dst, imm as i64),
+            Imm { dst, imm } => reg_imm(f, "MOV", dst, imm),
+            Bprel { dst, offset } => reg_imm(f, "BPREL", dst, offset as i64),
+            Neg { dst } => write!(f, "  NEG r{}", dst),
+            Extend { dst, size, signed } => {
+                let name = if signed { "EXTEND" } else { "ZEXTEND" };
+                write!(f, "  {}{} r{}", name, size, dst)
+            }
+            FNeg { dst } => write!(f, "  FNEG r{}", dst),
+            I2F { dst } => write!(f, "  I2F r{}", dst),
+            F2I { dst } => write!(f, "  F2I r{}", dst),
+            Return { src } => write!(f, "  RET r{}", src),
+            StoreSpill { slot, src } => write!(f, "  STORE_SPILL {}, r{}", slot, src),
+            LoadSpill { dst, slot } => write!(f, "  LOAD_SPILL r{}, {}", dst, slot),
+            VaStart { ap, gp, fp, stack } => {
+                write!(f, "  VA_START r{}, {}, {}, {}", ap, gp, fp, stack)
+            }
+            Kill(r) => write!(f, "  KILL r{}", r),
+            Nop => write!(f, "  NOP"),
+            Label(x) => write!(f, "{}:", mangle::label(x)),
+            Jmp(x) => write!(f, "  JMP {}", mangle::label(x)),
+            If { size, cond, label } => {
+                write!(f, "  IF{} r{}, {}", size, cond, mangle::label(label))
+            }
+            Unless { size, cond, label } => {
+                write!(f, "  UNLESS{} r{}, {}", size, cond, mangle::label(label))
+            }
+            LabelAddr { dst, name } => write!(f, "  LABEL_ADDR r{}, {}", dst, name),
+            Load { size, dst, addr } => write!(f, "  LOAD{} r{}, r{}", size, dst, addr),
+            Store { size, addr, src } => write!(f, "  STORE{} r{}, r{}", size, addr, src),
+            StoreArg { size, offset, arg } => write!(f, "  STORE_ARG{} {}, {}", size, offset, arg),
+            StoreStackArg {
+                size,
+                offset,
+                index,
+            } => write!(f, "  STORE_STACK_ARG{} {}, {}", size, offset, index),
+            FLoad { size, dst, addr } => write!(f, "  FLOAD{} r{}, r{}", size, dst, addr),
+            FStore { size, addr, src } => write!(f, "  FSTORE{} r{}, r{}", size, addr, src),
+            FStoreArg { size, offset, arg } => {
+                write!(f, "  FSTORE_ARG{} {}, {}", size, offset, arg)
+            }
+            Call {
+                dst,
+                name,
+                ptr,
+                ref args,
+                noreturn,
+                ..
+            } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|arg| match *arg {
+                        Arg::Reg(r) => format!("r{}", r),
+                        Arg::Slot(offset) => format!("[bp-{}]", offset),
+                    })
+                    .collect();
+                match ptr {
+                    Some(ptr) => write!(f, "  r{} = *r{}({})", dst, ptr, args.join(", "))?,
+                    None => write!(f, "  r{} = {}({})", dst, name, args.join(", "))?,
                 }
-                _ => unreachable!(),
-            },
-            RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i32),
-            RegLabel => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
-            Call => match self.op {
-                IROp::Call(ref name, nargs, args) => {
-                    let mut sb: String = format!("  r{} = {}(", lhs, name);
-                    for (i, arg) in args.iter().enumerate().take(nargs) {
-                        if i != 0 {
-                            sb.push_str(&", ".to_string());
-                        }
-                        sb.push_str(&format!("r{}", *arg));
-                    }
-                    sb.push_str(")");
-                    write!(f, "{}", sb)
+                if noreturn {
+                    write!(f, " noreturn")?;
                 }
-                _ => unreachable!(),
-            },
-            Noarg => write!(f, "  {}", info.name),
+                Ok(())
+            }
+            Line { line, ref text } => write!(f, "  # line {}: {}", line, text),
+            Memcpy { dst, src, size } => write!(f, "  MEMCPY r{}, r{}, {}", dst, src, size),
+            Memset { dst, value, size } => write!(f, "  MEMSET r{}, {}, {}", dst, value, size),
         }
     }
 }
@@ -110,3 +120,301 @@ pub fn dump_ir(fns: &[Function]) {
         }
     }
 }
+
+// Reads IR back from the text dump_ir prints, so that the backend can
+// be fed IR that the front end would never produce. A function starts
+// at a "name():" line, and registers are numbered as gen_ir numbers
+// them, each one used in a single function. The dump does not say which
+// call arguments and results are floating point, so calls read back
+// pass integers, and a function's frame is just large enough for the
+// BPREL offsets it uses.
+pub fn read_ir(text: &str) -> Result<Vec<Function>, String> {
+    let mut fns: Vec<(Symbol, Vec<IROp>)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_suffix("():") {
+            fns.push((Symbol::intern(name), vec![]));
+            continue;
+        }
+        let op = line
+            .parse()
+            .map_err(|msg| format!("line {}: {}: {}", i + 1, msg, line))?;
+        match fns.last_mut() {
+            Some((_, ir)) => ir.push(op),
+            None => return Err(format!("line {}: IR outside a function: {}", i + 1, line)),
+        }
+    }
+
+    let fns = fns.into_iter().map(|(name, ir)| {
+        let stacksize = ir
+            .iter()
+            .filter_map(|op| match *op {
+                IROp::Bprel { offset, .. } => Some(offset),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        Function::new(name, ir, stacksize, true, 0)
+    });
+    Ok(fns.collect())
+}
+
+fn num<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse()
+        .map_err(|_| format!("number expected, not '{}'", s))
+}
+
+fn reg(s: &str) -> Result<Reg, String> {
+    match s.strip_prefix('r') {
+        Some(n) => num(n),
+        None => Err(format!("register expected, not '{}'", s)),
+    }
+}
+
+fn label(s: &str) -> Result<usize, String> {
+    mangle::parse_label(s).ok_or_else(|| format!("label expected, not '{}'", s))
+}
+
+fn arg(s: &str) -> Result<Arg, String> {
+    match s.strip_prefix("[bp-").and_then(|s| s.strip_suffix(']')) {
+        Some(offset) => Ok(Arg::Slot(num(offset)?)),
+        None => Ok(Arg::Reg(reg(s)?)),
+    }
+}
+
+// r<dst> = <name>(<args>) or r<dst> = *r<ptr>(<args>), then maybe
+// "noreturn".
+fn call(dst: &str, rest: &str) -> Result<IROp, String> {
+    let (callee, rest) = rest.split_once('(').ok_or("'(' expected")?;
+    let (args, rest) = rest.split_once(')').ok_or("')' expected")?;
+    let args = if args.is_empty() {
+        vec![]
+    } else {
+        args.split(", ").map(arg).collect::<Result<Vec<_>, _>>()?
+    };
+    let (name, ptr) = match callee.strip_prefix('*') {
+        Some(ptr) => (Symbol::intern(""), Some(reg(ptr)?)),
+        None => (Symbol::intern(callee), None),
+    };
+    Ok(IROp::Call {
+        dst: reg(dst)?,
+        name,
+        ptr,
+        float_args: vec![0; args.len()],
+        args,
+        float_ret: 0,
+        noreturn: match rest.trim() {
+            "" => false,
+            "noreturn" => true,
+            s => return Err(format!("unexpected '{}'", s)),
+        },
+    })
+}
+
+impl FromStr for IROp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        use self::IROp::*;
+
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix("# line ") {
+            let (line, text) = rest.split_once(": ").ok_or("': ' expected")?;
+            let text = text.to_string();
+            return Ok(Line {
+                line: num(line)?,
+                text,
+            });
+        }
+        if let Some(l) = s.strip_suffix(':') {
+            return Ok(Label(label(l)?));
+        }
+        if let Some((dst, rest)) = s.split_once(" = ") {
+            return call(dst, rest);
+        }
+
+        // The size some ops take is written right after their name.
+        let (mnemonic, operands) = s.split_once(' ').unwrap_or((s, ""));
+        let name = mnemonic.trim_end_matches(|c: char| c.is_ascii_digit());
+        let size = || num::<u8>(&mnemonic[name.len()..]);
+        let ops: Vec<&str> = operands.split(", ").filter(|s| !s.is_empty()).collect();
+        let is_reg = |s: &str| reg(s).is_ok();
+
+        let op = match (mnemonic, &ops[..]) {
+            ("ADD", &[d, s]) if is_reg(s) => Add {
+                dst: reg(d)?,
+                src: reg(s)?,
+            },
+            ("ADD", &[d, imm]) => AddImm {
+                dst: reg(d)?,
+                imm: num(imm)?,
+            },
+            ("SUB", &[d, s]) if is_reg(s) => Sub {
+                dst: reg(d)?,
+                src: reg(s)?,
+            },
+            ("SUB", &[d, imm]) => SubImm {
+                dst: reg(d)?,
+                imm: num(imm)?,
+            },
+            ("MUL", &[d, s]) if is_reg(s) => Mul {
+                dst: reg(d)?,
+                src: reg(s)?,
+            },
+            ("MUL", &[d, imm]) => MulImm {
+                dst: reg(d)?,
+                imm: num(imm)?,
+            },
+            ("MOV", &[d, s]) if is_reg(s) => Mov {
+                dst: reg(d)?,
+                src: reg(s)?,
+            },
+            ("MOV", &[d, imm]) => Imm {
+                dst: reg(d)?,
+                imm: num(imm)?,
+            },
+            ("BPREL", &[d, offset]) => Bprel {
+                dst: reg(d)?,
+                offset: num(offset)?,
+            },
+            ("LE", &[d, s]) | ("LEU", &[d, s]) => LE {
+                dst: reg(d)?,
+                src: reg(s)?,
+                signed: mnemonic == "LE",
+            },
+            ("LT", &[d, s]) | ("LTU", &[d, s]) => LT {
+                dst: reg(d)?,
+                src: reg(s)?,
+                signed: mnemonic == "LT",
+            },
+            ("DIV", &[d, s]) | ("DIVU", &[d, s]) => Div {
+                dst: reg(d)?,
+                src: reg(s)?,
+                signed: mnemonic == "DIV",
+            },
+            ("MOD", &[d, s]) | ("MODU", &[d, s]) => Mod {
+                dst: reg(d)?,
+                src: reg(s)?,
+                signed: mnemonic == "MOD",
+            },
+            ("SHR", &[d, s]) | ("SHRU", &[d, s]) => SHR {
+                dst: reg(d)?,
+                src: reg(s)?,
+                signed: mnemonic == "SHR",
+            },
+            (_, &[d, s]) if is_reg(d) && is_reg(s) && name == mnemonic => {
+                let (dst, src) = (reg(d)?, reg(s)?);
+                match mnemonic {
+                    "EQ" => EQ { dst, src },
+                    "NE" => NE { dst, src },
+                    "AND" => AND { dst, src },
+                    "OR" => OR { dst, src },
+                    "XOR" => XOR { dst, src },
+                    "SHL" => SHL { dst, src },
+                    "FADD" => FAdd { dst, src },
+                    "FSUB" => FSub { dst, src },
+                    "FMUL" => FMul { dst, src },
+                    "FDIV" => FDiv { dst, src },
+                    "FEQ" => FEQ { dst, src },
+                    "FNE" => FNE { dst, src },
+                    "FLE" => FLE { dst, src },
+                    "FLT" => FLT { dst, src },
+                    _ => return Err(format!("unknown op '{}'", mnemonic)),
+                }
+            }
+            ("NEG", &[d]) => Neg { dst: reg(d)? },
+            ("FNEG", &[d]) => FNeg { dst: reg(d)? },
+            ("I2F", &[d]) => I2F { dst: reg(d)? },
+            ("F2I", &[d]) => F2I { dst: reg(d)? },
+            ("RET", &[s]) => Return { src: reg(s)? },
+            ("STORE_SPILL", &[slot, s]) => StoreSpill {
+                slot: num(slot)?,
+                src: reg(s)?,
+            },
+            ("LOAD_SPILL", &[d, slot]) => LoadSpill {
+                dst: reg(d)?,
+                slot: num(slot)?,
+            },
+            ("VA_START", &[ap, gp, fp, stack]) => VaStart {
+                ap: reg(ap)?,
+                gp: num(gp)?,
+                fp: num(fp)?,
+                stack: num(stack)?,
+            },
+            ("KILL", &[r]) => Kill(reg(r)?),
+            ("NOP", &[]) => Nop,
+            ("JMP", &[l]) => Jmp(label(l)?),
+            ("LABEL_ADDR", &[d, name]) => LabelAddr {
+                dst: reg(d)?,
+                name: Symbol::intern(name),
+            },
+            ("MEMCPY", &[d, s, size]) => Memcpy {
+                dst: reg(d)?,
+                src: reg(s)?,
+                size: num(size)?,
+            },
+            ("MEMSET", &[d, value, size]) => Memset {
+                dst: reg(d)?,
+                value: num(value)?,
+                size: num(size)?,
+            },
+            _ => match (name, &ops[..]) {
+                ("EXTEND", &[d]) | ("ZEXTEND", &[d]) => Extend {
+                    dst: reg(d)?,
+                    size: size()?,
+                    signed: name == "EXTEND",
+                },
+                ("IF", &[c, l]) => If {
+                    size: size()?,
+                    cond: reg(c)?,
+                    label: label(l)?,
+                },
+                ("UNLESS", &[c, l]) => Unless {
+                    size: size()?,
+                    cond: reg(c)?,
+                    label: label(l)?,
+                },
+                ("LOAD", &[d, a]) => Load {
+                    size: size()?,
+                    dst: reg(d)?,
+                    addr: reg(a)?,
+                },
+                ("STORE", &[a, s]) => Store {
+                    size: size()?,
+                    addr: reg(a)?,
+                    src: reg(s)?,
+                },
+                ("FLOAD", &[d, a]) => FLoad {
+                    size: size()?,
+                    dst: reg(d)?,
+                    addr: reg(a)?,
+                },
+                ("FSTORE", &[a, s]) => FStore {
+                    size: size()?,
+                    addr: reg(a)?,
+                    src: reg(s)?,
+                },
+                ("STORE_ARG", &[offset, arg]) => StoreArg {
+                    size: size()?,
+                    offset: num(offset)?,
+                    arg: num(arg)?,
+                },
+                ("FSTORE_ARG", &[offset, arg]) => FStoreArg {
+                    size: size()?,
+                    offset: num(offset)?,
+                    arg: num(arg)?,
+                },
+                ("STORE_STACK_ARG", &[offset, index]) => StoreStackArg {
+                    size: size()?,
+                    offset: num(offset)?,
+                    index: num(index)?,
+                },
+                _ => return Err(format!("unknown op '{}'", mnemonic)),
+            },
+        };
+        Ok(op)
+    }
+}
@@ -1,23 +1,181 @@
+pub mod callgraph;
+pub mod diag;
 pub mod gen_ir;
 pub mod gen_x86;
 pub mod irdump;
+pub mod loader;
+pub mod mangle;
+pub mod opt;
 pub mod parse;
 pub mod preprocess;
 pub mod regalloc;
 pub mod sema;
+pub mod stats;
+pub mod symbol;
+pub mod target;
 pub mod token;
+pub mod uninit;
 mod util;
 
+use crate::gen_x86::CodegenError;
+use crate::opt::OptError;
+use crate::preprocess::Preprocessor;
+use crate::stats::AsmStats;
+use crate::symbol::Symbol;
+use crate::token::{LexError, TokenStream};
+
+use std::fmt;
+
 #[macro_use]
 extern crate lazy_static;
 
 const REGS_N: usize = 7;
 
+// The compiler's version, e.g. "0.1.0 (1a2b3c4)", with the git commit it
+// was built from if it was built in a checkout.
+pub fn version() -> &'static str {
+    env!("R9CC_VERSION")
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    Lex(LexError),
+    Codegen(CodegenError),
+    Opt(OptError),
+    Fatal(LexError), // an error found while parsing or in sema
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Lex(e) => e.fmt(f),
+            CompileError::Codegen(e) => write!(f, "error: {}", e),
+            CompileError::Opt(e) => e.fmt(f),
+            CompileError::Fatal(e) => e.fmt(f),
+        }
+    }
+}
+
+// A file for compile_many: one on disk, or source text and the name to
+// report it by.
+#[derive(Debug, Clone)]
+pub enum SourceInput {
+    Path(String),
+    Text(String, String), // name, source
+}
+
+impl SourceInput {
+    // The name diagnostics give the input.
+    pub fn name(&self) -> &str {
+        match self {
+            SourceInput::Path(path) => path,
+            SourceInput::Text(name, _) => name,
+        }
+    }
+}
+
+// The flags of the same names, for compile_many. The target is the
+// one set with target::set_target.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub include_dirs: Vec<String>,             // -I
+    pub macros: Vec<(String, Option<String>)>, // -DNAME=VALUE, or -UNAME with no value, in order
+    pub optimize: bool,                        // -O
+    pub zero_locals: bool,                     // --ftrivial-auto-var-init=zero
+}
+
+#[derive(Debug)]
+pub struct CompileOutput {
+    pub asm: Vec<u8>,
+    pub stats: AsmStats,
+    pub warnings: Vec<String>, // as r9cc would print them
+}
+
+// Compiles the file at `path` to assembly with the default options,
+// the way `r9cc <file>` does, printing diagnostics as it goes.
+pub fn compile(path: String, ctx: &mut Preprocessor) -> Result<Vec<u8>, CompileError> {
+    diag::reset_counts();
+    let tokens = TokenStream::open(path, ctx).map_err(CompileError::Lex)?;
+    let (asm, _) = compile_tokens(tokens, &Options::default())?;
+    Ok(asm)
+}
+
+// Compiles each input as a separate run of r9cc would, and returns
+// what became of each, in order. An input that fails doesn't stop the
+// rest. No diagnostic is printed; errors and warnings come back in the
+// results.
+//
+// Each compilation keeps its state to itself, and diagnostics are
+// counted per thread, so threads may compile at the same time.
+pub fn compile_many(
+    inputs: &[SourceInput],
+    opts: &Options,
+) -> Vec<Result<CompileOutput, CompileError>> {
+    inputs
+        .iter()
+        .map(|input| {
+            diag::capture();
+            let result = compile_input(input, opts);
+            let warnings = diag::end_capture();
+            result.map(|out| CompileOutput { warnings, ..out })
+        })
+        .collect()
+}
+
+fn compile_input(input: &SourceInput, opts: &Options) -> Result<CompileOutput, CompileError> {
+    diag::reset_counts();
+    let mut ctx = Preprocessor::new();
+    for dir in &opts.include_dirs {
+        ctx.add_include_dir(dir);
+    }
+    for (name, value) in &opts.macros {
+        match value {
+            Some(value) => ctx.define(name, value).map_err(CompileError::Lex)?,
+            None => ctx.undefine(name),
+        }
+    }
+    let tokens = match input {
+        SourceInput::Path(path) => TokenStream::open(path.clone(), &mut ctx),
+        SourceInput::Text(name, text) => TokenStream::new(name.clone(), text, &mut ctx),
+    }
+    .map_err(CompileError::Lex)?;
+    let (asm, stats) = compile_tokens(tokens, opts)?;
+    Ok(CompileOutput {
+        asm,
+        stats,
+        warnings: vec![],
+    })
+}
+
+fn compile_tokens(
+    tokens: TokenStream,
+    opts: &Options,
+) -> Result<(Vec<u8>, AsmStats), CompileError> {
+    let nodes = parse::parse(tokens).map_err(CompileError::Fatal)?;
+    let prog = sema::sema(nodes).map_err(CompileError::Fatal)?;
+    uninit::check_uninitialized(&prog.nodes, &prog.noreturn);
+    let mut fns = gen_ir::gen_ir(prog.nodes, &prog.noreturn, opts.zero_locals);
+    if opts.optimize {
+        opt::optimize(&mut fns, opt::PASSES, opt::Fuel::default()).map_err(CompileError::Opt)?;
+    }
+    regalloc::alloc_regs(&mut fns);
+    let mut asm = vec![];
+    let stats = gen_x86::gen_x86(&mut asm, prog.globals, fns, false, opts.optimize)
+        .map_err(CompileError::Codegen)?;
+    Ok((asm, stats))
+}
+
 #[macro_export]
 macro_rules! matches(
-    ($e:expr, $p:pat) => (
+    ($e:expr, $($p:pat)|+) => (
         match $e {
-            $p => true,
+            $($p)|+ => true,
+            _ => false
+        }
+    );
+    ($e:expr, $($p:pat)|+ if $guard:expr) => (
+        match $e {
+            $($p)|+ if $guard => true,
             _ => false
         }
     )
@@ -26,15 +184,25 @@ macro_rules! matches(
 // Token type
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
-    Num(i32),            // Number literal
+    // Number literal. (value, is long, is unsigned)
+    Num(i64, bool, bool),
+    FNum(f64, bool),     // Floating point literal. (value, is float)
     Str(String, usize),  // String literal. (str, len)
     CharLiteral(String), // Char literal.
-    Ident(String),       // Identifier
+    HeaderName(String),  // <stdio.h> after #include, without the brackets
+    Ident(Symbol),       // Identifier
     Param(usize),        // Function-like macro parameter
     Arrow,               // ->
+    Ellipsis,            // ...
     Extern,              // "extern"
+    Noreturn,            // "_Noreturn"
+    Static,              // "static"
+    Const,               // "const"
     Typedef,             // "typedef"
     Int,                 // "int"
+    Long,                // "long"
+    Float,               // "float"
+    Double,              // "double"
     Char,                // "char"
     Void,                // "void"
     Struct,              // "struct"
@@ -51,12 +219,18 @@ pub enum TokenType {
     Hat,                 // ^
     Colon,               // :
     HashMark,            // #
+    HashHash,            // ##
     If,                  // "if"
     Else,                // "else"
     For,                 // "for"
     Do,                  // "do"
     While,               // "while"
     Break,               // "break"
+    Continue,            // "continue"
+    Switch,              // "switch"
+    Case,                // "case"
+    Default,             // "default"
+    Goto,                // "goto"
     EQ,                  // ==
     NE,                  // !=
     LE,                  // <=
@@ -92,6 +266,7 @@ pub enum TokenType {
     Sizeof,              // "sizeof"
     Alignof,             // "_Alignof"
     NewLine,             // preprocessor-only token
+    Eof,                 // parser-only token, past the last one
 }
 
 // Character Kind
@@ -138,21 +313,19 @@ impl TokenType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Ctype {
+    #[default]
     Int,
+    Long,
     Char,
+    Float,
+    Double,
     Void,
-    Ptr(Box<Type>),           // ptr of
-    Ary(Box<Type>, usize),    // ary of, len
-    Struct(Vec<parse::Node>), // members
-    Func(Box<Type>),
-}
-
-impl Default for Ctype {
-    fn default() -> Ctype {
-        Ctype::Int
-    }
+    Ptr(Box<Type>),                                 // ptr of
+    Ary(Box<Type>, Option<usize>),                  // ary of, len (None if incomplete)
+    Struct(Vec<Member>),                            // members
+    Func(Box<Type>, Option<Vec<Type>>, bool, bool), // returning, params (None if unspecified), variadic, noreturn
 }
 
 #[derive(Debug, Clone)]
@@ -160,15 +333,20 @@ pub struct Type {
     pub ty: Ctype,
     pub size: usize,  // sizeof
     pub align: usize, // alignof
+    pub unsigned: bool,
+}
+
+// A struct member and where it is in the struct.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: Symbol,
+    pub ty: Type,
+    pub offset: usize,
 }
 
 impl Default for Type {
     fn default() -> Type {
-        Type {
-            ty: Ctype::default(),
-            size: 4,
-            align: 4,
-        }
+        Type::int_ty()
     }
 }
 
@@ -181,16 +359,16 @@ pub enum Scope {
 #[derive(Debug, Clone)]
 pub struct Var {
     ty: Box<Type>,
-    pub name: String,
+    pub name: Symbol,
     pub scope: Scope,
 }
 
 impl Var {
-    fn new(ty: Box<Type>, name: String, scope: Scope) -> Self {
+    fn new(ty: Box<Type>, name: Symbol, scope: Scope) -> Self {
         Var { ty, name, scope }
     }
 
-    fn new_global(ty: Box<Type>, name: String, data: String, len: usize, is_extern: bool) -> Self {
-        Var::new(ty, name.clone(), Scope::Global(data, len, is_extern))
+    fn new_global(ty: Box<Type>, name: Symbol, data: String, len: usize, is_extern: bool) -> Self {
+        Var::new(ty, name, Scope::Global(data, len, is_extern))
     }
 }
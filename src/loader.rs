@@ -0,0 +1,149 @@
+// Where #include reads files from
+//
+// The preprocessor looks included files up and reads them through a
+// FileLoader. The compiler uses the disk; tests and programs embedding
+// the compiler can hand it files from memory instead. Paths reach a
+// loader already normalized by util::normalize_path, with '/' for a
+// separator.
+
+use crate::token::read_source;
+use crate::util::normalize_path;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub trait FileLoader {
+    // Whether `path` is a file or a directory, spelled exactly so.
+    fn exists(&self, path: &Path) -> bool;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    // The names in a directory, for the case-insensitive fallback.
+    fn read_dir(&self, dir: &Path) -> Vec<String>;
+
+    fn read(&self, path: &Path) -> Result<String, String>;
+
+    // One name for a file however it is spelled, for #pragma once.
+    fn canonicalize(&self, path: &Path) -> PathBuf;
+}
+
+// The file system.
+pub struct DiskLoader;
+
+impl FileLoader for DiskLoader {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read_dir(&self, dir: &Path) -> Vec<String> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .collect()
+    }
+
+    fn read(&self, path: &Path) -> Result<String, String> {
+        read_source(&path.to_string_lossy())
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| normalize_path(&path.to_string_lossy()))
+    }
+}
+
+// Files held in memory by path. A directory exists if some file is in
+// it; a relative path is relative to ".".
+#[derive(Default)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `path` may use either separator, as an #include may.
+    pub fn add(&mut self, path: &str, contents: &str) {
+        self.files
+            .insert(normalize_path(path), contents.to_string());
+    }
+}
+
+impl FileLoader for MemoryLoader {
+    fn exists(&self, path: &Path) -> bool {
+        path == Path::new(".") || self.files.keys().any(|file| file.starts_with(path))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn read_dir(&self, dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .files
+            .keys()
+            .filter_map(|file| {
+                let rest = if dir == Path::new(".") && file.is_relative() {
+                    file.as_path()
+                } else {
+                    file.strip_prefix(dir).ok()?
+                };
+                let first = rest.components().next()?;
+                Some(first.as_os_str().to_string_lossy().into_owned())
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn read(&self, path: &Path) -> Result<String, String> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| format!("cannot read file '{}': not found", path.display()))
+    }
+
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+}
+
+// Looks a file up component by component, falling back to a
+// case-insensitive match for components that don't exist as spelled.
+pub(crate) fn find_file(loader: &dyn FileLoader, path: &Path) -> Option<PathBuf> {
+    if loader.is_file(path) {
+        return Some(path.to_path_buf());
+    }
+
+    let mut found = PathBuf::new();
+    for c in path.components() {
+        let next = found.join(c);
+        if loader.exists(&next) {
+            found = next;
+            continue;
+        }
+
+        let name = c.as_os_str().to_str()?;
+        let dir = if found.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            found.as_path()
+        };
+        let entry = loader
+            .read_dir(dir)
+            .into_iter()
+            .find(|e| e.eq_ignore_ascii_case(name))?;
+        found.push(entry);
+    }
+    Some(found).filter(|path| loader.is_file(path))
+}
@@ -1,55 +1,218 @@
 extern crate r9cc;
 
+use r9cc::callgraph::{self, callgraph};
+use r9cc::diag;
 use r9cc::gen_ir::gen_ir;
 use r9cc::gen_x86::gen_x86;
-use r9cc::irdump::dump_ir;
+use r9cc::irdump::{dump_ir, read_ir};
+use r9cc::opt::{self, Fuel};
 use r9cc::parse::parse;
 use r9cc::preprocess::Preprocessor;
 use r9cc::regalloc::alloc_regs;
 use r9cc::sema::sema;
-use r9cc::token::tokenize;
+use r9cc::target::{set_target, Target};
+use r9cc::token::{dump_tokens, preprocess_to_string, tokenize, TokenStream};
+use r9cc::uninit::check_uninitialized;
+use r9cc::version;
 
+use std::collections::hash_map::RandomState;
 use std::env;
-use std::process;
+use std::fs::{self, DirBuilder};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
 
 fn usage() -> ! {
-    eprintln!("Usage: 9cc [-dump-ir1] [-dump-ir2] <file>");
+    eprintln!(concat!(
+        "Usage: 9cc [--version] [-E] [-O [--opt-fuel <n>]] [-dump-tokens] [-dump-ir1] [-dump-ir2] [--asm-comments] [--stats-asm[=json]]\n",
+        "           [--dump-callgraph[=dot]] [--trace-macro[=<name>]] [--trigraphs]\n",
+        "           [-D<name>[=<value>]] [-U<name>] [-I<dir>] [--sysroot <dir>] [--target <name>] [-fsigned-char] [-funsigned-char] [--ftrivial-auto-var-init=zero]\n",
+        "           [--max-errors <n>] [-Wno-<warning>] [--run [--save-temps]] [-o <output>] <file>\n",
+        "--run exits with the status of the program, which is what main returns masked to\n",
+        "its low 8 bits (300 becomes 44, -1 becomes 255), or 128+N if it was killed by signal N."
+    ));
     process::exit(1)
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        usage();
-    }
-
+    let mut preprocess_only = false;
+    let mut dump_tokens_only = false;
     let mut dump_ir1 = false;
     let mut dump_ir2 = false;
-    let path;
-
-    if args.len() == 3 && args[1] == "-dump-ir1" {
-        dump_ir1 = true;
-        path = args[2].clone();
-    } else if args.len() == 3 && args[1] == "-dump-ir2" {
-        dump_ir2 = true;
-        path = args[2].clone();
-    } else {
-        if args.len() != 2 {
-            usage();
+    let mut asm_comments = false;
+    let mut optimize = false;
+    let mut fuel = Fuel::default();
+    let mut run = false;
+    let mut save_temps = false;
+    let mut stats_asm = None;
+    let mut dump_callgraph = None;
+    let mut trace_macro = None;
+    let mut trigraphs = false;
+    let mut target = Target::default();
+    let mut char_is_signed = None;
+    let mut zero_locals = false;
+    let mut macros = vec![]; // -D and -U in order, with None for -U
+    let mut include_dirs = vec![];
+    let mut sysroot = None;
+    let mut path = None;
+    let mut output = None; // -o, or else stdout
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--version" => {
+                println!("r9cc {}", version());
+                return;
+            }
+            "-E" => preprocess_only = true,
+            "-O" => optimize = true,
+            "--run" => run = true,
+            "--save-temps" => save_temps = true,
+            "-dump-tokens" => dump_tokens_only = true,
+            "-dump-ir1" => dump_ir1 = true,
+            "-dump-ir2" => dump_ir2 = true,
+            "--asm-comments" => asm_comments = true,
+            "--stats-asm" => stats_asm = Some(false),
+            "--stats-asm=json" => stats_asm = Some(true),
+            "--dump-callgraph" => dump_callgraph = Some(false),
+            "--dump-callgraph=dot" => dump_callgraph = Some(true),
+            "--trace-macro" => trace_macro = Some(None),
+            "--trigraphs" => trigraphs = true,
+            "--target" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                target = Target::from_name(&name).unwrap_or_else(|| {
+                    eprintln!("unknown target: {}", name);
+                    process::exit(1)
+                });
+            }
+            "--opt-fuel" => {
+                let n = args.next().and_then(|n| n.parse().ok());
+                fuel.rewrites = n.unwrap_or_else(|| usage());
+            }
+            "--max-errors" => {
+                let n = args.next().and_then(|n| n.parse().ok());
+                diag::set_max_errors(n.unwrap_or_else(|| usage()));
+            }
+            "-fsigned-char" => char_is_signed = Some(true),
+            "-funsigned-char" => char_is_signed = Some(false),
+            "--ftrivial-auto-var-init=zero" => zero_locals = true,
+            "-D" | "-U" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                macros.push(macro_option(&arg, &name));
+            }
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-I" => include_dirs.push(args.next().unwrap_or_else(|| usage())),
+            "--sysroot" => sysroot = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with("-I") => include_dirs.push(arg[2..].to_string()),
+            _ if arg.starts_with("--sysroot=") => {
+                sysroot = Some(arg["--sysroot=".len()..].to_string())
+            }
+            _ if arg.starts_with("-D") || arg.starts_with("-U") => {
+                macros.push(macro_option(&arg[..2], &arg[2..]))
+            }
+            _ if arg.starts_with("--trace-macro=") => {
+                trace_macro = Some(Some(arg["--trace-macro=".len()..].to_string()))
+            }
+            _ if arg.starts_with("-Wno-") => diag::disable_warning(&arg["-Wno-".len()..]),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    if let Some(signed) = char_is_signed {
+        target.char_is_signed = signed;
+    }
+    set_target(target);
+
+    let mut ctx = Preprocessor::new();
+    if let Some(only) = trace_macro {
+        ctx.trace_macros(only.as_deref());
+    }
+    if trigraphs {
+        ctx.enable_trigraphs();
+    }
+    for dir in &include_dirs {
+        ctx.add_include_dir(dir);
+    }
+    if let Some(dir) = sysroot {
+        ctx.set_sysroot(&dir);
+    }
+    for (name, value) in macros {
+        match value {
+            Some(value) => {
+                if ctx.define(&name, &value).is_err() {
+                    finish();
+                }
+            }
+            None => ctx.undefine(&name),
+        }
+    }
+
+    // Errors are reported as they are found, so all that is left to do
+    // on one is to stop.
+    if preprocess_only {
+        match preprocess_to_string(path, &mut ctx) {
+            Ok(s) => write_output(output.as_deref(), s.as_bytes()),
+            Err(_) => finish(),
         }
-        path = args[1].clone();
+        return;
     }
 
     // Tokenize and parse.
-    let tokens = tokenize(path, &mut Preprocessor::new());
+    if dump_tokens_only {
+        let tokens = tokenize(path, &mut ctx).unwrap_or_else(|_| finish());
+        dump_tokens(&tokens);
+        return;
+    }
 
-    let nodes = parse(&tokens);
-    let (nodes, globals) = sema(nodes);
-    let mut fns = gen_ir(nodes);
+    // A .ir file holds IR as -dump-ir1 prints it, and skips the front
+    // end. That is the way to give the backend IR it does not support.
+    let (mut fns, globals) = if path.ends_with(".ir") {
+        let text = fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("cannot read {}: {}", path, e);
+            process::exit(1)
+        });
+        match read_ir(&text) {
+            Ok(fns) => (fns, vec![]),
+            Err(msg) => {
+                diag::error(&format!("error: {}: {}", path, msg));
+                finish();
+            }
+        }
+    } else {
+        let tokens = TokenStream::open(path.clone(), &mut ctx).unwrap_or_else(|_| finish());
+        let nodes = parse(tokens).unwrap_or_else(|_| finish());
+        let prog = sema(nodes).unwrap_or_else(|_| finish());
+        check_uninitialized(&prog.nodes, &prog.noreturn);
+        (
+            gen_ir(prog.nodes, &prog.noreturn, zero_locals),
+            prog.globals,
+        )
+    };
 
     if dump_ir1 {
         dump_ir(&fns);
     }
+    match dump_callgraph {
+        Some(false) => callgraph::print_text(&callgraph(&fns)),
+        Some(true) => callgraph::print_dot(&callgraph(&fns)),
+        None => (),
+    }
+
+    if !target.has_backend {
+        eprintln!("no code generator for target: {}", target.name);
+        process::exit(1);
+    }
+
+    if optimize {
+        if let Err(e) = opt::optimize(&mut fns, opt::PASSES, fuel) {
+            diag::error(&e.to_string());
+            finish();
+        }
+    }
 
     alloc_regs(&mut fns);
 
@@ -57,5 +220,160 @@ fn main() {
         dump_ir(&fns);
     }
 
-    gen_x86(globals, fns);
+    let mut asm = vec![];
+    let stats = match gen_x86(&mut asm, globals, fns, asm_comments, optimize) {
+        Ok(stats) => stats,
+        Err(e) => {
+            diag::error(&format!("error: {}", e));
+            finish();
+        }
+    };
+    match stats_asm {
+        Some(false) => stats.print_table(),
+        Some(true) => stats.print_json(),
+        None => (),
+    }
+    if run {
+        if let Some(summary) = diag::summary() {
+            eprintln!("{}", summary);
+        }
+        run_program(&path, &asm, save_temps);
+    }
+    write_output(output.as_deref(), &asm);
+    finish();
+}
+
+// Writes the assembly or the preprocessed source to the -o file, or to
+// stdout.
+fn write_output(path: Option<&str>, bytes: &[u8]) {
+    let res = match path {
+        Some(path) => fs::write(path, bytes),
+        None => io::stdout().write_all(bytes),
+    };
+    if let Err(e) = res {
+        eprintln!("cannot write {}: {}", path.unwrap_or("to stdout"), e);
+        process::exit(1);
+    }
+}
+
+// Splits the argument of -D into a name and a value, which is 1 if
+// there is no `=`. -U gives a name and no value.
+fn macro_option(flag: &str, arg: &str) -> (String, Option<String>) {
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) if flag == "-D" => (name, value),
+        _ => (arg, "1"),
+    };
+    let is_ident = |s: &str| {
+        s.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    if !is_ident(name) {
+        eprintln!("macro names must be identifiers: {}{}", flag, arg);
+        process::exit(1);
+    }
+    let value = if flag == "-D" {
+        Some(value.to_string())
+    } else {
+        None
+    };
+    (name.to_string(), value)
+}
+
+// Assembles and runs the program, then exits with its status, or with
+// 128+N if it was killed by signal N. Like any exit status, that is the
+// low 8 bits of what main returns: 300 becomes 44 and -1 becomes 255.
+// The intermediate files go to a fresh directory under TMPDIR that is
+// removed afterwards, whether or not the build succeeded. With
+// --save-temps they are kept next to the source as <stem>.s and
+// <stem>.out instead.
+fn run_program(path: &str, asm: &[u8], save_temps: bool) -> ! {
+    let (dir, stem) = if save_temps {
+        let path = Path::new(path);
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let stem = path
+            .file_stem()
+            .map_or("a".into(), |s| s.to_string_lossy().into_owned());
+        (dir, stem)
+    } else {
+        (make_temp_dir(), "a".to_string())
+    };
+
+    let status = build_and_run(
+        &dir.join(format!("{}.s", stem)),
+        &dir.join(format!("{}.out", stem)),
+        asm,
+    );
+    if !save_temps {
+        let _ = fs::remove_dir_all(&dir);
+    }
+    process::exit(status)
+}
+
+// Creates a directory under TMPDIR that no one else can have made or
+// can get into: the name is random, and one that is already there is
+// never reused.
+fn make_temp_dir() -> PathBuf {
+    let base = env::temp_dir();
+    let mut attempt = 0;
+    loop {
+        // RandomState is seeded at random for each process.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(attempt);
+        let dir = base.join(format!("r9cc-{:016x}", hasher.finish()));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return dir,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(e) => {
+                eprintln!("cannot create {}: {}", dir.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn build_and_run(asm_path: &Path, exe: &Path, asm: &[u8]) -> i32 {
+    if let Err(e) = fs::write(asm_path, asm) {
+        eprintln!("cannot write {}: {}", asm_path.display(), e);
+        return 1;
+    }
+
+    let built = Command::new("gcc")
+        .arg("-static")
+        .arg("-o")
+        .arg(exe)
+        .arg(asm_path)
+        .status();
+    match built {
+        Ok(status) if status.success() => (),
+        Ok(_) => {
+            eprintln!("assembler failed");
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("cannot run gcc: {}", e);
+            return 1;
+        }
+    }
+
+    match Command::new(exe).status() {
+        Ok(status) => status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+        Err(e) => {
+            eprintln!("cannot run {}: {}", exe.display(), e);
+            1
+        }
+    }
+}
+
+// Prints the diagnostics summary and exits, failing if there were
+// any errors.
+fn finish() -> ! {
+    if let Some(summary) = diag::summary() {
+        eprintln!("{}", summary);
+    }
+    process::exit(if diag::error_count() > 0 { 1 } else { 0 })
 }
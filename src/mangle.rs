@@ -0,0 +1,48 @@
+// Names of the symbols and labels the compiler makes up
+//
+// String literals, branch targets and the like need names in the
+// assembly that no C identifier can take. They all start with the
+// target's local label prefix, ".L" on ELF, where a '.' can't begin an
+// identifier. C symbols are written as they are, so a target whose
+// prefix could begin an identifier would also have to prefix those;
+// none does yet. Every generated name is built and read back here, so
+// that this is the only place that needs to know.
+
+use crate::target::target;
+
+// The runtime half of the predefined assert() macro. It is a reserved
+// identifier rather than a local label, because the macro names it.
+pub const ASSERT_FAIL: &str = "__r9cc_assert_fail";
+
+fn local(name: &str) -> String {
+    format!("{}{}", target().local_label_prefix, name)
+}
+
+// A branch target in a function, from an IR label number.
+pub fn label(n: usize) -> String {
+    local(&n.to_string())
+}
+
+// The IR label number of a name made by label().
+pub fn parse_label(name: &str) -> Option<usize> {
+    let n = name.strip_prefix(target().local_label_prefix)?;
+    if !n.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    n.parse().ok()
+}
+
+// Where the n-th function returns from.
+pub fn return_label(n: usize) -> String {
+    local(&format!("end{}", n))
+}
+
+// The anonymous array holding the n-th string literal.
+pub fn string_literal(n: usize) -> String {
+    local(&format!(".str{}", n))
+}
+
+// The format string of the assert() failure message.
+pub fn assert_fmt() -> String {
+    local(".assert_fmt")
+}
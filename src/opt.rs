@@ -0,0 +1,271 @@
+// IR optimizer
+//
+// Run by the driver with -O, between IR generation and register
+// allocation. Each pass rewrites a function's IR in place and says how
+// many rewrites it made. The passes run in turn over a function until a
+// whole round of them changes nothing.
+//
+// One pass can re-create what another one removes, and then the rounds
+// never settle. So the optimizer runs on fuel: a limit on rounds per
+// function and on rewrites in total. Running out is a bug in a pass,
+// and is reported as an internal compiler error naming the pass and the
+// function instead of hanging.
+
+use crate::gen_ir::{Function, IROp};
+use crate::symbol::Symbol;
+
+use std::fmt;
+
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(&mut Vec<IROp>) -> usize, // returns the number of rewrites
+}
+
+pub const PASSES: &[Pass] = &[
+    Pass {
+        name: "fold-imm",
+        run: fold_imm,
+    },
+    Pass {
+        name: "merge-imm",
+        run: merge_imm,
+    },
+    Pass {
+        name: "dce",
+        run: dce,
+    },
+];
+
+#[derive(Clone, Copy)]
+pub struct Fuel {
+    pub rounds: usize,   // per function
+    pub rewrites: usize, // in total
+}
+
+impl Default for Fuel {
+    fn default() -> Self {
+        Fuel {
+            rounds: 100,
+            rewrites: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OptError {
+    pub pass: &'static str,
+    pub function: Symbol,
+    pub msg: String,
+}
+
+impl fmt::Display for OptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "internal compiler error: pass {} on {}: {}",
+            self.pass, self.function, self.msg
+        )
+    }
+}
+
+pub fn optimize(fns: &mut [Function], passes: &[Pass], mut fuel: Fuel) -> Result<(), OptError> {
+    for f in fns {
+        let mut rounds = 0;
+        loop {
+            let mut changed = false;
+            for pass in passes {
+                let n = (pass.run)(&mut f.ir);
+                if n == 0 {
+                    continue;
+                }
+                changed = true;
+                let error = |msg| OptError {
+                    pass: pass.name,
+                    function: f.name,
+                    msg,
+                };
+                if n > fuel.rewrites {
+                    return Err(error("ran out of fuel for rewrites".into()));
+                }
+                fuel.rewrites -= n;
+                if rounds == fuel.rounds {
+                    return Err(error(format!(
+                        "still rewriting after {} rounds",
+                        fuel.rounds
+                    )));
+                }
+            }
+            if !changed {
+                break;
+            }
+            rounds += 1;
+        }
+    }
+    Ok(())
+}
+
+// Replaces the ops at ir[i..i + n] with `op`.
+fn replace(ir: &mut Vec<IROp>, i: usize, n: usize, op: IROp) {
+    ir.splice(i..i + n, Some(op));
+}
+
+// MOV r, k; ADD d, r; KILL r  =>  ADD d, k
+// and the same for SUB and MUL, when k fits in an immediate.
+fn fold_imm(ir: &mut Vec<IROp>) -> usize {
+    use self::IROp::*;
+    let mut n = 0;
+    let mut i = 0;
+    while i + 2 < ir.len() {
+        let folded = match (&ir[i], &ir[i + 1], &ir[i + 2]) {
+            (&Imm { dst: r, imm }, op, &Kill(k)) if k == r && imm as i32 as i64 == imm => {
+                let imm = imm as i32;
+                match *op {
+                    Add { dst, src } if src == r && dst != r => Some(AddImm { dst, imm }),
+                    Sub { dst, src } if src == r && dst != r => Some(SubImm { dst, imm }),
+                    Mul { dst, src } if src == r && dst != r => Some(MulImm { dst, imm }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(op) = folded {
+            replace(ir, i, 3, op);
+            n += 1;
+        }
+        i += 1;
+    }
+    n
+}
+
+// ADD d, a; ADD d, b  =>  ADD d, a+b
+// counting SUB d, a as ADD d, -a. A sum of 0 leaves nothing.
+fn merge_imm(ir: &mut Vec<IROp>) -> usize {
+    use self::IROp::*;
+    let addend = |op: &IROp| match *op {
+        AddImm { dst, imm } => Some((dst, imm as i64)),
+        SubImm { dst, imm } => Some((dst, -(imm as i64))),
+        _ => None,
+    };
+    let mut n = 0;
+    let mut i = 0;
+    while i + 1 < ir.len() {
+        if let (Some((d1, a)), Some((d2, b))) = (addend(&ir[i]), addend(&ir[i + 1])) {
+            let sum = a + b;
+            if d1 == d2 && sum as i32 as i64 == sum {
+                if sum == 0 {
+                    ir.drain(i..i + 2);
+                } else {
+                    replace(
+                        ir,
+                        i,
+                        2,
+                        AddImm {
+                            dst: d1,
+                            imm: sum as i32,
+                        },
+                    );
+                }
+                n += 1;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    n
+}
+
+// Deletes what follows a jump, a return or a call that never returns,
+// up to the next label, which is the only way back in. The KILLs stay,
+// since the register allocator frees registers there.
+fn dce(ir: &mut Vec<IROp>) -> usize {
+    use self::IROp::*;
+    let mut n = 0;
+    let mut dead = false;
+    ir.retain(|op| {
+        match *op {
+            Label(_) => dead = false,
+            Kill(_) | Line { .. } => (),
+            _ if dead => {
+                n += 1;
+                return false;
+            }
+            _ => (),
+        }
+        if let Jmp(_) | Return { .. } | Call { noreturn: true, .. } = *op {
+            dead = true;
+        }
+        true
+    });
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two passes that undo each other, so the rounds never settle.
+    fn add_nop(ir: &mut Vec<IROp>) -> usize {
+        if ir.contains(&IROp::Nop) {
+            return 0;
+        }
+        ir.push(IROp::Nop);
+        1
+    }
+
+    fn remove_nop(ir: &mut Vec<IROp>) -> usize {
+        let len = ir.len();
+        ir.retain(|op| *op != IROp::Nop);
+        len - ir.len()
+    }
+
+    const PING_PONG: &[Pass] = &[
+        Pass {
+            name: "add-nop",
+            run: add_nop,
+        },
+        Pass {
+            name: "remove-nop",
+            run: remove_nop,
+        },
+    ];
+
+    fn function() -> Function {
+        Function::new(Symbol::intern("f"), vec![], 0, false, 0)
+    }
+
+    #[test]
+    fn ping_pong_runs_out_of_rounds() {
+        let fuel = Fuel {
+            rounds: 10,
+            ..Fuel::default()
+        };
+        let err = optimize(&mut [function()], PING_PONG, fuel).unwrap_err();
+        assert_eq!(err.pass, "add-nop");
+        assert_eq!(err.function.to_string(), "f");
+        assert_eq!(err.msg, "still rewriting after 10 rounds");
+    }
+
+    #[test]
+    fn ping_pong_runs_out_of_rewrites() {
+        let fuel = Fuel {
+            rounds: 100,
+            rewrites: 5,
+        };
+        let err = optimize(&mut [function()], PING_PONG, fuel).unwrap_err();
+        assert_eq!(err.pass, "remove-nop");
+        assert_eq!(err.msg, "ran out of fuel for rewrites");
+        assert_eq!(
+            err.to_string(),
+            "internal compiler error: pass remove-nop on f: ran out of fuel for rewrites"
+        );
+    }
+
+    #[test]
+    fn settled_passes_need_one_round() {
+        let fuel = Fuel {
+            rounds: 0,
+            rewrites: 0,
+        };
+        assert!(optimize(&mut [function()], &PING_PONG[1..], fuel).is_ok());
+    }
+}
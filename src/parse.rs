@@ -1,8 +1,11 @@
-use crate::token::Token;
+use crate::symbol::Symbol;
+use crate::target::target;
+use crate::token::{error_at, LexError, Token, TokenStream};
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::{Ctype, Member, Scope, TokenType, Type};
 
 use std::collections::HashMap;
+use std::fmt;
 
 // Quoted from 9cc
 // > This is a recursive-descendent parser which constructs abstract
@@ -23,22 +26,37 @@ int main() {     ; +-+                        int   []         2
 }                ; +-+                  return        []      +->primary
                                                                  0
 */
-pub fn parse(tokens: &Vec<Token>) -> Vec<Node> {
-    let mut parser = Parser::new(tokens);
-
+//
+// Tokens are pulled from the preprocessor as they are needed, and those
+// of a toplevel declaration are dropped once it is parsed. The first
+// error, the preprocessor's or the parser's, is returned.
+pub fn parse(input: TokenStream) -> Result<Vec<Node>, LexError> {
+    let mut parser = Parser::new(input);
     let mut v = vec![];
-    while tokens.len() != parser.pos {
-        if let Some(node) = parser.toplevel() {
+    while !parser.at_eof() {
+        let node = parser.toplevel();
+        // The tokens end where the preprocessor failed, which is what
+        // the parser may have tripped over.
+        if let Some(e) = parser.error.take() {
+            return Err(e);
+        }
+        if let Some(node) = node? {
             v.push(node);
         }
+        parser.tokens.drain(..parser.pos);
+        parser.pos = 0;
+    }
+    match parser.error.take() {
+        Some(e) => Err(e),
+        None => Ok(v),
     }
-    v
 }
 
 #[derive(Debug, Clone)]
 struct Env {
-    tags: HashMap<String, Type>,
-    typedefs: HashMap<String, Type>,
+    tags: HashMap<Symbol, Type>,
+    typedefs: HashMap<Symbol, Type>,
+    consts: HashMap<Symbol, Option<i64>>, // const integer globals, None where a local hides one
     next: Option<Box<Env>>,
 }
 
@@ -48,6 +66,7 @@ impl Env {
             next,
             tags: HashMap::new(),
             typedefs: HashMap::new(),
+            consts: HashMap::new(),
         }
     }
 }
@@ -58,15 +77,54 @@ macro_rules! new_expr(
     )
 );
 
+// (operator, precedence, node constructor)
+struct BinOp(TokenType, u8, fn(Node, Node) -> Node);
+
+// Binary operators from the loosest to the tightest, as in C11 6.5.5
+// to 6.5.14. All of them group left to right. `?:`, assignment and
+// comma are looser and parsed apart. `>` and `>=` are built as `<`
+// and `<=` with their operands swapped.
+#[rustfmt::skip]
+static BINOPS: &[BinOp] = {
+    use self::TokenType::*;
+    &[
+        BinOp(Logor,              1, |l, r| Node::new_binop(Logor, l, r)),
+        BinOp(Logand,             2, |l, r| Node::new_binop(Logand, l, r)),
+        BinOp(VerticalBar,        3, |l, r| Node::new_binop(VerticalBar, l, r)),
+        BinOp(Hat,                4, |l, r| Node::new_binop(Hat, l, r)),
+        BinOp(And,                5, |l, r| Node::new_binop(And, l, r)),
+        BinOp(EQ,                 6, |l, r| Node::new_binop(EQ, l, r)),
+        BinOp(NE,                 6, |l, r| Node::new_binop(NE, l, r)),
+        BinOp(LeftAngleBracket,   7, |l, r| Node::new_binop(LeftAngleBracket, l, r)),
+        BinOp(RightAngleBracket,  7, |l, r| Node::new_binop(LeftAngleBracket, r, l)),
+        BinOp(LE,                 7, |l, r| Node::new_binop(LE, l, r)),
+        BinOp(GE,                 7, |l, r| Node::new_binop(LE, r, l)),
+        BinOp(SHL,                8, |l, r| Node::new_binop(SHL, l, r)),
+        BinOp(SHR,                8, |l, r| Node::new_binop(SHR, l, r)),
+        BinOp(Plus,               9, |l, r| Node::new_binop(Plus, l, r)),
+        BinOp(Minus,              9, |l, r| Node::new_binop(Minus, l, r)),
+        BinOp(Mul,               10, |l, r| Node::new_binop(Mul, l, r)),
+        BinOp(Div,               10, |l, r| Node::new_binop(Div, l, r)),
+        BinOp(Mod,               10, |l, r| Node::new_binop(Mod, l, r)),
+    ]
+};
+
+// Binding strength of a binary operator, also in #if; higher binds
+// tighter.
+pub(crate) fn binop_prec(op: &TokenType) -> Option<u8> {
+    BINOPS.iter().find(|b| b.0 == *op).map(|b| b.1)
+}
+
 #[derive(Debug, Clone)]
 pub enum NodeType {
-    Num(i32),                                        // Number literal
+    Num(i64),                                        // Number literal
+    FNum(f64),                                       // Floating point literal
     Str(String, usize),                              // String literal, (data, len)
-    Ident(String),                                   // Identifier
-    Decl(String),                                    // declaration
-    Vardef(String, Option<Box<Node>>, Scope),        // Variable definition, name = init
+    Ident(Symbol),                                   // Identifier
+    Decl(Symbol),                                    // declaration
+    Vardef(Symbol, Option<Box<Node>>, Scope),        // Variable definition, name = init
     Lvar(Scope),                                     // Variable reference
-    Gvar(String, String, usize),                     // Variable reference, (name, data, len)
+    Gvar(Symbol, String, usize),                     // Variable reference, (name, data, len)
     BinOp(TokenType, Box<Node>, Box<Node>),          // left-hand, right-hand
     If(Box<Node>, Box<Node>, Option<Box<Node>>),     // "if" ( cond ) then "else" els
     Ternary(Box<Node>, Box<Node>, Box<Node>),        // cond ? then : els
@@ -75,20 +133,24 @@ pub enum NodeType {
     DoWhile(Box<Node>, Box<Node>), // do { body } while(cond)
     Addr(Box<Node>),               // address-of operator("&"), expr
     Deref(Box<Node>),              // pointer dereference ("*"), expr
-    Dot(Box<Node>, String, usize), // Struct member accessm, (expr, name, offset)
+    Dot(Box<Node>, Symbol, usize), // Struct member accessm, (expr, name, offset)
     Exclamation(Box<Node>),        // !, expr
     Neg(Box<Node>),                // -
+    Cast(Box<Node>),               // "(" type ")" expr, converted to the node's type
     PostInc(Box<Node>),            // post ++
     PostDec(Box<Node>),            // post --
     Return(Box<Node>),             // "return", stmt
     Sizeof(Box<Node>),             // "sizeof", expr
     Alignof(Box<Node>),            // "_Alignof", expr
-    Call(String, Vec<Node>),       // Function call(name, args)
-    Func(String, Vec<Node>, Box<Node>, usize), // Function definition(name, args, body, stacksize)
-    CompStmt(Vec<Node>),           // Compound statement
-    VecStmt(Vec<Node>),            // For the purpose of assign a value when initializing an array.
-    ExprStmt(Box<Node>),           // Expression statement
-    StmtExpr(Box<Node>),           // Statement expression (GNU extn.)
+    Call(Symbol, Vec<Node>),       // Function call(name, args)
+    CallPtr(Box<Node>, Vec<Node>), // Call through a function pointer(expr, args)
+    VaStart(Box<Node>),            // va_start(ap, last), with last dropped
+    VaArg(Box<Node>),              // va_arg(ap, type), of the node's type
+    Func(Symbol, Vec<Node>, Box<Node>, usize, bool), // Function definition(name, args, body, stacksize, is_static)
+    CompStmt(Vec<Node>),                             // Compound statement
+    VecStmt(Vec<Node>), // For the purpose of assign a value when initializing an array.
+    ExprStmt(Box<Node>), // Expression statement
+    StmtExpr(Box<Node>), // Statement expression (GNU extn.)
     Null,
 }
 
@@ -96,6 +158,42 @@ pub enum NodeType {
 pub struct Node {
     pub op: NodeType,  // Node type
     pub ty: Box<Type>, // C type
+
+    // For error reporting
+    pub token: Option<Token>,
+
+    // Written in parentheses, which silences -Wparentheses.
+    pub parenthesized: bool,
+}
+
+// The value of an integer constant expression, if the node is one.
+// `find_const` gives the value of a name, for the parser's const
+// integer globals; by sema, names are resolved and there are none.
+pub(crate) fn const_value(node: &Node, find_const: &dyn Fn(Symbol) -> Option<i64>) -> Option<i64> {
+    use self::TokenType::*;
+    let value = |node| const_value(node, find_const);
+    match node.op {
+        NodeType::Num(n) => Some(n),
+        NodeType::Ident(name) => find_const(name),
+        NodeType::Neg(ref expr) => value(expr)?.checked_neg(),
+        NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+            let (l, r) = (value(lhs)?, value(rhs)?);
+            match op {
+                Plus => l.checked_add(r),
+                Minus => l.checked_sub(r),
+                Mul => l.checked_mul(r),
+                Div => l.checked_div(r),
+                Mod => l.checked_rem(r),
+                SHL => l.checked_shl(r as u32),
+                SHR => l.checked_shr(r as u32),
+                And => Some(l & r),
+                VerticalBar => Some(l | r),
+                Hat => Some(l ^ r),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
 }
 
 impl Node {
@@ -103,18 +201,20 @@ impl Node {
         Self {
             op,
             ty: Box::new(Type::default()),
+            token: None,
+            parenthesized: false,
         }
     }
 
-    pub fn new_int(val: i32) -> Self {
+    pub fn new_int(val: i64) -> Self {
         Node::new(NodeType::Num(val))
     }
 
-    pub fn scale_ptr(node: Box<Node>, ty: &Type) -> Self {
+    // Scales the integer side of `p + n`, `p - n`, `p += n` or `p -= n`
+    // to the byte offset it stands for.
+    pub fn scale_ptr(node: Node, ty: &Type) -> Self {
         match ty.ty {
-            Ctype::Ptr(ref ptr_to) => {
-                Node::new_binop(TokenType::Mul, *node, Node::new_int(ptr_to.size as i32))
-            }
+            Ctype::Ptr(_) => Node::new_binop(TokenType::Mul, node, Node::new_int(ty.step() as i64)),
             _ => panic!("expect ptr type"),
         }
     }
@@ -123,14 +223,19 @@ impl Node {
         Node::new(NodeType::BinOp(ty, Box::new(lhs), Box::new(rhs)))
     }
 
-    pub fn new_num(val: i32) -> Self {
+    pub fn new_num(val: i64) -> Self {
         Node::new(NodeType::Num(val))
     }
 
     pub fn is_null(&self) -> bool {
-        match self.op {
-            NodeType::Null => true,
-            _ => false,
+        matches!(self.op, NodeType::Null)
+    }
+
+    // "file:line:col: " for a warning about the node, if it has a token.
+    pub fn location(&self) -> String {
+        match self.token {
+            Some(ref t) => format!("{}:{}:{}: ", t.filename, t.line, t.col),
+            None => String::new(),
         }
     }
 }
@@ -141,6 +246,7 @@ impl Type {
             ty,
             size,
             align: size,
+            unsigned: false,
         }
     }
 
@@ -153,42 +259,205 @@ impl Type {
     }
 
     pub fn int_ty() -> Self {
-        Type::new(Ctype::Int, 4)
+        Type::new(Ctype::Int, target().int_size)
+    }
+
+    pub fn long_ty() -> Self {
+        Type::new(Ctype::Long, target().long_size)
+    }
+
+    // The unsigned version of an integer type.
+    pub fn to_unsigned(mut self) -> Self {
+        self.unsigned = true;
+        self
+    }
+
+    pub fn float_ty() -> Self {
+        Type::new(Ctype::Float, 4)
+    }
+
+    pub fn double_ty() -> Self {
+        Type::new(Ctype::Double, 8)
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self.ty, Ctype::Float | Ctype::Double)
+    }
+
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.ty, Ctype::Ary(_, None))
+    }
+
+    // How far adding 1 moves a value of this type: pointer arithmetic
+    // counts in elements of the pointee, anything else in units.
+    pub fn step(&self) -> usize {
+        match self.ty {
+            Ctype::Ptr(ref ptr_to) => ptr_to.size,
+            _ => 1,
+        }
     }
 
     pub fn ptr_to(base: Box<Type>) -> Self {
-        Type::new(Ctype::Ptr(base), 8)
+        let t = target();
+        let mut ty = Type::new(Ctype::Ptr(base), t.ptr_size);
+        ty.align = t.ptr_align;
+        ty
     }
 
-    pub fn ary_of(base: Box<Type>, len: usize) -> Self {
+    // An array without a length, as in `extern int a[];`, is incomplete
+    // and has no size until a later declaration gives it one.
+    pub fn ary_of(base: Box<Type>, len: Option<usize>) -> Self {
         let align = base.align;
-        let size = base.size * len;
+        let size = base.size * len.unwrap_or(0);
         let mut ty = Type::new(Ctype::Ary(base, len), size);
         ty.align = align;
         ty
     }
+
+    // Spells the type the way C declares it, with `inner` being the
+    // part of the declarator built so far (e.g. "int (*)[3]").
+    fn declarator(&self, inner: String) -> String {
+        let base = match self.ty {
+            Ctype::Int => "int",
+            Ctype::Long => "long",
+            Ctype::Float => "float",
+            Ctype::Double => "double",
+            Ctype::Char => "char",
+            Ctype::Void => "void",
+            Ctype::Struct(_) => "struct",
+            Ctype::Ptr(ref to) => {
+                return match to.ty {
+                    Ctype::Ary(_, _) | Ctype::Func(..) => to.declarator(format!("(*{})", inner)),
+                    _ => to.declarator(format!("*{}", inner)),
+                };
+            }
+            Ctype::Ary(ref of, len) => {
+                let len = len.map_or(String::new(), |len| len.to_string());
+                return of.declarator(format!("{}[{}]", inner, len));
+            }
+            Ctype::Func(ref returning, ref params, variadic, _) => {
+                let params = match params {
+                    None => String::new(),
+                    Some(params) if params.is_empty() => "void".to_string(),
+                    Some(params) => {
+                        let mut v: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                        if variadic {
+                            v.push("...".to_string());
+                        }
+                        v.join(", ")
+                    }
+                };
+                return returning.declarator(format!("{}({})", inner, params));
+            }
+        };
+        let base = if self.unsigned {
+            format!("unsigned {}", base)
+        } else {
+            base.to_string()
+        };
+        if inner.is_empty() {
+            base
+        } else {
+            format!("{} {}", base, inner)
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.declarator(String::new()))
+    }
 }
 
+// Tokens the parser looks at past the current one.
+const LOOKAHEAD: usize = 1;
+
 pub struct Parser<'a> {
-    tokens: &'a Vec<Token>,
+    input: TokenStream<'a>,
+    tokens: Vec<Token>, // read from input, from the start of this toplevel on
     pos: usize,
     env: Env,
+    variadic: bool,          // the function being defined takes `...`
+    loops: usize,            // loops around the statement, which `break` needs
+    error: Option<LexError>, // from the preprocessor, which ends the tokens
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(tokens: &'a Vec<Token>) -> Self {
-        Parser {
-            tokens,
+    pub fn new(input: TokenStream<'a>) -> Self {
+        let mut parser = Parser {
+            input,
+            tokens: vec![],
             pos: 0,
             env: Env::new(None),
+            variadic: false,
+            loops: 0,
+            error: None,
+        };
+        parser.fill();
+        parser
+    }
+
+    // Reads tokens up to LOOKAHEAD past the current one. A preprocessor
+    // error is kept for parse() and ends the input. Past the end come
+    // Eof tokens, so that the parser can always look ahead.
+    fn fill(&mut self) {
+        while self.tokens.len() <= self.pos + LOOKAHEAD {
+            let ended =
+                self.error.is_some() || self.tokens.last().map(|t| &t.ty) == Some(&TokenType::Eof);
+            let t = match if ended { None } else { self.input.next() } {
+                Some(Ok(t)) => t,
+                Some(Err(e)) => {
+                    self.error = Some(e);
+                    continue;
+                }
+                None => self.eof_token(),
+            };
+            self.tokens.push(t);
         }
     }
 
-    fn find_tag(&self, name: &str) -> Option<Type> {
+    // The end of the input, right after the last token, so that an
+    // error there points at where something is missing.
+    fn eof_token(&self) -> Token {
+        let last = match self.tokens.last() {
+            Some(t) if t.ty == TokenType::Eof => return t.clone(),
+            Some(t) => t,
+            None => {
+                return Token {
+                    ty: TokenType::Eof,
+                    ..Default::default()
+                }
+            }
+        };
+        let mut t = Token::at(TokenType::Eof, last);
+        if last.line != 0 {
+            t.start = last.end;
+            t.col += last.end - last.start;
+        }
+        t.end = t.start;
+        t
+    }
+
+    fn advance(&mut self) {
+        self.pos += 1;
+        self.fill();
+    }
+
+    // `node`, blamed on the token at `start` in errors.
+    fn located(&self, mut node: Node, start: usize) -> Node {
+        node.token = Some(self.tokens[start].clone());
+        node
+    }
+
+    fn at_eof(&self) -> bool {
+        self.tokens[self.pos].ty == TokenType::Eof
+    }
+
+    fn find_tag(&self, name: Symbol) -> Option<Type> {
         let mut next: &Option<Box<Env>> = &Some(Box::new(self.env.clone()));
         loop {
             if let Some(ref e) = next {
-                let ty = e.tags.get(name);
+                let ty = e.tags.get(&name);
                 if ty.is_some() {
                     return ty.cloned();
                 }
@@ -199,11 +468,11 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn find_typedef(&self, name: &str) -> Option<Type> {
+    fn find_typedef(&self, name: Symbol) -> Option<Type> {
         let mut next: &Option<Box<Env>> = &Some(Box::new(self.env.clone()));
         loop {
             if let Some(ref e) = next {
-                let ty = e.typedefs.get(name);
+                let ty = e.typedefs.get(&name);
                 if ty.is_some() {
                     return ty.cloned();
                 }
@@ -214,93 +483,166 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn expect(&mut self, ty: TokenType) {
+    fn find_const(&self, name: Symbol) -> Option<i64> {
+        let mut next = Some(&self.env);
+        while let Some(e) = next {
+            if let Some(val) = e.consts.get(&name) {
+                return *val;
+            }
+            next = e.next.as_deref();
+        }
+        None
+    }
+
+    // A local variable hides a const global of the same name.
+    fn hide_const(&mut self, node: &Node) {
+        let node = match node.op {
+            NodeType::VecStmt(ref stmts) => &stmts[0],
+            _ => node,
+        };
+        if let NodeType::Vardef(name, _, _) = node.op {
+            if self.find_const(name).is_some() {
+                self.env.consts.insert(name, None);
+            }
+        }
+    }
+
+    // The value of an integer constant expression, which may use const
+    // integer globals.
+    fn const_value(&self, node: &Node) -> Option<i64> {
+        const_value(node, &|name| self.find_const(name))
+    }
+
+    // The value of a floating point constant: a literal or an integer
+    // constant expression, possibly negated.
+    fn const_float(&self, node: &Node) -> Option<f64> {
+        match node.op {
+            NodeType::FNum(v) => Some(v),
+            NodeType::Neg(ref expr) => Some(-self.const_float(expr)?),
+            _ => self.const_value(node).map(|n| n as f64),
+        }
+    }
+
+    fn expect(&mut self, ty: TokenType) -> Result<(), LexError> {
         let t = &self.tokens[self.pos];
         if t.ty != ty {
-            t.bad_token(&format!("{:?} expected", ty));
+            return error_at(t, &format!("{:?} expected", ty));
         }
-        self.pos += 1;
+        self.advance();
+        Ok(())
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
-        let t = &self.tokens[self.pos];
-        if t.ty != ty {
+        if self.tokens[self.pos].ty != ty {
             return false;
         }
-        self.pos += 1;
+        self.advance();
         true
     }
 
+    // Is it `void)`, closing a parameter list?
+    fn is_void_params(&self) -> bool {
+        self.tokens[self.pos].ty == TokenType::Void
+            && self.tokens.get(self.pos + 1).map(|t| &t.ty) == Some(&TokenType::RightParen)
+    }
+
     fn is_typename(&self, t: &Token) -> bool {
         use self::TokenType::*;
         if let TokenType::Ident(ref name) = t.ty {
-            return self.find_typedef(name).is_some();
+            return self.find_typedef(*name).is_some();
         }
-        t.ty == Int || t.ty == Char || t.ty == Void || t.ty == Struct
+        t.ty == Int
+            || t.ty == Long
+            || t.ty == Float
+            || t.ty == Double
+            || t.ty == Char
+            || t.ty == Void
+            || t.ty == Struct
+            || t.ty == Const
     }
 
-    fn set_offset(members: &mut Vec<Node>) -> (usize, usize) {
+    // Lays out the members in order, each at its own alignment.
+    fn add_member(ty: &mut Type, decls: Vec<Node>) {
         let mut off = 0;
         let mut align = 0;
-        for node in members {
-            if let NodeType::Vardef(_, _, Scope::Local(offset)) = &mut node.op {
-                let t = &node.ty;
-                off = roundup(off, t.align);
-                *offset = off;
-                off += t.size;
-
-                if align < t.align {
-                    align = t.align;
-                }
-            } else {
-                panic!();
-            }
+        let mut members = vec![];
+        for node in decls {
+            let name = match node.op {
+                NodeType::Vardef(name, _, _) => name,
+                _ => unreachable!(),
+            };
+            let t = *node.ty;
+            off = roundup(off, t.align);
+            align = align.max(t.align);
+            let size = t.size;
+            members.push(Member {
+                name,
+                ty: t,
+                offset: off,
+            });
+            off += size;
         }
-        (off, align)
-    }
-
-    fn add_member(ty: &mut Type, mut members: Vec<Node>) {
-        let (off, align) = Self::set_offset(&mut members);
         if let Ctype::Struct(ref mut members2) = ty.ty {
             *members2 = members;
         }
         ty.size = roundup(off, align);
     }
 
-    fn decl_specifiers(&mut self) -> Option<Type> {
-        let t = &self.tokens[self.pos];
-        self.pos += 1;
-        match t.ty {
-            TokenType::Ident(ref name) => {
+    // `const` may come before or after the type. It is accepted, but not
+    // enforced.
+    fn decl_specifiers(&mut self) -> Result<Option<Type>, LexError> {
+        while self.consume(TokenType::Const) {}
+        let ty = self.base_type()?;
+        while self.consume(TokenType::Const) {}
+        Ok(ty)
+    }
+
+    fn base_type(&mut self) -> Result<Option<Type>, LexError> {
+        let start = self.pos;
+        self.advance();
+        match self.tokens[start].ty {
+            TokenType::Ident(name) => {
                 if let Some(ty) = self.find_typedef(name) {
-                    return Some(ty.clone());
+                    Ok(Some(ty.clone()))
                 } else {
                     self.pos -= 1;
-                    return None;
+                    Ok(None)
+                }
+            }
+            TokenType::Int => Ok(Some(Type::int_ty())),
+            TokenType::Long => {
+                // "long double" is just double.
+                if self.consume(TokenType::Double) {
+                    return Ok(Some(Type::double_ty()));
                 }
+                // "long", "long int", "long long" and "long long int" are
+                // all the same 64-bit type here.
+                self.consume(TokenType::Long);
+                self.consume(TokenType::Int);
+                Ok(Some(Type::long_ty()))
             }
-            TokenType::Int => Some(Type::int_ty()),
-            TokenType::Char => Some(Type::char_ty()),
-            TokenType::Void => Some(Type::void_ty()),
+            TokenType::Float => Ok(Some(Type::float_ty())),
+            TokenType::Double => Ok(Some(Type::double_ty())),
+            TokenType::Char => Ok(Some(Type::char_ty())),
+            TokenType::Void => Ok(Some(Type::void_ty())),
             TokenType::Struct => {
-                let mut tag_may: Option<String> = None;
-                let t = &self.tokens[self.pos];
-                if let TokenType::Ident(ref name) = t.ty {
-                    self.pos += 1;
-                    tag_may = Some(name.clone())
+                let mut tag_may: Option<Symbol> = None;
+                if let TokenType::Ident(name) = self.tokens[self.pos].ty {
+                    self.advance();
+                    tag_may = Some(name)
                 }
 
                 let mut members = vec![];
                 if self.consume(TokenType::LeftBrace) {
                     while !self.consume(TokenType::RightBrace) {
-                        members.push(self.declaration())
+                        members.push(self.declaration()?)
                     }
                 }
 
                 let mut ty_may: Option<Type> = None;
                 if let Some(ref tag) = tag_may {
                     if members.is_empty() {
-                        ty_may = self.find_tag(&tag);
+                        ty_may = self.find_tag(*tag);
                     }
                 }
                 let mut ty = ty_may.unwrap_or(Type::new(Ctype::Struct(vec![]), 10));
@@ -311,260 +653,325 @@ impl<'a> Parser<'a> {
                         self.env.tags.insert(tag, ty.clone());
                     }
                 }
-                Some(ty.clone())
+                Ok(Some(ty.clone()))
             }
-            _ => t.bad_token("typename expected"),
+            _ => error_at(&self.tokens[start], "typename expected"),
         }
     }
 
-    fn ident(&mut self) -> String {
+    fn ident(&mut self) -> Result<Symbol, LexError> {
         let t = &self.tokens[self.pos];
-        if let TokenType::Ident(ref name) = t.ty {
-            self.pos += 1;
-            name.clone()
+        if let TokenType::Ident(name) = t.ty {
+            self.advance();
+            Ok(name)
         } else {
-            t.bad_token("variable name expected");
+            bad_name(t, "variable name expected")
         }
     }
 
-    fn primary(&mut self) -> Node {
-        let t = &self.tokens[self.pos];
-        self.pos += 1;
-        match t.ty {
-            TokenType::Num(val) => Node::new_num(val),
-            TokenType::Str(ref str, len) => {
-                let mut node = Node::new(NodeType::Str(str.clone(), len));
-                node.ty = Box::new(Type::ary_of(Box::new(Type::char_ty()), len));
-                node
+    fn primary(&mut self) -> Result<Node, LexError> {
+        let start = self.pos;
+        self.advance();
+        match self.tokens[start].ty.clone() {
+            TokenType::Num(val, is_long, is_unsigned) => {
+                let mut node = Node::new_num(val);
+                let ty = if is_long {
+                    Type::long_ty()
+                } else {
+                    Type::int_ty()
+                };
+                node.ty = Box::new(if is_unsigned { ty.to_unsigned() } else { ty });
+                Ok(self.located(node, start))
+            }
+            TokenType::FNum(val, is_float) => {
+                let mut node = Node::new(NodeType::FNum(val));
+                node.ty = Box::new(if is_float {
+                    Type::float_ty()
+                } else {
+                    Type::double_ty()
+                });
+                Ok(self.located(node, start))
             }
-            TokenType::Ident(ref name) => {
+            TokenType::Str(str, len) => {
+                let mut node = Node::new(NodeType::Str(str, len));
+                node.ty = Box::new(Type::ary_of(Box::new(Type::char_ty()), Some(len)));
+                Ok(self.located(node, start))
+            }
+            TokenType::Ident(name) => {
+                let token = Some(self.tokens[start].clone());
                 if !self.consume(TokenType::LeftParen) {
-                    return Node::new(NodeType::Ident(name.clone()));
+                    let mut node = Node::new(NodeType::Ident(name));
+                    node.token = token;
+                    return Ok(node);
                 }
-
-                let mut args = vec![];
-                if self.consume(TokenType::RightParen) {
-                    return Node::new(NodeType::Call(name.clone(), args));
-                }
-
-                args.push(self.assign());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.assign());
-                }
-                self.expect(TokenType::RightParen);
-                Node::new(NodeType::Call(name.clone(), args))
+                self.call(name, token)
             }
             TokenType::LeftParen => {
                 if self.consume(TokenType::LeftBrace) {
-                    let stmt = Box::new(self.compound_stmt());
-                    self.expect(TokenType::RightParen);
-                    return Node::new(NodeType::StmtExpr(stmt));
+                    let stmt = Box::new(self.compound_stmt()?);
+                    self.expect(TokenType::RightParen)?;
+                    return Ok(self.located(Node::new(NodeType::StmtExpr(stmt)), start));
                 }
-                let node = self.expr();
-                self.expect(TokenType::RightParen);
+                let mut node = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                // `(f)(x)` calls f, and is how to get past a macro f(x).
+                if let NodeType::Ident(name) = node.op {
+                    if self.consume(TokenType::LeftParen) {
+                        return self.call(name, node.token);
+                    }
+                }
+                node.parenthesized = true;
+                Ok(node)
+            }
+            _ => error_at(&self.tokens[start], "number expected"),
+        }
+    }
+
+    // The arguments of a call, after the '('.
+    fn call_args(&mut self) -> Result<Vec<Node>, LexError> {
+        let mut args = vec![];
+        if !self.consume(TokenType::RightParen) {
+            args.push(self.assign()?);
+            while self.consume(TokenType::Comma) {
+                args.push(self.assign()?);
+            }
+            self.expect(TokenType::RightParen)?;
+        }
+        Ok(args)
+    }
+
+    // What the va_* macros of <stdarg.h> expand to. va_arg takes a type,
+    // so these are not calls.
+    fn va_builtin(&mut self, name: Symbol, t: &Token) -> Result<Option<Node>, LexError> {
+        let mut node = match &*name.as_str() {
+            "__builtin_va_start" => {
+                if !self.variadic {
+                    return error_at(t, "'va_start' used in function with fixed arguments");
+                }
+                let ap = self.assign()?;
+                self.expect(TokenType::Comma)?;
+                self.assign()?;
+                Node::new(NodeType::VaStart(Box::new(ap)))
+            }
+            "__builtin_va_arg" => {
+                let ap = self.assign()?;
+                self.expect(TokenType::Comma)?;
+                let mut node = Node::new(NodeType::VaArg(Box::new(ap)));
+                node.ty = Box::new(self.ctype()?);
+                node
+            }
+            // A va_list is 24 bytes: two offsets and two pointers.
+            "__builtin_va_copy" => {
+                let dst = self.assign()?;
+                self.expect(TokenType::Comma)?;
+                let src = self.assign()?;
+                let memcpy = Symbol::intern("__builtin_memcpy");
+                Node::new(NodeType::Call(memcpy, vec![dst, src, Node::new_num(24)]))
+            }
+            "__builtin_va_end" => {
+                let mut node = Node::new(NodeType::Cast(Box::new(self.assign()?)));
+                node.ty = Box::new(Type::void_ty());
                 node
             }
-            _ => t.bad_token("number expected"),
+            _ => return Ok(None),
+        };
+        self.expect(TokenType::RightParen)?;
+        node.token = Some(t.clone());
+        Ok(Some(node))
+    }
+
+    fn call(&mut self, name: Symbol, token: Option<Token>) -> Result<Node, LexError> {
+        if let Some(ref t) = token {
+            if let Some(node) = self.va_builtin(name, t)? {
+                return Ok(node);
+            }
         }
+        let mut node = Node::new(NodeType::Call(name, self.call_args()?));
+        node.token = token;
+        Ok(node)
     }
 
-    fn postfix(&mut self) -> Node {
-        let mut lhs = self.primary();
+    fn postfix(&mut self) -> Result<Node, LexError> {
+        let mut lhs = self.primary()?;
 
         loop {
+            let start = self.pos;
             if self.consume(TokenType::Inc) {
-                lhs = new_expr!(NodeType::PostInc, lhs);
+                lhs = self.located(new_expr!(NodeType::PostInc, lhs), start);
                 continue;
             }
 
             if self.consume(TokenType::Dec) {
-                lhs = new_expr!(NodeType::PostDec, lhs);
+                lhs = self.located(new_expr!(NodeType::PostDec, lhs), start);
                 continue;
             }
 
             if self.consume(TokenType::Dot) {
                 // TODO: Use new_expr!
-                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident(), 0));
+                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident()?, 0));
+                lhs = self.located(lhs, start);
                 continue;
             }
 
             if self.consume(TokenType::Arrow) {
-                lhs = Node::new(NodeType::Dot(
-                    Box::new(new_expr!(NodeType::Deref, lhs)),
-                    self.ident(),
-                    0,
-                ));
+                let deref = self.located(new_expr!(NodeType::Deref, lhs), start);
+                lhs = Node::new(NodeType::Dot(Box::new(deref), self.ident()?, 0));
+                lhs = self.located(lhs, start);
+                continue;
+            }
+
+            // `(*f)(x)` or `fns[i](x)`; a plain name is a Call already.
+            if self.consume(TokenType::LeftParen) {
+                let args = self.call_args()?;
+                lhs = Node::new(NodeType::CallPtr(Box::new(lhs), args));
+                lhs.token = Some(self.tokens[start].clone());
                 continue;
             }
 
             if self.consume(TokenType::LeftBracket) {
                 lhs = new_expr!(
                     NodeType::Deref,
-                    Node::new_binop(TokenType::Plus, lhs, self.assign())
+                    Node::new_binop(TokenType::Plus, lhs, self.assign()?)
                 );
-                self.expect(TokenType::RightBracket);
+                lhs.token = Some(self.tokens[start].clone());
+                self.expect(TokenType::RightBracket)?;
                 continue;
             }
-            return lhs;
+            return Ok(lhs);
         }
     }
 
-    fn unary(&mut self) -> Node {
+    // A '(' that starts a type name, as in a cast or `sizeof(int)`.
+    fn at_paren_type(&self) -> bool {
+        self.tokens[self.pos].ty == TokenType::LeftParen
+            && self.is_typename(&self.tokens[self.pos + 1])
+    }
+
+    // The type name in parentheses after a cast or sizeof.
+    fn paren_type(&mut self) -> Result<Type, LexError> {
+        self.expect(TokenType::LeftParen)?;
+        let ty = self.ctype()?;
+        let ty = self.read_array(Box::new(ty))?;
+        self.expect(TokenType::RightParen)?;
+        Ok(ty)
+    }
+
+    // cast = "(" type-name ")" cast | unary
+    //
+    // A cast applies to the whole unary expression after it, so
+    // `(long)-1` is a cast of -1.
+    fn cast(&mut self) -> Result<Node, LexError> {
+        if !self.at_paren_type() {
+            return self.unary();
+        }
+        let start = self.pos;
+        let ty = self.paren_type()?;
+        let mut node = new_expr!(NodeType::Cast, self.cast()?);
+        node.ty = Box::new(ty);
+        Ok(self.located(node, start))
+    }
+
+    // unary = ("-" | "*" | "&" | "!") cast
+    //       | ("++" | "--") unary
+    //       | ("sizeof" | "_Alignof") ("(" type-name ")" | unary)
+    //       | postfix
+    //
+    // `sizeof (long) - 1` is the size minus 1: a type name in
+    // parentheses ends the operand.
+    fn unary(&mut self) -> Result<Node, LexError> {
+        let start = self.pos;
         if self.consume(TokenType::Minus) {
-            return new_expr!(NodeType::Neg, self.unary());
+            let node = new_expr!(NodeType::Neg, self.cast()?);
+            return Ok(self.located(node, start));
         }
         if self.consume(TokenType::Mul) {
-            return new_expr!(NodeType::Deref, self.unary());
+            let node = new_expr!(NodeType::Deref, self.cast()?);
+            return Ok(self.located(node, start));
         }
         if self.consume(TokenType::And) {
-            return new_expr!(NodeType::Addr, self.unary());
+            let node = new_expr!(NodeType::Addr, self.cast()?);
+            return Ok(self.located(node, start));
         }
         if self.consume(TokenType::Exclamation) {
-            return new_expr!(NodeType::Exclamation, self.unary());
+            let node = new_expr!(NodeType::Exclamation, self.cast()?);
+            return Ok(self.located(node, start));
         }
         if self.consume(TokenType::Sizeof) {
-            return new_expr!(NodeType::Sizeof, self.unary());
+            if self.at_paren_type() {
+                let ty = self.paren_type()?;
+                if ty.is_incomplete() {
+                    return error_at(
+                        &self.tokens[start],
+                        &format!(
+                            "invalid application of 'sizeof' to an incomplete type '{}'",
+                            ty
+                        ),
+                    );
+                }
+                return Ok(Node::new_int(ty.size as i64));
+            }
+            let node = new_expr!(NodeType::Sizeof, self.unary()?);
+            return Ok(self.located(node, start));
         }
         if self.consume(TokenType::Alignof) {
-            return new_expr!(NodeType::Alignof, self.unary());
+            if self.at_paren_type() {
+                return Ok(Node::new_int(self.paren_type()?.align as i64));
+            }
+            let node = new_expr!(NodeType::Alignof, self.unary()?);
+            return Ok(self.located(node, start));
         }
 
         if self.consume(TokenType::Inc) {
-            return Node::new_binop(TokenType::AddEQ, self.unary(), Node::new_num(1));
+            let node = Node::new_binop(TokenType::AddEQ, self.unary()?, Node::new_num(1));
+            return Ok(self.located(node, start));
         }
         if self.consume(TokenType::Dec) {
-            return Node::new_binop(TokenType::SubEQ, self.unary(), Node::new_num(1));
+            let node = Node::new_binop(TokenType::SubEQ, self.unary()?, Node::new_num(1));
+            return Ok(self.located(node, start));
         }
 
         self.postfix()
     }
 
-    fn mul(&mut self) -> Node {
-        let mut lhs = self.unary();
-
-        loop {
-            if self.consume(TokenType::Mul) {
-                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary());
-            } else if self.consume(TokenType::Div) {
-                lhs = Node::new_binop(TokenType::Div, lhs, self.unary());
-            } else if self.consume(TokenType::Mod) {
-                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary());
-            } else {
-                return lhs;
-            }
-        }
-    }
-
-    fn add(&mut self) -> Node {
-        let mut lhs = self.mul();
-
-        loop {
-            if self.consume(TokenType::Plus) {
-                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul());
-            } else if self.consume(TokenType::Minus) {
-                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul());
-            } else {
-                return lhs;
-            }
-        }
-    }
-
-    fn shift(&mut self) -> Node {
-        let mut lhs = self.add();
-        loop {
-            if self.consume(TokenType::SHL) {
-                lhs = Node::new_binop(TokenType::SHL, lhs, self.add());
-            } else if self.consume(TokenType::SHR) {
-                lhs = Node::new_binop(TokenType::SHR, lhs, self.add());
-            } else {
-                return lhs;
-            }
-        }
-    }
-
-    fn relational(&mut self) -> Node {
-        let mut lhs = self.shift();
-        loop {
-            if self.consume(TokenType::LeftAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift());
-            } else if self.consume(TokenType::RightAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, self.shift(), lhs);
-            } else if self.consume(TokenType::LE) {
-                lhs = Node::new_binop(TokenType::LE, lhs, self.shift())
-            } else if self.consume(TokenType::GE) {
-                lhs = Node::new_binop(TokenType::LE, self.shift(), lhs);
-            } else {
-                return lhs;
-            }
-        }
-    }
-
-    fn equality(&mut self) -> Node {
-        let mut lhs = self.relational();
+    // Precedence climbing over BINOPS. Operands bind tighter than
+    // `min_prec`, and the right operand binds tighter than the
+    // operator, so that `a - b - c` is `(a - b) - c`.
+    fn binary(&mut self, min_prec: u8) -> Result<Node, LexError> {
+        let mut lhs = self.cast()?;
         loop {
-            if self.consume(TokenType::EQ) {
-                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational());
-            } else if self.consume(TokenType::NE) {
-                lhs = Node::new_binop(TokenType::NE, lhs, self.relational());
-            } else {
-                return lhs;
-            }
-        }
-    }
-
-    fn bit_and(&mut self) -> Node {
-        let mut lhs = self.equality();
-        while self.consume(TokenType::And) {
-            lhs = Node::new_binop(TokenType::And, lhs, self.equality());
-        }
-        lhs
-    }
-
-    fn bit_xor(&mut self) -> Node {
-        let mut lhs = self.bit_and();
-        while self.consume(TokenType::Hat) {
-            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and());
-        }
-        lhs
-    }
-
-    fn bit_or(&mut self) -> Node {
-        let mut lhs = self.bit_xor();
-        while self.consume(TokenType::VerticalBar) {
-            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor());
-        }
-        lhs
-    }
-
-    fn logand(&mut self) -> Node {
-        let mut lhs = self.bit_or();
-        while self.consume(TokenType::Logand) {
-            lhs = Node::new_binop(TokenType::Logand, lhs, self.logand());
-        }
-        lhs
-    }
-
-    fn logor(&mut self) -> Node {
-        let mut lhs = self.logand();
-        while self.consume(TokenType::Logor) {
-            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand());
+            let ty = &self.tokens[self.pos].ty;
+            let (prec, build) = match BINOPS.iter().find(|op| op.0 == *ty) {
+                Some(&BinOp(_, prec, build)) if prec >= min_prec => (prec, build),
+                _ => return Ok(lhs),
+            };
+            let start = self.pos;
+            self.advance();
+            let rhs = self.binary(prec + 1)?;
+            lhs = self.located(build(lhs, rhs), start);
         }
-        lhs
     }
 
-    fn conditional(&mut self) -> Node {
-        let cond = self.logor();
+    fn conditional(&mut self) -> Result<Node, LexError> {
+        let cond = self.binary(1)?;
+        let start = self.pos;
         if !self.consume(TokenType::Question) {
-            return cond;
+            return Ok(cond);
         }
-        let then = self.expr();
-        self.expect(TokenType::Colon);
-        let els = self.conditional();
-        Node::new(NodeType::Ternary(
+        // GNU extension: `x ?: y` is `x ? x : y` with x evaluated once.
+        // The omitted operand is represented by a Null node.
+        let then = if self.tokens[self.pos].ty == TokenType::Colon {
+            Node::new(NodeType::Null)
+        } else {
+            self.expr()?
+        };
+        self.expect(TokenType::Colon)?;
+        let els = self.conditional()?;
+        let node = Node::new(NodeType::Ternary(
             Box::new(cond),
             Box::new(then),
             Box::new(els),
-        ))
+        ));
+        Ok(self.located(node, start))
     }
 
     fn assign_op(ty: &TokenType) -> Option<&TokenType> {
@@ -576,326 +983,585 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn assign(&mut self) -> Node {
-        let lhs = self.conditional();
-        if let Some(op) = Self::assign_op(&self.tokens[self.pos].ty) {
-            self.pos += 1;
-            Node::new_binop(op.clone(), lhs, self.assign())
+    fn assign(&mut self) -> Result<Node, LexError> {
+        let lhs = self.conditional()?;
+        let start = self.pos;
+        if let Some(op) = Self::assign_op(&self.tokens[self.pos].ty).cloned() {
+            self.advance();
+            let node = Node::new_binop(op, lhs, self.assign()?);
+            Ok(self.located(node, start))
         } else {
-            lhs
+            Ok(lhs)
         }
     }
 
-    fn expr(&mut self) -> Node {
-        let lhs = self.assign();
+    fn expr(&mut self) -> Result<Node, LexError> {
+        let lhs = self.assign()?;
+        let start = self.pos;
         if !self.consume(TokenType::Comma) {
-            return lhs;
+            return Ok(lhs);
         }
-        Node::new_binop(TokenType::Comma, lhs, self.expr())
+        let node = Node::new_binop(TokenType::Comma, lhs, self.expr()?);
+        Ok(self.located(node, start))
     }
 
-    fn ctype(&mut self) -> Type {
-        let t = &self.tokens[self.pos];
-        if let Some(mut ty) = self.decl_specifiers() {
+    fn ctype(&mut self) -> Result<Type, LexError> {
+        let start = self.pos;
+        if let Some(mut ty) = self.decl_specifiers()? {
             while self.consume(TokenType::Mul) {
                 ty = Type::ptr_to(Box::new(ty));
+                while self.consume(TokenType::Const) {}
             }
-            ty
+            Ok(ty)
         } else {
-            t.bad_token("typename expected");
+            error_at(&self.tokens[start], "typename expected")
         }
     }
 
-    fn read_array(&mut self, mut ty: Box<Type>) -> Type {
-        let mut v: Vec<usize> = vec![];
+    fn read_array(&mut self, mut ty: Box<Type>) -> Result<Type, LexError> {
+        let mut v: Vec<Option<usize>> = vec![];
         while self.consume(TokenType::LeftBracket) {
             if self.consume(TokenType::RightBracket) {
-                v.push(0); // temporary value
+                v.push(None);
                 continue;
             }
 
-            let len = self.expr();
-            if let NodeType::Num(n) = len.op {
-                v.push(n as usize);
-                self.expect(TokenType::RightBracket);
-            } else {
-                panic!("number expected");
+            let start = self.pos;
+            let len = self.expr()?;
+            let t = &self.tokens[start];
+            match self.const_value(&len) {
+                Some(n) if n >= 0 => v.push(Some(n as usize)),
+                Some(_) => return error_at(t, "array size is negative"),
+                None => {
+                    let msg = match find_ident(&len) {
+                        Some(name) => format!(
+                            "array size must be a constant, and '{}' is not a const integer global with a constant initializer; use a #define, or 'static const int {} = ...;'",
+                            name, name
+                        ),
+                        None => "array size must be an integer constant expression".to_string(),
+                    };
+                    return error_at(t, &msg);
+                }
             }
+            self.expect(TokenType::RightBracket)?;
         }
 
         v.reverse();
         for val in v {
             ty = Box::new(Type::ary_of(ty, val));
         }
-        *ty
+        Ok(*ty)
     }
 
-    fn array_init_rval(&mut self, ident: Node) -> Node {
+    fn array_init_rval(&mut self, ident: Node) -> Result<Node, LexError> {
         let mut init = vec![];
         let mut i = 0;
         loop {
-            let val = self.primary();
+            let val = self.primary()?;
             let node = new_expr!(
                 NodeType::Deref,
-                Node::new_binop(TokenType::Plus, ident.clone(), Node::new(NodeType::Num(i)))
+                Node::new_binop(
+                    TokenType::Plus,
+                    ident.clone(),
+                    Node::new(NodeType::Num(i as i64))
+                )
             );
             init.push(Node::new(NodeType::ExprStmt(Box::new(Node::new_binop(
                 TokenType::Equal,
                 node,
                 val,
             )))));
-            if !self.consume(TokenType::Comma) {
+            // A comma may follow the last element.
+            if !self.consume(TokenType::Comma) || self.tokens[self.pos].ty == TokenType::RightBrace
+            {
                 break;
             }
             i += 1;
         }
-        self.expect(TokenType::RightBrace);
-        Node::new(NodeType::VecStmt(init))
+        self.expect(TokenType::RightBrace)?;
+        Ok(Node::new(NodeType::VecStmt(init)))
     }
 
+    // Puts `dst` where the placeholder is, innermost in `src`. An array
+    // is rebuilt to get the size of its new elements.
     fn update_ptr_to(&mut self, src: &mut Box<Type>, dst: Box<Type>) {
         match src.ty {
             Ctype::Ptr(ref mut ptr_to) => self.update_ptr_to(ptr_to, dst),
+            Ctype::Ary(ref mut of, len) => {
+                self.update_ptr_to(of, dst);
+                **src = Type::ary_of(of.clone(), len);
+            }
             _ => *src = dst,
         }
     }
 
-    fn direct_decl(&mut self, ty: Box<Type>) -> Node {
-        let t = &self.tokens[self.pos];
+    // The parameters of a function declarator, after the '(', and
+    // whether it is variadic. `(void)` declares no parameters; `()`
+    // leaves them unspecified, giving None.
+    fn param_list(&mut self) -> Result<(Option<Vec<Node>>, bool), LexError> {
+        let mut params = vec![];
+        let mut variadic = false;
+        if self.is_void_params() {
+            self.advance();
+            self.advance();
+            return Ok((Some(params), false));
+        }
+        if self.consume(TokenType::RightParen) {
+            return Ok((None, false));
+        }
+        params.push(self.param_declaration()?);
+        while self.consume(TokenType::Comma) {
+            // `...` can only come last, after a named parameter.
+            if self.consume(TokenType::Ellipsis) {
+                variadic = true;
+                break;
+            }
+            params.push(self.param_declaration()?);
+        }
+        self.expect(TokenType::RightParen)?;
+        Ok((Some(params), variadic))
+    }
+
+    // The type of a function declarator, after the '('.
+    fn func_suffix(&mut self, returning: Box<Type>) -> Result<Type, LexError> {
+        let (params, variadic) = self.param_list()?;
+        let params = params.map(|params| params.into_iter().map(|p| *p.ty).collect());
+        Ok(Type::new(
+            Ctype::Func(returning, params, variadic, false),
+            0,
+        ))
+    }
+
+    // The name and type of a declarator, without an initializer.
+    fn direct_declarator(&mut self, ty: Box<Type>) -> Result<Node, LexError> {
+        let start = self.pos;
         let mut placeholder = Box::new(Type::default());
         let mut node;
 
-        if let TokenType::Ident(_) = t.ty {
-            node = Node::new(NodeType::Vardef(self.ident(), None, Scope::Local(0)));
+        if let TokenType::Ident(_) = self.tokens[start].ty {
+            node = Node::new(NodeType::Vardef(self.ident()?, None, Scope::Local(0)));
+            node.token = Some(self.tokens[start].clone());
         } else if self.consume(TokenType::LeftParen) {
-            node = self.declarator(&mut placeholder);
-            self.expect(TokenType::RightParen);
+            node = self.declarator(&mut placeholder)?;
+            self.expect(TokenType::RightParen)?;
         } else {
-            t.bad_token("bad direct-declarator");
+            return bad_name(&self.tokens[start], "bad direct-declarator");
         }
 
-        // Read the second half of type name (e.g. `[3][5]`).
-        let ty = self.read_array(ty);
+        // Read the second half of type name (e.g. `[3][5]` or `(int, int)`).
+        let ty = if self.consume(TokenType::LeftParen) {
+            self.func_suffix(ty)?
+        } else {
+            self.read_array(ty)?
+        };
         self.update_ptr_to(&mut node.ty, Box::new(ty));
+        Ok(node)
+    }
+
+    fn direct_decl(&mut self, ty: Box<Type>) -> Result<Node, LexError> {
+        let start = self.pos;
+        let mut node = self.direct_declarator(ty)?;
 
         // Read an initializer.
         let init: Option<Box<Node>>;
         if self.consume(TokenType::Equal) {
             // Assign a value when initializing an array.
-            if let TokenType::Ident(ref name) = t.ty {
+            if let TokenType::Ident(name) = self.tokens[start].ty {
                 if self.consume(TokenType::LeftBrace) {
+                    let init_ary = self.array_init_rval(Node::new(NodeType::Ident(name)))?;
+
+                    // `int a[] = {1, 2, 3}` has as many elements as values.
+                    if let (Ctype::Ary(ref of, None), NodeType::VecStmt(ref init)) =
+                        (&node.ty.ty, &init_ary.op)
+                    {
+                        node.ty = Box::new(Type::ary_of(of.clone(), Some(init.len())));
+                    }
+
                     let mut stmts = vec![];
                     let mut ary_declaration =
-                        Node::new(NodeType::Vardef(name.clone(), None, Scope::Local(0)));
-                    ary_declaration.ty = node.ty;
+                        Node::new(NodeType::Vardef(name, None, Scope::Local(0)));
+                    ary_declaration.ty = node.ty.clone();
                     stmts.push(ary_declaration);
-                    let init_ary = self.array_init_rval(Node::new(NodeType::Ident(name.clone())));
+
+                    // Elements without an initializer are zero.
+                    if let (Ctype::Ary(_, Some(len)), NodeType::VecStmt(ref init)) =
+                        (&node.ty.ty, &init_ary.op)
+                    {
+                        if init.len() < *len {
+                            let args = vec![
+                                Node::new(NodeType::Ident(name)),
+                                Node::new_num(0),
+                                Node::new_num(node.ty.size as i64),
+                            ];
+                            let memset = Symbol::intern("__builtin_memset");
+                            let call = Node::new(NodeType::Call(memset, args));
+                            stmts.push(new_expr!(NodeType::ExprStmt, call));
+                        }
+                    }
                     stmts.push(init_ary);
-                    return Node::new(NodeType::VecStmt(stmts));
+                    return Ok(Node::new(NodeType::VecStmt(stmts)));
                 }
             }
 
-            init = Some(Box::new(self.assign()));
+            init = Some(Box::new(self.assign()?));
             match node.op {
                 NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
                 _ => unreachable!(),
             }
         }
-        node
+        Ok(node)
     }
 
-    fn declarator(&mut self, ty: &mut Type) -> Node {
+    fn declarator(&mut self, ty: &mut Type) -> Result<Node, LexError> {
         while self.consume(TokenType::Mul) {
             *ty = Type::ptr_to(Box::new(ty.clone()));
+            while self.consume(TokenType::Const) {}
         }
         self.direct_decl(Box::new(ty.clone()))
     }
 
-    fn declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let node = self.declarator(&mut ty);
-        self.expect(TokenType::Semicolon);
-        node
+    fn declaration(&mut self) -> Result<Node, LexError> {
+        let start = self.pos;
+        let mut ty = match self.decl_specifiers()? {
+            Some(ty) => ty,
+            None => return error_at(&self.tokens[start], "typename expected"),
+        };
+        let node = self.declarator(&mut ty)?;
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn param_declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let mut node = self.declarator(&mut ty);
-        if let Ctype::Ary(ary_of, _) = node.ty.ty {
-            node.ty = Box::new(Type::ptr_to(ary_of));
-        }
-        node
+    // A prototype may leave out the parameter name, as in `int f(int);`.
+    fn param_declaration(&mut self) -> Result<Node, LexError> {
+        let ty = Box::new(self.ctype()?);
+        let mut node = match self.tokens[self.pos].ty {
+            TokenType::Comma | TokenType::RightParen => {
+                let mut node = Node::new(NodeType::Null);
+                node.ty = ty;
+                node
+            }
+            _ => self.direct_decl(ty)?,
+        };
+        node.ty = Box::new(param_type(*node.ty));
+        Ok(node)
     }
 
-    fn expr_stmt(&mut self) -> Node {
-        let expr = self.expr();
+    fn expr_stmt(&mut self) -> Result<Node, LexError> {
+        let expr = self.expr()?;
         let node = new_expr!(NodeType::ExprStmt, expr);
-        self.expect(TokenType::Semicolon);
-        node
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn stmt(&mut self) -> Node {
-        let t = &self.tokens[self.pos];
-        self.pos += 1;
+    fn stmt(&mut self) -> Result<Node, LexError> {
+        let start = self.pos;
+        let mut node = self.stmt_inner()?;
+        node.token = Some(self.tokens[start].clone());
+        Ok(node)
+    }
+
+    fn stmt_inner(&mut self) -> Result<Node, LexError> {
+        let start = self.pos;
+        self.advance();
 
-        match t.ty {
+        match self.tokens[start].ty {
             TokenType::Typedef => {
-                let node = self.declaration();
+                let node = self.declaration()?;
                 if let NodeType::Vardef(name, _, _) = node.op {
                     self.env.typedefs.insert(name, *node.ty);
-                    return Node::new(NodeType::Null);
+                    Ok(Node::new(NodeType::Null))
                 } else {
                     unreachable!();
                 }
             }
             TokenType::If => {
                 let mut els = None;
-                self.expect(TokenType::LeftParen);
-                let cond = self.expr();
-                self.expect(TokenType::RightParen);
-                let then = self.stmt();
+                self.expect(TokenType::LeftParen)?;
+                let cond = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                let then = self.body_stmt("if")?;
                 if self.consume(TokenType::Else) {
-                    els = Some(Box::new(self.stmt()));
+                    els = Some(Box::new(self.body_stmt("else")?));
                 }
-                Node::new(NodeType::If(Box::new(cond), Box::new(then), els))
+                Ok(Node::new(NodeType::If(Box::new(cond), Box::new(then), els)))
             }
             TokenType::For => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
 
                 let init: Box<Node> = if self.is_typename(&self.tokens[self.pos]) {
-                    Box::new(self.declaration())
+                    let node = self.declaration()?;
+                    self.hide_const(&node);
+                    Box::new(node)
                 } else if self.consume(TokenType::Semicolon) {
                     Box::new(Node::new(NodeType::Null))
                 } else {
-                    Box::new(self.expr_stmt())
+                    Box::new(self.expr_stmt()?)
                 };
 
                 let cond;
                 if !self.consume(TokenType::Semicolon) {
-                    cond = Box::new(self.expr());
-                    self.expect(TokenType::Semicolon);
+                    cond = Box::new(self.expr()?);
+                    self.expect(TokenType::Semicolon)?;
                 } else {
                     cond = Box::new(Node::new(NodeType::Null))
                 }
 
                 let inc;
                 if !self.consume(TokenType::RightParen) {
-                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()));
-                    self.expect(TokenType::RightParen);
+                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()?));
+                    self.expect(TokenType::RightParen)?;
                 } else {
                     inc = Box::new(Node::new(NodeType::Null))
                 }
 
-                let body = Box::new(self.stmt());
-                Node::new(NodeType::For(init, cond, inc, body))
+                let body = Box::new(self.loop_body("for")?);
+                Ok(Node::new(NodeType::For(init, cond, inc, body)))
             }
             TokenType::While => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
                 let init = Box::new(Node::new(NodeType::Null));
                 let inc = Box::new(Node::new(NodeType::Null));
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                let body = Box::new(self.stmt());
-                Node::new(NodeType::For(init, cond, inc, body))
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                let body = Box::new(self.loop_body("while")?);
+                Ok(Node::new(NodeType::For(init, cond, inc, body)))
             }
             TokenType::Do => {
-                let body = Box::new(self.stmt());
-                self.expect(TokenType::While);
-                self.expect(TokenType::LeftParen);
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                self.expect(TokenType::Semicolon);
-                Node::new(NodeType::DoWhile(body, cond))
+                let body = Box::new(self.loop_body("do")?);
+                self.expect(TokenType::While)?;
+                self.expect(TokenType::LeftParen)?;
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                self.expect(TokenType::Semicolon)?;
+                Ok(Node::new(NodeType::DoWhile(body, cond)))
             }
             TokenType::Break => {
-                self.expect(TokenType::Semicolon);
-                Node::new(NodeType::Break)
+                if self.loops == 0 {
+                    return error_at(&self.tokens[start], "'break' statement not in loop");
+                }
+                self.expect(TokenType::Semicolon)?;
+                Ok(Node::new(NodeType::Break))
             }
             TokenType::Return => {
-                let expr = self.expr();
-                self.expect(TokenType::Semicolon);
-                Node::new(NodeType::Return(Box::new(expr)))
+                let expr = self.expr()?;
+                self.expect(TokenType::Semicolon)?;
+                Ok(Node::new(NodeType::Return(Box::new(expr))))
             }
             TokenType::LeftBrace => {
                 let mut stmts = vec![];
                 while !self.consume(TokenType::RightBrace) {
-                    stmts.push(self.stmt());
+                    stmts.push(self.stmt()?);
                 }
-                Node::new(NodeType::CompStmt(stmts))
+                Ok(Node::new(NodeType::CompStmt(stmts)))
             }
-            TokenType::Semicolon => Node::new(NodeType::Null),
+            TokenType::Semicolon => Ok(Node::new(NodeType::Null)),
+            TokenType::Static => error_at(
+                &self.tokens[start],
+                "static local variables are not supported",
+            ),
             _ => {
                 self.pos -= 1;
                 if self.is_typename(&self.tokens[self.pos]) {
-                    return self.declaration();
+                    let node = self.declaration()?;
+                    self.hide_const(&node);
+                    return Ok(node);
                 }
                 self.expr_stmt()
             }
         }
     }
 
-    fn compound_stmt(&mut self) -> Node {
+    // The body of if, else, for, while and do must be a statement, and
+    // a declaration is not one: `if (x) int y = 3;` would declare a
+    // variable that nothing can use.
+    fn body_stmt(&mut self, keyword: &str) -> Result<Node, LexError> {
+        let t = &self.tokens[self.pos];
+        if t.ty == TokenType::Typedef || self.is_typename(t) {
+            return error_at(
+                t,
+                &format!(
+                    "declaration cannot be the body of '{}'; put it in braces",
+                    keyword
+                ),
+            );
+        }
+        self.stmt()
+    }
+
+    // The body of a loop, which `break` may leave.
+    fn loop_body(&mut self, keyword: &str) -> Result<Node, LexError> {
+        self.loops += 1;
+        let body = self.body_stmt(keyword);
+        self.loops -= 1;
+        body
+    }
+
+    fn compound_stmt(&mut self) -> Result<Node, LexError> {
         let mut stmts = vec![];
 
         let new_env = Env::new(Some(Box::new(self.env.clone())));
         self.env = new_env;
         while !self.consume(TokenType::RightBrace) {
-            stmts.push(self.stmt());
+            stmts.push(self.stmt()?);
         }
         let next = self.env.next.clone();
         self.env = *next.unwrap();
-        Node::new(NodeType::CompStmt(stmts))
+        Ok(Node::new(NodeType::CompStmt(stmts)))
     }
 
-    fn toplevel(&mut self) -> Option<Node> {
-        let is_typedef = self.consume(TokenType::Typedef);
-        let is_extern = self.consume(TokenType::Extern);
+    fn toplevel(&mut self) -> Result<Option<Node>, LexError> {
+        // Storage classes and _Noreturn may come in any order.
+        let (mut is_typedef, mut noreturn, mut is_extern, mut is_static) =
+            (false, false, false, false);
+        loop {
+            let t = &self.tokens[self.pos];
+            match t.ty {
+                TokenType::Typedef => is_typedef = true,
+                TokenType::Noreturn => noreturn = true,
+                TokenType::Extern => is_extern = true,
+                TokenType::Static => is_static = true,
+                _ => break,
+            }
+            if is_static && is_extern {
+                return error_at(t, "cannot combine 'static' and 'extern'");
+            }
+            self.advance();
+        }
 
-        let mut ty = self.ctype();
-        let t = &self.tokens[self.pos];
-        let name: String;
-        if let TokenType::Ident(ref name2) = t.ty {
-            name = name2.clone();
+        let spec_start = self.pos;
+        let mut ty = self.ctype()?;
+        let is_const = self.tokens[spec_start..self.pos]
+            .iter()
+            .any(|t| t.ty == TokenType::Const);
+        let start = self.pos;
+        let name: Symbol;
+        if let TokenType::Ident(name2) = self.tokens[start].ty {
+            name = name2;
+            self.advance();
+        } else if self.tokens[start].ty == TokenType::LeftParen {
+            // A declarator in parentheses, as in `int (*f)(int);`,
+            // declares a variable or a typedef.
+            let node = self.direct_declarator(Box::new(ty))?;
+            match node.op {
+                NodeType::Vardef(name2, _, _) => name = name2,
+                _ => unreachable!(),
+            }
+            ty = *node.ty;
         } else {
-            t.bad_token("function or variable name expected");
+            return bad_name(&self.tokens[start], "function or variable name expected");
         }
-        self.pos += 1;
 
         // Function
         if self.consume(TokenType::LeftParen) {
-            let mut args = vec![];
-            if !self.consume(TokenType::RightParen) {
-                args.push(self.param_declaration());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.param_declaration());
-                }
-                self.expect(TokenType::RightParen);
-            }
+            let (args, variadic) = self.param_list()?;
+            let unspecified = args.is_none();
+            let args = args.unwrap_or_default();
 
+            let params = args.iter().map(|arg| *arg.ty.clone()).collect();
             if self.consume(TokenType::Semicolon) {
+                let params = if unspecified { None } else { Some(params) };
+                let ty = Type::new(Ctype::Func(Box::new(ty), params, variadic, noreturn), 0);
+                if is_typedef {
+                    self.env.typedefs.insert(name, ty);
+                    return Ok(None);
+                }
                 let mut node = Node::new(NodeType::Decl(name));
-                node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-                return Some(node);
+                node.ty = Box::new(ty);
+                return Ok(Some(node));
             }
 
             let t = &self.tokens[self.pos];
-            self.expect(TokenType::LeftBrace);
+            if t.ty != TokenType::LeftBrace {
+                return error_at(t, "expected '{' or ';' after function declarator");
+            }
             if is_typedef {
-                t.bad_token("typedef {} has function definition");
+                return error_at(t, "typedef {} has function definition");
             }
-            let body = self.compound_stmt();
-
-            let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0));
-            node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-            return Some(node);
+            if args.iter().any(|arg| arg.is_null()) {
+                return error_at(t, "parameter name omitted in function definition");
+            }
+            self.advance();
+            // Parameters hide const globals in the body.
+            self.env = Env::new(Some(Box::new(self.env.clone())));
+            for arg in &args {
+                self.hide_const(arg);
+            }
+            self.variadic = variadic;
+            let body = self.compound_stmt()?;
+            self.variadic = false;
+            self.env = *self.env.next.take().unwrap();
+
+            // A definition with `()` takes no arguments either.
+            let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0, is_static));
+            // The closing brace, where control falls off the end.
+            node.token = Some(self.tokens[self.pos - 1].clone());
+            node.ty = Box::new(Type::new(
+                Ctype::Func(Box::new(ty), Some(params), variadic, noreturn),
+                0,
+            ));
+            return Ok(Some(node));
         }
 
-        ty = self.read_array(Box::new(ty));
-        self.expect(TokenType::Semicolon);
+        if noreturn {
+            return error_at(
+                &self.tokens[start],
+                "'_Noreturn' can only appear on functions",
+            );
+        }
+        ty = self.read_array(Box::new(ty))?;
+
+        // A global's initial value is data, so it must be known now. A
+        // const integer one can also be used where a constant is needed.
+        let mut data = String::new();
+        if !is_typedef && self.consume(TokenType::Equal) {
+            let init_start = self.pos;
+            let t = &self.tokens[init_start];
+            if is_extern {
+                return error_at(t, "an 'extern' variable cannot have an initializer");
+            }
+            if !matches!(
+                ty.ty,
+                Ctype::Int
+                    | Ctype::Long
+                    | Ctype::Char
+                    | Ctype::Ptr(_)
+                    | Ctype::Double
+                    | Ctype::Float
+            ) {
+                return error_at(
+                    t,
+                    &format!("initializer for a global of type '{}' is not supported", ty),
+                );
+            }
+            let init = self.assign()?;
+            let val = if ty.size == 4 && ty.is_float() {
+                self.const_float(&init)
+                    .map(|v| i64::from((v as f32).to_bits()))
+            } else if ty.is_float() {
+                self.const_float(&init).map(|v| v.to_bits() as i64)
+            } else {
+                self.const_value(&init)
+            };
+            let val = match val {
+                Some(val) => val,
+                None => {
+                    let t = &self.tokens[init_start];
+                    return error_at(t, "initializer element is not a compile-time constant");
+                }
+            };
+            data = val.to_le_bytes()[..ty.size]
+                .iter()
+                .map(|&b| b as char)
+                .collect();
+            if is_const && !ty.is_float() && !matches!(ty.ty, Ctype::Ptr(_)) {
+                self.env.consts.insert(name, Some(val));
+            }
+        }
+        self.expect(TokenType::Semicolon)?;
 
         if is_typedef {
-            self.env.typedefs.insert(name.clone(), ty.clone());
-            return None;
+            self.env.typedefs.insert(name, ty.clone());
+            return Ok(None);
         }
 
         // Global variable
@@ -905,10 +1571,42 @@ impl<'a> Parser<'a> {
             if is_extern {
                 Scope::Global(String::new(), 0, true)
             } else {
-                Scope::Global(String::new(), ty.size, false)
+                Scope::Global(data, ty.size, false)
             },
         ));
         node.ty = Box::new(ty);
-        Some(node)
+        node.token = Some(self.tokens[start].clone());
+        Ok(Some(node))
+    }
+}
+
+// Reports a token where a name should be. A keyword is reserved, and
+// can't be one.
+fn bad_name<T>(t: &Token, msg: &str) -> Result<T, LexError> {
+    match t.keyword() {
+        Some(keyword) => error_at(
+            t,
+            &format!("expected identifier, found keyword '{}'", keyword),
+        ),
+        None => error_at(t, msg),
+    }
+}
+
+// A parameter declared as an array or a function is a pointer.
+fn param_type(ty: Type) -> Type {
+    match ty.ty {
+        Ctype::Ary(ary_of, _) => Type::ptr_to(ary_of),
+        Ctype::Func(..) => Type::ptr_to(Box::new(ty)),
+        _ => ty,
+    }
+}
+
+// The first identifier in an expression.
+fn find_ident(node: &Node) -> Option<Symbol> {
+    match node.op {
+        NodeType::Ident(name) => Some(name),
+        NodeType::Neg(ref expr) => find_ident(expr),
+        NodeType::BinOp(_, ref lhs, ref rhs) => find_ident(lhs).or_else(|| find_ident(rhs)),
+        _ => None,
     }
 }
@@ -1,105 +1,196 @@
 // C preprocessor
 
-use crate::token::{tokenize, Token};
+use crate::diag;
+use crate::loader::{find_file, DiskLoader, FileLoader, MemoryLoader};
+use crate::parse::binop_prec;
+use crate::symbol::Symbol;
+use crate::target::target;
+use crate::token::{
+    self, error_at, scan_file, str_data, tokenize_source, Expansion, LexError, Token,
+};
+use crate::util::{date_time, normalize_path};
 use crate::TokenType;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn preprocess(tokens: Vec<Token>, ctx: &mut Preprocessor) -> Vec<Token> {
-    ctx.preprocess_impl(tokens)
+// Macros every translation unit starts with. __r9cc_assert_fail is
+// provided by the code generator.
+const PREDEFINED: &str = "\
+#define assert(x) do { if (!(x)) { __r9cc_assert_fail(#x, __FILE__, __LINE__); } } while (0)
+";
+
+// Searched for #include after the -I directories and the current one,
+// under --sysroot if it is given.
+const SYSTEM_INCLUDE_DIR: &str = "/usr/include";
+
+// Headers built into the compiler, for a system without them. They are
+// held in memory under BUILTIN_DIR, which is searched after the
+// directories on disk.
+const BUILTIN_DIR: &str = "<built-in>";
+const BUILTIN_HEADERS: &[(&str, &str)] = &[
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("stddef.h", include_str!("../include/stddef.h")),
+];
+
+// How many files deep #include may go, as in gcc.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+// The time __DATE__ and __TIME__ are taken from: SOURCE_DATE_EPOCH if it
+// is set, for reproducible builds, or else now.
+fn build_time() -> u64 {
+    if let Some(secs) = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        return secs;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
-#[derive(Clone)]
+// The tokens being read, and what they expand to. Reading pops from the
+// end of `input`, which holds the tokens left in reverse, so that tokens
+// put back are pushed there without moving the rest.
+#[derive(Default)]
 pub struct Env {
-    input: Vec<Token>,
+    input: Vec<Token>, // the next token last
     output: Vec<Token>,
-    pos: usize,
-    next: Option<Box<Env>>,
+    conds: Vec<Cond>, // conditional groups open in this file, innermost last
+    in_cond: bool,    // expanding an #if expression
+    is_file: bool,    // the tokens of a file, where a '#' can start a directive
+    line: Option<LineMark>,
 }
 
-impl Default for Env {
-    fn default() -> Env {
-        Env {
-            input: vec![],
-            output: vec![],
-            pos: 0,
-            next: None,
+// Where #line says the lines after it are.
+#[derive(Clone)]
+struct LineMark {
+    delta: isize,          // added to the line number of each token read
+    filename: Arc<String>, // reported instead of the file's name
+    path: Arc<String>,     // the file's real name, for #include
+}
+
+// A file being read because of an #include.
+struct Include {
+    path: PathBuf, // canonical, see FileLoader::canonicalize
+    name: String,  // as found, for messages
+    at: Token,     // the file name in the #include
+}
+
+// A conditional group, from #if, #ifdef or #ifndef to its #endif.
+#[derive(Clone)]
+struct Cond {
+    at: Token,     // the '#' of the directive that opened it
+    taken: bool,   // whether one of its branches has been included
+    in_else: bool, // after its #else
+}
+
+impl Cond {
+    fn new(at: Token, taken: bool) -> Self {
+        Cond {
+            at,
+            taken,
+            in_else: false,
         }
     }
 }
 
 impl Env {
-    pub fn new(input: Vec<Token>, next: Option<Box<Env>>) -> Self {
+    pub fn new(mut input: Vec<Token>) -> Self {
+        input.reverse();
         Env {
             input,
-            next,
             ..Default::default()
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum MacroType {
     Objlike,
-    Funclike(Vec<String>),
+    Funclike(Vec<Symbol>, bool), // params, variadic (the last param is __VA_ARGS__)
 }
 
 #[derive(Debug, Clone)]
 struct Macro {
     ty: MacroType,
     pub tokens: Vec<Token>,
+    at: Token, // the name in the definition
 }
 
 impl Macro {
-    fn new(ty: MacroType) -> Self {
-        Macro { ty, tokens: vec![] }
+    fn new(ty: MacroType, at: Token) -> Self {
+        Macro {
+            ty,
+            tokens: vec![],
+            at,
+        }
+    }
+
+    // A macro can be defined again only the same way: with the same
+    // parameters, and the same tokens spelled the same, with whitespace
+    // between the same ones.
+    fn same_as(&self, other: &Macro) -> bool {
+        self.ty == other.ty
+            && self.tokens.len() == other.tokens.len()
+            && self
+                .tokens
+                .iter()
+                .zip(&other.tokens)
+                .enumerate()
+                .all(|(i, (a, b))| {
+                    a.ty == b.ty
+                        && a.stringize == b.stringize
+                        && (i == 0 || a.space == b.space)
+                        && (matches!(a.ty, TokenType::Param(_)) || a.text() == b.text())
+                })
     }
 
     fn replace_params(mut self) -> Self {
         match self.ty {
-            MacroType::Funclike(ref params) => {
+            MacroType::Funclike(ref params, _) => {
                 let mut map = HashMap::new();
-                for (i, item) in params.iter().enumerate() {
-                    let name = item.clone();
-                    map.insert(name, i);
+                for (i, name) in params.iter().enumerate() {
+                    map.insert(*name, i);
                 }
 
                 for i in 0..self.tokens.len() {
-                    let t = &self.tokens[i].clone();
-                    match t.ty {
-                        TokenType::Ident(ref name) => {
-                            if let Some(n) = map.get(name) {
-                                if let Some(elem) = self.tokens.get_mut(i) {
-                                    *elem = Token::new(
-                                        TokenType::Param(*n),
-                                        0,
-                                        t.filename.clone(),
-                                        t.buf.clone(),
-                                    );
-                                }
-                            } else {
-                                continue;
-                            }
-                        }
+                    let n = match self.tokens[i].ty {
+                        TokenType::Ident(name) => match map.get(&name) {
+                            Some(n) => *n,
+                            None => continue,
+                        },
                         _ => continue,
-                    }
+                    };
+                    let t = &self.tokens[i];
+                    let mut param = Token::at(TokenType::Param(n), t);
+                    param.space = t.space;
+                    self.tokens[i] = param;
                 }
 
                 // Process '#' followed by a macro parameter.
                 self.tokens = self
                     .tokens
                     .into_iter()
-                    .scan(false, |is_prev_hashmark, mut t| {
-                        if *is_prev_hashmark {
-                            match t.ty {
-                                TokenType::Param(_) => t.stringize = true,
-                                _ => *is_prev_hashmark = false,
-                            }
-                        } else {
-                            *is_prev_hashmark = t.ty == TokenType::HashMark;
+                    .scan(None, |prev_hashmark: &mut Option<bool>, mut t| {
+                        // The '#' goes away, and leaves its space to the
+                        // string.
+                        if let (Some(space), TokenType::Param(_)) = (*prev_hashmark, &t.ty) {
+                            t.stringize = true;
+                            t.space = space;
                         }
+                        *prev_hashmark = if t.ty == TokenType::HashMark {
+                            Some(t.space)
+                        } else {
+                            None
+                        };
                         Some(t)
                     })
                     .collect::<Vec<_>>();
@@ -128,50 +219,277 @@ impl Macro {
 }
 
 pub struct Preprocessor {
-    macros: HashMap<String, Macro>,
-    pub env: Box<Env>,
+    macros: HashMap<Symbol, Rc<Macro>>,
+    env: Env,
+    outer: Vec<Env>,            // to go back to when env is done, innermost last
+    pub(crate) trigraphs: bool, // --trigraphs
+    once: HashSet<PathBuf>,     // files with #pragma once
+    includes: Vec<Include>,     // innermost last, empty in the main file
+    include_dirs: Vec<String>,  // -I
+    sysroot: String,            // --sysroot, prefixed to SYSTEM_INCLUDE_DIR
+    loader: Box<dyn FileLoader>,
+    builtins: MemoryLoader,      // the headers in BUILTIN_DIR
+    ready: VecDeque<Token>,      // preprocessed, for next_token to hand out
+    counter: i64,                // the next __COUNTER__
+    specials: HashSet<Symbol>,   // __LINE__, __FILE__ and __COUNTER__ until #undef
+    emptied: Option<Vec<Token>>, // macros that expanded to nothing in the #if being read
+
+    // For --trace-macro
+    trace: bool,
+    trace_only: Option<Symbol>,
+    traced: Vec<bool>, // for each expansion in progress, whether it is printed
+}
+
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Preprocessor {
     pub fn new() -> Self {
-        Preprocessor {
+        let mut ctx = Preprocessor {
             macros: HashMap::new(),
-            env: Box::new(Env::new(vec![], None)),
+            env: Env::default(),
+            outer: vec![],
+            trigraphs: false,
+            once: HashSet::new(),
+            includes: vec![],
+            include_dirs: vec![],
+            sysroot: String::new(),
+            loader: Box::new(DiskLoader),
+            builtins: MemoryLoader::new(),
+            ready: VecDeque::new(),
+            counter: 0,
+            specials: ["__LINE__", "__FILE__", "__COUNTER__"]
+                .iter()
+                .map(|name| Symbol::intern(name))
+                .collect(),
+            emptied: None,
+            trace: false,
+            trace_only: None,
+            traced: vec![],
+        };
+
+        let t = target();
+        ctx.define_builtin("__STDC__", vec![TokenType::Num(1, false, false)]);
+        ctx.define_builtin(
+            "__SIZEOF_POINTER__",
+            vec![TokenType::Num(t.ptr_size as i64, false, false)],
+        );
+        ctx.define_builtin(
+            "__SIZEOF_INT__",
+            vec![TokenType::Num(t.int_size as i64, false, false)],
+        );
+        ctx.define_builtin(
+            "__SIZEOF_LONG__",
+            vec![TokenType::Num(t.long_size as i64, false, false)],
+        );
+        ctx.set_timestamp(build_time());
+        let version = crate::version();
+        let len = version.len() + 1;
+        ctx.define_builtin(
+            "__R9CC_VERSION__",
+            vec![TokenType::Str(str_data(version), len)],
+        );
+        tokenize_source("<built-in>".into(), PREDEFINED, &mut ctx).expect("bad predefined macros");
+        for (name, contents) in BUILTIN_HEADERS {
+            ctx.add_builtin_header(name, contents);
+        }
+        ctx
+    }
+
+    // Prints every macro expansion to stderr, or only those of the
+    // macro `only`.
+    pub fn trace_macros(&mut self, only: Option<&str>) {
+        self.trace = true;
+        self.trace_only = only.map(Symbol::intern);
+    }
+
+    // Replaces `??=` and the other trigraphs in files read from now on.
+    pub fn enable_trigraphs(&mut self) {
+        self.trigraphs = true;
+    }
+
+    fn is_traced(&self, name: Symbol) -> bool {
+        self.trace && self.trace_only.is_none_or(|only| only == name)
+    }
+
+    // Whether the innermost expansion in progress is printed.
+    fn tracing(&self) -> bool {
+        self.traced.last() == Some(&true)
+    }
+
+    // Lines about an expansion are indented one level deeper than the
+    // line that starts it.
+    fn trace_line(&self, msg: &str) {
+        eprintln!("{}{}", "  ".repeat(self.traced.len()), msg);
+    }
+
+    // Defines an object-like macro that expands to `tokens`, as if by
+    // a #define at the top of every file. A builtin is replaced without
+    // a warning, as __DATE__ is by set_timestamp.
+    pub fn define_builtin(&mut self, name: &str, tokens: Vec<TokenType>) {
+        let filename = Arc::new("<built-in>".to_string());
+        let name = Symbol::intern(name);
+        let at = Token::new(
+            TokenType::Ident(name),
+            0,
+            filename.clone(),
+            Arc::new(vec![]),
+        );
+        let mut m = Macro::new(MacroType::Objlike, at);
+        m.tokens = tokens
+            .into_iter()
+            .enumerate()
+            .map(|(i, ty)| {
+                let mut t = Token::new(ty, 0, filename.clone(), Arc::new(vec![]));
+                t.space = i > 0;
+                t
+            })
+            .collect();
+        self.macros.insert(name, Rc::new(m));
+    }
+
+    // Defines `name` as the tokens of `value`, the way -DNAME=VALUE does.
+    // An empty value, as in -DNAME=, defines it as nothing.
+    pub fn define(&mut self, name: &str, value: &str) -> Result<(), LexError> {
+        let tokens = token::scan("<command line>", value)?;
+        let tokens: Vec<Token> = tokens
+            .into_iter()
+            .filter(|t| t.ty != TokenType::NewLine)
+            .collect();
+        check_body(&tokens)?;
+        let name = Symbol::intern(name);
+        let filename = Arc::new("<command line>".to_string());
+        let at = Token::new(TokenType::Ident(name), 0, filename, Arc::new(vec![]));
+        let mut m = Macro::new(MacroType::Objlike, at);
+        m.tokens = tokens;
+        self.add_macro(name, m);
+        Ok(())
+    }
+
+    // Forgets a macro, builtins included, the way -UNAME does.
+    pub fn undefine(&mut self, name: &str) {
+        self.remove_macro(Symbol::intern(name));
+    }
+
+    // Searches `dir` for #include after the directories added before it,
+    // the way -Idir does.
+    pub fn add_include_dir(&mut self, dir: &str) {
+        self.include_dirs.push(dir.to_string());
+    }
+
+    // Looks for system headers under `dir`, as if it were the root
+    // directory.
+    pub fn set_sysroot(&mut self, dir: &str) {
+        self.sysroot = dir.trim_end_matches('/').to_string();
+    }
+
+    // Reads #include'd files through `loader` instead of from the disk.
+    pub fn set_loader(&mut self, loader: Box<dyn FileLoader>) {
+        self.loader = loader;
+    }
+
+    // Makes `#include <name>` read `contents` when no directory on disk
+    // has the file.
+    pub fn add_builtin_header(&mut self, name: &str, contents: &str) {
+        self.builtins
+            .add(&format!("{}/{}", BUILTIN_DIR, name), contents);
+    }
+
+    // Where a path find_include returned is read from.
+    fn loader_for(&self, path: &Path) -> &dyn FileLoader {
+        if path.starts_with(BUILTIN_DIR) {
+            &self.builtins
+        } else {
+            &*self.loader
+        }
+    }
+
+    // Defining a macro again differently is allowed, but likely a
+    // mistake, e.g. two headers that disagree.
+    fn add_macro(&mut self, name: Symbol, m: Macro) {
+        if let Some(old) = self.macros.get(&name) {
+            if !old.same_as(&m) {
+                diag::warning_named(
+                    "macro-redefined",
+                    &format!(
+                        "{}: '{}' redefined (previously defined at {})",
+                        def_location(&m.at),
+                        name,
+                        def_location(&old.at)
+                    ),
+                );
+            }
+        }
+        // A definition of __LINE__ and the like takes its place.
+        self.specials.remove(&name);
+        self.macros.insert(name, Rc::new(m));
+    }
+
+    fn remove_macro(&mut self, name: Symbol) {
+        self.macros.remove(&name);
+        self.specials.remove(&name);
+    }
+
+    // Sets __DATE__ and __TIME__ to a time in seconds since the epoch,
+    // in UTC.
+    pub fn set_timestamp(&mut self, secs: u64) {
+        let (date, time) = date_time(secs);
+        for (name, s) in [("__DATE__", date), ("__TIME__", time)] {
+            let len = s.len() + 1;
+            self.define_builtin(name, vec![TokenType::Str(str_data(&s), len)]);
         }
     }
 
     fn next(&mut self) -> Option<Token> {
-        if self.eof() {
-            return None;
+        let mut t = self.env.input.pop()?;
+        // Tokens read again after a macro expanded were moved already.
+        if let Some(ref mark) = self.env.line {
+            if t.expansion.is_none() && t.line > 0 {
+                t.line = (t.line as isize + mark.delta) as usize;
+                t.filename = mark.filename.clone();
+            }
         }
-        let pos = self.env.pos;
-        let t = Some(mem::replace(&mut self.env.input[pos], Token::default()));
-        self.env.pos += 1;
-        t
+        Some(t)
+    }
+
+    // The name of the file `t` is in, whatever #line says.
+    fn real_filename<'a>(&'a self, t: &'a Token) -> &'a str {
+        self.env
+            .line
+            .as_ref()
+            .map_or(&t.filename, |mark| &mark.path)
     }
 
     fn eof(&self) -> bool {
-        self.env.pos == self.env.input.len()
+        self.env.input.is_empty()
     }
 
-    fn get(&mut self, ty: TokenType, msg: &str) -> Token {
-        let t = self.next().expect(msg);
-        if t.ty != ty {
-            t.bad_token(msg);
+    // The error is at `at` if the input has run out.
+    fn get(&mut self, ty: TokenType, at: &Token, msg: &str) -> Result<Token, LexError> {
+        match self.next() {
+            Some(t) if t.ty == ty => Ok(t),
+            Some(t) => error_at(&t, msg),
+            None => error_at(at, msg),
         }
-        t
     }
 
-    fn ident(&mut self, msg: &str) -> String {
-        let t = self.next().expect(msg);
+    fn ident(&mut self, at: &Token, msg: &str) -> Result<Symbol, LexError> {
+        let t = match self.next() {
+            Some(t) => t,
+            None => return error_at(at, msg),
+        };
         match t.ty {
-            TokenType::Ident(s) | TokenType::Str(s, _) => s,
-            _ => t.bad_token(msg),
+            TokenType::Ident(s) => Ok(s),
+            TokenType::Str(ref s, _) => Ok(Symbol::intern(s)),
+            _ => error_at(&t, msg),
         }
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.env.input.get(self.env.pos)
+        self.env.input.last()
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
@@ -182,7 +500,23 @@ impl Preprocessor {
         } else {
             return false;
         }
-        self.env.pos += 1;
+        self.env.input.pop();
+        true
+    }
+
+    // Whether a '(' comes next, for a call of a function-like macro. In
+    // a file, its arguments may start on a later line, so the line
+    // breaks before the '(' are dropped.
+    fn paren_follows(&mut self) -> bool {
+        let input = &mut self.env.input;
+        let n = match input.iter().rposition(|t| t.ty != TokenType::NewLine) {
+            Some(n) => n,
+            None => return false,
+        };
+        if input[n].ty != TokenType::LeftParen || (n + 1 < input.len() && !self.env.is_file) {
+            return false;
+        }
+        input.truncate(n + 1);
         true
     }
 
@@ -197,21 +531,42 @@ impl Preprocessor {
         v
     }
 
-    fn read_one_arg(&mut self) -> Vec<Token> {
+    // Reads an argument up to the next comma, or with `rest` set, the
+    // rest of them along with the commas between. `name` and `paren`
+    // are those of the macro call.
+    fn read_one_arg(
+        &mut self,
+        rest: bool,
+        name: &Token,
+        paren: &Token,
+    ) -> Result<Vec<Token>, LexError> {
         let mut v = vec![];
-        let msg = "unclosed macro argument";
-        let start = self.peek().expect(msg).clone();
+        let mut bol = false; // at the beginning of a line
         let mut level = 0;
 
-        while !self.eof() {
-            let t = self.peek().expect(msg).clone();
-            if level == 0 {
-                if t.ty == TokenType::RightParen || t.ty == TokenType::Comma {
-                    return v;
-                }
+        while let Some(t) = self.peek() {
+            if level == 0 && (t.ty == TokenType::RightParen || (t.ty == TokenType::Comma && !rest))
+            {
+                return Ok(v);
             }
 
-            self.next();
+            let t = self.next().unwrap();
+            // An included file is tokenized on its own, so it can't
+            // supply part of the arguments.
+            if bol
+                && t.ty == TokenType::HashMark
+                && self.peek().is_some_and(|t| t.is_ident("include"))
+            {
+                return error_at(
+                    &t,
+                    &format!(
+                        "#include in the arguments of macro '{}' called at {}: macro arguments cannot cross a file boundary",
+                        name.text(),
+                        location(paren)
+                    ),
+                );
+            }
+            bol = t.ty == TokenType::NewLine;
             if t.ty == TokenType::LeftParen {
                 level += 1;
             } else if t.ty == TokenType::RightParen {
@@ -219,169 +574,1155 @@ impl Preprocessor {
             }
             v.push(t);
         }
-        start.bad_token(msg);
+
+        let mut msg = format!(
+            "unterminated argument list invoking macro '{}': the '(' at {} has no matching ')'",
+            name.text(),
+            location(paren)
+        );
+        if !self.includes.is_empty() {
+            msg +=
+                " in this file; macro arguments cannot continue past the end of an included file";
+        }
+        error_at(name, &msg)
     }
 
-    fn read_args(&mut self) -> Vec<Vec<Token>> {
+    // The arguments of a macro call. Those from the `va`th on are one
+    // argument, for __VA_ARGS__.
+    fn read_args(
+        &mut self,
+        va: Option<usize>,
+        name: &Token,
+        paren: &Token,
+    ) -> Result<Vec<Vec<Token>>, LexError> {
         let mut v = vec![];
         if self.consume(TokenType::RightParen) {
-            return v;
+            return Ok(v);
         }
-        v.push(self.read_one_arg());
+        v.push(self.read_one_arg(va == Some(0), name, paren)?);
         while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            v.push(self.read_one_arg());
+            self.get(TokenType::Comma, name, "comma expected")?;
+            v.push(self.read_one_arg(va == Some(v.len()), name, paren)?);
         }
-        v
+        Ok(v)
     }
 
-    fn stringize(tokens: &[Token], filename: Rc<String>, buf: Rc<Vec<char>>) -> Token {
+    // Whitespace between tokens, line breaks in arguments included,
+    // becomes a single space.
+    fn spell(tokens: &[Token]) -> String {
         let mut sb = String::new();
-        for (i, t) in tokens.iter().enumerate() {
-            if i != 0 {
+        let mut space = false;
+        for t in tokens {
+            if t.ty == TokenType::NewLine {
+                space = true;
+                continue;
+            }
+            if !sb.is_empty() && (space || t.space) {
                 sb.push(' ');
             }
-            sb.push_str(&t.tokstr());
+            sb.push_str(&t.text());
+            space = false;
         }
+        sb
+    }
 
-        let len = sb.len();
-        Token::new(TokenType::Str(sb, len), 0, filename, buf)
+    fn stringize(tokens: &[Token], at: &Token) -> Token {
+        let sb = str_data(&Self::spell(tokens));
+        let len = sb.chars().count() + 1;
+        Token::at(TokenType::Str(sb, len), at)
     }
 
-    fn add_special_macro(&mut self, t: &Token) -> bool {
-        if t.is_ident("__LINE__") {
-            self.env.output.push(Token::new(
-                TokenType::Num(t.get_line_number() as i32),
-                0,
-                t.filename.clone(),
-                t.buf.clone(),
+    // __LINE__ and __FILE__ refer to the place the enclosing macro
+    // was invoked, not to where it was defined. __COUNTER__ counts up
+    // from 0 each time it is expanded.
+    fn add_special_macro(&mut self, t: &Token, start: &Token) -> bool {
+        let name = match t.ty {
+            TokenType::Ident(name) if self.specials.contains(&name) => name,
+            _ => return false,
+        };
+        let start = origin(start);
+        let ty = match &*name.as_str() {
+            "__LINE__" => TokenType::Num(start.get_line_number() as i64, false, false),
+            "__FILE__" => {
+                let name = str_data(&start.filename);
+                let len = name.chars().count() + 1;
+                TokenType::Str(name, len)
+            }
+            _ => {
+                self.counter += 1;
+                TokenType::Num(self.counter - 1, false, false)
+            }
+        };
+        let mut val = Token::at(ty, start);
+        val.space = t.space;
+
+        if self.tracing() {
+            self.trace_line(&format!("{} -> {}", t.text(), val.text()));
+        } else if self.traced.is_empty() && self.is_traced(name) {
+            // Outside of any macro
+            self.trace_line(&format!("{} at {} => {}", name, location(t), val.text()));
+        }
+        self.env.output.push(val);
+        true
+    }
+
+    // Glues the first token of output[n..] to the one before it, for
+    // `##`. `before` is how many tokens the operand on the left became;
+    // if either side came to nothing, the other is left as it is.
+    fn paste(&mut self, n: usize, before: usize, start: &Token) -> Result<(), LexError> {
+        if before == 0 || n == self.env.output.len() {
+            return Ok(());
+        }
+        let rhs = self.env.output.remove(n);
+        let lhs = &self.env.output[n - 1];
+        let pasted = match token::paste(lhs, &rhs) {
+            Some(t) => t,
+            None => {
+                let msg = format!(
+                    "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                    lhs.text(),
+                    rhs.text()
+                );
+                return error_at(start, &msg);
+            }
+        };
+        if self.tracing() {
+            self.trace_line(&format!(
+                "{} ## {} -> {}",
+                lhs.text(),
+                rhs.text(),
+                pasted.text()
             ));
-            true
-        } else {
-            false
         }
+        self.env.output[n - 1] = pasted;
+        Ok(())
     }
 
-    fn apply_objlike(&mut self, tokens: Vec<Token>) {
+    fn apply_objlike(&mut self, tokens: &[Token], start: &Token) -> Result<(), LexError> {
+        if tokens.is_empty() {
+            return Ok(()); // #define NAME with no replacement list
+        }
+        let mut paste = false; // after a ##
+        let mut before = 0;
         for t in tokens {
-            if self.add_special_macro(&t) {
+            if t.ty == TokenType::HashHash {
+                paste = true;
                 continue;
-            } else {
-                self.env.output.push(t);
             }
+            let n = self.env.output.len();
+            if !self.add_special_macro(t, start) {
+                self.env.output.push(t.clone());
+            }
+            if paste {
+                self.paste(n, before, start)?;
+            }
+            before = 1;
+            paste = false;
         }
+        Ok(())
     }
 
-    fn apply_funclike(&mut self, tokens: Vec<Token>, params: &[String], start: &Token) {
-        self.get(TokenType::LeftParen, "comma expected");
-        let args = self.read_args();
+    fn apply_funclike(
+        &mut self,
+        tokens: &[Token],
+        params: &[Symbol],
+        variadic: bool,
+        start: &Token,
+    ) -> Result<(), LexError> {
+        let paren = self.get(TokenType::LeftParen, start, "'(' expected")?;
+        let va = if variadic {
+            Some(params.len() - 1)
+        } else {
+            None
+        };
+        let mut args = self.read_args(va, start, &paren)?;
+        // `F()` passes one empty argument to a macro that takes one, and
+        // leaving out the variable arguments passes none.
+        if args.len() + 1 == params.len() && (args.is_empty() || variadic) {
+            args.push(vec![]);
+        }
         if params.len() != args.len() {
-            start.bad_token("number of parameter does not match");
+            return error_at(start, "number of parameter does not match");
+        }
+        if self.tracing() {
+            for (param, arg) in params.iter().zip(&args) {
+                self.trace_line(&format!("{} = {}", param, Self::spell(arg)));
+            }
         }
 
-        for t in tokens {
-            if self.add_special_macro(&t) {
+        let mut expanded = vec![None; args.len()]; // each argument, once it is needed
+        let mut paste = false; // after a ##
+        let mut before = 0; // tokens the operand before it became
+        for (i, t) in tokens.iter().enumerate() {
+            if t.ty == TokenType::HashHash {
+                paste = true;
                 continue;
             }
+            let n = self.env.output.len();
+            let operand = paste
+                || tokens
+                    .get(i + 1)
+                    .is_some_and(|t| t.ty == TokenType::HashHash)
+                || (self.env.in_cond && after_defined(tokens, i));
+            self.apply_funclike_token(t, &args, &mut expanded, params, start, operand)?;
+            let added = self.env.output.len() - n;
+            let is_va = va.is_some_and(|va| t.ty == TokenType::Param(va));
+            if paste && is_va && before > 0 && self.env.output[n - 1].ty == TokenType::Comma {
+                // GNU `, ## __VA_ARGS__`: the comma is dropped when there
+                // are no variable arguments, and not pasted to them when
+                // there are.
+                if added == 0 {
+                    self.env.output.remove(n - 1);
+                }
+            } else if paste {
+                self.paste(n, before, start)?;
+            }
+            // An empty argument between two ##s passes the left side on.
+            if !(paste && added == 0) {
+                before = added;
+            }
+            paste = false;
+        }
+        Ok(())
+    }
 
-            match t.ty {
-                TokenType::Param(val) => {
-                    if t.stringize {
+    fn apply_funclike_token(
+        &mut self,
+        t: &Token,
+        args: &[Vec<Token>],
+        expanded: &mut [Option<Vec<Token>>], // args with their macros expanded
+        params: &[Symbol],
+        start: &Token,
+        operand: bool, // of ##, or of `defined` in an #if, which take arguments as written
+    ) -> Result<(), LexError> {
+        if self.add_special_macro(t, start) {
+            return Ok(());
+        }
+
+        match t.ty {
+            TokenType::Param(val) => {
+                if t.stringize {
+                    let mut s = Self::stringize(&args[val], start);
+                    s.space = t.space;
+                    if self.tracing() {
+                        self.trace_line(&format!("#{} -> {}", params[val], s.text()));
+                    }
+                    self.env.output.push(s);
+                } else {
+                    let n = self.env.output.len();
+                    if operand {
+                        self.env.output.extend(args[val].iter().cloned());
+                    } else {
+                        if expanded[val].is_none() {
+                            expanded[val] = Some(self.expand_arg(&args[val])?);
+                        }
                         self.env
                             .output
-                            .push(Self::stringize(&args[val], t.filename, t.buf));
-                    } else {
-                        self.env.output.append(&mut args[val].clone());
+                            .extend(expanded[val].iter().flatten().cloned());
+                    }
+                    if let Some(first) = self.env.output.get_mut(n) {
+                        first.space = t.space;
+                        first.apart = false;
                     }
                 }
-                _ => self.env.output.push(t),
             }
+            _ => self.env.output.push(t.clone()),
         }
+        Ok(())
     }
 
-    fn apply(&mut self, m: Macro, start: &Token) {
+    // Expands the macros in an argument on its own, before it is
+    // substituted. A function-like macro name at its end stays as it is,
+    // and may still be called by what follows the argument. An
+    // argument's __FILE__ is where the argument is.
+    fn expand_arg(&mut self, arg: &[Token]) -> Result<Vec<Token>, LexError> {
+        let env = Env {
+            in_cond: self.env.in_cond,
+            ..Env::new(arg.to_vec())
+        };
+        self.preprocess_impl(env)
+    }
+
+    // Keeps the tokens of an expansion that starts at output[n] from
+    // running into their neighbours when printed, e.g. `-A` with A
+    // defined as `-1` must not become `--1`. The whitespace they had
+    // is left as it was, for #.
+    fn separate_expansion(&mut self, n: usize) {
+        if let Some(first) = self.env.output.get_mut(n) {
+            first.apart = true;
+        }
+        if let Some(next) = self.env.input.last_mut() {
+            next.apart = true;
+        }
+    }
+
+    // Puts tokens back in front of the input, to be read again.
+    fn unread(&mut self, tokens: Vec<Token>) {
+        self.env.input.extend(tokens.into_iter().rev());
+    }
+
+    // The expansion is scanned again for more macros to expand, along
+    // with the rest of the input. Its tokens carry the names of the
+    // macros they came out of, which they don't expand: otherwise a
+    // macro that names itself, directly as in `#define FOO FOO + 1` or
+    // through another one, would expand forever.
+    fn apply(&mut self, name: Symbol, m: &Macro, start: &Token) -> Result<(), LexError> {
+        let traced = self.is_traced(name);
+        if traced {
+            self.trace_line(&format!("{} at {}", name, location(start)));
+        }
+        self.traced.push(traced);
+
+        let n = self.env.output.len();
         match m.ty {
-            MacroType::Objlike => self.apply_objlike(m.tokens),
-            MacroType::Funclike(ref params) => self.apply_funclike(m.tokens, params, start),
+            MacroType::Objlike => self.apply_objlike(&m.tokens, start)?,
+            MacroType::Funclike(ref params, variadic) => {
+                self.apply_funclike(&m.tokens, params, variadic, start)?
+            }
+        }
+
+        if traced {
+            self.trace_line(&format!("=> {}", Self::spell(&self.env.output[n..])));
+        }
+        self.traced.pop();
+
+        if self.env.output.len() == n {
+            self.expanded_to_nothing(start);
+            return Ok(());
+        }
+
+        let mut hideset = start
+            .expansion
+            .as_ref()
+            .map_or(vec![], |e| e.hideset.clone());
+        hideset.push(name);
+        let mut origin = origin(start).clone();
+        origin.expansion = None;
+        let expansion = Arc::new(Expansion {
+            hideset,
+            start: origin,
+        });
+
+        let mut tokens: Vec<Token> = self.env.output.drain(n..).collect();
+        for t in &mut tokens {
+            // An argument may have come out of another macro as well.
+            t.expansion = Some(match t.expansion.take() {
+                Some(own) if own.hideset.iter().any(|s| !expansion.hideset.contains(s)) => {
+                    let mut hideset = expansion.hideset.clone();
+                    hideset.extend(
+                        own.hideset
+                            .iter()
+                            .filter(|s| !expansion.hideset.contains(s)),
+                    );
+                    Arc::new(Expansion {
+                        hideset,
+                        start: expansion.start.clone(),
+                    })
+                }
+                _ => expansion.clone(),
+            });
+        }
+        // Keep the expansion from running into its neighbours when
+        // printed, as separate_expansion does. It starts with the
+        // whitespace before the macro name.
+        if let Some(first) = tokens.first_mut() {
+            first.space = start.space;
+            first.apart = true;
+        }
+        let rest = self.env.input.len();
+        self.unread(tokens);
+        if let Some(next) = self.env.input[..rest].last_mut() {
+            next.apart = true;
         }
+        Ok(())
     }
 
-    fn funclike_macro(&mut self, name: String) {
+    // There is nothing to scan again. If the macro was all there was
+    // between two tokens, e.g. an operand, the error is reported at the
+    // token after it, so that token remembers the macro. In an #if, the
+    // error is reported at the directive instead.
+    fn expanded_to_nothing(&mut self, start: &Token) {
+        let mut name = start.clone();
+        name.expansion = None;
+        if let Some(ref mut emptied) = self.emptied {
+            emptied.push(name.clone());
+        }
+        if let Some(next) = self.env.input.last_mut() {
+            next.space |= start.space;
+            next.apart = true;
+            next.after_empty = Some(Arc::new(name));
+        }
+    }
+
+    fn funclike_macro(&mut self, name: Symbol, at: Token) -> Result<(), LexError> {
         let mut params = vec![];
-        params.push(self.ident("parameter name expected"));
-        while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            params.push(self.ident("parameter name expected"));
+        let mut variadic = false;
+        // `#define F() ...` takes no arguments.
+        let mut done = self.consume(TokenType::RightParen);
+        while !done {
+            // `...` can only come last, and is named __VA_ARGS__.
+            if self.consume(TokenType::Ellipsis) {
+                params.push(Symbol::intern("__VA_ARGS__"));
+                variadic = true;
+                self.get(TokenType::RightParen, &at, "')' expected after '...'")?;
+                break;
+            }
+            params.push(self.ident(&at, "parameter name expected")?);
+            done = self.consume(TokenType::RightParen);
+            if !done {
+                self.get(TokenType::Comma, &at, "comma expected")?;
+            }
         }
 
-        let mut m = Macro::new(MacroType::Funclike(params));
-        m.tokens = self.read_until_eol();
+        let mut m = Macro::new(MacroType::Funclike(params, variadic), at);
+        m.tokens = self.read_body()?;
         m = m.replace_params();
-        self.macros.insert(name, m);
+        self.add_macro(name, m);
+        Ok(())
     }
 
-    fn objlike_macro(&mut self, name: String) {
-        let mut m = Macro::new(MacroType::Objlike);
-        m.tokens = self.read_until_eol();
-        self.macros.insert(name, m);
+    fn objlike_macro(&mut self, name: Symbol, at: Token) -> Result<(), LexError> {
+        let mut m = Macro::new(MacroType::Objlike, at);
+        m.tokens = self.read_body()?;
+        self.add_macro(name, m);
+        Ok(())
     }
 
-    fn define(&mut self) {
-        let name = self.ident("macro name expected");
-        if self.consume(TokenType::LeftParen) {
-            return self.funclike_macro(name);
+    // The replacement list of a #define.
+    fn read_body(&mut self) -> Result<Vec<Token>, LexError> {
+        let tokens = self.read_until_eol();
+        check_body(&tokens)?;
+        Ok(tokens)
+    }
+
+    // `#define F(x)` takes an argument, but in `#define P (x)` the '('
+    // starts the replacement list.
+    fn define_directive(&mut self, hash: &Token) -> Result<(), LexError> {
+        let at = self.peek().cloned().unwrap_or_else(|| hash.clone());
+        let name = self.ident(hash, "macro name expected")?;
+        if self.peek().is_some_and(|t| !t.space) && self.consume(TokenType::LeftParen) {
+            return self.funclike_macro(name, at);
         }
-        self.objlike_macro(name);
+        self.objlike_macro(name, at)
     }
 
-    fn include(&mut self) {
-        let path = self.ident("string expected");
-        let t = self.next().expect("newline expected");
-        if t.ty != TokenType::NewLine {
-            t.bad_token("newline expected");
+    // Reads the newline that ends a directive.
+    fn expect_eol(&mut self, directive: &str) -> Result<(), LexError> {
+        match self.next() {
+            Some(t) if t.ty != TokenType::NewLine => error_at(
+                &t,
+                &format!("extra tokens at end of #{} directive", directive),
+            ),
+            _ => Ok(()),
         }
-        let mut v = tokenize(path, self);
-        self.env.output.append(&mut v);
     }
 
-    fn preprocess_impl(&mut self, tokens: Vec<Token>) -> Vec<Token> {
-        self.env = Box::new(Env::new(tokens, Some(self.env.clone())));
+    // Forgetting a macro that was never defined is fine.
+    fn undef(&mut self, hash: &Token) -> Result<(), LexError> {
+        let name = self.ident(hash, "macro name expected")?;
+        self.expect_eol("undef")?;
+        self.remove_macro(name);
+        Ok(())
+    }
 
-        while !self.eof() {
-            let t = self.next().unwrap();
-            let macro_name;
-            if let TokenType::Ident(ref name) = t.ty {
-                macro_name = Some(name.clone());
-            } else {
-                macro_name = None;
-            }
-            if let Some(name) = macro_name {
-                if let Some(m) = self.macros.get(&name).cloned() {
-                    self.apply(m, &t);
-                } else {
-                    self.env.output.push(t);
+    fn is_defined(&self, name: Symbol) -> bool {
+        self.macros.contains_key(&name) || self.specials.contains(&name)
+    }
+
+    // #ifdef, or #ifndef if `defined` is false.
+    fn ifdef(&mut self, hash: Token, defined: bool) -> Result<(), LexError> {
+        let name = self.ident(&hash, "macro name expected")?;
+        self.expect_eol(if defined { "ifdef" } else { "ifndef" })?;
+        let taken = self.is_defined(name) == defined;
+        self.env.conds.push(Cond::new(hash, taken));
+        if !taken {
+            self.skip_cond();
+        }
+        Ok(())
+    }
+
+    // `defined X` and `defined(X)` become 1 or 0. This has to happen
+    // before macros are expanded, or X would be replaced.
+    fn replace_defined(&self, tokens: Vec<Token>) -> Result<Vec<Token>, LexError> {
+        let mut v = vec![];
+        let mut it = tokens.into_iter();
+        while let Some(t) = it.next() {
+            if !t.is_ident("defined") {
+                v.push(t);
+                continue;
+            }
+            let msg = "macro name expected";
+            let mut name = match it.next() {
+                Some(name) => name,
+                None => return error_at(&t, msg),
+            };
+            let paren = name.ty == TokenType::LeftParen;
+            if paren {
+                name = match it.next() {
+                    Some(name) => name,
+                    None => return error_at(&t, msg),
+                };
+            }
+            let defined = match name.ty {
+                TokenType::Ident(name) => self.is_defined(name),
+                _ => return error_at(&name, msg),
+            };
+            if paren && !it.next().is_some_and(|t| t.ty == TokenType::RightParen) {
+                return error_at(&name, "')' expected after defined");
+            }
+            let mut val = Token::at(TokenType::Num(defined as i64, false, false), &t);
+            val.space = t.space;
+            v.push(val);
+        }
+        Ok(v)
+    }
+
+    // Reads and evaluates the rest of an #if or #elif line.
+    fn cond_expr(&mut self, hash: &Token, directive: &str) -> Result<bool, LexError> {
+        let line = self.read_until_eol();
+        let line = self.replace_defined(line)?;
+        self.emptied = Some(vec![]);
+        let line = self.preprocess_impl(Env {
+            in_cond: true,
+            ..Env::new(line)
+        });
+        let emptied = self.emptied.take().unwrap();
+        // A macro that expands to `defined X` is undefined behavior, but
+        // gcc evaluates it as if it had been written out, and so do we.
+        let line = self.replace_defined(line?)?;
+        Ok(CondExpr::new(line, hash, directive, emptied).eval()? != 0)
+    }
+
+    fn if_(&mut self, hash: Token) -> Result<(), LexError> {
+        let taken = self.cond_expr(&hash, "if")?;
+        self.env.conds.push(Cond::new(hash, taken));
+        if !taken {
+            self.skip_cond();
+        }
+        Ok(())
+    }
+
+    // The group whose #elif, #else or #endif is at `hash`.
+    fn current_cond(&mut self, hash: &Token, directive: &str) -> Result<&mut Cond, LexError> {
+        match self.env.conds.last_mut() {
+            Some(cond) => Ok(cond),
+            None => error_at(hash, &format!("#{} without #if", directive)),
+        }
+    }
+
+    // Once a branch has been taken, the expressions of those after it
+    // are not even evaluated.
+    fn elif(&mut self, hash: &Token) -> Result<(), LexError> {
+        let cond = self.current_cond(hash, "elif")?;
+        if cond.in_else {
+            return error_at(hash, "#elif after #else");
+        }
+        if cond.taken {
+            self.read_until_eol();
+            self.skip_cond();
+            return Ok(());
+        }
+        let taken = self.cond_expr(hash, "elif")?;
+        if taken {
+            self.current_cond(hash, "elif")?.taken = true;
+        } else {
+            self.skip_cond();
+        }
+        Ok(())
+    }
+
+    fn else_(&mut self, hash: &Token) -> Result<(), LexError> {
+        let cond = self.current_cond(hash, "else")?;
+        if cond.in_else {
+            return error_at(hash, "#else after #else");
+        }
+        cond.in_else = true;
+        let skip = mem::replace(&mut cond.taken, true);
+        self.expect_eol("else")?;
+        if skip {
+            self.skip_cond();
+        }
+        Ok(())
+    }
+
+    fn endif(&mut self, hash: &Token) -> Result<(), LexError> {
+        self.current_cond(hash, "endif")?;
+        self.env.conds.pop();
+        self.expect_eol("endif")
+    }
+
+    // Skips a branch that is not taken, without expanding or including
+    // anything, up to the #elif, #else or #endif that ends it. Groups
+    // nested in it are skipped whole.
+    fn skip_cond(&mut self) {
+        let mut depth = 0;
+        let mut bol = true;
+        while let Some(t) = self.peek() {
+            if bol && t.ty == TokenType::HashMark {
+                match self
+                    .env
+                    .input
+                    .iter()
+                    .rev()
+                    .nth(1)
+                    .and_then(directive_name)
+                    .as_deref()
+                {
+                    Some("if") | Some("ifdef") | Some("ifndef") => depth += 1,
+                    Some("elif") | Some("else") | Some("endif") if depth == 0 => return,
+                    Some("endif") => depth -= 1,
+                    _ => (),
                 }
+            }
+            bol = t.ty == TokenType::NewLine;
+            self.env.input.pop();
+        }
+    }
+
+    fn include(&mut self, hash: &Token) -> Result<(), LexError> {
+        // A header name is not a string literal; backslashes in it are
+        // path separators, not escapes, so use the raw spelling.
+        let msg = "\"filename\" or <filename> expected";
+        let name = match self.next() {
+            Some(name) => name,
+            None => return error_at(hash, msg),
+        };
+        let (path, quoted) = match name.ty {
+            TokenType::Str(_, _) => {
+                let s = name.tokstr();
+                (s[1..s.len() - 1].to_string(), true)
+            }
+            TokenType::HeaderName(ref s) => (s.clone(), false),
+            _ => return error_at(&name, msg),
+        };
+        self.expect_eol("include")?;
+        let path = match self.find_include(&path, quoted, self.real_filename(&name)) {
+            Ok(path) => path,
+            Err(dirs) => {
+                let mut msg = format!("'{}' file not found", path);
+                if !dirs.is_empty() {
+                    msg += &format!(", searched: {}", dirs.join(", "));
+                }
+                return Err(LexError::at(&name, msg).report());
+            }
+        };
+        let canonical = self
+            .loader_for(Path::new(&path))
+            .canonicalize(Path::new(&path));
+        if self.once.contains(&canonical) {
+            return Ok(());
+        }
+
+        // Headers with include guards may include each other: the second
+        // time round, the guard leaves nothing to include. Without one,
+        // the same #include comes up again while it is still being read,
+        // and would forever after.
+        let again = self
+            .includes
+            .iter()
+            .position(|inc| inc.path == canonical && same_place(&inc.at, &name));
+        if let Some(i) = again {
+            return Err(LexError::at(&name, self.include_cycle(i)).report());
+        }
+        if self.includes.len() == MAX_INCLUDE_DEPTH {
+            let msg = format!("#include nested more than {} deep", MAX_INCLUDE_DEPTH);
+            return Err(LexError::at(&name, msg).report());
+        }
+
+        // Blame a file that can't be read on the #include naming it.
+        let input = self
+            .loader_for(Path::new(&path))
+            .read(Path::new(&path))
+            .map_err(|msg| LexError::at(&name, msg).report())?;
+        let tokens = scan_file(path.clone(), &input, self)?;
+        self.includes.push(Include {
+            path: canonical,
+            name: path,
+            at: name,
+        });
+        self.push_file(tokens);
+        Ok(())
+    }
+
+    // The message for an #include that goes round the cycle of files
+    // from includes[i] on once more, with where each includes the next:
+    //
+    //   #include cycle: a.h -> b.h -> a.h
+    //     a.h:1: includes b.h
+    //     b.h:1: includes a.h
+    fn include_cycle(&self, i: usize) -> String {
+        let cycle = &self.includes[i..];
+        let mut files = vec![cycle[0].at.filename.to_string()];
+        files.extend(cycle.iter().map(|inc| inc.name.clone()));
+        let mut msg = format!("#include cycle: {}", files.join(" -> "));
+        for inc in cycle {
+            msg += &format!(
+                "\n  {}:{}: includes {}",
+                inc.at.filename, inc.at.line, inc.name
+            );
+        }
+        msg
+    }
+
+    // Looks for an included file: next to the file including it if the
+    // name was quoted, then in the -I directories and the defaults.
+    // Returns the directories searched if it is in none of them.
+    fn find_include(&self, name: &str, quoted: bool, from: &str) -> Result<String, Vec<String>> {
+        let name = normalize_path(name);
+        if name.is_absolute() {
+            return match find_file(&*self.loader, &name) {
+                Some(path) => Ok(path.to_string_lossy().into_owned()),
+                None => Err(vec![]),
+            };
+        }
+
+        let mut dirs: Vec<String> = vec![];
+        if quoted {
+            let dir = Path::new(from)
+                .parent()
+                .map_or("", |p| p.to_str().unwrap_or(""));
+            dirs.push(if dir.is_empty() { "." } else { dir }.to_string());
+        }
+        let system = format!("{}{}", self.sysroot, SYSTEM_INCLUDE_DIR);
+        let rest = self.include_dirs.iter().map(String::as_str);
+        for dir in rest.chain([".", system.as_str()].iter().copied()) {
+            if !dirs.iter().any(|d| d == dir) {
+                dirs.push(dir.to_string());
+            }
+        }
+
+        for dir in &dirs {
+            let path = normalize_path(&format!("{}/{}", dir, name.display()));
+            if let Some(path) = find_file(&*self.loader, &path) {
+                return Ok(path.to_string_lossy().into_owned());
+            }
+        }
+
+        let path = Path::new(BUILTIN_DIR).join(&name);
+        if self.builtins.is_file(&path) {
+            return Ok(path.to_string_lossy().into_owned());
+        }
+        Err(dirs)
+    }
+
+    // #pragma once keeps the file it is in from being included again.
+    // Other pragmas are ignored.
+    fn pragma(&mut self, hash: &Token) {
+        let line = self.read_until_eol();
+        if let [t] = &line[..] {
+            if t.is_ident("once") {
+                let path = Path::new(self.real_filename(hash));
+                self.once.insert(self.loader_for(path).canonicalize(path));
+            }
+        }
+    }
+
+    // #line N, or #line N "file", says that the next line is line N, of
+    // "file" if given. Lines after it count on from there, in __LINE__
+    // and in diagnostics. Macros in the directive are expanded first.
+    fn line(&mut self, hash: &Token) -> Result<(), LexError> {
+        let mut line = vec![];
+        let eol = loop {
+            match self.next() {
+                Some(t) if t.ty == TokenType::NewLine => break Some(t),
+                Some(t) => line.push(t),
+                None => break None,
+            }
+        };
+        let line = self.preprocess_impl(Env::new(line))?;
+
+        let mut it = line.iter();
+        let n = match it.next() {
+            Some(t) => match t.ty {
+                TokenType::Num(n, false, false) if t.text().bytes().all(|c| c.is_ascii_digit()) => {
+                    if n == 0 || n > i32::MAX as i64 {
+                        return error_at(t, "line number out of range");
+                    }
+                    n as isize
+                }
+                _ => {
+                    let msg = format!("\"{}\" after #line is not a positive integer", t.text());
+                    return error_at(t, &msg);
+                }
+            },
+            None => return error_at(hash, "line number expected after #line"),
+        };
+        let filename = match it.next() {
+            Some(t) => match t.ty {
+                TokenType::Str(ref s, _) => {
+                    let bytes: Vec<u8> = s.chars().map(|c| c as u8).collect();
+                    Some(Arc::new(String::from_utf8_lossy(&bytes).into_owned()))
+                }
+                _ => return error_at(t, &format!("invalid filename \"{}\"", t.text())),
+            },
+            None => None,
+        };
+        if let Some(t) = it.next() {
+            return error_at(t, "extra tokens at end of #line directive");
+        }
+
+        let eol = match eol {
+            Some(eol) => eol,
+            None => return Ok(()),
+        };
+        let (delta, path) = match self.env.line.take() {
+            Some(mark) => (mark.delta, mark.path),
+            None => (0, hash.filename.clone()),
+        };
+        self.env.line = Some(LineMark {
+            delta: delta + n - (eol.line as isize + 1),
+            filename: filename.unwrap_or_else(|| hash.filename.clone()),
+            path,
+        });
+        Ok(())
+    }
+
+    fn preprocess_impl(&mut self, env: Env) -> Result<Vec<Token>, LexError> {
+        self.outer.push(mem::replace(&mut self.env, env));
+        let res = self.expand_all();
+
+        let env = mem::replace(&mut self.env, self.outer.pop().unwrap());
+        res.map(|()| env.output)
+    }
+
+    // Starts on the tokens of the main file, to be handed out by
+    // next_token.
+    pub fn start(&mut self, tokens: Vec<Token>) {
+        self.includes.clear();
+        self.push_file(tokens);
+    }
+
+    // Reads a file's tokens before the rest of the current one.
+    fn push_file(&mut self, tokens: Vec<Token>) {
+        self.ready.extend(self.env.output.drain(..));
+        let env = Env {
+            is_file: true,
+            ..Env::new(tokens)
+        };
+        self.outer.push(mem::replace(&mut self.env, env));
+    }
+
+    // The next preprocessed token, newlines included. Only as much of
+    // the input is read as it takes to make it. None at the end of the
+    // main file.
+    pub fn next_token(&mut self) -> Result<Option<Token>, LexError> {
+        loop {
+            if let Some(t) = self.ready.pop_front() {
+                return Ok(Some(t));
+            }
+            if !self.env.output.is_empty() {
+                self.ready.extend(self.env.output.drain(..));
                 continue;
             }
+            if !self.eof() {
+                self.expand_one()?;
+                continue;
+            }
+            if !self.env.is_file {
+                return Ok(None);
+            }
 
-            if t.ty != TokenType::HashMark {
+            // A group can't continue in the file that includes this one.
+            if let Some(cond) = self.env.conds.last() {
+                return error_at(&cond.at, "unterminated conditional directive");
+            }
+            self.env = self.outer.pop().unwrap();
+            if self.includes.pop().is_none() {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Expands the macros of env.input into env.output.
+    fn expand_all(&mut self) -> Result<(), LexError> {
+        while !self.eof() {
+            self.expand_one()?;
+        }
+        Ok(())
+    }
+
+    // Runs the directive or expands the macro at the start of env.input,
+    // or else moves one token to env.output.
+    fn expand_one(&mut self) -> Result<(), LexError> {
+        let t = self.next().unwrap();
+        let macro_name;
+        if let TokenType::Ident(name) = t.ty {
+            macro_name = Some(name);
+        } else {
+            macro_name = None;
+        }
+        if let Some(name) = macro_name {
+            // The operand of a `defined` that a macro produced in
+            // an #if is left for replace_defined to look up.
+            if self.env.in_cond && t.is_ident("defined") {
                 self.env.output.push(t);
-                continue;
+                if self.peek().map(|t| &t.ty) == Some(&TokenType::LeftParen) {
+                    let paren = self.next().unwrap();
+                    self.env.output.push(paren);
+                }
+                if let Some(t) = self.next() {
+                    self.env.output.push(t);
+                }
+                return Ok(());
+            }
+            let n = self.env.output.len();
+            if self.add_special_macro(&t, &t) {
+                self.separate_expansion(n);
+                return Ok(());
+            }
+            // A function-like macro name without arguments is just
+            // a name, e.g. in `(min)(a, b)`.
+            let m = self.macros.get(&name).cloned();
+            let m = m.filter(|m| match m.ty {
+                MacroType::Funclike(..) => self.paren_follows(),
+                MacroType::Objlike => true,
+            });
+            match m {
+                Some(ref m) if !is_hidden(&t, name) => self.apply(name, m, &t)?,
+                _ => self.env.output.push(t),
+            }
+            return Ok(());
+        }
+
+        // A '#' out of a macro, or in an argument or a directive's
+        // expansion, doesn't start a directive.
+        if t.ty != TokenType::HashMark || t.expansion.is_some() || !self.env.is_file {
+            self.env.output.push(t);
+            return Ok(());
+        }
+
+        let name = match self.next() {
+            Some(name) => name,
+            None => return error_at(&t, "identifier expected"),
+        };
+        match directive_name(&name).as_deref() {
+            Some("define") => self.define_directive(&t),
+            Some("undef") => self.undef(&t),
+            Some("include") => self.include(&t),
+            Some("if") => self.if_(t),
+            Some("elif") => self.elif(&t),
+            Some("else") => self.else_(&t),
+            Some("ifdef") => self.ifdef(t, true),
+            Some("ifndef") => self.ifdef(t, false),
+            Some("endif") => self.endif(&t),
+            Some("pragma") => {
+                self.pragma(&t);
+                Ok(())
+            }
+            Some("line") => self.line(&t),
+            Some(_) => error_at(&t, "unknown directive"),
+            None => error_at(&name, "identifier expected"),
+        }
+    }
+}
+
+// The controlling expression of an #if, after macro expansion. It is
+// evaluated in i64, and identifiers that are left are 0. Errors are
+// reported at the directive, since the tokens may come from a macro.
+struct CondExpr<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    at: &'a Token,
+    directive: &'a str,  // "if" or "elif"
+    emptied: Vec<Token>, // macros in it that expanded to nothing, noted in errors
+}
+
+impl<'a> CondExpr<'a> {
+    fn new(tokens: Vec<Token>, at: &'a Token, directive: &'a str, emptied: Vec<Token>) -> Self {
+        CondExpr {
+            tokens,
+            pos: 0,
+            at,
+            directive,
+            emptied,
+        }
+    }
+
+    fn eval(mut self) -> Result<i64, LexError> {
+        if self.tokens.is_empty() {
+            let msg = format!("#{} with no expression", self.directive);
+            return Err(self.fail(&msg));
+        }
+        let val = self.expr(true)?;
+        if self.pos < self.tokens.len() {
+            return Err(self.error("missing binary operator"));
+        }
+        Ok(val)
+    }
+
+    fn error(&self, msg: &str) -> LexError {
+        self.fail(&format!("{} in #{}", msg, self.directive))
+    }
+
+    fn fail(&self, msg: &str) -> LexError {
+        let notes: String = self.emptied.iter().map(Token::empty_note).collect();
+        LexError::at(self.at, format!("{}{}", msg, notes)).report()
+    }
+
+    fn consume(&mut self, ty: TokenType) -> bool {
+        if self.tokens.get(self.pos).is_some_and(|t| t.ty == ty) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    // Operands that are not evaluated, e.g. the right of `0 && x`, are
+    // read with `live` false so that dividing by zero there is fine.
+    fn expr(&mut self, live: bool) -> Result<i64, LexError> {
+        let cond = self.binary(1, live)?;
+        if !self.consume(TokenType::Question) {
+            return Ok(cond);
+        }
+        let then = self.expr(live && cond != 0)?;
+        if !self.consume(TokenType::Colon) {
+            return Err(self.error("':' expected"));
+        }
+        let els = self.expr(live && cond == 0)?;
+        Ok(if cond != 0 { then } else { els })
+    }
+
+    // Operators of precedence `min` and tighter.
+    fn binary(&mut self, min: u8, live: bool) -> Result<i64, LexError> {
+        let mut lhs = self.unary(live)?;
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(t) => t.ty.clone(),
+                None => return Ok(lhs),
+            };
+            let prec = match binop_prec(&op) {
+                Some(prec) if prec >= min => prec,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs_live = match op {
+                TokenType::Logand => live && lhs != 0,
+                TokenType::Logor => live && lhs == 0,
+                _ => live,
+            };
+            let rhs = self.binary(prec + 1, rhs_live)?;
+            lhs = self.binop(&op, lhs, rhs, live)?;
+        }
+    }
+
+    fn binop(&self, op: &TokenType, lhs: i64, rhs: i64, live: bool) -> Result<i64, LexError> {
+        use self::TokenType::*;
+        Ok(match op {
+            Div | Mod if rhs == 0 => {
+                if live {
+                    return Err(self.error("division by zero"));
+                }
+                0
             }
+            Div => lhs.wrapping_div(rhs),
+            Mod => lhs.wrapping_rem(rhs),
+            Mul => lhs.wrapping_mul(rhs),
+            Plus => lhs.wrapping_add(rhs),
+            Minus => lhs.wrapping_sub(rhs),
+            SHL => lhs.wrapping_shl(rhs as u32),
+            SHR => lhs.wrapping_shr(rhs as u32),
+            LeftAngleBracket => (lhs < rhs) as i64,
+            RightAngleBracket => (lhs > rhs) as i64,
+            LE => (lhs <= rhs) as i64,
+            GE => (lhs >= rhs) as i64,
+            EQ => (lhs == rhs) as i64,
+            NE => (lhs != rhs) as i64,
+            And => lhs & rhs,
+            Hat => lhs ^ rhs,
+            VerticalBar => lhs | rhs,
+            Logand => (lhs != 0 && rhs != 0) as i64,
+            Logor => (lhs != 0 || rhs != 0) as i64,
+            _ => unreachable!(),
+        })
+    }
+
+    fn unary(&mut self, live: bool) -> Result<i64, LexError> {
+        if self.consume(TokenType::Exclamation) {
+            return Ok((self.unary(live)? == 0) as i64);
+        }
+        if self.consume(TokenType::Minus) {
+            return Ok(self.unary(live)?.wrapping_neg());
+        }
+        if self.consume(TokenType::Plus) {
+            return self.unary(live);
+        }
+        self.primary(live)
+    }
 
-            let ident = self.ident("identifier expected");
-            if &*ident == "define" {
-                self.define();
-            } else if &*ident == "include" {
-                self.include();
-            } else {
-                t.bad_token("unknown directive");
+    fn primary(&mut self, live: bool) -> Result<i64, LexError> {
+        if self.consume(TokenType::LeftParen) {
+            let val = self.expr(live)?;
+            if !self.consume(TokenType::RightParen) {
+                return Err(self.error("')' expected"));
             }
+            return Ok(val);
+        }
+        let val = match self.tokens.get(self.pos).map(|t| &t.ty) {
+            Some(TokenType::Num(val, ..)) => *val,
+            Some(TokenType::Ident(_)) => 0,
+            _ => return Err(self.error("expected value")),
+        };
+        self.pos += 1;
+        Ok(val)
+    }
+}
+
+// A ## in a replacement list needs a token on each side.
+fn check_body(tokens: &[Token]) -> Result<(), LexError> {
+    for t in [tokens.first(), tokens.last()].iter().flatten() {
+        if t.ty == TokenType::HashHash {
+            return error_at(t, "'##' cannot appear at either end of a macro expansion");
         }
+    }
+    Ok(())
+}
+
+// The name after a '#', which may be a keyword, as in #if and #else.
+fn directive_name(t: &Token) -> Option<Arc<str>> {
+    match t.ty {
+        TokenType::Ident(name) => Some(name.as_str()),
+        TokenType::If => Some("if".into()),
+        TokenType::Else => Some("else".into()),
+        _ => None,
+    }
+}
+
+// Whether tokens[i] is the operand of a `defined`, which in an #if
+// takes a macro argument as written.
+fn after_defined(tokens: &[Token], i: usize) -> bool {
+    let before = |k: usize| i.checked_sub(k).map(|j| &tokens[j]);
+    before(1).is_some_and(|t| t.is_ident("defined"))
+        || (before(1).is_some_and(|t| t.ty == TokenType::LeftParen)
+            && before(2).is_some_and(|t| t.is_ident("defined")))
+}
+
+// The token a macro name is taken to be at: the name itself, or if it
+// came out of another macro, the name that started the outermost one.
+fn origin(t: &Token) -> &Token {
+    t.expansion.as_ref().map_or(t, |e| &e.start)
+}
+
+// Whether the token came out of the macro `name`, and so can't expand it.
+fn is_hidden(t: &Token, name: Symbol) -> bool {
+    t.expansion
+        .as_ref()
+        .is_some_and(|e| e.hideset.contains(&name))
+}
+
+// Whether two tokens were read from the same place in the same file.
+fn same_place(a: &Token, b: &Token) -> bool {
+    a.filename == b.filename && a.line == b.line && a.col == b.col
+}
+
+// "file:line:col" of a token, for --trace-macro
+fn location(t: &Token) -> String {
+    format!("{}:{}:{}", t.filename, t.line, t.col)
+}
 
-        let mut output = vec![];
-        mem::swap(&mut self.env.output, &mut output);
-        self.env = self.env.next.take().unwrap();
-        output
+// Builtins and -D have no line to point at.
+fn def_location(t: &Token) -> String {
+    if t.line == 0 {
+        return t.filename.to_string();
     }
+    location(t)
 }
@@ -1,8 +1,7 @@
-use crate::gen_ir::{Function, IROp, IRType, IR};
-use crate::irdump::IRInfo;
+use crate::gen_ir::{Function, IROp, Reg};
 use crate::REGS_N;
 
-use std::sync::Mutex;
+use std::collections::HashMap;
 
 // Quoted from 9cc
 // > Register allocator.
@@ -16,92 +15,140 @@ use std::sync::Mutex;
 // > This design choice simplifies the implementation a lot, since
 // > practically we don't have to think about the case in which
 // > registers are exhausted and need to be spilled to memory.
-
-lazy_static! {
-    static ref USED: Mutex<[bool; REGS_N]> = Mutex::new([false; REGS_N]);
-    static ref REG_MAP: Mutex<[Option<usize>; 8192]> = Mutex::new([None; 8192]);
-}
-
-fn used_get(i: usize) -> bool {
-    USED.lock().unwrap()[i]
+//
+// An expression can still want more than 7 registers at once, as
+// `a+(a+(a+...))` nested deep enough does. Then the register that has
+// been held longest is spilled and the function allocated again. A
+// spilled register lives in a frame slot for the whole function: each
+// instruction that uses it loads it into a free register first and, if
+// it writes it, stores it back after. As the slot holds the value on
+// every path, jumps within the expression (`&&`, `?:`, statement
+// expressions) need no care.
+
+// Where each virtual register is while the instructions are visited.
+struct Alloc<'a> {
+    spilled: &'a HashMap<Reg, usize>, // virtual register -> spill slot
+    map: HashMap<Reg, Reg>,
+    held: [Option<(Reg, usize)>; REGS_N], // virtual register, since when
 }
 
-fn used_set(i: usize, val: bool) {
-    USED.lock().unwrap()[i] = val;
-}
+impl<'a> Alloc<'a> {
+    fn free(&self) -> Option<Reg> {
+        (0..REGS_N).find(|&r| self.held[r].is_none())
+    }
 
-fn reg_map_get(i: usize) -> Option<usize> {
-    REG_MAP.lock().unwrap().get(i).cloned().unwrap()
+    // The register held longest by something `ir` does not use.
+    fn victim(&self, ir: &[Reg]) -> Reg {
+        self.held
+            .iter()
+            .flatten()
+            .filter(|(v, _)| !ir.contains(v))
+            .min_by_key(|&&(_, since)| since)
+            .map(|&(v, _)| v)
+            .unwrap_or_else(|| panic!("register exhausted: {} live in one instruction", ir.len()))
+    }
 }
 
-fn reg_map_set(i: usize, val: usize) {
-    REG_MAP.lock().unwrap()[i] = Some(val);
+// Whether an instruction writes its first register operand.
+fn writes_dst(ir: &IROp) -> bool {
+    use self::IROp::*;
+    !matches!(
+        ir,
+        Store { .. }
+            | FStore { .. }
+            | Return { .. }
+            | If { .. }
+            | Unless { .. }
+            | Memcpy { .. }
+            | Memset { .. }
+            | Kill(_)
+            | StoreSpill { .. }
+            | VaStart { .. }
+    )
 }
 
-fn alloc(ir_reg: usize) -> usize {
-    if REG_MAP.lock().unwrap().len() <= ir_reg {
-        panic!("program too big");
-    }
-
-    if let Some(r) = reg_map_get(ir_reg) {
-        assert!(used_get(r));
-        return r;
-    }
-
-    for i in 0..REGS_N {
-        if used_get(i) {
+// Maps the registers of `irv`, or returns a register to spill if they
+// do not fit.
+fn visit(irv: &[IROp], spilled: &HashMap<Reg, usize>) -> Result<Vec<IROp>, Reg> {
+    let mut a = Alloc {
+        spilled,
+        map: HashMap::new(),
+        held: [None; REGS_N],
+    };
+    let mut out = vec![];
+    for (index, ir) in irv.iter().enumerate() {
+        if let IROp::Kill(v) = *ir {
+            if let Some(r) = a.map.remove(&v) {
+                a.held[r] = None;
+            }
+            out.push(IROp::Nop);
             continue;
         }
-        reg_map_set(ir_reg, i);
-        used_set(i, true);
-        return i;
-    }
-    panic!("register exhauseted: {}", ir_reg);
-}
 
-fn visit(irv: &mut Vec<IR>) {
-    use self::IRType::*;
+        let mut regs = vec![];
+        ir.clone().for_each_reg(|v| regs.push(*v));
 
-    for item in irv {
-        let mut ir = item.clone();
-        let info = &IRInfo::from(&ir.op);
-
-        match info.ty {
-            Reg | RegImm | RegLabel | LabelAddr => ir.lhs = Some(alloc(ir.lhs.unwrap())),
-            Mem | RegReg => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                ir.rhs = Some(alloc(ir.rhs.unwrap()));
+        // Spilled registers get a register for this instruction only.
+        let mut temps: Vec<(Reg, Reg)> = vec![];
+        for &v in &regs {
+            if a.map.contains_key(&v) || temps.iter().any(|&(t, _)| t == v) {
+                continue;
             }
-            Call => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                match ir.op {
-                    IROp::Call(name, nargs, args) => {
-                        let mut args_new: [usize; 6] = [0; 6];
-                        for i in 0..nargs {
-                            args_new[i] = alloc(args[i]);
-                        }
-                        ir.op = IROp::Call(name, nargs, args_new);
-                    }
-                    _ => unreachable!(),
-                }
+            let r = match a.free() {
+                Some(r) => r,
+                None => return Err(a.victim(&regs)),
+            };
+            if spilled.contains_key(&v) {
+                temps.push((v, r));
+            } else {
+                a.map.insert(v, r);
             }
-            _ => (),
+            a.held[r] = Some((v, index));
         }
 
-        if ir.op == IROp::Kill {
-            let lhs = ir.lhs.unwrap();
-            assert!(used_get(lhs));
-            used_set(lhs, false);
-            ir.op = IROp::Nop;
+        let phys = |v: Reg| {
+            a.map
+                .get(&v)
+                .or_else(|| temps.iter().find(|&&(t, _)| t == v).map(|(_, r)| r))
+                .cloned()
+                .unwrap()
+        };
+        for &(v, r) in &temps {
+            out.push(IROp::LoadSpill {
+                dst: r,
+                slot: a.spilled[&v],
+            });
+        }
+        let mut new = ir.clone();
+        new.for_each_reg(|v| *v = phys(*v));
+        out.push(new);
+        if writes_dst(ir) {
+            if let Some(&(v, r)) = temps.iter().find(|&&(t, _)| Some(&t) == regs.first()) {
+                out.push(IROp::StoreSpill {
+                    slot: a.spilled[&v],
+                    src: r,
+                });
+            }
+        }
+        for (_, r) in temps {
+            a.held[r] = None;
         }
-        *item = ir;
     }
+    Ok(out)
 }
 
 pub fn alloc_regs(fns: &mut Vec<Function>) {
     for f in fns {
-        *USED.lock().unwrap() = [false; REGS_N];
-
-        visit(&mut f.ir);
+        let mut spilled = HashMap::new();
+        f.ir = loop {
+            match visit(&f.ir, &spilled) {
+                Ok(ir) => break ir,
+                Err(v) => {
+                    let slot = spilled.len();
+                    spilled.insert(v, slot);
+                }
+            }
+        };
+        f.spills = spilled.len();
     }
 }
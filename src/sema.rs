@@ -1,11 +1,14 @@
+use crate::diag;
+use crate::mangle;
 use crate::matches;
-use crate::parse::{Node, NodeType};
+use crate::parse::{const_value, Node, NodeType};
+use crate::symbol::Symbol;
+use crate::token::{error_at, LexError};
 use crate::util::roundup;
 use crate::{Ctype, Scope, TokenType, Type, Var};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
-use std::sync::Mutex;
 
 // Quoted from 9cc
 // > Semantics analyzer. This pass plays a few important roles as shown
@@ -32,16 +35,28 @@ fn swap(p: &mut Node, q: &mut Node) {
     mem::swap(p, q);
 }
 
-lazy_static! {
-    static ref GLOBALS: Mutex<Vec<Var>> = Mutex::new(vec![]);
-    static ref ENV: Mutex<Env> = Mutex::new(Env::new(None));
-    static ref STRLABEL: Mutex<usize> = Mutex::new(0);
-    static ref STACKSIZE: Mutex<usize> = Mutex::new(0);
+// A translation unit after sema.
+pub struct Program {
+    pub nodes: Vec<Node>,
+    pub globals: Vec<Var>,
+    // Functions whose calls never return: those declared _Noreturn,
+    // and the library functions known not to.
+    pub noreturn: HashSet<Symbol>,
+}
+
+// The state of analyzing one translation unit.
+struct Sema {
+    globals: Vec<Var>,
+    env: Env,
+    strlabel: usize,
+    stacksize: usize,
+    return_ty: Option<Type>, // what `return` converts its value to
+    noreturn: HashSet<Symbol>,
 }
 
 #[derive(Debug, Clone)]
 struct Env {
-    vars: HashMap<String, Var>,
+    vars: HashMap<Symbol, Var>,
     next: Option<Box<Env>>,
 }
 
@@ -54,37 +69,19 @@ impl Env {
     }
 }
 
-fn into_new_range<T: Sized>(param: T, f: Box<dyn Fn(T) -> T>) -> T {
-    let env = ENV.lock().unwrap().clone();
-    *ENV.lock().unwrap() = Env::new(Some(Box::new(env)));
-    let ret = f(param);
-    // Rollback
-    let env = ENV.lock().unwrap().clone();
-    *ENV.lock().unwrap() = *env.next.unwrap();
-    ret
-}
-
-fn find_var(name: &str) -> Option<Var> {
-    let env = ENV.lock().unwrap().clone();
-    let mut next: &Option<Box<Env>> = &Some(Box::new(env));
-    loop {
-        if let Some(ref e) = next {
-            let var = e.vars.get(name);
-            if var.is_some() {
-                return var.cloned();
-            }
-            next = &e.next;
-        } else {
-            return None;
-        }
-    }
-}
-
 fn maybe_decay(base: Node, decay: bool) -> Node {
     if !decay {
         return base;
     }
 
+    // A function name stands for its address.
+    if let Ctype::Func(..) = base.ty.ty {
+        let ty = Box::new(Type::ptr_to(base.ty.clone()));
+        let mut node = Node::new(NodeType::Addr(Box::new(base)));
+        node.ty = ty;
+        return node;
+    }
+
     if let Ctype::Ary(ary_of, _) = base.ty.ty.clone() {
         let mut node = Node::new(NodeType::Addr(Box::new(base)));
         node.ty = Box::new(Type::ptr_to(ary_of.clone()));
@@ -94,307 +91,777 @@ fn maybe_decay(base: Node, decay: bool) -> Node {
     }
 }
 
-fn check_lval(node: &Node) {
+// Reports an error at the node's token, if it has one, to be returned.
+fn error<T>(node: &Node, msg: &str) -> Result<T, LexError> {
+    match node.token {
+        Some(ref t) => error_at(t, msg),
+        None => Err(LexError::new(msg.to_string(), "").report()),
+    }
+}
+
+fn check_lval(node: &Node) -> Result<(), LexError> {
     let op = &node.op;
     if !matches!(op, NodeType::Lvar(_))
         && !matches!(op, NodeType::Gvar(_, _, _))
         && !matches!(op, NodeType::Deref(_))
         && !matches!(op, NodeType::Dot(_, _, _))
     {
-        panic!("not an lvalue: {:?}", node.op);
+        return error(node, "lvalue required");
     }
+    Ok(())
 }
 
-fn walk(mut node: Node, decay: bool) -> Node {
-    use self::NodeType::*;
-    let op = node.op.clone();
-    match op {
-        Num(_) | Null | Break => (),
-        Str(data, len) => {
-            // Quoted from 9cc
-            // > A string literal is converted to a reference to an anonymous
-            // > global variable of type char array.
-            let name = format!(".L.str{}", *STRLABEL.lock().unwrap());
-            *STRLABEL.lock().unwrap() += 1;
-            let var = Var::new_global(node.ty.clone(), name, data, len, false);
-            let name = var.name.clone();
-            GLOBALS.lock().unwrap().push(var);
-
-            let mut ret = Node::new(NodeType::Gvar(name, "".into(), len));
-            ret.ty = node.ty;
-            return maybe_decay(ret, decay);
+// Arrays and functions are lvalues, but they cannot be assigned to.
+fn check_assignable(node: &Node) -> Result<(), LexError> {
+    check_lval(node)?;
+    match node.ty.ty {
+        Ctype::Ary(_, _) => error(node, &format!("array type '{}' is not assignable", node.ty)),
+        Ctype::Func(..) => error(
+            node,
+            &format!("function type '{}' is not assignable", node.ty),
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Warns about `if (x = y)`, which is likely a typo for `==`.
+fn check_condition(cond: &Node) {
+    if let NodeType::BinOp(TokenType::Equal, _, _) = cond.op {
+        if !cond.parenthesized {
+            diag::warning_named(
+                "parentheses",
+                &format!(
+                    "{}suggest parentheses around assignment used as truth value",
+                    cond.location()
+                ),
+            );
         }
-        Ident(ref name) => {
-            if let Some(var) = find_var(name) {
-                match var.scope {
-                    Scope::Local(offset) => {
-                        let mut ret = Node::new(NodeType::Lvar(Scope::Local(offset)));
-                        ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
-                    }
-                    Scope::Global(ref data, len, _) => {
-                        let mut ret =
-                            Node::new(NodeType::Gvar(var.name.clone(), data.clone(), len));
-                        ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
-                    }
+    }
+}
+
+// The usual arithmetic conversions, for the types this compiler has:
+// double, float, long and int, in that order of precedence. The integer
+// result is unsigned if an operand of its size is, as a long holds any
+// unsigned int.
+fn arith_ty(lhs: &Type, rhs: &Type) -> Box<Type> {
+    let either = |f: fn(&Ctype) -> bool| f(&lhs.ty) || f(&rhs.ty);
+    let ty = if either(|ty| matches!(ty, Ctype::Double)) {
+        return Box::new(Type::double_ty());
+    } else if either(|ty| matches!(ty, Ctype::Float)) {
+        return Box::new(Type::float_ty());
+    } else if either(|ty| matches!(ty, Ctype::Long)) {
+        Type::long_ty()
+    } else {
+        Type::int_ty()
+    };
+    if [lhs, rhs].iter().any(|t| t.unsigned && t.size == ty.size) {
+        Box::new(ty.to_unsigned())
+    } else {
+        Box::new(ty)
+    }
+}
+
+fn is_arith(ty: &Type) -> bool {
+    matches!(
+        ty.ty,
+        Ctype::Int | Ctype::Long | Ctype::Char | Ctype::Float | Ctype::Double
+    )
+}
+
+// Makes a conversion between integer and floating point explicit.
+// Integer types need none, as stores truncate and loads extend, except
+// that an unsigned int must have its upper half cleared. Conversions to
+// unsigned types are made explicit all the same, so that an operand says
+// it is compared unsigned.
+fn convert(node: Node, ty: &Type) -> Node {
+    let to_unsigned = ty.unsigned && !(node.ty.unsigned && node.ty.size == ty.size);
+    if node.ty.is_float() == ty.is_float()
+        && (!ty.is_float() || node.ty.size == ty.size)
+        && !to_unsigned
+    {
+        return node;
+    }
+    let mut cast = Node::new(NodeType::Cast(Box::new(node)));
+    cast.ty = Box::new(ty.clone());
+    cast
+}
+
+// Brings both operands to their common floating point or unsigned
+// type, if either is one.
+fn convert_operands(lhs: Box<Node>, rhs: Box<Node>) -> (Box<Node>, Box<Node>) {
+    let ty = arith_ty(&lhs.ty, &rhs.ty);
+    if !ty.is_float() && !ty.unsigned {
+        return (lhs, rhs);
+    }
+    (Box::new(convert(*lhs, &ty)), Box::new(convert(*rhs, &ty)))
+}
+
+// Shifts, remainders and bitwise operators take integers only.
+fn check_integer_op(node: &Node, op: &TokenType, lhs: &Node, rhs: &Node) -> Result<(), LexError> {
+    use self::TokenType::*;
+    if !lhs.ty.is_float() && !rhs.ty.is_float() {
+        return Ok(());
+    }
+    if let Mod | ModEQ | And | BitandEQ | Hat | XorEQ | VerticalBar | BitorEQ | SHL | ShlEQ | SHR
+    | ShrEQ = op
+    {
+        return error(
+            node,
+            &format!(
+                "invalid operands to binary expression ('{}' and '{}')",
+                lhs.ty, rhs.ty
+            ),
+        );
+    }
+    Ok(())
+}
+
+// Warns about `a[n]` or `*(a + n)` with a constant n outside an array
+// whose length is known. `&a[n]` reads nothing, so n may also be the
+// length there: that is the address one past the end.
+fn check_bounds(deref: &Node, ptr: &Node, past_end_ok: bool) {
+    let (minus, base, offset) = match ptr.op {
+        NodeType::BinOp(ref op, ref base, ref offset)
+            if *op == TokenType::Plus || *op == TokenType::Minus =>
+        {
+            (*op == TokenType::Minus, base, offset)
+        }
+        _ => return,
+    };
+    // An array operand has decayed to its address.
+    let len = match base.op {
+        NodeType::Addr(ref ary) => match ary.ty.ty {
+            Ctype::Ary(_, Some(len)) => len as i64,
+            _ => return,
+        },
+        _ => return,
+    };
+    // and the offset has been scaled by the element size.
+    let index = match offset.op {
+        NodeType::BinOp(TokenType::Mul, ref index, _) => const_value(index, &|_| None),
+        _ => None,
+    };
+    let index = match index {
+        Some(index) if minus => -index,
+        Some(index) => index,
+        None => return,
+    };
+
+    let end = if past_end_ok { len } else { len - 1 };
+    let place = if index < 0 {
+        "before the beginning of"
+    } else if index > end {
+        "past the end of"
+    } else {
+        return;
+    };
+    diag::warning_named(
+        "array-bounds",
+        &format!(
+            "{}array index {} is {} the array, which has {} elements",
+            deref.location(),
+            index,
+            place,
+            len
+        ),
+    );
+}
+
+// The type a call returns, after checking the number of arguments
+// against the prototype of `func`, if it has one. Only a prototype says
+// how many arguments to expect, and `...` lets any number follow the
+// named ones.
+fn call_type(node: &Node, what: &str, func: &Type, args: &[Node]) -> Result<Box<Type>, LexError> {
+    match func.ty {
+        Ctype::Func(ref returning, ref params, variadic, _) => {
+            if let Some(params) = params {
+                if args.len() > params.len() && !variadic {
+                    return error(node, &format!("too many arguments to {}", what));
+                }
+                if args.len() < params.len() {
+                    return error(node, &format!("too few arguments to {}", what));
                 }
-            } else {
-                panic!("undefined variable: {}", name);
             }
+            Ok(returning.clone())
         }
-        Vardef(name, init_may, _) => {
-            let stacksize = *STACKSIZE.lock().unwrap();
-            *STACKSIZE.lock().unwrap() = roundup(stacksize, node.ty.align);
-            *STACKSIZE.lock().unwrap() += node.ty.size;
-            let offset = *STACKSIZE.lock().unwrap();
-
-            ENV.lock().unwrap().vars.insert(
-                name.clone(),
-                Var::new(node.ty.clone(), name.clone(), Scope::Local(offset)),
-            );
+        _ => unreachable!(),
+    }
+}
 
-            let mut init = None;
-            if let Some(init2) = init_may {
-                init = Some(Box::new(walk(*init2, true)));
-            }
-            node.op = Vardef(name, init, Scope::Local(offset));
+// Whether two declarations of a global agree on its type. An array
+// without a length agrees with one of any length.
+fn compatible(a: &Type, b: &Type) -> bool {
+    match (&a.ty, &b.ty) {
+        (Ctype::Ary(a_of, a_len), Ctype::Ary(b_of, b_len)) => {
+            (a_len.is_none() || b_len.is_none() || a_len == b_len) && compatible(a_of, b_of)
         }
-        If(mut cond, mut then, els_may) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
-            let mut new_els = None;
-            if let Some(els) = els_may {
-                new_els = Some(Box::new(walk(*els, true)));
+        _ => a.to_string() == b.to_string(),
+    }
+}
+
+impl Sema {
+    fn enter_scope(&mut self) {
+        let outer = mem::replace(&mut self.env, Env::new(None));
+        self.env.next = Some(Box::new(outer));
+    }
+
+    fn leave_scope(&mut self) {
+        let outer = self.env.next.take().unwrap();
+        self.env = *outer;
+    }
+
+    fn find_var(&self, name: Symbol) -> Option<Var> {
+        let mut next = Some(&self.env);
+        while let Some(e) = next {
+            if let Some(var) = e.vars.get(&name) {
+                return Some(var.clone());
             }
-            node.op = If(cond, then, new_els);
-        }
-        Ternary(mut cond, mut then, mut els) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
-            els = Box::new(walk(*els, true));
-            node.ty = then.ty.clone();
-            node.op = Ternary(cond, then, els);
+            next = e.next.as_deref();
         }
-        For(init, cond, inc, body) => {
-            let f = |(init, cond, inc, body)| -> (Node, Node, Node, Node) {
-                (
-                    walk(init, true),
-                    walk(cond, true),
-                    walk(inc, true),
-                    walk(body, true),
-                )
-            };
-            let (init, cond, inc, body) = into_new_range((*init, *cond, *inc, *body), Box::new(f));
-            node.op = For(
-                Box::new(init),
-                Box::new(cond),
-                Box::new(inc),
-                Box::new(body),
-            );
-        }
-        DoWhile(body, cond) => {
-            node.op = DoWhile(Box::new(walk(*body, true)), Box::new(walk(*cond, true)));
+        None
+    }
+
+    // *expr, where `past_end_ok` is set for &*expr.
+    fn deref(
+        &mut self,
+        mut node: Node,
+        expr: Node,
+        decay: bool,
+        past_end_ok: bool,
+    ) -> Result<Node, LexError> {
+        let expr = Box::new(self.walk(expr, true)?);
+        match expr.ty.ty {
+            Ctype::Ptr(ref ptr_to) => node.ty = ptr_to.clone(),
+            Ctype::Void => return error(&node, "cannot dereference void pointer"),
+            _ => return error(&node, "operand must be a pointer"),
         }
-        Dot(mut expr, name, _) => {
-            expr = Box::new(walk(*expr, true));
-            let offset;
-            if let Ctype::Struct(ref members) = expr.ty.ty {
-                if members.is_empty() {
-                    panic!("incomplete type");
-                }
-                let m_may = members.iter().find(|m| {
-                    if let NodeType::Vardef(ref m_name, _, _) = m.op {
-                        if m_name != &name {
-                            return false;
+        check_bounds(&node, &expr, past_end_ok);
+        node.op = NodeType::Deref(expr);
+        Ok(maybe_decay(node, decay))
+    }
+
+    fn walk(&mut self, mut node: Node, decay: bool) -> Result<Node, LexError> {
+        use self::NodeType::*;
+        let op = node.op.clone();
+        match op {
+            Num(_) | FNum(_) | Null | Break => (),
+            Str(data, len) => {
+                // Quoted from 9cc
+                // > A string literal is converted to a reference to an anonymous
+                // > global variable of type char array.
+                let name = Symbol::intern(&mangle::string_literal(self.strlabel));
+                self.strlabel += 1;
+                let var = Var::new_global(node.ty.clone(), name, data, len, false);
+                let name = var.name;
+                self.globals.push(var);
+
+                let mut ret = Node::new(NodeType::Gvar(name, "".into(), len));
+                ret.ty = node.ty;
+                return Ok(maybe_decay(ret, decay));
+            }
+            Ident(name) => {
+                if let Some(var) = self.find_var(name) {
+                    match var.scope {
+                        Scope::Local(offset) => {
+                            let mut ret = Node::new(NodeType::Lvar(Scope::Local(offset)));
+                            ret.ty = var.ty.clone();
+                            ret.token = node.token.clone();
+                            return Ok(maybe_decay(ret, decay));
+                        }
+                        Scope::Global(ref data, len, _) => {
+                            let mut ret = Node::new(NodeType::Gvar(var.name, data.clone(), len));
+                            ret.ty = var.ty.clone();
+                            return Ok(maybe_decay(ret, decay));
                         }
-                        return true;
                     }
-                    false
-                });
+                } else {
+                    return error(&node, &format!("undefined variable: {}", name));
+                }
+            }
+            Vardef(name, init_may, _) => {
+                if self.env.vars.contains_key(&name) {
+                    return error(&node, &format!("redefinition of '{}'", name));
+                }
+                if node.ty.is_incomplete() {
+                    return error(
+                        &node,
+                        &format!(
+                            "definition of variable with array type needs an explicit size or an initializer: '{}'",
+                            name
+                        ),
+                    );
+                }
+                if let Ctype::Func(..) = node.ty.ty {
+                    return error(
+                        &node,
+                        &format!("function '{}' cannot be declared inside a function", name),
+                    );
+                }
+                self.stacksize = roundup(self.stacksize, node.ty.align) + node.ty.size;
+                let offset = self.stacksize;
+                self.env
+                    .vars
+                    .insert(name, Var::new(node.ty.clone(), name, Scope::Local(offset)));
 
-                if let Some(m) = m_may {
-                    if let NodeType::Vardef(_, _, Scope::Local(offset2)) = m.op {
-                        node.ty = m.ty.clone();
-                        offset = offset2;
-                    } else {
-                        unreachable!()
+                let mut init = None;
+                if let Some(init2) = init_may {
+                    let init2 = convert(self.walk(*init2, true)?, &node.ty);
+                    if matches!(node.ty.ty, Ctype::Struct(_)) {
+                        check_lval(&init2)?;
                     }
+                    init = Some(Box::new(init2));
+                }
+                node.op = Vardef(name, init, Scope::Local(offset));
+            }
+            If(mut cond, mut then, els_may) => {
+                cond = Box::new(self.walk(*cond, true)?);
+                check_condition(&cond);
+                then = Box::new(self.walk(*then, true)?);
+                let mut new_els = None;
+                if let Some(els) = els_may {
+                    new_els = Some(Box::new(self.walk(*els, true)?));
+                }
+                node.op = If(cond, then, new_els);
+            }
+            Ternary(mut cond, mut then, mut els) => {
+                cond = Box::new(self.walk(*cond, true)?);
+                check_condition(&cond);
+                then = Box::new(self.walk(*then, true)?);
+                els = Box::new(self.walk(*els, true)?);
+                // `x ?: y` yields x when it is true.
+                let then_ty = match then.op {
+                    Null => cond.ty.clone(),
+                    _ => then.ty.clone(),
+                };
+                node.ty = if is_arith(&then_ty) && is_arith(&els.ty) {
+                    arith_ty(&then_ty, &els.ty)
+                } else if !matches!(then_ty.ty, Ctype::Ptr(_)) && matches!(els.ty.ty, Ctype::Ptr(_))
+                {
+                    els.ty.clone()
                 } else {
-                    panic!("member missing: {}", name);
+                    then_ty
+                };
+                if !then.is_null() {
+                    (then, els) = convert_operands(then, els);
+                } else if node.ty.is_float() {
+                    els = Box::new(convert(*els, &node.ty));
                 }
-            } else {
-                panic!("struct expected before '.'");
+                node.op = Ternary(cond, then, els);
             }
-
-            node.op = NodeType::Dot(expr, name, offset);
-            return maybe_decay(node, decay);
-        }
-        BinOp(token_type, mut lhs, mut rhs) => {
-            use self::TokenType::*;
-            match token_type {
-                Plus | Minus => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
-
-                    if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
-                        swap(&mut lhs, &mut rhs);
+            For(init, cond, inc, body) => {
+                self.enter_scope();
+                let init = self.walk(*init, true)?;
+                let cond = self.walk(*cond, true)?;
+                let inc = self.walk(*inc, true)?;
+                let body = self.walk(*body, true)?;
+                self.leave_scope();
+                check_condition(&cond);
+                node.op = For(
+                    Box::new(init),
+                    Box::new(cond),
+                    Box::new(inc),
+                    Box::new(body),
+                );
+            }
+            DoWhile(body, cond) => {
+                let body = self.walk(*body, true)?;
+                let cond = self.walk(*cond, true)?;
+                check_condition(&cond);
+                node.op = DoWhile(Box::new(body), Box::new(cond));
+            }
+            Dot(mut expr, name, _) => {
+                expr = Box::new(self.walk(*expr, true)?);
+                let offset;
+                if let Ctype::Struct(ref members) = expr.ty.ty {
+                    if members.is_empty() {
+                        return error(&node, "incomplete type");
                     }
-                    if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
-                        panic!("'pointer {:?} pointer' is not defined", node.op)
+                    match members.iter().find(|m| m.name == name) {
+                        Some(m) => {
+                            node.ty = Box::new(m.ty.clone());
+                            offset = m.offset;
+                        }
+                        None => return error(&node, &format!("no member named '{}'", name)),
                     }
+                } else {
+                    return error(&node, "struct expected before '.'");
+                }
 
-                    if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
-                    }
+                node.op = NodeType::Dot(expr, name, offset);
+                return Ok(maybe_decay(node, decay));
+            }
+            BinOp(token_type, mut lhs, mut rhs) => {
+                use self::TokenType::*;
+                match token_type {
+                    Plus | Minus => {
+                        *lhs = self.walk(*lhs, true)?;
+                        rhs = Box::new(self.walk(*rhs, true)?);
 
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
-                }
-                AddEQ | SubEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&*lhs);
-                    rhs = Box::new(walk(*rhs, true));
+                        if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
+                            swap(&mut lhs, &mut rhs);
+                        }
+                        if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
+                            let msg = format!(
+                                "invalid operands to binary expression ('{}' and '{}')",
+                                lhs.ty, rhs.ty
+                            );
+                            return error(&node, &msg);
+                        }
+
+                        if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
+                            rhs = Box::new(Node::scale_ptr(*rhs, &lhs.ty));
+                        } else {
+                            (lhs, rhs) = convert_operands(lhs, rhs);
+                        }
+
+                        node.ty = if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
+                            lhs.ty.clone()
+                        } else {
+                            arith_ty(&lhs.ty, &rhs.ty)
+                        };
+                        node.op = BinOp(token_type, lhs, rhs);
+                    }
+                    AddEQ | SubEQ => {
+                        *lhs = self.walk(*lhs, false)?;
+                        check_assignable(&lhs)?;
+                        rhs = Box::new(self.walk(*rhs, true)?);
 
-                    if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                        if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
+                            rhs = Box::new(Node::scale_ptr(*rhs, &lhs.ty));
+                        } else {
+                            rhs = Box::new(convert(*rhs, &lhs.ty));
+                        }
+                        node.op = BinOp(token_type, lhs.clone(), rhs);
+                        node.ty = lhs.ty;
+                    }
+                    Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
+                        *lhs = self.walk(*lhs, false)?;
+                        check_assignable(&lhs)?;
+                        *rhs = convert(self.walk(*rhs, true)?, &lhs.ty);
+                        // A struct is copied from memory, so it must have an address.
+                        if matches!(lhs.ty.ty, Ctype::Struct(_)) {
+                            check_lval(&rhs)?;
+                        }
+                        if token_type != Equal {
+                            check_integer_op(&node, &token_type, &lhs, &rhs)?;
+                        }
+                        node.op = BinOp(token_type, lhs.clone(), rhs);
+                        node.ty = lhs.ty;
+                    }
+                    _ => {
+                        *lhs = self.walk(*lhs, true)?;
+                        rhs = Box::new(self.walk(*rhs, true)?);
+                        if token_type == Logor {
+                            for operand in &[&lhs, &rhs] {
+                                if matches!(operand.op, BinOp(Logand, _, _))
+                                    && !operand.parenthesized
+                                {
+                                    diag::warning_named(
+                                        "parentheses",
+                                        &format!(
+                                            "{}suggest parentheses around '&&' within '||'",
+                                            operand.location()
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                        check_integer_op(&node, &token_type, &lhs, &rhs)?;
+                        // A shift has the type of its left operand alone.
+                        if !matches!(token_type, Logand | Logor | Comma | SHL | SHR) {
+                            (lhs, rhs) = convert_operands(lhs, rhs);
+                        }
+                        // Comparisons and logical operators yield an int,
+                        // whatever their operands are.
+                        node.ty = match token_type {
+                            EQ | NE | LE | GE | LeftAngleBracket | RightAngleBracket | Logand
+                            | Logor => Box::new(Type::int_ty()),
+                            Mul | Div | Mod | And | Hat | VerticalBar => arith_ty(&lhs.ty, &rhs.ty),
+                            SHL | SHR => arith_ty(&lhs.ty, &Type::int_ty()),
+                            _ => lhs.ty.clone(),
+                        };
+                        node.op = BinOp(token_type, lhs, rhs);
                     }
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
                 }
-                Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&*lhs);
-                    node.op = BinOp(token_type, lhs.clone(), Box::new(walk(*rhs, true)));
-                    node.ty = lhs.ty;
+            }
+            PostInc(mut expr) => {
+                expr = Box::new(self.walk(*expr, false)?);
+                check_assignable(&expr)?;
+                node.ty = expr.ty.clone();
+                node.op = PostInc(expr);
+            }
+            PostDec(mut expr) => {
+                expr = Box::new(self.walk(*expr, false)?);
+                check_assignable(&expr)?;
+                node.ty = expr.ty.clone();
+                node.op = PostDec(expr);
+            }
+            Neg(mut expr) => {
+                expr = Box::new(self.walk(*expr, true)?);
+                node.ty = expr.ty.clone();
+                node.op = Neg(expr);
+            }
+            Cast(mut expr) => {
+                expr = Box::new(self.walk(*expr, true)?);
+                node.op = Cast(expr);
+            }
+            Exclamation(mut expr) => {
+                expr = Box::new(self.walk(*expr, true)?);
+                node.ty = Box::new(Type::int_ty());
+                node.op = Exclamation(expr);
+            }
+            Addr(expr) => {
+                let expr = Box::new(match expr.op.clone() {
+                    Deref(ptr) => self.deref(*expr, *ptr, true, true)?,
+                    _ => self.walk(*expr, false)?,
+                });
+                check_lval(&expr)?;
+                node.ty = Box::new(Type::ptr_to(expr.ty.clone()));
+                node.op = Addr(expr);
+            }
+            Deref(expr) => return self.deref(node, *expr, decay, false),
+            Return(expr) => {
+                let mut expr = self.walk(*expr, true)?;
+                if let Some(ref ty) = self.return_ty {
+                    expr = convert(expr, ty);
                 }
-                _ => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
+                node.op = Return(Box::new(expr));
+            }
+            ExprStmt(expr) => node.op = ExprStmt(Box::new(self.walk(*expr, true)?)),
+            Sizeof(mut expr) => {
+                *expr = self.walk(*expr, false)?;
+                if expr.ty.is_incomplete() {
+                    return error(
+                        &node,
+                        &format!(
+                            "invalid application of 'sizeof' to an incomplete type '{}'",
+                            expr.ty
+                        ),
+                    );
                 }
+                node = Node::new_int(expr.ty.size as i64)
             }
-        }
-        PostInc(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = PostInc(expr);
-        }
-        PostDec(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = PostDec(expr);
-        }
-        Neg(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = Neg(expr);
-        }
-        Exclamation(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
-            node.op = Exclamation(expr);
-        }
-        Addr(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            check_lval(&*expr);
-            node.ty = Box::new(Type::ptr_to(expr.ty.clone()));
-            node.op = Addr(expr);
-        }
-        Deref(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            match expr.ty.ty {
-                Ctype::Ptr(ref ptr_to) => node.ty = ptr_to.clone(),
-                Ctype::Void => panic!("cannot dereference void pointer"),
-                _ => panic!("operand must be a pointer"),
+            Alignof(mut expr) => {
+                *expr = self.walk(*expr, false)?;
+                node = Node::new_int(expr.ty.align as i64)
             }
-            node.op = Deref(expr);
-            return maybe_decay(node, decay);
-        }
-        Return(expr) => node.op = Return(Box::new(walk(*expr, true))),
-        ExprStmt(expr) => node.op = ExprStmt(Box::new(walk(*expr, true))),
-        Sizeof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.size as i32)
-        }
-        Alignof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.align as i32)
-        }
-        Call(name, mut args) => {
-            if let Some(var) = find_var(&name) {
-                if let Ctype::Func(returning) = var.ty.ty {
-                    node.ty = returning;
-                } else {
-                    eprint!("bad function: {}", name);
+            Call(name, args) => match self.find_var(name) {
+                Some(var) => match var.ty.ty {
+                    Ctype::Func(..) => {
+                        let what = format!("function '{}'", name);
+                        node.ty = call_type(&node, &what, &var.ty, &args)?;
+                        node.op = Call(name, self.convert_args(args, &var.ty)?);
+                    }
+                    // A variable holding a function pointer.
+                    _ => {
+                        let mut callee = Node::new(Ident(name));
+                        callee.token = node.token.clone();
+                        node.op = CallPtr(Box::new(callee), args);
+                        return self.walk(node, decay);
+                    }
+                },
+                None => {
+                    diag::warning(&format!("bad function: {}", name));
+                    node.op = Call(name, self.convert_args(args, &Type::default())?);
+                }
+            },
+            VaStart(ap) => {
+                node.op = VaStart(Box::new(self.walk(*ap, true)?));
+                node.ty = Box::new(Type::void_ty());
+            }
+            VaArg(ap) => {
+                if matches!(node.ty.ty, Ctype::Struct(_)) {
+                    return error(&node, "va_arg of a struct is not supported");
                 }
-            } else {
-                eprint!("bad function: {}", name);
+                node.op = VaArg(Box::new(self.walk(*ap, true)?));
+            }
+            CallPtr(expr, args) => {
+                let expr = self.walk(*expr, true)?;
+                let func = match expr.ty.ty {
+                    Ctype::Ptr(ref to) if matches!(to.ty, Ctype::Func(..)) => to.clone(),
+                    _ => {
+                        return error(
+                            &node,
+                            &format!("called object of type '{}' is not a function", expr.ty),
+                        )
+                    }
+                };
+                let what = format!("function pointer of type '{}'", expr.ty);
+                node.ty = call_type(&node, &what, &func, &args)?;
+                node.op = CallPtr(Box::new(expr), self.convert_args(args, &func)?);
+            }
+            CompStmt(mut stmts) => {
+                self.enter_scope();
+                stmts = stmts
+                    .into_iter()
+                    .map(|stmt| self.walk(stmt, true))
+                    .collect::<Result<_, _>>()?;
+                self.leave_scope();
+                node.op = CompStmt(stmts);
+            }
+            VecStmt(mut stmts) => {
+                stmts = stmts
+                    .into_iter()
+                    .map(|stmt| self.walk(stmt, true))
+                    .collect::<Result<_, _>>()?;
+                node.op = VecStmt(stmts);
+            }
+            StmtExpr(body) => {
+                // `return` in a statement expression gives its value, an int.
+                let outer = self.return_ty.replace(Type::int_ty());
+                node.op = StmtExpr(Box::new(self.walk(*body, true)?));
+                self.return_ty = outer;
+                node.ty = Box::new(Type::int_ty())
             }
+            _ => unreachable!("unknown node type"),
+        };
+        Ok(node)
+    }
+
+    // Arguments convert to the parameter types of `func`. The rest only
+    // have float promoted to double.
+    fn convert_args(&mut self, args: Vec<Node>, func: &Type) -> Result<Vec<Node>, LexError> {
+        let params = match func.ty {
+            Ctype::Func(_, Some(ref params), ..) => &params[..],
+            _ => &[],
+        };
+        let double = Type::double_ty();
+        args.into_iter()
+            .enumerate()
+            .map(|(i, arg)| {
+                let arg = self.walk(arg, true)?;
+                Ok(match params.get(i) {
+                    Some(ty) => convert(arg, ty),
+                    None if arg.ty.is_float() => convert(arg, &double),
+                    None => arg,
+                })
+            })
+            .collect()
+    }
 
-            args = args.into_iter().map(|arg| walk(arg, true)).collect();
-            node.op = Call(name, args);
+    // Adds a global, or merges it into an earlier declaration of the same
+    // name: a definition takes the place of an extern, and a length
+    // completes `extern int a[];` from then on. Returns the merged variable.
+    // `node` is the declaration, for errors.
+    fn declare_global(&mut self, node: &Node, var: Var) -> Result<Var, LexError> {
+        let prev = match self.globals.iter_mut().find(|g| g.name == var.name) {
+            Some(prev) => prev,
+            None => {
+                self.globals.push(var.clone());
+                return Ok(var);
+            }
+        };
+        if !compatible(&prev.ty, &var.ty) {
+            let msg = format!(
+                "conflicting types for '{}': '{}' and '{}'",
+                var.name, prev.ty, var.ty
+            );
+            return error(node, &msg);
         }
-        CompStmt(mut stmts) => {
-            let f = |stmts: Vec<Node>| -> Vec<Node> {
-                stmts.into_iter().map(|stmt| walk(stmt, true)).collect()
-            };
-            stmts = into_new_range(stmts, Box::new(f));
-            node.op = CompStmt(stmts);
+        if prev.ty.is_incomplete() {
+            prev.ty = var.ty;
         }
-        VecStmt(mut stmts) => {
-            stmts = stmts.into_iter().map(|stmt| walk(stmt, true)).collect();
-            node.op = VecStmt(stmts);
+        if let Scope::Global(_, _, false) = var.scope {
+            prev.scope = var.scope;
         }
-        StmtExpr(body) => {
-            node.op = StmtExpr(Box::new(walk(*body, true)));
-            node.ty = Box::new(Type::int_ty())
+        // A definition before the length was known has no space yet.
+        if let Scope::Global(_, ref mut len, false) = prev.scope {
+            *len = prev.ty.size;
         }
-        _ => panic!("unknown node type"),
-    };
-    node
+        Ok(prev.clone())
+    }
 }
 
-pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
+pub fn sema(nodes: Vec<Node>) -> Result<Program, LexError> {
+    let mut sema = Sema {
+        globals: vec![],
+        env: Env::new(None),
+        strlabel: 0,
+        stacksize: 0,
+        return_ty: None,
+        noreturn: HashSet::new(),
+    };
     let mut new_nodes = vec![];
 
-    for mut node in nodes {
-        if let NodeType::Vardef(name, _, Scope::Global(data, len, is_extern)) = node.op {
-            let var = Var::new_global(node.ty, name.clone(), data, len, is_extern);
-            GLOBALS.lock().unwrap().push(var.clone());
-            ENV.lock().unwrap().vars.insert(name, var);
-            continue;
-        }
+    // The helper behind the predefined assert() macro, emitted by the
+    // code generator.
+    let name = Symbol::intern("__r9cc_assert_fail");
+    let ty = Box::new(Type::new(
+        Ctype::Func(Box::new(Type::void_ty()), None, false, false),
+        0,
+    ));
+    let var = Var::new_global(ty, name, "".into(), 0, false);
+    sema.env.vars.insert(name, var);
+
+    // Bulk copy and fill, lowered by gen_ir when the size is constant.
+    for name in &["__builtin_memcpy", "__builtin_memset"] {
+        let name = Symbol::intern(name);
+        let void_ptr = Box::new(Type::ptr_to(Box::new(Type::void_ty())));
+        let ty = Box::new(Type::new(Ctype::Func(void_ptr, None, false, false), 0));
+        let var = Var::new_global(ty, name, "".into(), 0, false);
+        sema.env.vars.insert(name, var);
+    }
+
+    // Declared implicitly, as they are often called without
+    // <stdlib.h>, and never return.
+    for name in &["exit", "abort", "_Exit"] {
+        let name = Symbol::intern(name);
+        let void = Box::new(Type::void_ty());
+        let ty = Box::new(Type::new(Ctype::Func(void, None, false, true), 0));
+        let var = Var::new_global(ty, name, "".into(), 0, false);
+        sema.env.vars.insert(name, var);
+        sema.noreturn.insert(name);
+    }
 
-        let var;
-        match &node.op {
-            NodeType::Func(name, _, _, _) | NodeType::Decl(name) => {
-                var = Var::new_global(node.ty.clone(), name.clone(), "".into(), 0, false);
-                ENV.lock().unwrap().vars.insert(name.clone(), var);
+    // Register every function before walking any body, so that a call
+    // resolves the same way no matter where the callee is defined.
+    // Being _Noreturn in one declaration is enough.
+    for node in &nodes {
+        if let NodeType::Func(name, ..) | NodeType::Decl(name) = &node.op {
+            let var = Var::new_global(node.ty.clone(), *name, "".into(), 0, false);
+            sema.env.vars.insert(*name, var);
+            if let Ctype::Func(_, _, _, true) = node.ty.ty {
+                sema.noreturn.insert(*name);
             }
-            _ => unreachable!(),
         }
+    }
 
-        if matches!(node.op, NodeType::Decl(_)) {
+    for mut node in nodes {
+        if let NodeType::Vardef(name, _, Scope::Global(ref data, len, is_extern)) = node.op {
+            let var = Var::new_global(node.ty.clone(), name, data.clone(), len, is_extern);
+            let var = sema.declare_global(&node, var)?;
+            sema.env.vars.insert(name, var);
             continue;
         }
 
-        if let NodeType::Func(name, args, body, _) = node.op {
-            let mut args2 = vec![];
-            for arg in args {
-                args2.push(walk(arg, true));
+        if let NodeType::Func(name, args, mut body, _, is_static) = node.op {
+            if let Ctype::Func(ref returning, ..) = node.ty.ty {
+                sema.return_ty = Some(*returning.clone());
             }
-            let body2 = walk(*body, true);
-            node.op = NodeType::Func(
-                name.clone(),
-                args2,
-                Box::new(body2),
-                *STACKSIZE.lock().unwrap(),
-            );
-            *STACKSIZE.lock().unwrap() = 0;
+            // Each function gets a fresh scope for its parameters, which
+            // the outermost block of the body shares: a local there
+            // cannot redefine a parameter.
+            sema.enter_scope();
+            let args = args
+                .into_iter()
+                .map(|arg| sema.walk(arg, true))
+                .collect::<Result<_, _>>()?;
+            if let NodeType::CompStmt(stmts) = body.op {
+                let stmts = stmts
+                    .into_iter()
+                    .map(|stmt| sema.walk(stmt, true))
+                    .collect::<Result<_, _>>()?;
+                body.op = NodeType::CompStmt(stmts);
+            }
+            sema.leave_scope();
+            node.op = NodeType::Func(name, args, body, sema.stacksize, is_static);
+            sema.stacksize = 0;
             new_nodes.push(node);
         }
     }
-    (new_nodes, GLOBALS.lock().unwrap().clone())
+    Ok(Program {
+        nodes: new_nodes,
+        globals: sema.globals,
+        noreturn: sema.noreturn,
+    })
 }
@@ -0,0 +1,89 @@
+// Code size statistics
+//
+// Filled in by the code generator as it emits each function, from the
+// IR it was given and the instructions it wrote, and reported by the
+// driver with --stats-asm, so that size regressions show up as numbers
+// rather than as diffs of assembly listings.
+
+use crate::symbol::Symbol;
+
+#[derive(Debug, Clone)]
+pub struct FuncStats {
+    pub name: Symbol,
+    pub insns: usize,
+    pub calls: usize,
+    pub spills: usize, // registers the allocator spilled to the frame
+}
+
+impl FuncStats {
+    pub fn new(name: Symbol) -> Self {
+        FuncStats {
+            name,
+            insns: 0,
+            calls: 0,
+            spills: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AsmStats {
+    pub funcs: Vec<FuncStats>,
+    pub data_bytes: usize,
+}
+
+impl AsmStats {
+    pub fn insns(&self) -> usize {
+        self.funcs.iter().map(|f| f.insns).sum()
+    }
+
+    pub fn calls(&self) -> usize {
+        self.funcs.iter().map(|f| f.calls).sum()
+    }
+
+    pub fn spills(&self) -> usize {
+        self.funcs.iter().map(|f| f.spills).sum()
+    }
+
+    pub fn print_table(&self) {
+        eprintln!(
+            "{:<24} {:>8} {:>8} {:>8}",
+            "function", "insns", "calls", "spills"
+        );
+        for f in &self.funcs {
+            eprintln!(
+                "{:<24} {:>8} {:>8} {:>8}",
+                f.name, f.insns, f.calls, f.spills
+            );
+        }
+        eprintln!(
+            "{:<24} {:>8} {:>8} {:>8}",
+            "total",
+            self.insns(),
+            self.calls(),
+            self.spills()
+        );
+        eprintln!("data bytes: {}", self.data_bytes);
+    }
+
+    pub fn print_json(&self) {
+        let funcs: Vec<String> = self
+            .funcs
+            .iter()
+            .map(|f| {
+                format!(
+                    "{{\"name\":\"{}\",\"insns\":{},\"calls\":{},\"spills\":{}}}",
+                    f.name, f.insns, f.calls, f.spills
+                )
+            })
+            .collect();
+        eprintln!(
+            "{{\"functions\":[{}],\"insns\":{},\"calls\":{},\"spills\":{},\"data_bytes\":{}}}",
+            funcs.join(","),
+            self.insns(),
+            self.calls(),
+            self.spills(),
+            self.data_bytes
+        );
+    }
+}
@@ -0,0 +1,53 @@
+// Identifier interning
+//
+// Every identifier is stored once and referred to by a small copyable
+// id afterwards, so that passing names between phases doesn't clone
+// strings. The text is looked up again only for diagnostics and output.
+//
+// The table only grows: a Symbol stays valid for the life of the
+// process, whichever compilation made it, and a name seen again by a
+// later compilation gets the id it had before. Nothing the compiler
+// emits depends on the ids themselves, so an input compiles the same
+// whatever was interned before it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    map: HashMap<Arc<str>, Symbol>,
+    names: Vec<Arc<str>>,
+}
+
+lazy_static! {
+    static ref INTERNER: Mutex<Interner> = Mutex::new(Interner::default());
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Self {
+        let mut interner = INTERNER.lock().unwrap();
+        if let Some(sym) = interner.map.get(name) {
+            return *sym;
+        }
+        let name: Arc<str> = Arc::from(name);
+        let sym = Symbol(interner.names.len() as u32);
+        interner.names.push(name.clone());
+        interner.map.insert(name, sym);
+        sym
+    }
+
+    // The name, shared with the table.
+    pub fn as_str(self) -> Arc<str> {
+        INTERNER.lock().unwrap().names[self.0 as usize].clone()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.as_str())
+    }
+}
@@ -0,0 +1,74 @@
+// Target description
+//
+// Sizes and alignments of the basic types live here rather than being
+// spelled as constants across the parser and sema, so that the layout
+// math has a single source of truth. Only x86-64 has a code generator;
+// `test32` exists to exercise the layout code with different numbers.
+
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub name: &'static str,
+    pub ptr_size: usize,
+    pub ptr_align: usize,
+    pub int_size: usize,
+    pub long_size: usize,
+    pub char_is_signed: bool,
+    pub has_backend: bool,
+    pub local_label_prefix: &'static str, // see mangle.rs
+}
+
+impl Target {
+    pub fn x86_64() -> Self {
+        Target {
+            name: "x86_64",
+            ptr_size: 8,
+            ptr_align: 8,
+            int_size: 4,
+            long_size: 8,
+            char_is_signed: true,
+            has_backend: true,
+            local_label_prefix: ".L",
+        }
+    }
+
+    pub fn test32() -> Self {
+        Target {
+            name: "test32",
+            ptr_size: 4,
+            ptr_align: 4,
+            int_size: 4,
+            long_size: 4,
+            char_is_signed: true,
+            has_backend: false,
+            local_label_prefix: ".L",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "x86_64" | "x86-64" => Some(Target::x86_64()),
+            "test32" => Some(Target::test32()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::x86_64()
+    }
+}
+
+lazy_static! {
+    static ref TARGET: Mutex<Target> = Mutex::new(Target::default());
+}
+
+pub fn target() -> Target {
+    *TARGET.lock().unwrap()
+}
+
+pub fn set_target(t: Target) {
+    *TARGET.lock().unwrap() = t;
+}
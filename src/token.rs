@@ -1,43 +1,236 @@
+use crate::diag;
 use crate::preprocess;
+use crate::symbol;
+use crate::target::target;
 use crate::CharacterType;
 use crate::TokenType;
 
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::rc::Rc;
-
-pub fn tokenize(path: String, ctx: &mut preprocess::Preprocessor) -> Vec<Token> {
-    let mut tokenizer = Tokenizer::new(Rc::new(path));
-    tokenizer.canonicalize_newline();
-    tokenizer.remove_backslash_newline();
-    tokenizer.scan(&keyword_map());
-
-    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, ctx);
-    tokenizer.strip_newlines_tokens();
-    tokenizer.join_string_literals();
-    tokenizer.tokens
+use std::sync::Arc;
+
+// Reads, tokenizes and preprocesses a file. Each error is reported as
+// it is found; the first one is also returned.
+pub fn tokenize(path: String, ctx: &mut preprocess::Preprocessor) -> Result<Vec<Token>, LexError> {
+    TokenStream::open(path, ctx)?.collect()
+}
+
+// Reads a source file, or stdin if `path` is "-". Files with NUL bytes
+// are rejected as binary, since no C source contains them. Invalid
+// UTF-8 is replaced with U+FFFD after a warning.
+pub fn read_source(path: &str) -> Result<String, String> {
+    let mut bytes = vec![];
+    let res = if path == "-" {
+        io::stdin().read_to_end(&mut bytes)
+    } else {
+        File::open(path).and_then(|mut fp| fp.read_to_end(&mut bytes))
+    };
+    if let Err(e) = res {
+        return Err(format!("cannot read file '{}': {}", path, e));
+    }
+
+    if bytes.contains(&0) {
+        return Err(format!("file '{}' appears to be binary", path));
+    }
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            diag::warning(&format!("file '{}' is not valid UTF-8", path));
+            Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        }
+    }
+}
+
+// Same as `tokenize`, but reads the source from a string. `name` is
+// used as the filename in diagnostics and __FILE__.
+pub fn tokenize_source(
+    name: String,
+    input: &str,
+    ctx: &mut preprocess::Preprocessor,
+) -> Result<Vec<Token>, LexError> {
+    TokenStream::new(name, input, ctx)?.collect()
+}
+
+// The preprocessed tokens of a file, made as they are asked for. Only
+// the files being read and what the reader keeps are held in memory,
+// not the whole translation unit. Newlines are dropped and adjacent
+// string literals joined, the way the parser wants them.
+pub struct TokenStream<'a> {
+    ctx: &'a mut preprocess::Preprocessor,
+    peeked: Option<Token>, // read to see whether a string literal goes on
+}
+
+impl<'a> TokenStream<'a> {
+    pub fn open(path: String, ctx: &'a mut preprocess::Preprocessor) -> Result<Self, LexError> {
+        let input = read_source(&path).map_err(|msg| LexError::new(msg, &path).report())?;
+        Self::new(path, &input, ctx)
+    }
+
+    pub fn new(
+        name: String,
+        input: &str,
+        ctx: &'a mut preprocess::Preprocessor,
+    ) -> Result<Self, LexError> {
+        let tokens = Tokenizer::scanned(name, input, ctx)?;
+        ctx.start(tokens);
+        Ok(TokenStream { ctx, peeked: None })
+    }
+
+    fn next_line_token(&mut self) -> Result<Option<Token>, LexError> {
+        if let Some(t) = self.peeked.take() {
+            return Ok(Some(t));
+        }
+        while let Some(t) = self.ctx.next_token()? {
+            if t.ty != TokenType::NewLine {
+                return Ok(Some(t));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_joined(&mut self) -> Result<Option<Token>, LexError> {
+        let mut t = match self.next_line_token()? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        while let TokenType::Str(ref s, _) = t.ty {
+            match self.next_line_token()? {
+                Some(next) => match next.ty {
+                    TokenType::Str(ref next_s, _) => t = Tokenizer::append(s, next_s, &t),
+                    _ => {
+                        self.peeked = Some(next);
+                        break;
+                    }
+                },
+                None => break,
+            }
+        }
+        Ok(Some(t))
+    }
+}
+
+impl<'a> Iterator for TokenStream<'a> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_joined().transpose()
+    }
+}
+
+// Returns the preprocessed source as text, for -E.
+pub fn preprocess_to_string(
+    path: String,
+    ctx: &mut preprocess::Preprocessor,
+) -> Result<String, LexError> {
+    let input = read_source(&path).map_err(|msg| LexError::new(msg, &path).report())?;
+    let tokens = Tokenizer::scanned(path, &input, ctx)?;
+    ctx.start(tokens);
+    let mut tokens = vec![];
+    while let Some(t) = ctx.next_token()? {
+        tokens.push(t);
+    }
+    Ok(tokens_to_source(&tokens))
+}
+
+// Writes tokens back out as source text that reads back as the same
+// tokens: a line for each NewLine, a space where there was whitespace,
+// and one wherever two tokens would otherwise run together.
+pub fn tokens_to_source(tokens: &[Token]) -> String {
+    let mut sb = String::new();
+    let mut prev: Option<String> = None; // on this line
+    for t in tokens {
+        if t.ty == TokenType::NewLine {
+            sb.push('\n');
+            prev = None;
+            continue;
+        }
+        let text = t.text();
+        if let Some(prev) = prev {
+            if t.space || t.apart || would_glue(&prev, &text) {
+                sb.push(' ');
+            }
+        }
+        sb.push_str(&text);
+        prev = Some(text);
+    }
+    if prev.is_some() {
+        sb.push('\n');
+    }
+    sb
+}
+
+// Whether `a` and `b` written with nothing between them read back as
+// something else, like `-` `-` as `--` or `int` `x` as `intx`.
+fn would_glue(a: &str, b: &str) -> bool {
+    let apart = |c: char| "()[]{};,?~".contains(c);
+    if a.ends_with(apart) || b.starts_with(apart) {
+        return false;
+    }
+    let text = format!("{}{}", a, b);
+    let mut tokenizer = Tokenizer::new(Arc::new(String::new()), &text);
+    tokenizer.quiet = true;
+    tokenizer.scan();
+    tokenizer.error.is_some() || tokenizer.tokens.len() != 2 || tokenizer.tokens[0].tokstr() != a
+}
+
+// String literal data keeps one char per byte, so that an escape like
+// "\xe9" and UTF-8 text like "é" both stand for the bytes they encode,
+// and the length of the data is its size in memory.
+pub fn str_data(s: &str) -> String {
+    s.bytes().map(char::from).collect()
+}
+
+// Prints one preprocessed token per line, for -dump-tokens.
+pub fn dump_tokens(tokens: &[Token]) {
+    for t in tokens {
+        let kind = match t.ty {
+            TokenType::Num(..) => "Num".to_string(),
+            TokenType::FNum(_, _) => "FNum".to_string(),
+            TokenType::Str(_, _) => "Str".to_string(),
+            TokenType::CharLiteral(_) => "CharLiteral".to_string(),
+            TokenType::Ident(_) => "Ident".to_string(),
+            ref ty => format!("{:?}", ty),
+        };
+        eprintln!("{}:{}: {} {}", t.line, t.col, kind, t.text());
+    }
 }
 
-fn keyword_map() -> HashMap<String, TokenType> {
-    let mut map = HashMap::new();
-    map.insert("_Alignof".into(), TokenType::Alignof);
-    map.insert("break".into(), TokenType::Break);
-    map.insert("char".into(), TokenType::Char);
-    map.insert("void".into(), TokenType::Void);
-    map.insert("do".into(), TokenType::Do);
-    map.insert("else".into(), TokenType::Else);
-    map.insert("extern".into(), TokenType::Extern);
-    map.insert("for".into(), TokenType::For);
-    map.insert("if".into(), TokenType::If);
-    map.insert("int".into(), TokenType::Int);
-    map.insert("return".into(), TokenType::Return);
-    map.insert("sizeof".into(), TokenType::Sizeof);
-    map.insert("struct".into(), TokenType::Struct);
-    map.insert("typedef".into(), TokenType::Typedef);
-    map.insert("while".into(), TokenType::While);
-    map
+// Built once, as would_glue and paste scan a few characters at a time.
+lazy_static! {
+    static ref KEYWORDS: HashMap<&'static str, TokenType> = [
+        ("_Alignof", TokenType::Alignof),
+        ("_Noreturn", TokenType::Noreturn),
+        ("break", TokenType::Break),
+        ("case", TokenType::Case),
+        ("char", TokenType::Char),
+        ("const", TokenType::Const),
+        ("void", TokenType::Void),
+        ("continue", TokenType::Continue),
+        ("default", TokenType::Default),
+        ("do", TokenType::Do),
+        ("else", TokenType::Else),
+        ("extern", TokenType::Extern),
+        ("for", TokenType::For),
+        ("goto", TokenType::Goto),
+        ("if", TokenType::If),
+        ("int", TokenType::Int),
+        ("long", TokenType::Long),
+        ("float", TokenType::Float),
+        ("double", TokenType::Double),
+        ("return", TokenType::Return),
+        ("sizeof", TokenType::Sizeof),
+        ("static", TokenType::Static),
+        ("struct", TokenType::Struct),
+        ("switch", TokenType::Switch),
+        ("typedef", TokenType::Typedef),
+        ("while", TokenType::While),
+    ]
+    .iter()
+    .cloned()
+    .collect();
 }
 
 #[derive(Debug, Clone)]
@@ -46,29 +239,55 @@ pub struct Token {
 
     // For preprocessor
     pub stringize: bool,
+    pub space: bool,                       // preceded by whitespace
+    pub apart: bool,                       // set apart from a macro expansion when printed
+    pub expansion: Option<Arc<Expansion>>, // the macro expansion it came out of
+    pub after_empty: Option<Arc<Token>>,   // a macro right before it that expanded to nothing
 
     // For error reporting
-    pub buf: Rc<Vec<char>>,
-    pub filename: Rc<String>,
+    pub buf: Arc<Vec<char>>,
+    pub filename: Arc<String>,
     pub start: usize,
     pub end: usize,
+    pub line: usize, // 1-based; 0 if the token has no source position
+    pub col: usize,  // 1-based, in characters rather than bytes
+}
+
+// What a token that came out of a macro expansion remembers of it.
+#[derive(Debug)]
+pub struct Expansion {
+    pub hideset: Vec<symbol::Symbol>, // the macros it came out of, not to be expanded again
+    pub start: Token,                 // the outermost macro name, for __LINE__ and __FILE__
+}
+
+// Shared by every default token, so that making one (or taking one
+// out of a vector with mem::take) doesn't allocate.
+lazy_static! {
+    static ref EMPTY_BUF: Arc<Vec<char>> = Arc::new(vec![]);
+    static ref EMPTY_FILENAME: Arc<String> = Arc::new(String::new());
 }
 
 impl Default for Token {
     fn default() -> Token {
         Token {
             ty: TokenType::Int,
-            buf: Rc::new(vec![]),
-            filename: Rc::new("".to_string()),
+            buf: EMPTY_BUF.clone(),
+            filename: EMPTY_FILENAME.clone(),
             start: 0,
             end: 0,
+            line: 0,
+            col: 0,
             stringize: false,
+            space: false,
+            apart: false,
+            expansion: None,
+            after_empty: None,
         }
     }
 }
 
 impl Token {
-    pub fn new(ty: TokenType, start: usize, filename: Rc<String>, buf: Rc<Vec<char>>) -> Self {
+    pub fn new(ty: TokenType, start: usize, filename: Arc<String>, buf: Arc<Vec<char>>) -> Self {
         Token {
             ty,
             buf,
@@ -78,25 +297,107 @@ impl Token {
         }
     }
 
-    pub fn bad_token(&self, msg: &str) -> ! {
-        print_line(&*self.buf, &*self.filename, self.start);
-        panic!("{}", msg);
+    // A token the preprocessor made up, reported at `at`.
+    pub fn at(ty: TokenType, at: &Token) -> Self {
+        Token {
+            ty,
+            buf: at.buf.clone(),
+            filename: at.filename.clone(),
+            start: at.start,
+            line: at.line,
+            col: at.col,
+            ..Default::default()
+        }
+    }
+
+    // The note on an error caused by the macro named by this token
+    // expanding to nothing.
+    pub fn empty_note(&self) -> String {
+        format!(
+            "\n{}:{}:{}: note: '{}' expanded to nothing",
+            self.filename,
+            self.line,
+            self.col,
+            self.text()
+        )
+    }
+
+    // A continued line runs on into the lines joined to it.
+    fn physical_line(&self) -> String {
+        let start = self.start - self.col.saturating_sub(1);
+        self.buf[start..]
+            .iter()
+            .take_while(|c| **c != '\n')
+            .collect()
     }
 
     pub fn tokstr(&self) -> String {
         self.buf[self.start..self.end].iter().collect()
     }
 
+    // Like tokstr, but also spells tokens the preprocessor made up
+    // (e.g. __LINE__ or a stringized argument), which have no source.
+    pub fn text(&self) -> String {
+        if self.start < self.end {
+            return self.tokstr();
+        }
+        match self.ty {
+            TokenType::Num(n, ..) => n.to_string(),
+            TokenType::FNum(n, _) => format!("{:?}", n),
+            TokenType::Ident(name) => name.to_string(),
+            TokenType::Str(ref s, _) => {
+                // The data is bytes. Those that are not UTF-8, and
+                // control characters, are written as octal escapes.
+                let bytes: Vec<u8> = s.chars().map(|c| c as u8).collect();
+                let (text, utf8) = match String::from_utf8(bytes) {
+                    Ok(text) => (text, true),
+                    Err(_) => (s.clone(), false),
+                };
+                let mut sb = String::from("\"");
+                for c in text.chars() {
+                    match c {
+                        '"' | '\\' => {
+                            sb.push('\\');
+                            sb.push(c);
+                        }
+                        '\n' => sb.push_str("\\n"),
+                        '\t' => sb.push_str("\\t"),
+                        _ if c.is_ascii_control() || !(utf8 || c.is_ascii()) => {
+                            sb.push_str(&format!("\\{:03o}", c as u32))
+                        }
+                        _ => sb.push(c),
+                    }
+                }
+                sb.push('"');
+                sb
+            }
+            _ => spelling(&self.ty).unwrap_or_else(|| self.tokstr()),
+        }
+    }
+
     pub fn get_line_number(&self) -> usize {
-        self.buf[..self.end].iter().filter(|c| *c == &'\n').count()
+        self.line
+    }
+
+    // Returns the physical source line this token starts on.
+    pub fn source_line(&self) -> String {
+        self.physical_line().trim().to_string()
     }
 
     pub fn is_ident(&self, s: &str) -> bool {
         match self.ty {
-            TokenType::Ident(ref name) => name == s,
+            TokenType::Ident(name) => *name.as_str() == *s,
             _ => false,
         }
     }
+
+    // The keyword this token is, if it is one.
+    pub fn keyword(&self) -> Option<String> {
+        KEYWORDS
+            .iter()
+            .find(|(_, keyword)| **keyword == self.ty)
+            .map(|(name, _)| name.to_string())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -113,6 +414,9 @@ impl Symbol {
 
 lazy_static! {
     static ref SYMBOLS: Vec<Symbol> = [
+        Symbol::new("...", TokenType::Ellipsis),
+        Symbol::new("##", TokenType::HashHash),
+        Symbol::new("%:%:", TokenType::HashHash),
         Symbol::new("<<=", TokenType::ShlEQ),
         Symbol::new(">>=", TokenType::ShrEQ),
         Symbol::new("!=", TokenType::NE),
@@ -134,49 +438,114 @@ lazy_static! {
         Symbol::new("&=", TokenType::BitandEQ),
         Symbol::new("^=", TokenType::XorEQ),
         Symbol::new("|=", TokenType::BitorEQ),
+        // Digraphs
+        Symbol::new("<%", TokenType::LeftBrace),
+        Symbol::new("%>", TokenType::RightBrace),
+        Symbol::new("<:", TokenType::LeftBracket),
+        Symbol::new(":>", TokenType::RightBracket),
+        Symbol::new("%:", TokenType::HashMark),
     ]
     .to_vec();
 }
 
 // Tokenizer
 struct Tokenizer {
-    p: Rc<Vec<char>>,
+    p: Arc<Vec<char>>,
     pos: usize,
     tokens: Vec<Token>,
+    space: bool, // whitespace seen since the last token
+
+    // Line of `line_pos`, and where that line starts. Advanced lazily
+    // to each new token.
+    line: usize,
+    line_start: usize,
+    line_pos: usize,
+
+    // Where each physical line that was joined to the previous one by
+    // a backslash-newline starts, and the next of those to pass.
+    splices: Vec<usize>,
+    splice: usize,
 
     // Error reporting
-    filename: Rc<String>,
+    filename: Arc<String>,
+
+    // Reused to spell each identifier before it is interned.
+    name: String,
+
+    // The first error found, if any.
+    error: Option<LexError>,
+    quiet: bool, // keep errors to ourselves rather than printing them
 }
 
 impl Tokenizer {
-    fn new(filename: Rc<String>) -> Self {
+    fn new(filename: Arc<String>, input: &str) -> Self {
         Tokenizer {
-            p: Rc::new(Self::read_file(&filename).chars().collect()),
+            p: Arc::new(input.chars().collect()),
             filename,
             pos: 0,
             tokens: vec![],
+            space: true,
+            line: 1,
+            line_start: 0,
+            line_pos: 0,
+            splices: vec![],
+            splice: 0,
+            name: String::new(),
+            error: None,
+            quiet: false,
         }
     }
 
-    fn read_file(filename: &str) -> String {
-        let mut input = String::new();
-        let mut fp = io::stdin();
-        if filename != &"-".to_string() {
-            let mut fp = File::open(filename).expect("file not found");
-            fp.read_to_string(&mut input)
-                .expect("something went wrong reading the file");
-            return input;
+    // Scans the input and runs the preprocessor over it. The result
+    // still has newline tokens in it.
+    fn scanned(
+        name: String,
+        input: &str,
+        ctx: &preprocess::Preprocessor,
+    ) -> Result<Vec<Token>, LexError> {
+        let mut tokenizer = Tokenizer::new(Arc::new(name), input);
+        tokenizer.canonicalize_newline();
+        if ctx.trigraphs {
+            tokenizer.replace_trigraphs();
+        }
+        tokenizer.remove_backslash_newline();
+        tokenizer.scan();
+        if let Some(err) = tokenizer.error.take() {
+            return Err(err);
         }
-        fp.read_to_string(&mut input)
-            .expect("something went wrong reading the file");
-        input
+        Ok(tokenizer.tokens)
     }
 
-    fn new_token(&self, ty: TokenType) -> Token {
-        Token::new(ty, self.pos, self.filename.clone(), self.p.clone())
+    fn new_token(&mut self, ty: TokenType) -> Token {
+        self.new_token_at(ty, self.pos)
     }
 
-    // This does not support non-ASCII characters.
+    fn new_token_at(&mut self, ty: TokenType, start: usize) -> Token {
+        loop {
+            while self.splices.get(self.splice) == Some(&self.line_pos) {
+                self.line += 1;
+                self.line_start = self.line_pos;
+                self.splice += 1;
+            }
+            if self.line_pos >= start {
+                break;
+            }
+            if self.p[self.line_pos] == '\n' {
+                self.line += 1;
+                self.line_start = self.line_pos + 1;
+            }
+            self.line_pos += 1;
+        }
+
+        let mut t = Token::new(ty, start, self.filename.clone(), self.p.clone());
+        t.line = self.line;
+        t.col = start - self.line_start + 1;
+        t.space = self.space;
+        self.space = false;
+        t
+    }
+
+    // Non-ASCII letters are alphabetic, so identifiers may be UTF-8.
     fn get_character(&self, advance_from_pos: usize) -> Option<CharacterType> {
         self.p.get(self.pos + advance_from_pos).map(|ch| {
             if ch == &'\n' {
@@ -193,7 +562,7 @@ impl Tokenizer {
         })
     }
 
-    fn scan(&mut self, keywords: &HashMap<String, TokenType>) -> Vec<Token> {
+    fn scan(&mut self) -> Vec<Token> {
         'outer: while let Some(head_char) = self.get_character(0) {
             match head_char {
                 CharacterType::NewLine => {
@@ -201,11 +570,21 @@ impl Tokenizer {
                     self.pos += 1;
                     t.end = self.pos;
                     self.tokens.push(t);
+                    self.space = true;
+                }
+                CharacterType::Whitespace => {
+                    self.pos += 1;
+                    self.space = true;
                 }
-                CharacterType::Whitespace => self.pos += 1,
-                CharacterType::Alphabetic => self.ident(&keywords),
+                CharacterType::Alphabetic => self.ident(),
                 CharacterType::Digit => self.number(),
+                CharacterType::NonAlphabetic('.')
+                    if self.p.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit()) =>
+                {
+                    self.float_number()
+                }
 
+                CharacterType::NonAlphabetic('<') if self.in_include() => self.header_name(),
                 CharacterType::NonAlphabetic('\'') => self.char_literal(),
                 CharacterType::NonAlphabetic('\"') => self.string_literal(),
                 CharacterType::NonAlphabetic('/') => match self.p.get(self.pos + 1) {
@@ -235,7 +614,7 @@ impl Tokenizer {
                         }
 
                         let first = &self.p[self.pos..self.pos + len];
-                        if name != first.iter().collect::<String>() {
+                        if !name.chars().eq(first.iter().cloned()) {
                             continue;
                         }
 
@@ -254,22 +633,36 @@ impl Tokenizer {
                         self.tokens.push(t);
                         continue 'outer;
                     }
-                    self.bad_position("Unknown symbol.");
+                    // Skip it and carry on, so that one run reports them all.
+                    self.error_position("Unknown symbol.");
+                    self.pos += 1;
+                    if diag::too_many_errors() {
+                        break;
+                    }
+                }
+                CharacterType::Unknown(_) => {
+                    self.error_position("Unknwon character type.");
+                    self.pos += 1;
                 }
-                CharacterType::Unknown(_) => self.bad_position("Unknwon character type."),
             }
         }
 
         self.tokens.clone()
     }
 
+    // The newline is left for the scanner, since the preprocessor needs
+    // it to find the end of a directive.
     fn line_comment(&mut self) {
-        while self.p.get(self.pos) != Some(&'\n') {
+        self.space = true;
+        while !matches!(self.p.get(self.pos), Some('\n') | None) {
             self.pos += 1;
         }
     }
 
+    // Block comments do not nest: the first "*/" ends the comment.
     fn block_comment(&mut self) {
+        self.space = true;
+        let start = self.pos;
         self.pos += 2;
         loop {
             if let Some(two_char) = self.p.get(self.pos..self.pos + 2) {
@@ -279,87 +672,193 @@ impl Tokenizer {
                     return;
                 }
             } else {
-                self.bad_position("unclosed comment");
+                self.error_at(start, "unterminated comment");
+                self.pos = self.p.len();
+                return;
             }
         }
     }
 
-    fn escaped(c: char) -> Option<char> {
-        // Issue: https://github.com/rust-lang/rfcs/issues/751
+    // Reads an escape sequence; `self.pos` points just past the
+    // backslash. Returns the byte value, or 0 after reporting a bad
+    // sequence.
+    fn read_escape(&mut self) -> u8 {
+        let c = match self.p.get(self.pos) {
+            Some(c) => *c,
+            None => {
+                self.error_position("premature end of input");
+                return 0;
+            }
+        };
+        self.pos += 1;
         match c {
-            // 'a' => Some('\a'),
-            // 'b' => Some('\b'),
-            // 'f' => Some('\f'),
-            'n' => Some('\n'),
-            'r' => Some('\r'),
-            't' => Some('\t'),
-            // 'v' => Some('\v'),
-            _ => None,
+            'a' => 7,
+            'b' => 8,
+            'f' => 12,
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'v' => 11,
+            'e' => 27, // GNU extension
+            '\\' | '\'' | '"' | '?' => c as u8,
+            '0'..='7' => {
+                // Up to three octal digits
+                let mut val = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match self.p.get(self.pos).and_then(|c| c.to_digit(8)) {
+                        Some(d) => val = val * 8 + d,
+                        None => break,
+                    }
+                    self.pos += 1;
+                }
+                self.escape_value(val)
+            }
+            'x' => {
+                let start = self.pos;
+                let mut val: u32 = 0;
+                while let Some(d) = self.p.get(self.pos).and_then(|c| c.to_digit(16)) {
+                    val = val.saturating_mul(16).saturating_add(d);
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    self.error_position("\\x used with no following hex digits");
+                    return 0;
+                }
+                self.escape_value(val)
+            }
+            _ => {
+                self.error_at(self.pos - 1, &format!("unknown escape sequence: '\\{}'", c));
+                0
+            }
+        }
+    }
+
+    fn escape_value(&mut self, val: u32) -> u8 {
+        if val > 0xff {
+            self.error_position("escape sequence out of range");
+            return 0;
         }
+        val as u8
     }
 
     fn char_literal(&mut self) {
+        let start = self.pos;
         self.pos += 1;
-        let result: char;
-        let c = self.p.get(self.pos).expect("premature end of input");
-        if c != &'\\' {
-            result = *c;
-            self.pos += 1;
-        } else {
-            self.pos += 1;
-            let c2 = self.p.get(self.pos).unwrap();
-            result = if let Some(esc) = Self::escaped(*c2) {
-                esc
-            } else {
-                *c2
-            };
-            self.pos += 1;
-        }
+        let result = match self.p.get(self.pos) {
+            Some('\\') => {
+                self.pos += 1;
+                self.read_escape()
+            }
+            Some('\'') => {
+                self.error_position("empty character constant");
+                self.pos += 1;
+                return;
+            }
+            Some('\n') | None => {
+                self.error_at(start, "unclosed character literal");
+                return;
+            }
+            Some(c) if !c.is_ascii() => {
+                self.error_position("character too large for enclosing character literal type");
+                self.pos += 1;
+                if self.p.get(self.pos) == Some(&'\'') {
+                    self.pos += 1;
+                }
+                return;
+            }
+            Some(c) => {
+                self.pos += 1;
+                *c as u8
+            }
+        };
 
         if self.p.get(self.pos) != Some(&'\'') {
-            panic!("unclosed character literal");
+            let rest = &self.p[self.pos..];
+            let eol = rest.iter().position(|c| *c == '\n').unwrap_or(rest.len());
+            match rest[..eol].iter().position(|c| *c == '\'') {
+                Some(end) => {
+                    self.error_position("multi-character character constant");
+                    self.pos += end + 1;
+                }
+                None => {
+                    self.error_at(start, "unclosed character literal");
+                    self.pos += eol;
+                }
+            }
+            return;
         }
 
-        let mut t = self.new_token(TokenType::Num(result as u8 as i32));
+        let val = if target().char_is_signed {
+            result as i8 as i32
+        } else {
+            result as i32
+        };
+        let mut t = self.new_token_at(TokenType::Num(val as i64, false, false), start);
         self.pos += 1;
-        t.end = self.pos + 1;
+        t.end = self.pos;
+        self.tokens.push(t);
+    }
+
+    // True right after `#include`, where a header name follows.
+    // A header name is not a string literal and has no escapes.
+    fn in_include(&self) -> bool {
+        let n = self.tokens.len();
+        n >= 2
+            && self.tokens[n - 1].is_ident("include")
+            && self.tokens[n - 2].ty == TokenType::HashMark
+            && (n == 2 || self.tokens[n - 3].ty == TokenType::NewLine)
+    }
+
+    // <stdio.h> would otherwise be five tokens.
+    fn header_name(&mut self) {
+        let start = self.pos;
+        let end = self.p[start..]
+            .iter()
+            .position(|c| *c == '>' || *c == '\n')
+            .map_or(self.p.len(), |n| start + n);
+        if self.p.get(end) != Some(&'>') {
+            self.error_at(start, "missing terminating '>' in header name");
+            self.pos = end;
+            return;
+        }
+        let name = self.p[start + 1..end].iter().collect();
+        let mut t = self.new_token_at(TokenType::HeaderName(name), start);
+        self.pos = end + 1;
+        t.end = self.pos;
         self.tokens.push(t);
     }
 
     fn string_literal(&mut self) {
+        let header_name = self.in_include();
+        let start = self.pos;
         self.pos += 1;
         let mut sb = String::new();
-        let mut len = 0;
         loop {
-            let mut c2 = self.p.get(self.pos + len).expect("PREMATURE end of input");
-            if c2 == &'"' {
-                len += 1;
-                self.pos += len;
-                let mut t = self.new_token(TokenType::Str(sb, len));
-                t.start = self.pos - len - 1;
-                t.end = self.pos + 1;
-                self.tokens.push(t);
-                return;
-            }
-
-            if c2 != &'\\' {
-                len += 1;
-                sb.push(c2.clone());
-                continue;
-            }
-
-            len += 1;
-            c2 = self.p.get(self.pos + len).unwrap();
-            if let Some(esc) = Self::escaped(*c2) {
-                sb.push(esc);
-            } else {
-                sb.push(c2.clone());
+            let c = match self.p.get(self.pos) {
+                Some('\n') | None => {
+                    self.error_at(start, "unclosed string literal");
+                    return;
+                }
+                Some(c) => *c,
+            };
+            self.pos += 1;
+            match c {
+                '"' => break,
+                // Decoded bytes are kept one per char.
+                '\\' if !header_name => sb.push(self.read_escape() as char),
+                _ if !c.is_ascii() && !header_name => sb.push_str(&str_data(&c.to_string())),
+                _ => sb.push(c),
             }
-            len += 1;
         }
+
+        // +1 for the terminating '\0'.
+        let len = sb.chars().count() + 1;
+        let mut t = self.new_token_at(TokenType::Str(sb, len), start);
+        t.end = self.pos;
+        self.tokens.push(t);
     }
 
-    fn ident(&mut self, keywords: &HashMap<String, TokenType>) {
+    fn ident(&mut self) {
         let mut len = 1;
         while let Some(c2) = self.p.get(self.pos + len) {
             if c2.is_alphabetic() || c2.is_ascii_digit() || c2 == &'_' {
@@ -369,45 +868,157 @@ impl Tokenizer {
             break;
         }
 
-        let name: String = self.p[self.pos..self.pos + len].iter().collect();
-        let mut t;
-        if let Some(keyword) = keywords.get(&name) {
-            t = self.new_token(keyword.clone());
-        } else {
-            t = self.new_token(TokenType::Ident(name.clone()));
-        }
+        self.name.clear();
+        self.name.extend(&self.p[self.pos..self.pos + len]);
+        let mut t = match KEYWORDS.get(self.name.as_str()) {
+            Some(keyword) => self.new_token(keyword.clone()),
+            None => self.new_token(TokenType::Ident(symbol::Symbol::intern(&self.name))),
+        };
         self.pos += len;
         t.end = self.pos;
         self.tokens.push(t);
     }
 
-    fn number(&mut self) {
-        match self.p.get(self.pos..self.pos + 2) {
-            Some(&['0', 'x']) | Some(&['0', 'X']) => {
-                self.pos += 2;
-                self.parse_number(16);
+    // A decimal literal with a '.' or an exponent is floating point.
+    fn is_float_number(&self) -> bool {
+        if let Some(&['0', 'x' | 'X' | 'b' | 'B']) = self.p.get(self.pos..self.pos + 2) {
+            return false;
+        }
+        let mut pos = self.pos;
+        while self.p.get(pos).is_some_and(|c| c.is_ascii_digit()) {
+            pos += 1;
+        }
+        matches!(self.p.get(pos), Some('.' | 'e' | 'E'))
+    }
+
+    fn float_number(&mut self) {
+        let start = self.pos;
+        let mut t = self.new_token_at(TokenType::FNum(0.0, false), start);
+        let digits = |tokenizer: &mut Self| {
+            let from = tokenizer.pos;
+            while tokenizer
+                .p
+                .get(tokenizer.pos)
+                .is_some_and(|c| c.is_ascii_digit())
+            {
+                tokenizer.pos += 1;
+            }
+            tokenizer.pos > from
+        };
+        digits(self);
+        if self.p.get(self.pos) == Some(&'.') {
+            self.pos += 1;
+            digits(self);
+        }
+        if let Some('e' | 'E') = self.p.get(self.pos) {
+            self.pos += 1;
+            if let Some('+' | '-') = self.p.get(self.pos) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return self.error_at(start, "exponent has no digits");
             }
-            Some(&['0', _]) => {
-                self.parse_number(8);
+        }
+        let text: String = self.p[start..self.pos].iter().collect();
+
+        let suffix_start = self.pos;
+        while let Some(c) = self.p.get(self.pos) {
+            if !c.is_alphanumeric() && c != &'_' {
+                break;
             }
-            _ => self.parse_number(10),
+            self.pos += 1;
         }
+        t.end = self.pos;
+        let suffix: String = self.p[suffix_start..self.pos].iter().collect();
+        // long double is just double.
+        let is_float = match suffix.as_str() {
+            "" | "l" | "L" => false,
+            "f" | "F" => true,
+            _ => {
+                let msg = format!("invalid suffix '{}' on floating constant", suffix);
+                return self.error_at(start, &msg);
+            }
+        };
+
+        let val: f64 = text.parse().unwrap();
+        t.ty = TokenType::FNum(val, is_float);
+        self.tokens.push(t);
     }
 
-    fn parse_number(&mut self, base: u32) {
-        let mut sum: i32 = 0;
-        let mut len = 0;
-        for c in self.p[self.pos..].iter() {
-            if let Some(val) = c.to_digit(base) {
-                sum = sum * base as i32 + val as i32;
-                len += 1;
-            } else {
+    fn number(&mut self) {
+        if self.is_float_number() {
+            return self.float_number();
+        }
+        let start = self.pos;
+        let base = match self.p.get(self.pos..self.pos + 2) {
+            Some(&['0', 'x']) | Some(&['0', 'X']) => 16,
+            // GNU extension
+            Some(&['0', 'b']) | Some(&['0', 'B']) => 2,
+            Some(&['0', _]) => 8,
+            _ => 10,
+        };
+        if base == 16 || base == 2 {
+            self.pos += 2;
+        }
+
+        let mut t = self.new_token_at(TokenType::Num(0, false, false), start);
+
+        let mut sum: u64 = 0;
+        let mut overflow = false;
+        let digits = self.pos;
+        while let Some(val) = self.p.get(self.pos).and_then(|c| c.to_digit(base)) {
+            match sum
+                .checked_mul(base as u64)
+                .and_then(|n| n.checked_add(val as u64))
+            {
+                Some(n) => sum = n,
+                None => overflow = true,
+            }
+            self.pos += 1;
+        }
+        t.end = self.pos;
+
+        if (base == 16 || base == 2) && self.pos == digits {
+            return self.error_at(start, "invalid integer literal");
+        }
+
+        let suffix_start = self.pos;
+        while let Some(c) = self.p.get(self.pos) {
+            if !c.is_alphanumeric() && c != &'_' {
                 break;
             }
+            self.pos += 1;
         }
-        let mut t = self.new_token(TokenType::Num(sum as i32));
-        self.pos += len;
+        let suffix: String = self.p[suffix_start..self.pos].iter().collect();
+        let (unsigned, long) = match suffix.as_str() {
+            "" => (false, false),
+            "u" | "U" => (true, false),
+            "l" | "L" | "ll" | "LL" => (false, true),
+            "ul" | "uL" | "Ul" | "UL" | "ull" | "uLL" | "Ull" | "ULL" | "lu" | "lU" | "Lu"
+            | "LU" | "llu" | "llU" | "LLu" | "LLU" => (true, true),
+            _ => {
+                let c = self.p[suffix_start];
+                let msg = if c.is_ascii_digit() {
+                    format!("invalid digit '{}' in integer literal", c)
+                } else {
+                    format!("invalid suffix '{}' on integer literal", suffix)
+                };
+                return self.error_at(start, &msg);
+            }
+        };
         t.end = self.pos;
+        if overflow {
+            return self.error_at(start, "integer literal is too large");
+        }
+
+        // A literal has the first of int, unsigned int, long and unsigned
+        // long that holds it, skipping the unsigned ones for a decimal
+        // literal without 'u' and the signed ones for one with it.
+        let is_long =
+            long || sum > u32::MAX as u64 || (base == 10 && !unsigned && sum > i32::MAX as u64);
+        let max = if is_long { i64::MAX } else { i32::MAX as i64 };
+        let is_unsigned = unsigned || (sum > max as u64 && (base != 10 || !is_long));
+        t.ty = TokenType::Num(sum as i64, is_long, is_unsigned);
         self.tokens.push(t);
     }
 
@@ -415,121 +1026,230 @@ impl Tokenizer {
         let mut pos = 0;
         while pos < self.p.len() {
             if self.p[pos] == '\r' && self.p[pos + 1] == '\n' {
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
+                Arc::get_mut(&mut self.p).unwrap().remove(pos);
+                Arc::get_mut(&mut self.p).unwrap().remove(pos);
             }
             pos += 1;
         }
     }
 
-    // Quoted from 9cc
-    // > Concatenates continuation lines. We keep the total number of
-    // > newline characters the same to keep the line counter sane.
-    fn remove_backslash_newline(&mut self) {
+    // For --trigraphs. This comes before line splicing, since `??/` is a
+    // backslash. Columns after a trigraph count it as one character, as
+    // in the line shown under an error.
+    fn replace_trigraphs(&mut self) {
+        if !self.p.windows(2).any(|w| w == ['?', '?']) {
+            return;
+        }
+        let mut p = Vec::with_capacity(self.p.len());
         let mut pos = 0;
-        let mut cnt = 0;
         while pos < self.p.len() {
-            if self.p[pos] == '\\' && self.p[pos + 1] == '\n' {
-                cnt += 1;
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-                pos += 1;
-            } else if self.p[pos] == '\n' {
-                for _ in 0..cnt {
-                    Rc::get_mut(&mut self.p).unwrap().insert(pos, '\n');
-                    pos += 1;
+            if let ['?', '?', c, ..] = self.p[pos..] {
+                if let Some(c) = trigraph(c) {
+                    p.push(c);
+                    pos += 3;
+                    continue;
                 }
-                pos += 1;
-                cnt = 0;
-            } else {
-                pos += 1;
             }
+            p.push(self.p[pos]);
+            pos += 1;
+        }
+        self.p = Arc::new(p);
+    }
+
+    // Concatenates continuation lines. The places they were joined are
+    // kept in `splices`, so that tokens after a backslash-newline still
+    // report the physical line they are on.
+    fn remove_backslash_newline(&mut self) {
+        if !self.p.contains(&'\\') {
+            return;
+        }
+        let mut p = Vec::with_capacity(self.p.len());
+        let mut chars = self.p.iter().peekable();
+        while let Some(&c) = chars.next() {
+            if c == '\\' && chars.peek() == Some(&&'\n') {
+                chars.next();
+                self.splices.push(p.len());
+                continue;
+            }
+            p.push(c);
         }
+        self.p = Arc::new(p);
     }
 
-    fn append(&mut self, x_str: &str, y_str: &str, start: usize) -> Token {
+    fn append(x_str: &str, y_str: &str, at: &Token) -> Token {
         let concated = format!("{}{}", x_str, y_str);
-        let l = concated.len() + 1; // Because `+1` has `\0`.
-        Token::new(
-            TokenType::Str(concated, l),
-            start,
-            self.filename.clone(),
-            self.p.clone(),
-        )
+        let l = concated.chars().count() + 1; // Because `+1` has `\0`.
+        Token::at(TokenType::Str(concated, l), at)
     }
 
-    fn join_string_literals(&mut self) {
-        let mut v = vec![];
-        let mut last_may: Option<Token> = None;
+    // Reports a malformed token and lets the scan go on, so that one
+    // run reports every error in the file.
+    fn error_at(&mut self, pos: usize, msg: &str) {
+        let pos = pos.min(self.p.len());
+        let newline = self.p[..pos].iter().filter(|c| **c == '\n').count();
+        let spliced = self.splices.iter().filter(|s| **s <= pos).count();
+        let start = self.p[..pos]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |p| p + 1)
+            .max(self.splices[..spliced].last().map_or(0, |s| *s));
+        let end = self.splices[spliced..].first().map_or(self.p.len(), |s| *s);
+        let src = self.p[start..end].iter().take_while(|c| **c != '\n');
 
-        for t in self.tokens.clone().into_iter() {
-            if let Some(ref last) = last_may {
-                if let (TokenType::Str(ref last_str, _), TokenType::Str(ref t_str, _)) =
-                    (&last.ty, &t.ty)
-                {
-                    let new = self.append(last_str, t_str, last.start);
-                    v.pop();
-                    v.push(new);
-                    continue;
-                }
-            }
+        let err = LexError {
+            msg: msg.to_string(),
+            filename: self.filename.to_string(),
+            line: newline + spliced + 1,
+            col: pos - start + 1,
+            src: src.collect(),
+        };
+        let err = if self.quiet { err } else { err.report() };
+        self.error.get_or_insert(err);
+    }
 
-            last_may = Some(t.clone());
-            v.push(t);
-        }
-        self.tokens = v;
+    fn error_position(&mut self, msg: &str) {
+        self.error_at(self.pos, msg)
     }
+}
 
-    fn strip_newlines_tokens(&mut self) {
-        self.tokens = self
-            .tokens
-            .clone()
-            .into_iter()
-            .filter(|t| t.ty != TokenType::NewLine)
-            .collect()
+// The tokens of a piece of text, such as the value of a -D option,
+// without preprocessing them.
+pub fn scan(name: &str, input: &str) -> Result<Vec<Token>, LexError> {
+    let mut tokenizer = Tokenizer::new(Arc::new(name.to_string()), input);
+    tokenizer.scan();
+    match tokenizer.error.take() {
+        Some(err) => Err(err),
+        None => Ok(tokenizer.tokens),
     }
+}
+
+// The tokens of a source file, before preprocessing.
+pub(crate) fn scan_file(
+    name: String,
+    input: &str,
+    ctx: &preprocess::Preprocessor,
+) -> Result<Vec<Token>, LexError> {
+    Tokenizer::scanned(name, input, ctx)
+}
 
-    fn bad_position(&self, msg: &'static str) {
-        print_line(&self.p, &self.filename, self.pos);
-        panic!("{}", msg);
+// The token that `lhs ## rhs` makes, placed where `lhs` was. None if
+// the two spellings together are not exactly one token.
+pub fn paste(lhs: &Token, rhs: &Token) -> Option<Token> {
+    let text = format!("{}{}", lhs.text(), rhs.text());
+    let mut tokenizer = Tokenizer::new(lhs.filename.clone(), &text);
+    tokenizer.quiet = true;
+    tokenizer.scan();
+    if tokenizer.error.is_some() || tokenizer.tokens.len() != 1 {
+        return None;
     }
+    let pasted = tokenizer.tokens.pop().unwrap();
+    let mut t = Token::at(pasted.ty, lhs);
+    t.space = lhs.space;
+    Some(t)
 }
 
-// Finds a line pointed by a given pointer from the input file
-// to print it out.
-fn print_line(buf: &[char], path: &str, pos: usize) {
-    let mut p = 0;
-    let mut start = 0;
-    let mut line = 0;
-    let mut col = 0;
-    for c in buf.iter() {
-        if c == &'\n' {
-            start = pos + 1;
-            line += 1;
-            col = 0;
-            p += 1;
-            continue;
+// How a keyword or punctuator is written, for a token the preprocessor
+// made up, which has no source to take it from.
+fn spelling(ty: &TokenType) -> Option<String> {
+    let mut single = "+-*/&;=()[]{}<>,.!?|^%:#".chars();
+    if let Some(c) = single.find(|c| TokenType::new_single_letter(*c).as_ref() == Some(ty)) {
+        return Some(c.to_string());
+    }
+    if let Some(symbol) = SYMBOLS.iter().find(|symbol| symbol.ty == *ty) {
+        return Some(symbol.name.to_string());
+    }
+    KEYWORDS
+        .iter()
+        .find(|(_, keyword)| *keyword == ty)
+        .map(|(name, _)| name.to_string())
+}
+
+// The character `??c` stands for.
+fn trigraph(c: char) -> Option<char> {
+    let to = match c {
+        '=' => '#',
+        '(' => '[',
+        ')' => ']',
+        '<' => '{',
+        '>' => '}',
+        '/' => '\\',
+        '\'' => '^',
+        '!' => '|',
+        '-' => '~',
+        _ => return None,
+    };
+    Some(to)
+}
+
+// An error found in the source, e.g. an unclosed string literal, a file
+// that can't be read, a missing ';' or an undefined variable. The
+// parser and sema stop at their first one.
+#[derive(Debug, Clone)]
+pub struct LexError {
+    pub msg: String,
+    pub filename: String,
+    pub line: usize, // 1-based; 0 if the error has no source position
+    pub col: usize,  // 1-based, in characters rather than bytes
+    src: String,     // the source line, for the caret
+}
+
+impl LexError {
+    pub fn new(msg: String, filename: &str) -> Self {
+        LexError {
+            msg,
+            filename: filename.to_string(),
+            line: 0,
+            col: 0,
+            src: String::new(),
         }
+    }
 
-        if p != pos {
-            col += 1;
-            p += 1;
-            continue;
+    // An error blamed on a token, e.g. the name in an #include. A macro
+    // that expanded to nothing is often why the token after it is
+    // unexpected, so the error points that out.
+    pub fn at(t: &Token, msg: String) -> Self {
+        let note = t.after_empty.as_ref().map(|name| name.empty_note());
+        LexError {
+            msg: msg + &note.unwrap_or_default(),
+            filename: t.filename.to_string(),
+            line: t.line,
+            col: t.col,
+            src: t.physical_line(),
         }
+    }
 
-        print!("error at {}:{}:{}\n\n", path, line + 1, col);
-        break;
+    // Prints the error and counts it.
+    pub fn report(self) -> Self {
+        diag::error(&self.to_string());
+        self
     }
+}
 
-    for p in buf[start..].iter() {
-        if p == &'\n' {
-            break;
+// Reports an error at `t`, to be returned.
+pub(crate) fn error_at<T>(t: &Token, msg: &str) -> Result<T, LexError> {
+    Err(LexError::at(t, msg.to_string()).report())
+}
+
+// "file:line:col: error: message", the source line with a caret under
+// the column, and then any notes, which follow the message's first line.
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "error: {}", self.msg);
         }
-        print!("{}", p);
-    }
-    println!();
-    for _ in 0..col - 1 {
-        print!(" ");
+        let (msg, notes) = self
+            .msg
+            .split_at(self.msg.find('\n').unwrap_or(self.msg.len()));
+        write!(
+            f,
+            "{}:{}:{}: error: {}\n{}\n{}^{}",
+            self.filename,
+            self.line,
+            self.col,
+            msg,
+            self.src,
+            " ".repeat(self.col.saturating_sub(1)),
+            notes
+        )
     }
-    print!("^\n\n");
 }
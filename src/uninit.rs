@@ -0,0 +1,374 @@
+// Uninitialized variable warnings
+//
+// Runs over each function after sema and follows its control flow,
+// keeping the set of locals that are assigned on every path so far and
+// the set assigned on at least one. A read of a local in neither set is
+// -Wuninitialized; a read of one only in the second is
+// -Wmaybe-uninitialized.
+//
+// Only scalar locals are tracked. Arrays, structs and anything whose
+// address is taken can be written without naming the variable, so
+// they are left alone.
+//
+// The same walk tells whether the end of a function body is reachable,
+// which for a non-void function other than main is -Wreturn-type.
+
+use crate::diag;
+use crate::parse::{Node, NodeType};
+use crate::symbol::Symbol;
+use crate::{Ctype, Scope, TokenType, Type};
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+// `noreturn` holds the functions whose calls never return, as sema
+// found them.
+pub fn check_uninitialized(nodes: &[Node], noreturn: &HashSet<Symbol>) {
+    for node in nodes {
+        if let NodeType::Func(name, ref args, ref body, ..) = node.op {
+            let mut checker = Checker::new(noreturn);
+            checker.find_address_taken(body);
+            // Parameters are assigned by the caller.
+            for arg in args {
+                if let NodeType::Vardef(_, _, Scope::Local(offset)) = arg.op {
+                    checker.assign(offset);
+                }
+            }
+            checker.stmt(body);
+            if !checker.state.dead && returns_value(node) && &*name.as_str() != "main" {
+                let msg = format!(
+                    "{}control reaches end of non-void function '{}'",
+                    node.location(),
+                    name
+                );
+                diag::warning_named("return-type", &msg);
+            }
+        }
+    }
+}
+
+fn returns_value(func: &Node) -> bool {
+    match func.ty.ty {
+        Ctype::Func(ref returning, ..) => !matches!(returning.ty, Ctype::Void),
+        _ => false,
+    }
+}
+
+// What is known to be assigned at one point of a function.
+#[derive(Clone, Default)]
+struct State {
+    assigned: HashSet<usize>, // on every path
+    maybe: HashSet<usize>,    // on some path
+    dead: bool,               // after return or break
+}
+
+impl State {
+    // Nothing reaches here, so nothing is missing.
+    fn dead() -> State {
+        State {
+            dead: true,
+            ..State::default()
+        }
+    }
+
+    // Where two paths join.
+    fn merge(self, other: State) -> State {
+        if self.dead {
+            return other;
+        }
+        if other.dead {
+            return self;
+        }
+        State {
+            assigned: &self.assigned & &other.assigned,
+            maybe: &self.maybe | &other.maybe,
+            dead: false,
+        }
+    }
+}
+
+struct Checker<'a> {
+    noreturn: &'a HashSet<Symbol>,
+    state: State,
+    names: HashMap<usize, Symbol>, // tracked locals by stack offset
+    address_taken: HashSet<usize>,
+    breaks: Vec<State>,     // paths leaving each loop we are in
+    returns: Vec<State>,    // paths leaving each ({ ... }) we are in
+    quiet: bool,            // set on a first pass over a loop body
+    warned: HashSet<usize>, // one warning per variable is enough
+}
+
+fn is_tracked(ty: &Type) -> bool {
+    matches!(
+        ty.ty,
+        Ctype::Int | Ctype::Long | Ctype::Char | Ctype::Float | Ctype::Double | Ctype::Ptr(_)
+    )
+}
+
+fn local(node: &Node) -> Option<usize> {
+    match node.op {
+        NodeType::Lvar(Scope::Local(offset)) => Some(offset),
+        _ => None,
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn new(noreturn: &'a HashSet<Symbol>) -> Self {
+        Checker {
+            noreturn,
+            state: State::default(),
+            names: HashMap::new(),
+            address_taken: HashSet::new(),
+            breaks: vec![],
+            returns: vec![],
+            quiet: false,
+            warned: HashSet::new(),
+        }
+    }
+
+    fn find_address_taken(&mut self, node: &Node) {
+        if let NodeType::Addr(ref expr) = node.op {
+            if let Some(offset) = local(expr) {
+                self.address_taken.insert(offset);
+            }
+        }
+        for child in children(node) {
+            self.find_address_taken(child);
+        }
+    }
+
+    fn assign(&mut self, offset: usize) {
+        self.state.assigned.insert(offset);
+        self.state.maybe.insert(offset);
+    }
+
+    fn read(&mut self, node: &Node, offset: usize) {
+        let name = match self.names.get(&offset) {
+            Some(name) => *name,
+            None => return,
+        };
+        if self.quiet || self.state.dead || self.state.assigned.contains(&offset) {
+            return;
+        }
+        if !self.warned.insert(offset) {
+            return;
+        }
+        let at = node.location();
+        let (warning, is) = if self.state.maybe.contains(&offset) {
+            ("maybe-uninitialized", "may be")
+        } else {
+            ("uninitialized", "is")
+        };
+        let msg = format!(
+            "{}variable '{}' {} uninitialized when used here",
+            at, name, is
+        );
+        diag::warning_named(warning, &msg);
+    }
+
+    // Joins another path into the current one.
+    fn join(&mut self, other: State) {
+        let here = mem::take(&mut self.state);
+        self.state = here.merge(other);
+    }
+
+    // Runs `f` on a copy of the current state, and returns the state
+    // it ends in. The current state is left as it was.
+    fn branch(&mut self, f: impl FnOnce(&mut Self)) -> State {
+        let saved = self.state.clone();
+        f(self);
+        mem::replace(&mut self.state, saved)
+    }
+
+    // A loop body may run after itself, so whatever it assigns may
+    // already be assigned at its top. A quiet first pass finds that out.
+    fn loop_body(&mut self, body: &Node, inc: Option<&Node>) {
+        let quiet = self.quiet;
+        self.quiet = true;
+        self.breaks.push(State::dead());
+        let end = self.branch(|c| {
+            c.stmt(body);
+            if let Some(inc) = inc {
+                c.stmt(inc);
+            }
+        });
+        self.breaks.pop();
+        self.quiet = quiet;
+        self.state.maybe.extend(end.maybe);
+
+        self.stmt(body);
+        if let Some(inc) = inc {
+            self.stmt(inc);
+        }
+    }
+
+    fn stmt(&mut self, node: &Node) {
+        use self::NodeType::*;
+        match node.op {
+            Vardef(name, ref init, Scope::Local(offset)) => {
+                if is_tracked(&node.ty) && !self.address_taken.contains(&offset) {
+                    self.names.insert(offset, name);
+                }
+                // A definition inside a loop starts over each time round.
+                self.state.assigned.remove(&offset);
+                self.state.maybe.remove(&offset);
+                if let Some(init) = init {
+                    self.stmt(init);
+                    self.assign(offset);
+                }
+            }
+            If(ref cond, ref then, ref els) => {
+                self.stmt(cond);
+                let then = self.branch(|c| c.stmt(then));
+                if let Some(els) = els {
+                    self.stmt(els);
+                }
+                self.join(then);
+            }
+            Ternary(ref cond, ref then, ref els) => {
+                self.stmt(cond);
+                let then = self.branch(|c| c.stmt(then));
+                self.stmt(els);
+                self.join(then);
+            }
+            BinOp(TokenType::Logand, ref lhs, ref rhs)
+            | BinOp(TokenType::Logor, ref lhs, ref rhs) => {
+                self.stmt(lhs);
+                let rhs = self.branch(|c| c.stmt(rhs));
+                self.join(rhs);
+            }
+            BinOp(ref op, ref lhs, ref rhs) if is_assign(op) => match local(lhs) {
+                Some(offset) => {
+                    if *op != TokenType::Equal {
+                        self.read(lhs, offset);
+                    }
+                    self.stmt(rhs);
+                    self.assign(offset);
+                }
+                None => {
+                    self.stmt(lhs);
+                    self.stmt(rhs);
+                }
+            },
+            For(ref init, ref cond, ref inc, ref body) => {
+                self.stmt(init);
+                self.stmt(cond);
+                // for (;;) and while (1) only end by a break.
+                let forever = is_forever(cond);
+                let mut skip = self.state.clone();
+                skip.dead |= forever;
+
+                self.breaks.push(State::dead());
+                self.loop_body(body, Some(inc));
+                self.stmt(cond);
+                self.state.dead |= forever;
+                self.join(skip);
+                let exit = self.breaks.pop().unwrap();
+                self.join(exit);
+            }
+            DoWhile(ref body, ref cond) => {
+                self.breaks.push(State::dead());
+                self.loop_body(body, None);
+                self.stmt(cond);
+                let exit = self.breaks.pop().unwrap();
+                self.join(exit);
+            }
+            Break => {
+                let here = mem::replace(&mut self.state, State::dead());
+                if let Some(exit) = self.breaks.pop() {
+                    self.breaks.push(exit.merge(here));
+                }
+            }
+            Return(ref expr) => {
+                self.stmt(expr);
+                let here = mem::replace(&mut self.state, State::dead());
+                if let Some(value) = self.returns.pop() {
+                    self.returns.push(value.merge(here));
+                }
+            }
+            // Nothing after exit() runs.
+            Call(name, ref args) if self.noreturn.contains(&name) => {
+                for arg in args {
+                    self.stmt(arg);
+                }
+                self.state = State::dead();
+            }
+            StmtExpr(ref body) => {
+                // `return` only leaves the statement expression.
+                self.returns.push(State::dead());
+                self.stmt(body);
+                let value = self.returns.pop().unwrap();
+                self.join(value);
+            }
+            Lvar(Scope::Local(offset)) => self.read(node, offset),
+            // Taking the address reads nothing.
+            Addr(ref expr) if local(expr).is_some() => (),
+            _ => {
+                for child in children(node) {
+                    self.stmt(child);
+                }
+            }
+        }
+    }
+}
+
+// A loop condition that is missing or a nonzero constant.
+fn is_forever(cond: &Node) -> bool {
+    match cond.op {
+        NodeType::Num(n) => n != 0,
+        _ => cond.is_null(),
+    }
+}
+
+fn is_assign(op: &TokenType) -> bool {
+    use self::TokenType::*;
+    matches!(
+        op,
+        Equal | MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ
+    )
+}
+
+// The operands and sub-statements of a node, in evaluation order.
+fn children(node: &Node) -> Vec<&Node> {
+    use self::NodeType::*;
+    match node.op {
+        Vardef(_, Some(ref init), _) => vec![init],
+        BinOp(_, ref lhs, ref rhs) => vec![lhs, rhs],
+        If(ref cond, ref then, ref els) => {
+            let mut v = vec![&**cond, &**then];
+            v.extend(els.as_deref());
+            v
+        }
+        Ternary(ref cond, ref then, ref els) => vec![cond, then, els],
+        For(ref init, ref cond, ref inc, ref body) => vec![init, cond, body, inc],
+        DoWhile(ref body, ref cond) => vec![body, cond],
+        Addr(ref expr)
+        | Deref(ref expr)
+        | Dot(ref expr, _, _)
+        | Exclamation(ref expr)
+        | Neg(ref expr)
+        | Cast(ref expr)
+        | PostInc(ref expr)
+        | PostDec(ref expr)
+        | Return(ref expr)
+        | Sizeof(ref expr)
+        | Alignof(ref expr)
+        | ExprStmt(ref expr)
+        | StmtExpr(ref expr)
+        | VaStart(ref expr)
+        | VaArg(ref expr) => vec![expr],
+        Call(_, ref args) => args.iter().collect(),
+        CallPtr(ref expr, ref args) => {
+            let mut v = vec![&**expr];
+            v.extend(args);
+            v
+        }
+        Func(_, ref args, ref body, ..) => {
+            let mut v: Vec<&Node> = args.iter().collect();
+            v.push(body);
+            v
+        }
+        CompStmt(ref stmts) | VecStmt(ref stmts) => stmts.iter().collect(),
+        _ => vec![],
+    }
+}
@@ -1,3 +1,55 @@
+use std::path::{Component, Path, PathBuf};
+
 pub fn roundup(x: usize, align: usize) -> usize {
     (x + align - 1) & !(align - 1)
 }
+
+// Converts both separator styles to '/' (which Windows also accepts)
+// and folds away "." and "dir/.." components, so that one file reached
+// through different spellings ends up with the same path.
+pub fn normalize_path(path: &str) -> PathBuf {
+    let path = path.replace('\\', "/");
+    let mut v: Vec<Component> = vec![];
+    for c in Path::new(&path).components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => match v.last() {
+                Some(Component::Normal(_)) => {
+                    v.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => v.push(c),
+            },
+            _ => v.push(c),
+        }
+    }
+
+    if v.is_empty() {
+        return PathBuf::from(".");
+    }
+    v.iter().collect()
+}
+
+// Formats a time in seconds since the epoch the way __DATE__ and
+// __TIME__ spell it, e.g. ("Jan  1 1970", "00:00:00").
+pub fn date_time(secs: u64) -> (String, String) {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+
+    // Days to a civil date, counting in 400-year eras from 0000-03-01.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{} {:2} {}", MONTHS[month as usize - 1], day, year);
+    (date, time)
+}
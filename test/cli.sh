@@ -0,0 +1,1330 @@
+#!/bin/sh
+# This file contains tests for the driver: command-line flags, dumps and
+# diagnostics that cannot be checked from inside a compiled program.
+
+r9cc=./target/debug/r9cc
+src=tmp-cli.c
+out=tmp-cli.out
+
+# Compiling the same file twice gives the same __DATE__ and __TIME__.
+export SOURCE_DATE_EPOCH=1700000000
+
+fail() {
+  echo "$1"
+  echo "--- output ---"
+  cat $out
+  exit 1
+}
+
+# compile <source> <flags...>
+compile() {
+  printf '%s\n' "$1" > $src
+  shift
+  $r9cc "$@" $src > $out 2>&1
+}
+
+# expect_output <expected> <source> <flags...>
+expect_output() {
+  expected="$1"
+  shift
+  compile "$@"
+  grep -qF -- "$expected" $out || fail "expected '$expected' in output of: $1"
+  echo "$1 => $expected"
+}
+
+# expect_error <message> <source> <flags...>
+expect_error() {
+  msg="$1"
+  shift
+  compile "$@" && fail "expected an error for: $1"
+  grep -qF -- "$msg" $out || fail "expected '$msg' for: $1"
+  echo "$1 => $msg"
+}
+
+# expect_preprocessed <expected> <source> <flags...>
+expect_preprocessed() {
+  expected="$1"
+  source="$2"
+  shift 2
+  compile "$source" -E "$@" || fail "-E failed for: $source"
+  printf '%s\n' "$expected" | cmp -s - $out || fail "expected '$expected' from -E for: $source"
+  echo "$source => $expected"
+}
+
+# expect_exit <status> <source> <flags...>
+# Builds and runs the program and checks its exit status.
+expect_exit() {
+  status="$1"
+  shift
+  compile "$@" || fail "failed to compile: $1"
+  cp $out tmp-cli.s
+  gcc -static -o tmp-cli tmp-cli.s || fail "failed to assemble: $1"
+  ./tmp-cli
+  actual=$?
+  [ "$actual" = "$status" ] || fail "expected exit status $status, got $actual: $1"
+  echo "$1 => exit $status"
+}
+
+# Target layout
+expect_output 'MOV r0, 4' 'int main() { int *p; return sizeof(p); }' --target test32 -dump-ir1
+expect_output 'MOV r0, 8' 'int main() { int *p; return sizeof(p); }' -dump-ir1
+expect_output 'MOV r0, 8' 'int main() { struct { char a; int *b; } s; return sizeof(s); }' --target test32 -dump-ir1
+expect_output 'MOV r0, 16' 'int main() { struct { char a; int *b; } s; return sizeof(s); }' -dump-ir1
+expect_output 'MOV r0, 4' 'int main() { return __SIZEOF_POINTER__; }' --target test32 -dump-ir1
+expect_output 'MOV r0, 8' 'int main() { return __SIZEOF_POINTER__; }' -dump-ir1
+expect_error 'no code generator for target: test32' 'int main() { return 0; }' --target test32
+expect_error 'unknown target: pdp11' 'int main() { return 0; }' --target pdp11
+
+# Generated names
+# User symbols spelled like the labels the compiler makes up must not
+# clash with them; the assembler would reject a duplicate.
+mimic='int L1; int Lend0; int L_str0; int Lstr0; int str0; int _L1; int assert_fmt;
+int Lend1() { return 3; }
+int main() {
+  char *s = "abc";
+  L1 = 1; Lend0 = 2; L_str0 = 4; Lstr0 = 8; str0 = 16; _L1 = 32; assert_fmt = 64;
+  int n = 0;
+  for (int i = 0; i < 3; i++) if (i) n++;
+  assert(n == 2);
+  return L1 + Lend0 + L_str0 + Lstr0 + str0 + _L1 + assert_fmt + Lend1() + s[2] - 99;
+}'
+expect_exit 130 "$mimic"
+for target in x86_64 test32; do
+  expect_output 'LABEL_ADDR r1, .L.str0' "$mimic" --target $target -dump-ir1
+  expect_output 'LABEL_ADDR r8, L_str0' "$mimic" --target $target -dump-ir1
+done
+
+# Token dump
+expect_output '1:1: While while' 'while break continue' -dump-tokens
+expect_output '1:7: Break break' 'while break continue' -dump-tokens
+expect_output '1:13: Continue continue' 'while break continue' -dump-tokens
+expect_output '1:1: Switch switch' 'switch case default goto' -dump-tokens
+expect_output '1:8: Case case' 'switch case default goto' -dump-tokens
+expect_output '1:13: Default default' 'switch case default goto' -dump-tokens
+expect_output '1:21: Goto goto' 'switch case default goto' -dump-tokens
+expect_output '1:14: Goto goto' '#define JUMP goto done
+JUMP;' -dump-tokens
+expect_output '1:1: Ident continued' 'continued' -dump-tokens
+
+# Macro expansion trace
+printf '%s\n' '#define LEN 3
+#define SHOW(x, y) check(#x, x + LEN, __LINE__)
+int a = SHOW(a[0], b);
+int n = LEN;
+int l = __LINE__;' > $src
+$r9cc -E --trace-macro $src 2> $out > /dev/null || fail "--trace-macro failed"
+printf '%s\n' 'SHOW at tmp-cli.c:3:9
+  x = a[0]
+  y = b
+  #x -> "a[0]"
+  __LINE__ -> 3
+  => check("a[0]", a[0] + LEN, 3)
+LEN at tmp-cli.c:2:34
+  => 3
+LEN at tmp-cli.c:4:9
+  => 3
+__LINE__ at tmp-cli.c:5:9 => 5' | diff - $out || fail "wrong --trace-macro output"
+echo "--trace-macro => SHOW, LEN, __LINE__"
+$r9cc -E --trace-macro=LEN $src 2> $out > /dev/null
+printf '%s\n' 'LEN at tmp-cli.c:2:34' '  => 3' 'LEN at tmp-cli.c:4:9' '  => 3' | diff - $out || fail "wrong --trace-macro=LEN output"
+echo "--trace-macro=LEN => LEN only"
+$r9cc --trace-macro=SHOW $src 2> $out > /dev/null
+grep -q '^SHOW at tmp-cli.c:3:9$' $out || fail "no trace without -E"
+grep -q '^LEN at' $out && fail "--trace-macro=SHOW traced LEN"
+echo "--trace-macro=SHOW => SHOW only"
+
+# Assembly comments
+expect_output '# line 3: return x + 1;' 'int main() {
+  int x = 1;
+  return x + 1;
+}' --asm-comments
+$r9cc test/test.c > tmp-cli-plain.s
+$r9cc --asm-comments test/test.c > tmp-cli-comments.s
+grep -q '# line' tmp-cli-comments.s || fail "no comments in --asm-comments output"
+grep -v '# line' tmp-cli-comments.s | cmp -s - tmp-cli-plain.s ||
+  fail "--asm-comments changed the generated code"
+echo "--asm-comments => same code"
+
+# Include path spelling
+mkdir -p tmp-cli-inc/Sub
+echo '#define VALUE 42' > tmp-cli-inc/Sub/Defs.h
+expect_output 'MOV r0, 42' '#include "tmp-cli-inc\Sub\Defs.h"
+int main() { return VALUE; }' -dump-ir1
+expect_output 'MOV r0, 42' '#include "./tmp-cli-inc/other/../sub/DEFS.H"
+int main() { return VALUE; }' -dump-ir1
+
+# Binary and non-UTF-8 input
+printf '\211PNG\r\n\032\n\000\000\000\rIHDR' > tmp-cli-inc/blob.png
+$r9cc tmp-cli-inc/blob.png > $out 2>&1 && fail "binary main input accepted"
+grep -qF "error: file 'tmp-cli-inc/blob.png' appears to be binary" $out ||
+  fail "no binary file error for the main input"
+echo "blob.png => appears to be binary"
+expect_error "file 'tmp-cli-inc/blob.png' appears to be binary" 'int x;
+#include "tmp-cli-inc/blob.png"
+int main() { return 0; }'
+grep -q "^$src:2:10: error: " $out || fail "binary include not reported at the #include"
+echo '#include "tmp-cli-inc/blob.png" => reported at 2:10'
+printf '#include "tmp-cli-inc/blob.png"\n' > tmp-cli-inc/nested.h
+expect_error "file 'tmp-cli-inc/blob.png' appears to be binary" '#include "tmp-cli-inc/nested.h"
+int main() { return 0; }'
+grep -q "^tmp-cli-inc/nested.h:1:10: error: " $out ||
+  fail "nested binary include not reported in the header"
+echo '#include "tmp-cli-inc/nested.h" => reported at nested.h:1:10'
+printf 'int main() { return 3; } // caf\351\n' > tmp-cli-inc/latin1.c
+$r9cc tmp-cli-inc/latin1.c > tmp-cli.s 2> $out || fail "non-UTF-8 input rejected"
+grep -qF "warning: file 'tmp-cli-inc/latin1.c' is not valid UTF-8" $out ||
+  fail "no warning for non-UTF-8 input"
+echo "latin1.c => warning"
+$r9cc -E tmp-cli-inc/blob.png > $out 2>&1 && fail "-E accepted binary input"
+echo "-E blob.png => error"
+printf 'int x;\nchar *s = "open;\n' > tmp-cli-inc/unclosed.h
+expect_error 'tmp-cli-inc/unclosed.h:2:11: error: ' '#include "tmp-cli-inc/unclosed.h"
+int main() { return 0 }'
+[ "$(grep -c ': error: ' $out)" = 1 ] || fail "kept going after a lexer error in a header"
+$r9cc -E tmp-cli-inc/unclosed.h > $out 2>&1 && fail "-E accepted an unclosed string"
+grep -q 'unclosed string literal' $out || fail "-E: no error for an unclosed string"
+echo "-E unclosed.h => error"
+
+# Diagnostics in and after included files
+printf 'int x;\nint y + ;\n' > tmp-cli-inc/bad.h
+printf '// header\nint w;\n' > tmp-cli-inc/good.h
+expect_error 'tmp-cli-inc/bad.h:2:7: error: ' '#include "tmp-cli-inc/bad.h"
+int main() { return 0; }'
+expect_error 'tmp-cli.c:4:23: error: ' 'int z;
+#include "tmp-cli-inc/good.h"
+
+int main() { return 0 }'
+printf 'int f() {\n  return undefined_in_header;\n}\n' > tmp-cli-inc/undef.h
+expect_error 'tmp-cli-inc/undef.h:2:10: error: ' '#include "tmp-cli-inc/undef.h"
+int main() { return f(); }'
+grep -qF 'undefined_in_header;' $out || fail "no source line for the undefined variable"
+expect_error 'tmp-cli.c:3:21: error: ' 'int f(int x);
+int main() {
+  int a = 1; return f(a, a);
+}'
+expect_error 'tmp-cli.c:2:30: error: ' '
+int main() { return __LINE__ __LINE__; }'
+rm -rf tmp-cli-inc
+
+# Empty macro arguments
+expect_preprocessed '[x|] [|] [] [|y] [(a,)]' '#define F(a, b) [a|b]
+#define G(a) [a]
+F(x,) F(,) G() F(,y) G((a,))'
+expect_preprocessed '[|2|3] [1| |3] [1|2|] [| |] x y' '#define T(a, b, c) [a|b|c]
+#define WRAP(a, b) a b
+T(,2,3) T(1,,3) T(1,2,) T(,,) WRAP(, x) WRAP(y, )'
+expect_preprocessed '"" "" "" "x" "y"' '#define S(x) #x
+#define SS(a, b) #a #b
+S() SS(,) SS(x, y)'
+expect_error 'number of parameter does not match' '#define F(a, b) a b
+F(x,,)'
+
+# Variadic macros
+expect_preprocessed 'f("a",) f("a", 1) f("a", 1, (2, 3), 4)' '#define F(fmt, ...) f(fmt, __VA_ARGS__)
+F("a") F("a", 1) F("a", 1, (2, 3), 4)'
+expect_preprocessed 'g() g(a,b , c) "a, b,c"' '#define G(...) g(__VA_ARGS__)
+#define S(...) #__VA_ARGS__
+G() G(a,b , c) S(a, b,c)'
+expect_preprocessed 'f("a") f("a", x, y)' '#define F(fmt, ...) f(fmt, ## __VA_ARGS__)
+F("a") F("a", x, y)'
+expect_error 'number of parameter does not match' '#define F(a, b, ...) a b
+F(x)'
+expect_error "')' expected after '...'" '#define F(..., a) a'
+
+# Unterminated macro calls
+expect_error "unterminated argument list invoking macro 'F': the '(' at tmp-cli.c:2:10 has no matching ')'" '#define F(a, b) a b
+int x = F(1,
+  2'
+grep -q '^tmp-cli.c:2:9: error: ' $out || fail "unterminated call not reported at the macro name"
+mkdir -p tmp-cli-inc
+printf '#define F(a, b) a b\nint x = F(1,\n' > tmp-cli-inc/open.h
+expect_error 'macro arguments cannot continue past the end of an included file' '#include "tmp-cli-inc/open.h"
+2);'
+grep -q '^tmp-cli-inc/open.h:2:9: error: ' $out || fail "unterminated call not reported in the header"
+printf 'int y;\n' > tmp-cli-inc/y.h
+expect_error "#include in the arguments of macro 'F' called at tmp-cli.c:2:10: macro arguments cannot cross a file boundary" '#define F(a, b) a b
+int x = F(1,
+#include "tmp-cli-inc/y.h"
+  2);'
+rm -rf tmp-cli-inc
+
+# Nested macros
+expect_preprocessed '1 + 1 * 1 + 1' '#define ONE 1
+#define TWO ONE+ONE
+#define FOUR TWO*TWO
+FOUR'
+expect_preprocessed 'f(3)' '#define F(x) G(x)
+#define G(x) f(x)
+#define THREE 3
+F(THREE)'
+# Only a '(' right after the name makes a function-like macro.
+expect_preprocessed '(x) [1]' '#define P (x)
+#define F(x) [x]
+P F(1)'
+expect_preprocessed '(x) (y)' "$(printf '#define P\t(x)\n#define Q/**/(y)\nP Q')"
+# Arguments are expanded before they are substituted, but not for # and ##.
+expect_preprocessed '1+1+1' '#define f(x) x+1
+f(f(1))'
+expect_preprocessed '2' '#define COMMA ,
+#define SECOND(a, b) b
+#define CALL(x) SECOND(x)
+CALL(1 COMMA 2)'
+expect_preprocessed '"ONE" "1" ONE_1' '#define ONE 1
+#define STR(x) #x
+#define XSTR(x) STR(x)
+#define CAT(a, b) a ## _ ## b
+STR(ONE) XSTR(ONE) CAT(ONE, 1)'
+
+# Self-referential macros: a name is left alone inside its own
+# expansion, as in C11 6.10.3.4 and its example 3
+expect_preprocessed 'FOO + 1 FOO + 1' '#define FOO FOO + 1
+FOO FOO'
+expect_preprocessed 'A B' '#define A B
+#define B A
+A B'
+expect_preprocessed 'F(1) + F(1) F(2) + G(2)' '#define F(x) F(x) + G(x)
+#define G(x) F(x)
+F(1) G(2)'
+expect_preprocessed 'h( 2 * (h( 2 * (z[0]))))' '#define x 3
+#define h(a) h(x * (a))
+#undef x
+#define x 2
+#define z z[0]
+h(h(z))'
+
+# static and const
+compile 'static int helper() { return 1; }
+int main() { return helper(); }' || fail "static function: compile failed"
+grep -q '^\.global helper' $out && fail "static function was made global"
+grep -q '^\.global main' $out || fail "main was not made global"
+echo "static int helper() => not global"
+expect_error "array size must be a constant, and 'n' is not a const integer global with a constant initializer; use a #define, or 'static const int n = ...;'" 'int n = 4;
+int a[n];'
+expect_error "array size must be a constant, and 'N' is not a const integer global" 'static const int N = 2;
+int main() { int N = 5; int a[N]; return 0; }'
+expect_error 'array size must be an integer constant expression' 'int f(); int a[f()];'
+expect_error 'array size is negative' 'int a[2 - 3];'
+expect_error 'initializer element is not a compile-time constant' 'int x = 3; int y = x;'
+expect_error "an 'extern' variable cannot have an initializer" 'extern int x = 3;'
+expect_error "initializer for a global of type 'struct' is not supported" 'struct { int a; } s = 1;'
+expect_error "cannot combine 'static' and 'extern'" 'static extern int x;'
+expect_error 'static local variables are not supported' 'int main() { static int x; return 0; }'
+
+# #pragma once
+mkdir -p tmp-cli-inc
+printf '#pragma once\n#ifdef ONCE\nagain\n#endif\n#define ONCE\nint once() { return 1; }\n' > tmp-cli-inc/once.h
+expect_preprocessed 'int once() { return 1; }
+int main() { return once(); }' '#include "tmp-cli-inc/once.h"
+#include "tmp-cli-inc/./once.h"
+#pragma weak and unknown
+int main() { return once(); }'
+compile '#include "tmp-cli-inc/once.h"
+#include "tmp-cli-inc/../tmp-cli-inc/once.h"
+int main() { return once(); }' || fail "#pragma once: compile failed"
+[ "$(grep -c '^once:' $out)" = 1 ] || fail "#pragma once: expected one copy of once()"
+echo "#pragma once => one copy of once()"
+rm -rf tmp-cli-inc
+
+# Include search paths
+mkdir -p tmp-cli-inc/sys tmp-cli-inc/proj/sub
+printf 'int sys() { return 1; }\n' > tmp-cli-inc/sys/sys.h
+printf '#include "local.h"\n' > tmp-cli-inc/proj/sub/a.h
+printf 'int local() { return 2; }\n' > tmp-cli-inc/proj/sub/local.h
+printf 'int other() { return 3; }\n' > tmp-cli-inc/sys/other.h
+expect_preprocessed 'int sys() { return 1; }
+int local() { return 2; }
+int other() { return 3; }' '#include <sys.h>
+#include "tmp-cli-inc/proj/sub/a.h"
+#include "other.h"' -Itmp-cli-inc/sys
+expect_preprocessed 'int sys() { return 1; }' '#include <sys.h>' -I tmp-cli-inc/proj -I tmp-cli-inc/sys
+expect_error "'sys.h' file not found, searched: ., tmp-cli-inc/proj, /usr/include" '#include "sys.h"' -Itmp-cli-inc/proj
+expect_error "'local.h' file not found, searched: ., /usr/include" '#include <local.h>'
+grep -q "^$src:1:10: error: " $out || fail "missing header not reported at its name"
+expect_error "missing terminating '>' in header name" '#include <sys.h
+int x;'
+expect_error "'local.h' file not found, searched: ., tmp-cli-none/usr/include" '#include <local.h>' --sysroot tmp-cli-none
+rm -rf tmp-cli-inc
+
+# Bundled headers
+# These are found even with no system headers on disk, as under an
+# empty --sysroot, but a header on the search path comes first.
+expect_exit 1 '#include <stdbool.h>
+int main() { bool ok = true; return ok; }' --sysroot tmp-cli-none
+expect_exit 33 '#include <stddef.h>
+#include <stdarg.h>
+int vprintf(char *fmt, va_list ap);
+typedef struct { int a; long b; } pair;
+int main() { size_t n = offsetof(pair, b); return n + (NULL == 0) + sizeof(va_list); }' --sysroot=tmp-cli-none
+expect_exit 12 '#include <stdarg.h>
+int f(int n, ...) { va_list ap; va_start(ap, n); int x = va_arg(ap, int); va_end(ap); return n + x; }
+int main() { return f(5, 7); }' --sysroot tmp-cli-none
+expect_error "'va_start' used in function with fixed arguments" '#include <stdarg.h>
+int f(int n) { va_list ap; va_start(ap, n); return n; }' --sysroot tmp-cli-none
+grep -q '^<built-in>/stdarg.h:[0-9]*:[0-9]*: error: ' $out || fail "va_start not reported in the bundled <stdarg.h>"
+mkdir -p tmp-cli-inc
+printf '#define true 7\n#define bool int\n' > tmp-cli-inc/stdbool.h
+expect_exit 7 '#include <stdbool.h>
+int main() { bool ok = true; return ok; }' -Itmp-cli-inc --sysroot tmp-cli-none
+rm -rf tmp-cli-inc
+
+# Nested includes
+# Each quoted #include is looked up next to the file it is in first.
+mkdir -p tmp-cli-inc/chain/sub/deeper
+printf '#include "sub/two.h"\nint one() { return 1 + two(); }\n' > tmp-cli-inc/chain/one.h
+printf '#include "deeper/three.h"\nint two() { return 2 + three(); }\n' > tmp-cli-inc/chain/sub/two.h
+printf '#include "../../last.h"\nint three() { return 3 + last(); }\n' > tmp-cli-inc/chain/sub/deeper/three.h
+printf 'int last() { return 4; }\n' > tmp-cli-inc/chain/last.h
+expect_exit 10 '#include "tmp-cli-inc/chain/one.h"
+int main() { return one(); }'
+i=0
+while [ $i -le 200 ]; do
+  printf '#include "d%d.h"\n' $((i + 1)) > tmp-cli-inc/d$i.h
+  i=$((i + 1))
+done
+printf 'int deepest;\n' > tmp-cli-inc/d201.h
+expect_error '#include nested more than 200 deep' '#include "tmp-cli-inc/d0.h"'
+grep -q '^tmp-cli-inc/d199.h:1:10: error: ' $out || fail "deep #include not reported at the #include"
+rm -rf tmp-cli-inc
+
+# Circular includes
+mkdir -p tmp-cli-inc
+printf '#include "self.h"\n' > tmp-cli-inc/self.h
+expect_error '#include cycle: tmp-cli-inc/self.h -> tmp-cli-inc/self.h' '#include "tmp-cli-inc/self.h"'
+grep -q '^tmp-cli-inc/self.h:1:10: error: ' $out || fail "#include cycle not reported at the #include"
+printf '#include "b.h"\nint a;\n' > tmp-cli-inc/a.h
+printf '\n#include "a.h"\n' > tmp-cli-inc/b.h
+expect_error '#include cycle: tmp-cli-inc/a.h -> tmp-cli-inc/b.h -> tmp-cli-inc/a.h' '#include "tmp-cli-inc/a.h"'
+grep -qxF '  tmp-cli-inc/a.h:1: includes tmp-cli-inc/b.h' $out || fail "#include of b.h not listed in the cycle"
+grep -qxF '  tmp-cli-inc/b.h:2: includes tmp-cli-inc/a.h' $out || fail "#include of a.h not listed in the cycle"
+# Include guards end the cycle the second time round.
+printf '#ifndef GA\n#define GA\n#include "gb.h"\nint ga() { return 1; }\n#endif\n' > tmp-cli-inc/ga.h
+printf '#ifndef GB\n#define GB\n#include "ga.h"\nint gb() { return 2; }\n#endif\n' > tmp-cli-inc/gb.h
+expect_exit 3 '#include "tmp-cli-inc/ga.h"
+int main() { return ga() + gb(); }'
+rm -rf tmp-cli-inc
+
+# __FILE__
+mkdir -p tmp-cli-inc
+printf 'char *header_file() { return __FILE__; }\n' > tmp-cli-inc/file.h
+printf '#include "tmp-cli-inc/file.h"\nint printf(char *fmt, ...);\nint main() { printf("%%s %%s\\n", __FILE__, header_file()); return 0; }\n' > $src
+$r9cc --run $src > $out 2>&1 || fail "__FILE__ program failed"
+grep -qxF 'tmp-cli.c tmp-cli-inc/file.h' $out || fail "__FILE__ did not name the main file and the header"
+echo "__FILE__ => tmp-cli.c tmp-cli-inc/file.h"
+rm -rf tmp-cli-inc
+expect_preprocessed 'x__LINE__ "tmp-cli.c" 3' '#define GLUE(a, b) a ## b
+#define ID(a) a
+GLUE(x, __LINE__) ID(__FILE__) ID(__LINE__)'
+
+# __COUNTER__
+expect_preprocessed 'int x0 ; int x1 ; 2 3
+yes' '#define CAT(a, b) a ## b
+#define XCAT(a, b) CAT(a, b)
+#define UNIQUE(p) XCAT(p, __COUNTER__)
+int UNIQUE(x); int UNIQUE(x); __COUNTER__ __COUNTER__
+#if defined(__COUNTER__) && __COUNTER__ == 4
+yes
+#endif'
+
+# #line
+expect_preprocessed '1 "tmp-cli.c"
+100 "foo.c"
+
+102 "foo.c"
+7 "foo.c"' '__LINE__ __FILE__
+#line 100 "foo.c"
+__LINE__ __FILE__
+
+__LINE__ __FILE__
+#define SEVEN 7
+#line SEVEN
+__LINE__ __FILE__'
+expect_error 'foo.c:102:' '#line 100 "foo.c"
+int f() { return __LINE__; }
+
+int main() { return 1 +; }'
+expect_output "gen.y:51:10: variable 'x' is uninitialized" 'int main() {
+#line 50 "gen.y"
+  int x;
+  return x;
+}'
+expect_error 'line number expected after #line' '#line'
+expect_error '"x" after #line is not a positive integer' '#line x'
+expect_error '"0x10" after #line is not a positive integer' '#line 0x10'
+expect_error 'line number out of range' '#line 0'
+expect_error 'invalid filename "foo"' '#line 5 foo'
+expect_error 'extra tokens at end of #line directive' '#line 5 "a" 6'
+# Included files are still found next to the file they are really in.
+mkdir -p tmp-cli-inc
+printf '#line 1 "gen/parser.y"\n#include "value.h"\n' > tmp-cli-inc/parser.h
+printf 'int value() { return 1; }\n' > tmp-cli-inc/value.h
+expect_preprocessed 'int value() { return 1; }' '#include "tmp-cli-inc/parser.h"'
+rm -rf tmp-cli-inc
+
+# -D and -U
+debug='int main() {
+#ifdef DEBUG
+  return DEBUG + 10;
+#else
+  return 3;
+#endif
+}'
+expect_exit 3 "$debug"
+expect_exit 11 "$debug" -DDEBUG=1
+expect_exit 11 "$debug" -DDEBUG
+expect_exit 15 "$debug" -D DEBUG=5
+expect_exit 3 "$debug" -DDEBUG -UDEBUG
+expect_preprocessed '__STDC__ a "b" (c)' '__STDC__ X' -U__STDC__ '-DX=a  "b" (c)'
+expect_preprocessed '__LINE__ __FILE__ __COUNTER__
+no' '__LINE__ __FILE__ __COUNTER__
+#if defined(__LINE__) || defined __FILE__ || defined __COUNTER__
+yes
+#else
+no
+#endif' -U__LINE__ -U__FILE__ -U__COUNTER__
+expect_preprocessed '7 7' '__COUNTER__ __COUNTER__' -D__COUNTER__=7
+expect_preprocessed '__LINE__ "tmp-cli.c"' '#undef __LINE__
+__LINE__ __FILE__'
+expect_error 'macro names must be identifiers: -D1x' 'int main() { return 0; }' -D1x
+expect_error 'unclosed string literal' 'int main() { return 0; }' '-DX="abc'
+
+# --version, .ident and __R9CC_VERSION__ name the same build
+version=$($r9cc --version | sed -n 's/^r9cc //p')
+[ -n "$version" ] || fail "--version printed no version"
+expect_preprocessed "\"$version\"" '__R9CC_VERSION__'
+compile 'int main() { return 0; }' || fail "failed to compile for .ident"
+grep -qxF ".ident \"r9cc $version\"" $out || fail ".ident does not name r9cc $version"
+echo "--version => $version in all three"
+
+# __DATE__, __TIME__ and __STDC__
+expect_preprocessed '"Nov 14 2023" "22:13:20" 1' '__DATE__ __TIME__ __STDC__'
+printf '%s\n' 'int printf(char *fmt, ...);' 'int main() { printf("%s\n", __DATE__); return __STDC__; }' > $src
+$r9cc --run $src > $out 2>&1
+[ $? = 1 ] || fail "__STDC__: expected exit status 1"
+grep -qxF 'Nov 14 2023' $out || fail "__DATE__: expected Nov 14 2023"
+echo "__DATE__ => Nov 14 2023, __STDC__ => 1"
+
+# Preprocessor output spacing
+expect_preprocessed 'int x = - - 1;' '#define A -
+int x = -A 1;'
+expect_preprocessed 'int x = - -1;' '#define A -
+int x = A-1;'
+expect_preprocessed 'int x = a b ;' '#define A a
+#define B b
+int x = A B;'
+expect_preprocessed 'B B' '#define CAT(x, y) x y
+CAT(B,B)'
+expect_preprocessed 'char *s = "a +b \"q\"" ;' '#define S(x) #x
+char *s = S( a  +b   "q" );'
+expect_preprocessed '"\"a\\\"b\\\\\"" "L\"\\n\"" "a->b[1]++ - -x" "a b"' '#define S(x) #x
+S("a\"b\\") S(L"\n") S(a->b[1]++ - -x) S(a
+b)'
+# Whitespace in an argument that came out of other macros is the
+# whitespace the macros had, as gcc has it.
+expect_preprocessed '"(1)+(2)" "a (1)" "a +b" "x (1)(2) y"' '#define S(x) #x
+#define XS(x) S(x)
+#define P(x) (x)
+#define E
+XS(P(1)+P( 2 )) XS(a P(1)) XS(a E+b) XS(x P(1)P(2) y)'
+
+# -E output reads back as the same tokens
+cat > $src <<'EOF'
+#define M -
+#define ID(x) x
+#define S(x) #x
+int x = -M 1; int ID(y)ID(x); x ID(+)= 1; a M> b; a ID(/)ID(/) b;
+char *s = S(a	"\n\t" '"') "c" "d"; char *f = __FILE__;
+ID(u8)"x"; ID(L)'a'; 1 ID(.)5;
+EOF
+$r9cc -E -o tmp-cli-e.c $src > $out 2>&1 || fail "-E -o failed"
+$r9cc -dump-tokens $src 2>&1 | cut -d' ' -f2- > tmp-cli-a.out
+$r9cc -dump-tokens tmp-cli-e.c 2>&1 | cut -d' ' -f2- > tmp-cli-b.out
+cmp -s tmp-cli-a.out tmp-cli-b.out || { diff tmp-cli-a.out tmp-cli-b.out > $out; fail "-E output tokenizes differently"; }
+$r9cc -E tmp-cli-e.c | cmp -s - tmp-cli-e.c || fail "-E output changes when preprocessed again"
+echo "-E => $(grep -c . tmp-cli-a.out) tokens read back unchanged"
+
+# Token pasting
+expect_preprocessed 'foobar ();
+int var2 = 12 + x + y + ac + 0;
+x1 ; += ; <<= ; [ 0.5 "s" ;' '#define GLUE(a, b) a##b
+#define GLUE3(a, b, c) a ## b ## c
+#define OBJ x ## 1
+#define OP(a, b) a %:%: b
+GLUE(foo, bar)();
+int GLUE(var, 2) = GLUE(1, 2) + GLUE(, x) + GLUE(y, ) + GLUE3(a, , c) + GLUE3(, , ) 0;
+OBJ; OP(+, =); OP(<, <=); GLUE(<, :) GLUE(., 5) GLUE("s", );'
+expect_error 'pasting "+" and "-" does not give a valid preprocessing token' '#define GLUE(a, b) a ## b
+int x = GLUE(+, -);'
+grep -q '^tmp-cli.c:2:9: error: ' $out || fail "bad paste not reported at the macro"
+expect_error "'##' cannot appear at either end of a macro expansion" '#define GLUE(a, b) ## b'
+expect_error "'##' cannot appear at either end of a macro expansion" '#define GLUE(a, b) a ##'
+expect_error "'##' cannot appear at either end of a macro expansion" '#define GLUE ## x'
+
+# #undef
+expect_preprocessed 'int a = 1 ;
+int b = FOO;
+int c = 2 ;' '#define FOO 1
+int a = FOO;
+#undef FOO
+int b = FOO;
+#undef NEVER_DEFINED
+#define FOO 2
+int c = FOO;'
+expect_error 'extra tokens at end of #undef directive' '#define FOO 1
+#undef FOO BAR'
+grep -q '^tmp-cli.c:2:12: error: ' $out || fail "extra token after #undef not reported at 2:12"
+
+# Macro redefinition
+expect_output "tmp-cli.c:2:9: 'FOO' redefined (previously defined at tmp-cli.c:1:9) [-Wmacro-redefined]" '#define FOO 1
+#define FOO 2
+int x = FOO;' -E
+grep -q '^int x = 2 ;$' $out || fail "a redefinition does not replace the macro"
+expect_output "tmp-cli.c:2:9: 'F' redefined" '#define F(a) a
+#define F(b) b' -E
+expect_output "tmp-cli.c:2:9: 'F' redefined" '#define F(a) a+1
+#define F(a) a + 1' -E
+expect_output "tmp-cli.c:1:9: 'FOO' redefined (previously defined at <command line>)" '#define FOO 2' -E -DFOO=1
+expect_output "tmp-cli.c:1:9: '__STDC__' redefined (previously defined at <built-in>)" '#define __STDC__ 2' -E
+compile '#define FOO(a, b)  a  +  b  
+#define FOO(a, b) a + b
+#define E
+#define E
+#define N 1
+#define N 1
+#include <stddef.h>
+#define NULL ((void *)0)
+#undef N
+#define N 2' -E
+grep -q redefined $out && fail "warning for a redefinition that is the same"
+echo "same redefinitions => no warning"
+compile '#define FOO 1
+#define FOO 2' -E -Wno-macro-redefined
+grep -q redefined $out && fail "-Wno-macro-redefined did not silence the warning"
+echo "-Wno-macro-redefined => no warning"
+
+# #ifdef, #ifndef and #endif
+expect_preprocessed 'int a;
+int d;' '#define FOO
+#ifdef FOO
+int a;
+#endif
+#ifndef FOO
+int b;
+#ifdef FOO
+int c;
+#endif
+#endif
+#ifdef BAR
+#include "no/such/file.h"
+#unknown_directive
+#endif
+int d;'
+expect_error 'unterminated conditional directive' 'int a;
+#ifdef FOO
+#ifndef BAR
+#endif'
+grep -q '^tmp-cli.c:2:1: error: ' $out || fail "unterminated #ifdef not reported at 2:1"
+expect_error '#endif without #if' 'int a;
+#endif'
+expect_error 'extra tokens at end of #ifdef directive' '#ifdef FOO BAR
+#endif'
+
+# Function-like macro names without arguments
+expect_preprocessed 'int x = 100 ;
+int y = (min)(1, 2);
+int min = 3, z = min;' '#define min(a, b) 100
+int x = min(1, 2);
+int y = (min)(1, 2);
+int min = 3, z = min;'
+expect_exit 3 'int min(int a, int b) { return a < b ? a : b; }
+#define min(a, b) 100
+int main() { return (min)(5, 3); }'
+# In a file, the arguments can start on a later line.
+expect_preprocessed '[f] g
+m
+h' '#define m(x) [x]
+m
+(f) g
+m
+h'
+
+# #if
+expect_preprocessed 'a
+b
+c
+d' '#define V 5
+#define TWICE(x) ((x) * 2)
+#if V > 3 && V <= 5
+a
+#endif
+#if defined(V) && !defined UNDEFINED && TWICE(3) == 6
+b
+#endif
+#if V % 3 == 2 ? defined V + defined(TWICE) == 2 : 0
+c
+#endif
+#if UNDEFINED || 0 && 1 / 0
+#if 1
+not reached
+#endif
+#elif_is_skipped_too
+#endif
+#if (1 << 4) - 16 | (2 ^ 3) == 1 && -V + 5 == 0 && __LINE__ == 18
+d
+#endif'
+expect_error 'division by zero in #if' 'int a;
+#define V 5
+#if 1 / (V - 5)
+#endif'
+grep -q '^tmp-cli.c:3:1: error: ' $out || fail "division by zero not reported at the #if"
+expect_error '#if with no expression' '#if
+#endif'
+expect_error 'missing binary operator in #if' '#if 1 2
+#endif'
+expect_error "')' expected after defined" '#if defined(X
+#endif'
+
+# defined
+for case in '#define FOO 2=big' '#define BAR 2=none' '#define FOO 0=small'; do
+  expect_preprocessed "${case#*=}" "${case%%=*}
+#if defined(FOO) && FOO > 1
+big
+#elif defined FOO
+small
+#else
+none
+#endif"
+done
+expect_preprocessed 'yes' '#define EMPTY
+#define ALIAS UNDEFINED
+#if defined EMPTY && defined(ALIAS) && !defined(UNDEFINED)
+yes
+#endif'
+expect_preprocessed 'yes' '#define HAS(x) defined(x)
+#define HAS_FOO defined FOO
+#define FOO
+#if HAS(FOO) && HAS_FOO && !HAS(BAR)
+yes
+#endif'
+
+# #else and #elif
+expect_preprocessed 'two
+nested' '#define N 2
+#if N == 1
+one
+#elif N == 2
+two
+#if 0
+#elif 1
+nested
+#else
+#endif
+#elif 1 / 0
+three
+#else
+other
+#endif'
+expect_error '#else after #else' '#if 1
+#else
+#else
+#endif'
+grep -q '^tmp-cli.c:3:1: error: ' $out || fail "second #else not reported at 3:1"
+expect_error '#elif after #else' '#if 0
+#else
+#elif 1
+#endif'
+grep -q '^tmp-cli.c:3:1: error: ' $out || fail "#elif after #else not reported at 3:1"
+expect_error '#else without #if' '#else'
+expect_error '#elif with no expression' '#if 0
+#elif
+#endif'
+
+# Macros that expand to nothing
+expect_exit 3 '#define DEBUG
+#ifdef DEBUG
+int main() { return 3; }
+#else
+int main() { return 4; }
+#endif'
+expect_exit 2 'int main() { return 2 EMPTY; }' -DEMPTY=
+expect_preprocessed 'int a = 2 ;
+int b = 2 + [] ;
+int c = 3;' '#define EMPTY
+#define F(a, b) a b
+#define G(x) [x]
+int a = F(EMPTY, 2);
+int b = F(2, EMPTY) + G(EMPTY);
+int c EMPTY = EMPTY 3;'
+expect_error "tmp-cli.c:2:21: note: 'EMPTY' expanded to nothing" '#define EMPTY
+int main() { return EMPTY; }'
+grep -q '^tmp-cli.c:2:26: error: ' $out || fail "missing operand not reported after the macro"
+expect_error "tmp-cli.c:2:12: note: 'EMPTY' expanded to nothing" '#define EMPTY
+#define E2 EMPTY
+int main() { return 1 + E2; }'
+expect_error "tmp-cli.c:2:9: note: 'EMPTY' expanded to nothing" '#define EMPTY
+#if 1 + EMPTY
+#endif'
+grep -q ': error: expected value in #if$' $out || fail "bad #if expression not reported"
+expect_error "tmp-cli.c:2:5: note: 'EMPTY' expanded to nothing" '#define EMPTY
+#if EMPTY
+#endif'
+grep -q ': error: #if with no expression$' $out || fail "empty #if expression not reported"
+# `+ 1` is still an expression.
+expect_preprocessed 'yes' '#define EMPTY
+#if EMPTY + 1
+yes
+#endif'
+
+# Digraphs and trigraphs
+for digraph in 'LeftBrace <%' 'RightBrace %>' 'LeftBracket <:' 'RightBracket :>'; do
+  expect_output "1:2: $digraph" "a${digraph#* }b" -dump-tokens
+done
+expect_preprocessed 'int a<:2:> = <% 1 %> ;' '%:define ARY(x) x<:2:> = <% 1 %>
+ARY(int a);'
+expect_exit 12 '??=define A(x) x??(1??)
+int main() ??< int a??(2??); A(a) = 12; return a??(1??) ??!??! 0 ? A(a) : 0; ??>' --trigraphs
+expect_preprocessed 'char *s = "??=??/??-";' 'char *s = "??=??/??-";'
+expect_error 'unclosed string literal' 'char *s = "??/";' --trigraphs
+
+# Comments
+expect_preprocessed 'int x = (2) + 1 ;' '#define F(x) /* a */ (x) + 1 // b
+int x = F(/* c */ 2);'
+expect_error 'unterminated comment' 'int main() { return 0; } /* never closed'
+grep -qF 'tmp-cli.c:1:26' $out || fail "unterminated comment not reported at its start"
+printf 'int main() { return 0; } // no newline' > $src
+$r9cc $src > $out 2>&1 || fail "line comment at end of file"
+echo "// at EOF => ok"
+
+# Line splicing
+expect_output '((1) + (2) + (3) + 5) ;' '#define SUM3(a, b, c) \
+  ((a) + \
+   (b) + (c) + __LINE__)
+int x =
+  SUM3(1, 2, 3);' -E
+expect_output 'int y = 5 ;' '#define ONE \
+  1
+int x = ONE \
+  ;
+int y = __LINE__;' -E
+expect_output '3:3: Semicolon ;' 'int x = 1 \
+  + \
+  ;' -dump-tokens
+expect_error 'tmp-cli.c:2:3: error: ' 'int main() { return 1 + \
+  @; }'
+grep -qxF '  @; }' $out || fail "wrong source line for an error after a splice"
+
+# && binds tighter than ||
+expect_exit 1 'int main() { return 1 || 0 && 0; }' -Wno-parentheses
+expect_exit 1 'int main() { return 0 && 0 || 1; }' -Wno-parentheses
+
+# Signedness of plain char
+expect_exit 1 'int main() { char c = 200; return c < 0; }'
+expect_exit 1 'int main() { char c = 200; return c < 0; }' -fsigned-char
+expect_exit 0 'int main() { char c = 200; return c < 0; }' -funsigned-char
+expect_exit 1 'int main() { char c = 255; return c == -1; }' -fsigned-char
+expect_exit 0 'int main() { char c = 255; return c == -1; }' -funsigned-char
+
+# Integer literals
+expect_error 'integer literal is too large' 'int main() { return 0x1ffffffffffffffff; }'
+expect_error 'integer literal is too large' 'int main() { return 99999999999999999999; }'
+expect_error "invalid suffix 'lul' on integer literal" 'int main() { return 1lul; }'
+expect_error "invalid suffix 'lL' on integer literal" 'int main() { return 1lL; }'
+expect_error "invalid digit '9' in integer literal" 'int main() { return 099; }'
+expect_error "invalid digit '2' in integer literal" 'int main() { return 0b102; }'
+expect_error 'invalid integer literal' 'int main() { return 0x; }'
+expect_error 'exponent has no digits' 'int main() { return 1e+; }'
+expect_error "invalid suffix 'x' on floating constant" 'int main() { return 1.5x; }'
+expect_error "invalid operands to binary expression ('double' and 'int')" 'int main() { return 1.5 % 2; }'
+compile 'long f() { return 4294967296; }'
+grep -q 'movabs' $out || fail "movabs for a 64-bit immediate"
+echo "movabs => ok"
+
+# Character literals
+expect_error 'multi-character character constant' "int main() { return 'ab'; }"
+expect_error 'empty character constant' "int main() { return ''; }"
+expect_error 'unclosed character literal' "int main() { return 'a; }"
+expect_error "unknown escape sequence: '\\q'" "int main() { return '\\q'; }"
+expect_error 'escape sequence out of range' "int main() { return '\\777'; }"
+
+# String literals
+expect_error "unknown escape sequence: '\\q'" 'int main() { char *p = "a\qb"; return 0; }'
+expect_error 'unclosed string literal' 'int main() { char *p = "abc; return 0; }'
+printf '%s\n' 'int printf();
+int main() { printf("a\tb\n"); return 0; }' > $src
+$r9cc $src > tmp-cli.s && gcc -static -o tmp-cli tmp-cli.s || fail "failed to build printf test"
+./tmp-cli > $out
+printf 'a\tb\n' | cmp -s - $out || fail "printf(\"a\\tb\\n\") printed the wrong bytes"
+printf '%s\n' 'printf("a\tb\n") => tab and newline'
+
+# UTF-8 source
+expect_output 'char *s = "héllo 😀";' '// コメント
+char *s = "héllo 😀"; /* ü */' -E
+expect_output '.ascii "h\303\251llo\000"' 'char *f() { return "héllo"; }'
+expect_output 'char *s = "ñ" ;' '#define S(x) #x
+char *s = S(ñ);' -E
+expect_error 'character too large for enclosing character literal type' "int main() { return 'é'; }"
+expect_error 'tmp-cli.c:1:30: error: ' 'int main() { char *s = "日本"; @ }'
+
+# Malformed tokens are reported with their position, never by a panic.
+expect_error 'unclosed string literal' 'int main() { char *p = "abc; return 0; }'
+grep -q '^tmp-cli.c:1:24: error: ' $out || fail "unclosed string not reported at 1:24"
+expect_error 'Unknown symbol.' 'int main() {
+  return 1 @ 2;
+}'
+grep -q '^tmp-cli.c:2:12: error: ' $out || fail "stray @ not reported at 2:12"
+compile 'int main() {
+  return 1 + ;
+}'
+printf '%s\n' 'tmp-cli.c:2:14: error: number expected' '  return 1 + ;' '             ^' > tmp-cli.expected
+head -3 $out | cmp -s - tmp-cli.expected || fail "diagnostic not in file:line:col: message form"
+echo "parse error => file:line:col: error: message, the line and a caret"
+for bad in '"abc' "'a" "'" "''" '"\' "'\\x'" '"\q"' "'\\777'" '@' '`' '$x' '/* x' \
+  '0x' '1e' '1.5x' '099' '1lul' "'ab'" '99999999999999999999' '"a" "b' \
+  'int main() { return "x\qy" + '"'"'\\'"'"' @ 0b; }'; do
+  compile "$bad" -dump-tokens
+  status=$?
+  [ $status = 1 ] || fail "expected exit status 1, got $status for: $bad"
+  grep -q '^tmp-cli.c:1:[0-9]*: error: ' $out || fail "no located error for: $bad"
+  grep -q 'panicked' $out && fail "tokenizer panicked on: $bad"
+done
+echo "malformed tokens => located errors"
+
+# So are parse and sema errors, with exit status 1.
+for case in '1:6|int x' '1:13|int main() {' '1:12|struct S { x; };' \
+  '1:14|int main() { break; }' '1:22|int main() { int a; a.x = 1; }' \
+  '1:22|int main() { return &1; }' '1:14|int main() { 1 = 2; }' \
+  '1:29|int main() { int a; return a->x; }' '1:24|int main() { int a[3]; a = 0; }'; do
+  at=${case%%|*}
+  bad=${case#*|}
+  compile "$bad"
+  status=$?
+  [ $status = 1 ] || fail "expected exit status 1, got $status for: $bad"
+  grep -q "^tmp-cli.c:$at: error: " $out || fail "no error at $at for: $bad"
+  grep -q 'panicked' $out && fail "compiler panicked on: $bad"
+done
+echo "parse and sema errors => located errors"
+
+# Assignability
+expect_error "array type 'int [3]' is not assignable" 'int main() { int a[3]; int *p; a = p; return 0; }'
+expect_error "array type 'int [3]' is not assignable" 'int main() { int a[3]; a += 1; return 0; }'
+expect_error "array type 'int [3]' is not assignable" 'int main() { int a[3]; a++; return 0; }'
+expect_error "array type 'int [3]' is not assignable" 'int main() { int a[3]; ++a; return 0; }'
+expect_error "array type 'char [2][4]' is not assignable" 'int main() { char a[2][4]; a = 0; return 0; }'
+expect_error "function type 'int (void)' is not assignable" 'int foo() { return 0; }
+int bar() { return 1; }
+int main() { foo = bar; return 0; }'
+expect_error "function type 'int ()' is not assignable" 'int foo();
+int main() { foo = 0; return 0; }'
+expect_error "function type 'int (int, char *)' is not assignable" 'int foo(int x, char *y);
+int main() { foo = 0; return 0; }'
+expect_error "function type 'int (char *, ...)' is not assignable" 'int foo(char *fmt, ...);
+int main() { foo = 0; return 0; }'
+
+# Parameter lists
+expect_error "too many arguments to function 'f'" 'int f(void) { return 0; }
+int main() { return f(1); }'
+expect_error "too many arguments to function 'f'" 'int f() { return 0; }
+int main() { return f(1); }'
+expect_error "too few arguments to function 'f'" 'int f(int x, int y);
+int main() { return f(1); }'
+compile 'int f();
+int main() { return f(1, 2) + f(); }' || fail "call through an unspecified parameter list failed"
+grep -q 'warning\|error' $out && fail "diagnostic for a call through an unspecified parameter list"
+echo "int f(); f(1, 2) => no diagnostic"
+expect_error "too few arguments to function 'f'" 'int f(int x, int y, ...);
+int main() { return f(1); }'
+expect_exit 140 'int f(int a, int b, int c, int d, int e, int f, int g) { return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g; }
+int main() { return f(1, 2, 3, 4, 5, 6, 7); }'
+expect_exit 47 'int f(float a, double b, double c, double d, double e, double f, double g, double h, float i, double j) { return a + b + c + d + e + f + g + h + i * 2 + j; }
+int main() { return f(1, 2, 3, 4, 5, 6, 7, 8, 1.5, 8); }'
+expect_exit 60 'int f(int a, double x, int b, int c, int d, int e, int g, long h, char i, double y) { return a + x + b + c + d + e + g + h + i + y; }
+int main() { return f(1, 2, 3, 4, 5, 6, 7, 8, 9, 15); }'
+expect_error 'RightParen expected' 'int f(int x, ..., int y);'
+expect_exit 5 'int f(int, char *);
+int main() { return f(2, "abc"); }
+int f(int n, char *s) { return n + s[2] - 96; }'
+expect_error 'parameter name omitted in function definition' 'int f(int) { return 0; }'
+
+# Function pointers
+expect_exit 13 'int compare(int a, int b) { return a - b; }
+typedef int (*cmp_fn)(int, int);
+int apply(cmp_fn f, int a, int b) { return f(a, b); }
+int main() { cmp_fn f = compare; int (*g)(int, int) = compare; return f(5, 2) + apply(compare, 10, 1) + g(1, 0); }'
+expect_error "too few arguments to function pointer of type 'int (*)(int, int)'" 'typedef int (*cmp_fn)(int, int);
+int apply(cmp_fn f) { return f(1); }'
+expect_error "too many arguments to function pointer of type 'int (*)(void)'" 'int apply(int (*f)(void)) { return (*f)(1); }'
+expect_error "called object of type 'int' is not a function" 'int main() { int x = 1; return x(2); }'
+expect_error "function 'g' cannot be declared inside a function" 'int main() { int g(int); return 0; }'
+
+# Function and statement bodies
+expect_error "expected '{' or ';' after function declarator" 'int f() return 3;'
+grep -q '^tmp-cli.c:1:9: error: ' $out || fail "missing '{' not reported at 1:9"
+expect_error "declaration cannot be the body of 'if'; put it in braces" 'int main() { int x = 1; if (x) int y = 3; return 0; }'
+grep -q '^tmp-cli.c:1:32: error: ' $out || fail "declaration under if not reported at 1:32"
+expect_error "declaration cannot be the body of 'else'; put it in braces" 'int main() { if (1) return 0; else int y; return 1; }'
+expect_error "declaration cannot be the body of 'while'; put it in braces" 'int main() { while (0) char c; return 0; }'
+expect_error "declaration cannot be the body of 'for'; put it in braces" 'int main() { for (;;) struct { int a; } s; return 0; }'
+expect_error "declaration cannot be the body of 'do'; put it in braces" 'int main() { do int y; while (0); return 0; }'
+expect_error "declaration cannot be the body of 'if'; put it in braces" 'typedef int myint;
+int main() { if (1) myint y; return 0; }'
+expect_exit 3 'int main() { int y = 1; if (y) { int y = 3; return y; } return 0; }'
+
+# Keywords are reserved
+expect_error "expected identifier, found keyword 'return'" 'int main() { int return = 3; return 0; }'
+grep -q '^tmp-cli.c:1:18: error: ' $out || fail "keyword not reported at 1:18"
+expect_error "expected identifier, found keyword 'for'" 'int for;'
+expect_error "expected identifier, found keyword 'while'" 'int while() { return 0; }'
+expect_error "expected identifier, found keyword 'if'" 'int f(int if) { return 0; }'
+expect_error "expected identifier, found keyword 'do'" 'int f(char *, int do);'
+expect_error "expected identifier, found keyword 'else'" 'int main() { struct { int else; } s; return 0; }'
+expect_error "expected identifier, found keyword 'goto'" 'int main() { struct { int x; } s; return s.goto; }'
+expect_error "expected identifier, found keyword 'switch'" 'typedef int switch;'
+expect_error "expected identifier, found keyword 'int'" 'int main() { int *int; return 0; }'
+
+# -Wparentheses
+expect_output 'tmp-cli.c:2:27: suggest parentheses around assignment used as truth value [-Wparentheses]' 'int f() { return 1; }
+int main() { int x; if (x = f()) return 1; return 0; }'
+compile 'int f() { return 1; }
+int main() { int x; if ((x = f())) return 1; return 0; }'
+grep -q warning $out && fail "warning for a parenthesized assignment"
+expect_output "tmp-cli.c:1:39: suggest parentheses around '&&' within '||'" 'int main() { int a = 0; return a || a && a; }'
+compile 'int main() { int a = 0; return a || (a && a); }'
+grep -q warning $out && fail "warning for parenthesized '&&' within '||'"
+compile 'int main() { int a = 0; return a || a && a; }' -Wno-parentheses
+grep -q warning $out && fail "-Wno-parentheses did not silence the warning"
+echo "parenthesized and -Wno-parentheses => no warning"
+
+# Uninitialized variables
+expect_output "tmp-cli.c:1:39: variable 'x' is uninitialized when used here [-Wuninitialized]" 'int main() { int x; int y = 1; return x + y; }'
+expect_output "tmp-cli.c:2:67: variable 'x' may be uninitialized when used here [-Wmaybe-uninitialized]" 'int c;
+int main() { int x; if (c) x = 1; for (;;) { if (c) x = 2; return x; } }'
+expect_output "variable 'x' may be uninitialized when used here" 'int main() { int x; for (int i = 0; i < 3; i++) { if (i) return x; x = i; } return 0; }'
+expect_output "variable 'x' may be uninitialized when used here" 'int c;
+int main() { int x; while (c) x = 1; return x; }'
+for fine in 'int c; int main() { int x; if (c) x = 1; else x = 2; return x; }' \
+  'int c; int main() { int x; if (c) x = 1; else return 0; return x; }' \
+  'int main() { int x; int *p = &x; *p = 1; return x; }' \
+  'int main() { int x; for (;;) { x = 1; break; } return x; }' \
+  'int main() { int x; while (1) { x = 1; break; } return x; }' \
+  'int c; int main() { int x; do { x = 1; } while (c); return x; }' \
+  'int main() { int a[2]; a[0] = 1; return a[0]; }' \
+  'int f(int x) { int y; y = x; return y; }'; do
+  compile "$fine" || fail "failed to compile: $fine"
+  grep -q warning $out && fail "unexpected warning for: $fine"
+done
+echo "initialized on every path => no warning"
+compile 'int main() { int x; return x; }' -Wno-uninitialized
+grep -q warning $out && fail "-Wno-uninitialized did not silence the warning"
+echo "-Wno-uninitialized => no warning"
+
+# Array bounds
+expect_output "tmp-cli.c:1:32: array index 5 is past the end of the array, which has 3 elements [-Warray-bounds]" 'int main() { int a[3]; return a[5]; }'
+expect_output "tmp-cli.c:1:25: array index 3 is past the end of the array, which has 3 elements [-Warray-bounds]" 'int main() { int a[3]; a[1 + 2] = 1; return 0; }'
+expect_output "array index 4 is past the end of the array, which has 3 elements" 'int main() { int a[3]; return a[1 << 3 >> 1]; }'
+expect_output "array index 4 is past the end of the array, which has 3 elements" 'int main() { int a[3]; int *p = &a[4]; return 0; }'
+expect_output "array index 5 is past the end of the array, which has 3 elements" 'int main() { int a[3]; return *(a + 5); }'
+expect_output "array index -1 is before the beginning of the array, which has 3 elements" 'int main() { int a[3]; return *(a - 1); }'
+expect_output "array index 2 is past the end of the array, which has 2 elements" 'int main() { struct { int v[2]; } s; s.v[2] = 0; return 0; }'
+for fine in 'int main() { int a[3]; a[0] = 1; a[2] = 3; return a[0] + *(a + 2) + 1[a]; }' \
+  'int main() { int a[3]; int *p = &a[3]; return *(p - 1); }' \
+  'int main() { int a[3]; int *p = a + 3; return *(p - 1); }' \
+  'int main() { int a[3]; int i = 5; return a[i]; }'; do
+  compile "$fine" || fail "failed to compile: $fine"
+  grep -q warning $out && fail "unexpected warning for: $fine"
+done
+echo "in range, one past the end with & and variable indexes => no warning"
+compile 'int main() { int a[3]; return a[5]; }' -Wno-array-bounds
+grep -q warning $out && fail "-Wno-array-bounds did not silence the warning"
+echo "-Wno-array-bounds => no warning"
+
+# Zeroed locals
+expect_output 'MEMSET r0, 0, 4' 'int main() { int x = 1; return x; }' -dump-ir1 --ftrivial-auto-var-init=zero
+expect_output 'MEMSET r0, 0, 24' 'int main() { struct { int a[4]; long b; } s; s.b = 0; return s.b; }' -dump-ir1 --ftrivial-auto-var-init=zero
+compile 'int main() { int x = 1; char buf[8]; return x; }' -dump-ir1
+grep -q MEMSET $out && fail "locals zeroed without --ftrivial-auto-var-init=zero"
+echo "no --ftrivial-auto-var-init => no MEMSET"
+
+# Scope of for-loop declarations
+expect_error 'undefined variable: i' 'int main() { for (int i = 0; i < 3; i++); return i; }'
+expect_error 'undefined variable: x' 'int f(int x) { return x; } int g() { return x; }'
+expect_error "redefinition of 'x'" 'int f(int x) { int x; return x; }'
+expect_error "redefinition of 'x'" 'int f(int x, int x) { return x; }'
+expect_error "redefinition of 'y'" 'int main() { int y; int y; return 0; }'
+expect_exit 2 'int f(int x) { { int x = 2; return x; } } int main() { return f(1); }'
+
+# Arrays without a length
+expect_error "invalid application of 'sizeof' to an incomplete type 'int []'" 'extern int t[]; int main() { return sizeof(t); }'
+grep -q '^tmp-cli.c:1:37: error: ' $out || fail "sizeof of an incomplete array reported at the wrong place"
+expect_error "invalid application of 'sizeof' to an incomplete type 'int []'" 'int main() { return sizeof(int[]); }'
+expect_error "array type needs an explicit size or an initializer: 'a'" 'int main() { int a[]; return 0; }'
+expect_error "conflicting types for 't': 'int []' and 'long []'" 'extern int t[]; extern long t[]; int main() { return 0; }'
+grep -q '^tmp-cli.c:1:29: error: ' $out || fail "conflicting types not reported at the second declaration"
+expect_exit 12 'extern int t[]; int *p() { return t + 1; } int t[3]; int main() { *p() = 7; return sizeof(t) + t[1] - 7; }'
+
+# Error limit and summary
+garbage=$(yes '@ x $' | head -100)
+compile "$garbage" && fail "garbage input compiled"
+[ "$(grep -c ': error: ' $out)" = 20 ] || fail "expected 20 errors by default"
+grep -q '^too many errors, stopping$' $out || fail "no 'too many errors' message"
+grep -q '^20 errors generated.$' $out || fail "wrong summary for garbage input"
+echo "garbage => 20 errors"
+compile "$garbage" --max-errors 3 && fail "garbage input compiled"
+[ "$(grep -c ': error: ' $out)" = 3 ] || fail "expected 3 errors with --max-errors 3"
+grep -q '^3 errors generated.$' $out || fail "wrong summary with --max-errors 3"
+echo "garbage --max-errors 3 => 3 errors"
+compile '@ $' || true
+[ "$(grep -c ': error: ' $out)" = 2 ] || fail "expected 2 errors"
+grep -q '^2 errors generated.$' $out || fail "wrong summary for 2 errors"
+grep -q 'too many errors' $out && fail "stopped early with 2 errors"
+echo "@ $ => 2 errors"
+expect_output '1 warning generated.' 'int main() { return foo(); }'
+
+# Code size. Raise the limits deliberately when a change is expected
+# to grow the output.
+$r9cc --stats-asm=json examples/fib.c 2> $out > /dev/null || fail "--stats-asm failed"
+insns=$(sed -n 's/.*"insns":\([0-9]*\),"calls".*/\1/p' $out | tail -1)
+calls=$(sed -n 's/.*"calls":\([0-9]*\),"spills".*/\1/p' $out | tail -1)
+[ -n "$insns" ] && [ "$insns" -le 200 ] || fail "fib.c: $insns instructions, expected at most 200"
+[ "$calls" = 4 ] || fail "fib.c: $calls call sites, expected 4"
+echo "--stats-asm => $insns instructions, $calls calls"
+$r9cc --stats-asm examples/fib.c 2> $out > /dev/null
+grep -q '^total ' $out || fail "no table from --stats-asm"
+# Calls are counted from the IR: a large struct copy is a call to
+# memcpy, a small one is not, and the name in a string is no call.
+printf '%s\n' 'typedef struct { char a[4096]; } big;
+typedef struct { long a; } small;
+void f(big *a, big *b, small *c, small *d) { char *s = "call f"; *a = *b; *c = *d; }' > $src
+$r9cc --stats-asm=json $src 2> $out > /dev/null || fail "--stats-asm failed"
+calls=$(sed -n 's/.*"calls":\([0-9]*\),"spills".*/\1/p' $out | tail -1)
+[ "$calls" = 1 ] || fail "struct copies: $calls call sites, expected 1"
+printf '%s\n' 'int f(int a, int b) { return a+(b+(a+(b+(a+(b+(a+(b+(a+(b+(a+(b))))))))))); }' > $src
+$r9cc --stats-asm=json $src 2> $out > /dev/null || fail "--stats-asm failed"
+spills=$(sed -n 's/.*"spills":\([0-9]*\),"data_bytes".*/\1/p' $out)
+[ -n "$spills" ] && [ "$spills" -gt 0 ] || fail "deep expression: '$spills' spills, expected some"
+$r9cc --stats-asm $src 2> $out > /dev/null
+grep -q "^total  *[0-9]*  *0  *$spills\$" $out || fail "no spills in the --stats-asm table"
+echo "--stats-asm => $spills spills"
+
+# Call graph
+printf '%s\n' 'int is_odd(int n);
+int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+int fact(int n) { if (n < 2) return 1; return n * fact(n - 1); }
+int leaf() { return 1; }
+int main() { return is_even(4) + fact(3) + leaf() + leaf(); }' > $src
+$r9cc --dump-callgraph $src 2> $out > /dev/null || fail "--dump-callgraph failed"
+grep -q '^is_even  *[0-9]*  *4 yes  *is_odd$' $out || fail "is_even not reported as recursive"
+grep -q '^is_odd  *[0-9]*  *4 yes  *is_even$' $out || fail "is_odd not reported as recursive"
+grep -q '^fact  *[0-9]*  *4 yes  *fact$' $out || fail "fact not reported as recursive"
+grep -q '^leaf  *[0-9]*  *0 no$' $out || fail "leaf reported with callees or as recursive"
+grep -q '^main  *[0-9]*  *0 no  *is_even, fact, leaf$' $out || fail "wrong callees for main"
+echo "--dump-callgraph => is_even <-> is_odd cycle"
+$r9cc --dump-callgraph=dot $src 2> $out > /dev/null || fail "--dump-callgraph=dot failed"
+grep -q '^  "is_even" -> "is_odd";$' $out || fail "no is_even -> is_odd edge"
+grep -q '^  "is_odd" -> "is_even";$' $out || fail "no is_odd -> is_even edge"
+grep -q '^  "is_odd" \[.*style=bold\];$' $out || fail "is_odd not marked recursive"
+grep -q '^  "leaf" \[label="leaf\\n[0-9]* insns, stack 0"\];$' $out || fail "leaf marked recursive"
+[ "$(grep -c '"main" ->' $out)" = 3 ] || fail "expected 3 edges from main"
+echo "--dump-callgraph=dot => is_even <-> is_odd cycle"
+
+# Compound conditions branch directly instead of computing 0 or 1.
+printf '%s\n' 'int skip(int *a, int n) { int i = 0; while (i < n && (a[i] == 1 || a[i] == 2)) i++; return i; }' > $src
+$r9cc --stats-asm=json $src 2> $out > /dev/null || fail "--stats-asm failed"
+insns=$(sed -n 's/.*"insns":\([0-9]*\),"calls".*/\1/p' $out | tail -1)
+[ -n "$insns" ] && [ "$insns" -le 70 ] || fail "compound loop: $insns instructions, expected at most 70"
+echo "compound loop => $insns instructions"
+
+# Signed and unsigned comparisons
+expect_output 'LT r' 'int f(int a, int b) { return a < b; }' -dump-ir1
+expect_output 'setl ' 'int f(int a, int b) { return a < b; }'
+expect_output 'LTU r' 'int f(char *p, char *q) { return p < q; }' -dump-ir1
+expect_output 'setb ' 'int f(char *p, char *q) { return p < q; }'
+expect_output 'LEU r' 'int f(char *p) { return 0 >= p; }' -dump-ir1
+expect_output 'setbe ' 'int f(char *p) { return 0 >= p; }'
+
+# Leaf functions under -O
+printf '%s\n' 'int get_x(int *p) { return p[0]; }
+int get_y(int *p) { return p[1]; }
+int sum(int a, int b) { int s = a + b; return s; }
+int zero() { return 0; }
+int addr(int a) { int *p = &a; return *p; }
+int big() { int a[40]; a[0] = 1; return a[0]; }
+int caller(int *p) { return get_x(p); }
+int main() { return 0; }' > $src
+$r9cc -O $src > tmp-cli.s || fail "-O failed"
+# body <function>: the instructions of one function
+body() {
+  sed -n "/^$1:/,/\tret$/p" tmp-cli.s
+}
+for f in get_x get_y sum zero; do
+  body $f | grep -qE 'push rbp|sub rsp' && fail "$f has a frame under -O"
+done
+for f in addr big caller; do
+  body $f | grep -q 'push rbp' || fail "$f has no frame under -O"
+done
+$r9cc $src | sed -n '/^zero:/,/\tret$/p' | grep -q 'push rbp' || fail "zero has no frame without -O"
+plain=$($r9cc --stats-asm=json $src 2>&1 >/dev/null | sed -n 's/.*"insns":\([0-9]*\),"calls".*/\1/p' | tail -1)
+opt=$($r9cc -O --stats-asm=json $src 2>&1 >/dev/null | sed -n 's/.*"insns":\([0-9]*\),"calls".*/\1/p' | tail -1)
+[ "$opt" -lt "$plain" ] || fail "-O did not shrink the accessors: $opt vs $plain instructions"
+echo "-O => accessors without frames, $plain -> $opt instructions"
+
+# _Noreturn
+for fine in 'int exit(); int f(int c) { int x; if (c) x = 1; else exit(1); return x; }' \
+  '_Noreturn void die(void); int main() { int x; die(); return x; }' \
+  'int exit(); int f() { exit(1); }' 'int f() { exit(0); }' \
+  'int f(int c) { if (c) abort(); else _Exit(2); }'; do
+  compile "$fine" || fail "failed to compile: $fine"
+  grep -q warning $out && fail "unexpected warning for: $fine"
+done
+echo "reads after exit() and a _Noreturn call, undeclared exit() => no warning"
+expect_output "tmp-cli.c:1:33: control reaches end of non-void function 'f' [-Wreturn-type]" 'int f(int c) { if (c) return 1; }'
+for fine in 'int f() { for (;;) {} }' 'void f(int c) { if (c) c = 2; }' 'int main() {}'; do
+  compile "$fine" || fail "failed to compile: $fine"
+  grep -q warning $out && fail "unexpected warning for: $fine"
+done
+compile 'int f(int c) { if (c) return 1; }' -Wno-return-type
+grep -q warning $out && fail "-Wno-return-type did not silence the warning"
+echo "falling off the end => -Wreturn-type"
+expect_output 'r0 = abort() noreturn' 'void abort(); int main() { abort(); return 42; }' -dump-ir1
+expect_output 'mov r10, 42' 'void abort(); int main() { abort(); return 42; }'
+compile 'void abort(); int main() { abort(); return 42; }' -O
+grep -q ', 42$' $out && fail "-O kept the code after abort()"
+echo "-O => no code after abort()"
+expect_error "'_Noreturn' can only appear on functions" '_Noreturn int x;'
+
+# Conditions test as many bytes as their type has
+compile 'int f(long l, int i, char *p) { if (l) return 1; while (i) return 2; return p ? 3 : 4; }' -dump-ir1
+[ "$(grep -oE '(IF|UNLESS)[0-9]+' $out | tr '\n' ' ')" = 'UNLESS8 UNLESS4 UNLESS8 ' ] || fail "wrong condition widths"
+echo "long, int and pointer conditions => UNLESS8 UNLESS4 UNLESS8"
+
+# IR optimizer
+expect_output 'ADD r0, 1' 'int main() { int x = 3; return x + 2 - 1; }' -O -dump-ir2
+grep -qE 'MOV r[0-9]+, [12]$' $out && fail "-O left an added constant in a register"
+expect_exit 11 'int main() { int x = 3; return x * 4 - 2 + 1; }' -O
+expect_error 'internal compiler error: pass fold-imm on main: ran out of fuel for rewrites' 'int main() { int x = 3; return x + 2 - 1; }' -O --opt-fuel 1
+expect_output 'MOV r1, 2' 'int main() { int x = 3; return x + 2 - 1; }' -dump-ir2
+
+# --run
+mkdir -p tmp-cli-tmpdir
+run() {
+  printf '%s\n' "$1" > $src
+  shift
+  TMPDIR=tmp-cli-tmpdir $r9cc --run "$@" $src > $out 2>&1
+}
+run 'int main() { return 42; }'
+[ $? = 42 ] || fail "--run: expected exit status 42"
+[ -z "$(ls tmp-cli-tmpdir)" ] || fail "--run left temporaries behind"
+echo "--run => exit 42"
+TMPDIR=tmp-cli-none $r9cc --run $src > $out 2>&1 && fail "--run: built under a missing TMPDIR"
+grep -q '^cannot create tmp-cli-none/r9cc-[0-9a-f]*: ' $out || fail "--run: missing TMPDIR not reported"
+echo "--run under a missing TMPDIR => error"
+for status in '300 44' '-1 255' '256 0'; do
+  set -- $status
+  run "int main() { return $1; }"
+  [ $? = $2 ] || fail "--run: expected main returning $1 to exit with $2"
+  echo "return $1 => exit $2"
+done
+$r9cc 2>&1 | grep -qF 'masked to' || fail "usage does not say how --run masks the exit status"
+run 'int printf(char *fmt, ...);
+int main() {
+  printf("none\n");
+  printf("%d\n", 1);
+  printf("%d %d\n", 1, 2);
+  printf("%d %s %c\n", 1, "two", 51);
+  return printf("%d%d%d%d\n", 1, 2, 3, 4);
+}'
+[ $? = 5 ] || fail "--run: printf through a variadic prototype"
+printf 'none\n1\n1 2\n1 two 3\n1234\n' > tmp-cli-expected
+grep -v '^/usr/bin/ld' $out | cmp -s - tmp-cli-expected || fail "wrong output from printf"
+echo "int printf(char *fmt, ...) => 1 to 4 arguments"
+run 'int printf(char *fmt, ...);
+double half(double x) { return x / 2; }
+int main() {
+  float f = 2.25f;
+  printf("%.2f %.2f %g\n", half(3), f, 1e3 + 0.5);
+  return 0;
+}'
+printf '1.50 2.25 1000.5\n' > tmp-cli-expected
+grep -v '^/usr/bin/ld' $out | cmp -s - tmp-cli-expected || fail "wrong output from printf with doubles"
+echo "printf with doubles => $(cat tmp-cli-expected)"
+run 'int dirty() { int a[8]; for (int i = 0; i < 8; i++) a[i] = 7; return a[7]; }
+int garbage() { int x; char buf[16]; struct { int a; long b; } s; return x + buf[15] + s.b; }
+int main() { dirty(); return garbage(); }' --ftrivial-auto-var-init=zero
+[ $? = 0 ] || fail "--ftrivial-auto-var-init=zero: locals were not zeroed"
+grep -qF "variable 'x' is uninitialized when used here" $out ||
+  fail "--ftrivial-auto-var-init=zero silenced -Wuninitialized"
+echo "--ftrivial-auto-var-init=zero => exit 0, still warns"
+run 'int main() { int *p = 0; return *p; }'
+[ $? = 139 ] || fail "--run: expected 139 for a segfault"
+[ -z "$(ls tmp-cli-tmpdir)" ] || fail "--run left temporaries behind after a crash"
+echo "--run segfault => exit 139"
+run 'int rax; int main() { return rax; }' && fail "--run: assembler failure went unnoticed"
+grep -q 'assembler failed' $out || fail "--run: no assembler failure message"
+[ -z "$(ls tmp-cli-tmpdir)" ] || fail "--run left temporaries behind after an assembler failure"
+echo "--run bad asm => assembler failed"
+mkdir -p tmp-cli-save
+echo 'int main() { return 3; }' > tmp-cli-save/prog.c
+TMPDIR=tmp-cli-tmpdir $r9cc --run --save-temps tmp-cli-save/prog.c > $out 2>&1
+[ $? = 3 ] || fail "--run --save-temps: expected exit status 3"
+[ "$(ls tmp-cli-save | tr '\n' ' ')" = "prog.c prog.out prog.s " ] ||
+  fail "--save-temps left $(ls tmp-cli-save), expected prog.c prog.out prog.s"
+[ -z "$(ls tmp-cli-tmpdir)" ] || fail "--save-temps wrote to TMPDIR"
+echo "--run --save-temps => prog.s prog.out"
+rm -r tmp-cli-save
+rm -r tmp-cli-tmpdir
+
+# Built-in assert
+printf '%s\n' 'int main() {
+  int x = 2;
+  assert(x == 2);
+  assert(x + 1 == 4);
+  return 0;
+}' > $src
+$r9cc $src > tmp-cli.s && gcc -static -o tmp-cli tmp-cli.s || fail "failed to build assert test"
+./tmp-cli > $out 2>&1 && fail "failed assert did not exit with an error"
+grep -qF "$src:4: assertion failed: x + 1 == 4" $out || fail "wrong assert message"
+echo "assert => $(cat $out)"
+# __FILE__ in the expansion names the file assert() is used in.
+mkdir -p tmp-cli-inc
+printf 'int check(int x) {\n  assert(x > 0);\n  return x;\n}\n' > tmp-cli-inc/check.h
+printf '%s\n' '#include "tmp-cli-inc/check.h"' 'int main() { return check(0); }' > $src
+$r9cc $src > tmp-cli.s && gcc -static -o tmp-cli tmp-cli.s || fail "failed to build assert-in-header test"
+./tmp-cli > $out 2>&1 && fail "failed assert in a header did not exit with an error"
+grep -qF "tmp-cli-inc/check.h:2: assertion failed: x > 0" $out || fail "assert in a header did not name the header"
+echo "assert in a header => $(cat $out)"
+rm -rf tmp-cli-inc
+
+# IR input
+# A .ir file is read as the IR that -dump-ir1 prints.
+printf '%s\n' 'int sq(int x) { return x * x; }
+int main() { int i; int n = 0; for (i = 0; i < 4; i++) n = n + sq(i); return n; }' > $src
+$r9cc -dump-ir1 $src 2> tmp-cli.ir > /dev/null || fail "-dump-ir1 failed"
+$r9cc --run tmp-cli.ir > $out 2>&1
+[ $? = 14 ] || fail "IR read back from -dump-ir1 did not run the same"
+echo "-dump-ir1 output => runs again as tmp-cli.ir"
+printf '%s\n' 'main():' '  MOV r0, 1' '  JUMP .L1' > tmp-cli.ir
+$r9cc tmp-cli.ir > $out 2>&1 && fail "bad IR was accepted"
+grep -qF "tmp-cli.ir: line 3: unknown op 'JUMP': JUMP .L1" $out || fail "wrong error for bad IR"
+echo "JUMP .L1 => $(head -1 $out)"
+printf '%s\n' 'main():' '  MOV r0, 1' '  JMP L1' > tmp-cli.ir
+$r9cc tmp-cli.ir > $out 2>&1 && fail "label without the .L prefix was accepted"
+grep -qF "tmp-cli.ir: line 3: label expected, not 'L1': JMP L1" $out || fail "wrong error for a bad label"
+echo "JMP L1 => $(head -1 $out)"
+
+# Backend errors
+# IR the backend has no instructions for is given to it directly.
+printf '%s\n' 'f():' '  MOV r0, 1' '  RET r0' '  KILL r0' \
+  'main():' '  BPREL r1, 12' '  LOAD12 r1, r1' '  RET r1' '  KILL r1' > tmp-cli.ir
+$r9cc tmp-cli.ir > tmp-cli.s 2> $out && fail "backend error went unnoticed"
+[ -s tmp-cli.s ] && fail "partial assembly written after a backend error"
+grep -qF 'cannot generate code for main: IR #1 (LOAD12 r0, r0): unsupported operand size 12' $out ||
+  fail "wrong backend error: $(cat $out)"
+rm -f tmp-cli-o.s
+$r9cc -o tmp-cli-o.s tmp-cli.ir > $out 2>&1 && fail "-o: backend error went unnoticed"
+[ -e tmp-cli-o.s ] && fail "-o file written after a backend error"
+mkdir -p tmp-cli-tmpdir
+TMPDIR=tmp-cli-tmpdir $r9cc --run tmp-cli.ir > $out 2>&1 && fail "--run: backend error went unnoticed"
+[ -z "$(ls tmp-cli-tmpdir)" ] || fail "--run wrote files after a backend error"
+rm -r tmp-cli-tmpdir
+echo "backend error => $(head -1 $out)"
+
+echo OK
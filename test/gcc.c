@@ -1,3 +1,10 @@
 // This file is compiled by gcc.
 
  int global_arr[1] = {5};
+ int global_table[3] = {7, 8, 9};
+
+// gcc returns all of x in rax, so the upper half is left over.
+int narrow(long x) { return x; }
+
+// rsp is 16-aligned at a call, so the frame this sets up is too.
+int stack_aligned(void) { return ((long)__builtin_frame_address(0) & 15) == 0; }
@@ -1,8 +1,11 @@
+#include <stdarg.h>
+
 extern void *stderr;
 
-int printf();
-int fprintf();
+int printf(char *fmt, ...);
+int fprintf(void *fp, char *fmt, ...);
 int exit();
+int strcmp(char *a, char *b);
 
 #define EXPECT(expected, expr)                                  \
   do {                                                          \
@@ -26,10 +29,68 @@ int add2(int (*a)[2]) { return a[0][0] + a[1][0]; }
 int add3(int a[][2]) { return a[0][0] + a[1][0]; }
 int add4(int a[2][2]) { return a[0][0] + a[1][0]; }
 void nop() {}
+int no_params(void) { return 3; }
+int unspecified();
+int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+
+#define SWAP_INC(a, b) do { int t_ = a; a = b; b = t_; a++; } while (0)
+int swap_if(int c) {
+  int x = 1;
+  int y = 2;
+  if (c)
+    SWAP_INC(x, y);
+  else
+    x = 100;
+  return x * 10 + y;
+}
+int elvis_count;
+int elvis_next(int v) { elvis_count++; return v; }
+int skip_ones_twos(int *a, int n) {
+  int i = 0;
+  while (i < n && (a[i] == 1 || a[i] == 2))
+    i++;
+  return i;
+}
+int assert_if(int c) {
+  if (c)
+    assert(c);
+  else
+    return 2;
+  return 1;
+}
+
+int dirty_stack() { int a[100]; for (int i = 0; i < 100; i++) a[i] = 7; return a[99]; }
+int zero_init_sum() {
+  int a[100] = {1, 2};
+  int s = 0;
+  for (int i = 0; i < 100; i++) s = s + a[i];
+  return s;
+}
 
 int var1;
 int var2[5];
 extern int global_arr[1];
+extern int global_table[];
+int table_at(int i) { return global_table[i]; }
+int narrow(long x);
+int narrow_if() { if (narrow(4294967296)) return 1; return 0; }
+int narrow_and() { return narrow(4294967296) && 1; }
+int narrow_or() { return narrow(4294967296) || 0; }
+int narrow_cond() { return narrow(4294967296) ? 1 : narrow(4294967296) ?: 0; }
+int wide_if() { long l = 4294967296; if (l) return 1; return 0; }
+int wide_ptr() { char *p = 0; p = p + 4294967296; return p ? 1 : 0; }
+int *table_next(int i) { return global_table + i + 1; }
+
+extern int later[];
+int later_set() { later[0] = 3; later[4] = 4; return later[0] + later[4]; }
+int later[5];
+int later_size() { return sizeof(later); }
+int later_sum() { return later[0] + later[4]; }
+_Noreturn void die(int status);
+void die(int status) { exit(status); }
+int die_unless(int c) { if (c) return 3; die(1); }
+int die_or_set(int c) { int x; if (c) x = 4; else die(1); return x; }
 typedef int myint;
 
 // Single-line comment test
@@ -38,15 +99,363 @@ typedef int myint;
  * Multi-line comment test *
  ***************************/
 
+#define PLUS_ONE(x) /* block */ (x) + 1 // line
+int commented_args() {
+  return plus(/* first */ PLUS_ONE(2), // second
+              4);
+}
+
+#define SUM3(a, b, c) \
+  ((a) +               \
+   (b) + (c))
+int spliced() { return SUM3(1, 2, 3) * 10 + SUM3(4, 5, 6); }
+
+int twice(int x) { return x * 2; }
+
+#define UNDEF_ME 10
+int undef_before() { return UNDEF_ME; }
+#undef UNDEF_ME
+int undef_after() { int UNDEF_ME = 3; return UNDEF_ME; }
+#undef NEVER_DEFINED
+
+#include "test/guard.inc"
+#include "test/guard.inc"
+#include "test/once.inc"
+#include "test/../test/once.inc"
+#pragma GCC diagnostic ignored "-Wall"
+#ifdef GUARD_INC
+int guard_seen() { return 1; }
+#endif
+#ifndef GUARD_INC
+int guard_seen() { return 2; }
+#endif
+#ifdef UNDEF_ME
+#include "no/such/file.h"
+#endif
+
+#if defined(GUARD_INC) && __SIZEOF_INT__ * 2 == 8 && !defined(NEVER_DEFINED)
+int if_taken() { return 1; }
+#endif
+#if defined UNDEF_ME || NEVER_DEFINED + 1 != 1
+int if_taken() { return 2; }
+#endif
+
+int chain_one() {
+#if __SIZEOF_INT__ == 8
+  return 1;
+#elif __SIZEOF_INT__ == 4
+  return 2;
+#else
+  return 3;
+#endif
+}
+int chain_two() {
+#ifdef NEVER_DEFINED
+  return 1;
+#elif defined(GUARD_INC) && 0
+  return 2;
+#else
+  return 3;
+#endif
+}
+int chain_three() {
+#if 1
+  return 1;
+#elif 1 / 0
+  return 2;
+#else
+  return 3;
+#endif
+}
+
+int pick(int a, int b) { return a < b ? a : b; }
+#define pick(a, b) 100
+int pick_macro() { return pick(3, 4); }
+int pick_func() { return (pick)(3, 4); }
+int pick_var() { int pick = 7; return pick + 1; }
+
+char *main_file() { return __FILE__; }
+char *macro_file() { return GUARD_WHERE; }
+int stdc() { return __STDC__; }
+int date_len() { return sizeof(__DATE__); }
+int time_len() { return sizeof(__TIME__); }
+
+int foobar() { return 7; }
+#define GLUE(a, b) a##b
+#define GLUE3(a, b, c) a ## b ## c
+int pasted_call() { return GLUE(foo, bar)(); }
+int pasted_var() { int GLUE(var, 2) = 5; return var2 + GLUE(1, 0); }
+int pasted_empty() { int xy = 3; return GLUE3(x, , y) + GLUE(, 4); }
+
+int va_sum(int a, int b, int c) { return a + b + c; }
+int va_twice(int a) { return a * 2; }
+#define CALL(f, ...) f(__VA_ARGS__)
+#define CALL_ALL(...) va_sum(__VA_ARGS__)
+int va_none() { return CALL(foobar); }
+int va_one() { return CALL(va_twice, 5); }
+int va_three() { return CALL(va_sum, 1, (2, 3), 4); }
+int va_only() { return CALL_ALL(1, 2, 3); }
+
+#define ONE 1
+#define TWO ONE+ONE
+#define FOUR TWO*TWO
+int nested_four() { return FOUR; }
+int nested_call() { return CALL(CALL_ALL, TWO, TWO, 0); }
+
+#define ADD(a, b) ((a) + (b))
+#define SQUARE(x) ((x) * (x))
+#define STR(x) #x
+#define XSTR(x) STR(x)
+int nested_args() { return SQUARE(ADD(1, SQUARE(2))); }
+
+static const int TABLE_SIZE = 64;
+const int HALF_TABLE = TABLE_SIZE / 2;
+int table[TABLE_SIZE];
+int half_table[HALF_TABLE + 1];
+int global_init = 5;
+char global_char = -1;
+static int static_first(const char *s) { return s[0]; }
+int table_len() { return sizeof(table) / sizeof(table[0]); }
+int half_table_len() { return sizeof(half_table) / sizeof(half_table[0]); }
+int hidden_const(int HALF_TABLE) { const int n = 2; return HALF_TABLE * n; }
+int stringized_args() { return strcmp(STR(ONE), "ONE") == 0 && strcmp(XSTR(ONE), "1") == 0; }
+int stringized_literals() {
+  return strcmp(STR("a\"b\\" '\'' '"'), "\"a\\\"b\\\\\" '\\'' '\"'") == 0 &&
+         strcmp(XSTR(ADD(1,2)+x), "((1) + (2))+x") == 0;
+}
+
+%:define DIGRAPH_STR(x) %:x
+int digraphs() <%
+  int a<:2:>;
+  a<:0:> = 3;
+  a<:1:> = 4;
+  return a<:0:> * 10 + a<:1:>;
+%>
+char *digraph_str() { return DIGRAPH_STR(<:); }
+
+int mylen(char *s) { int n = 0; while (s[n]) n++; return n; }
+int größe(int n) { return n * 2; } // UTF-8 in identifiers and comments: ½
+char *mycpy(char *d, char *s) { char *p = d; while ((*p++ = *s++)); return d; }
+double fhalf(double x) { return x / 2; }
+float fsquare(float x) { return x * x; }
+double fmix(int a, double b, int c, float d) { return a + b + c + d; }
+int fsign(double x) { if (x < 0) return -1; if (x > 0) return 1; return 0; }
+// Seven integer and floating point arguments, and eight of floating
+// point, all still go in registers.
+double fmix7(int a, int b, int c, int d, int e, int f, double g) { return a + b + c + d + e + f + g; }
+double fsum8(double a, double b, double c, double d, double e, double f, double g, double h) {
+  return a + b + c + d + e + f + g + h;
+}
+double global_double = 2.5;
+float global_float = -0.75f;
+
+int stack_aligned();
+int use_rbx(int x) { return x + (x * (x + (x * (x + 1)))); }
+int keeps_rbx(int a, int b, int c) { return a + (b + (c + use_rbx(2))); }
+int odd_frame() { char c[3]; c[0] = 1; return stack_aligned() + c[0]; }
+int big_frame() {
+  char buf[300];
+  double d = 1.5;
+  __builtin_memset(buf, 7, 300);
+  int aligned = stack_aligned();
+  return aligned + buf[299] + fhalf(d * 4) + keeps_rbx(100, 20, 3);
+}
+
+int compare(int a, int b) { return a - b; }
+typedef int (*cmp_fn)(int, int);
+typedef int cmp_t(int, int);
+cmp_fn last_cmp;
+int apply(cmp_fn f, int a, int b) { return f(a, b); }
+int apply_deref(cmp_t *f, int a, int b) { return (*f)(a, b); }
+int apply_twice(int (*f)(int, int), int a) { return f(f(a, a), a); }
+cmp_fn pick_cmp(int i) { return i ? plus : compare; }
+int call_typedef() { cmp_fn f = compare; return f(1, 2); }
+int call_table(int i) {
+  int (*fns[2])(int, int);
+  fns[0] = compare;
+  fns[1] = &plus;
+  return fns[i](10, 3);
+}
+int call_member() {
+  struct { cmp_fn op; int x; } s;
+  s.op = plus;
+  s.x = 4;
+  return s.op(s.x, 1);
+}
+double fcall(double (*f)(double), double x) { return f(x); }
+
+// Nested deeper than there are registers, so some are spilled.
+int spill_deep(int a, int b) { return a+(b+(a+(b+(a+(b+(a+(b+(a+(b+(a+(b))))))))))); }
+int spill_branch(int a, int b) { return b+(a+(b+(a+(b+(a+(b+(a+(a ? (a+(b+(a+(b+(a+(b+(a+(b)))))))) : 0)))))))); }
+int spill_loop(int a, int b) {
+  return a+(b+(a+(b+(a+(b+(a+(b+(a+(({ int i; int s = 0; for (i = 0; i < 3; i++) s = s + (a+(b+(a+(b+(a+(b+(a+(b)))))))); return s; }))))))))));
+}
+
+// Arguments past the sixth, spilled registers and a frame larger than
+// the red zone, all in one function.
+int stack8(int a, int b, int c, int d, int e, int f, int g, int h) { return a+b+c+d+e+f+g*h; }
+int frame_mix(int a, int b, int c, int d, int e, int f, int g, int h) {
+  char big[4096];
+  big[0] = g;
+  big[4095] = h;
+  return stack8(a, b, c, d, e, f, g, h) + (a+(b+(a+(b+(a+(b+(a+(b+(a+(b+(big[0]+big[4095])))))))))));
+}
+
+int vararg_sum(int n, ...) {
+  va_list ap;
+  va_start(ap, n);
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s = s + va_arg(ap, int);
+  va_end(ap);
+  return s;
+}
+double vararg_mixed(int n, double x, ...) {
+  va_list ap;
+  va_list aq;
+  va_start(ap, x);
+  va_copy(aq, ap);
+  double s = n + x;
+  for (int i = 0; i < n; i++) {
+    s = s + va_arg(ap, int);
+    s = s + va_arg(ap, double);
+  }
+  s = s + va_arg(aq, int) * 100;
+  va_end(ap);
+  va_end(aq);
+  return s;
+}
+
 int main() {
   EXPECT(0, 0);
   EXPECT(1, 1);
   EXPECT(493, 0755);
   EXPECT(48879, 0xBEEF);
   EXPECT(255, 0Xff);
+  EXPECT(27, 0x10 + 010 + 0b11);
+  EXPECT(5, 0B101);
+  EXPECT(0, 0);
+  EXPECT(-1, 0xffffffff);
+  EXPECT(1, (int)(1L << 40 >> 40));
+  EXPECT(1, ({ long x = 4294967296; return x >> 32; }));
+  EXPECT(0, ({ long x = 4294967296; return (int)x; }));
+  EXPECT(1, ({ long x = 0x100000000; return x == 4294967296LL; }));
+  EXPECT(8, sizeof(1L));
+  EXPECT(8, sizeof(2147483648));
+  EXPECT(4, sizeof(2147483647));
+  EXPECT(4, sizeof(0xffffffff));
+  EXPECT(4, sizeof(1u));
+  EXPECT(8, sizeof(1ul));
+  EXPECT(8, sizeof(1LLU));
+  EXPECT(2147483647, 4294967295u >> 1);
+  EXPECT(2147483647, 4294967295u / 2);
+  EXPECT(1, 4294967295u % 2);
+  EXPECT(0, ({ long x = 4294967295u; return x < 0; }));
+  EXPECT(1, (long)4294967295u == 4294967295L);
+  EXPECT(1, (long)0xffffffff == 4294967295L);
+  EXPECT(-1, -1 >> 1u);
+  EXPECT(2147483647, (0u - 1) / 2);
+  EXPECT(2147483647, -1u >> 1);
+  EXPECT(-1, (int)4294967295u);
+  EXPECT(0, -1 < 1u);
+  EXPECT(1, -1 > 1u);
+  EXPECT(0, -1 < 1ul);
+  EXPECT(1, -1L < 1u);
+  EXPECT(1, 1u >= -1L);
+  EXPECT(8, ({ long long int x; return sizeof(x); }));
+  EXPECT(8, ({ int a = 1; return sizeof(a + 1L); }));
+  EXPECT(44, (char)300);
+
+  EXPECT(-4, -sizeof(int));
+  EXPECT(-1, (long)-1);
+  EXPECT(8, sizeof((long)-1));
+  EXPECT(-6, -twice(3));
+  EXPECT(1, ({ struct { int flag; } s; s.flag = 0; struct { int flag; } *p = &s; return !p->flag; }));
+  EXPECT(0, ({ struct { int flag; } s; s.flag = 5; struct { int flag; } *p = &s; return !p->flag; }));
+  EXPECT(8, sizeof(int) * 2);
+  EXPECT(7, sizeof (long) - 1);
+  EXPECT(4, sizeof -1);
+  EXPECT(4, sizeof -twice(1));
+  EXPECT(45, (char)300 + 1);
+  EXPECT(1, -(char)-1);
+  EXPECT(-1, !sizeof(char) - 1);
+  EXPECT(6, -(long)-2 * 3);
+  EXPECT(1, !(char)256);
+  EXPECT(12, sizeof(int[3]));
+  EXPECT(8, sizeof(char *));
+  EXPECT(8, _Alignof(long));
+  EXPECT(-8, -sizeof(long) * 1);
+  EXPECT(3, ({ int x = 3; int *p = &x; return *&*p; }));
+  EXPECT(-3, ({ int x = 3; int *p = &x; return -*p; }));
+  EXPECT(0, ({ int x = 3; int *p = &x; return !*p; }));
+  EXPECT(4, ({ int x = 3; return (long)++x; }));
+  EXPECT(1, (int)4294967297);
+  EXPECT(1, ({ int x = -1; return x < 0; }));
+  EXPECT(-3, ({ int x = -6; return x / 2; }));
+  EXPECT(-2, ({ int x = -6; return x % 4; }));
+  EXPECT(-3, ({ int x = -6; return x >> 1; }));
+  EXPECT(3, (int)3.99);
+  EXPECT(-3, (int)-3.99);
+  EXPECT(15, ({ double d = 1.5; return d * 10; }));
+  EXPECT(1, 0.5 == .5);
+  EXPECT(1, 1e3 == 1000);
+  EXPECT(1, 2.5e-1 == 0.25);
+  EXPECT(8, sizeof(1.0));
+  EXPECT(4, sizeof(1.0f));
+  EXPECT(8, ({ long double d; return sizeof(d); }));
+  EXPECT(1, ({ double d = 1.5; float f = 2.25f; return d < f; }));
+  EXPECT(0, ({ double d = 1.5; float f = 2.25f; return d > f; }));
+  EXPECT(1, ({ double d = 1.5; return d <= 1.5 && d >= 1.5 && !(d != 1.5); }));
+  EXPECT(3, ({ int i = 7; return i / 2.0 == 3.5 ? 3 : 0; }));
+  EXPECT(-1, ({ double d = 1.5; return -d == -1.5 ? -1 : 0; }));
+  EXPECT(3, ({ double d = 1.5; d += 1; d++; return d; }));
+  EXPECT(2, ({ float f = 2.75f; int i = f; return i; }));
+  EXPECT(1, ({ double z = -0.0; return z ? 0 : 1; }));
+  EXPECT(1, ({ double z = -0.0; return !z; }));
+  EXPECT(5, (int)(fhalf(5) * 2));
+  EXPECT(1, fsquare(1.5f) == 2.25);
+  EXPECT(7, (int)fmix(1, 2.5, 3, 0.5f));
+  EXPECT(-1, fsign(-0.5));
+  EXPECT(1, fsign(3));
+  EXPECT(1, fmix7(1, 2, 3, 4, 5, 6, 0.5) == 21.5);
+  EXPECT(1, fsum8(0.5, 1, 2, 3, 4, 5, 6, 7) == 28.5);
+  EXPECT(1, ({ double (*p)(double, double, double, double, double, double, double, double) = fsum8; return p(1, 1, 1, 1, 1, 1, 1, 1.5) == 8.5; }));
+  EXPECT(5, (int)(global_double * 2));
+  EXPECT(1, global_float == -0.75);
   EXPECT(2, 1+1);
   EXPECT(10, 2*3+4);
   EXPECT(26, 2*3+4*5);
+
+  // Each adjacent pair of precedence levels, both ways round, with a
+  // value that depends on the grouping. || against && is in cli.sh, as
+  // it warns.
+  EXPECT(0, 0 && 0 | 1);
+  EXPECT(0, 1 | 0 && 0);
+  EXPECT(1, 1 | 1 ^ 1);
+  EXPECT(1, 1 ^ 1 | 1);
+  EXPECT(1, 1 ^ 1 & 0);
+  EXPECT(1, 0 & 1 ^ 1);
+  EXPECT(0, 2 & 2 == 2);
+  EXPECT(0, 2 == 2 & 2);
+  EXPECT(0, 2 == 1 < 2);
+  EXPECT(1, 3 < 2 == 0);
+  EXPECT(0, 2 > 1 == 0);
+  EXPECT(1, 1 < 1 << 1);
+  EXPECT(0, 1 << 1 < 1);
+  EXPECT(4, 1 << 1 + 1);
+  EXPECT(4, 1 + 1 << 1);
+  EXPECT(7, 1 + 2 * 3);
+  EXPECT(7, 2 * 3 + 1);
+  EXPECT(-5, -2 * 3 + 1);
+  // Every binary operator groups left to right.
+  EXPECT(5, 10 - 3 - 2);
+  EXPECT(8, 64 / 4 / 2);
+  EXPECT(1, 7 % 4 % 2);
+  EXPECT(4, 256 >> 4 >> 2);
+  EXPECT(1, 2 < 1 < 1);
+  EXPECT(0, 3 > 2 > 1);
+  EXPECT(1, 1 == 2 == 0);
+  EXPECT(0, 2 != 2 != 0);
   EXPECT(5, 50/10);
   EXPECT(9, 6*3/2);
   EXPECT(45, (2+3)*(4+5));
@@ -65,6 +474,13 @@ int main() {
   EXPECT(6, mul(2, 3));
   EXPECT(21, add(1,2,3,4,5,6));
 
+  EXPECT(1, is_even(10));
+  EXPECT(0, is_odd(10));
+  EXPECT(1, is_odd2(7));
+  EXPECT(0, is_even2(7));
+  EXPECT(is_even(9), is_even2(9));
+  EXPECT(is_odd(9), is_odd2(9));
+
   EXPECT(0, 0 || 0);
   EXPECT(1, 1 || 0);
   EXPECT(1, 0 || 1);
@@ -95,6 +511,17 @@ int main() {
   EXPECT(1, 5 >= 5);
   EXPECT(1, 6 >= 5);
 
+  EXPECT(1, -1 < 1);
+  EXPECT(1, -1 <= -1);
+  EXPECT(0, 1 <= -1);
+  EXPECT(1, -2147483647 - 1 < 0);
+  EXPECT(1, -1L < 1L);
+  // Addresses compare unsigned, even near the top of the address space.
+  EXPECT(1, (char *)-1 > (char *)1);
+  EXPECT(1, (char *)1 < (char *)-1);
+  EXPECT(1, (char *)-1 >= (char *)-2);
+  EXPECT(0, (char *)-1 <= (char *)1);
+
   EXPECT(8, 1 << 3);
   EXPECT(4, 16 >> 2);
 
@@ -113,6 +540,36 @@ int main() {
 
   EXPECT(5, 0 ? 3 : 5);
   EXPECT(3, 1 ? 3 : 5);
+  EXPECT(5, elvis_next(5) ?: 7);
+  EXPECT(7, elvis_next(0) ?: 7);
+  EXPECT(2, elvis_count);
+  EXPECT(8, sizeof(1 ? 1.5 : 2));
+  EXPECT(8, sizeof(1 ? 1 : 2L));
+  EXPECT(4, sizeof(1 ? 'a' : 'b'));
+  EXPECT(5, (int)((0 ? 1.5 : 2) * 2.5));
+  EXPECT(1, fsign(elvis_next(0) ?: 0.5));
+  EXPECT(6, (int)((elvis_next(3) ?: 0.5) * 2));
+  EXPECT(8, ({ int *p = 0; return sizeof(0 ? 0 : p); }));
+
+  EXPECT(0, ({ elvis_count=0; int r=0; if (0 && elvis_next(1)) r=9; return r+elvis_count; }));
+  EXPECT(1, ({ elvis_count=0; int r=0; if (1 || elvis_next(1)) r=1; return r+elvis_count*10; }));
+  EXPECT(21, ({ elvis_count=0; int r=0; if (elvis_next(1) && elvis_next(0)) r=5; else r=1; return r+elvis_count*10; }));
+  EXPECT(25, ({ elvis_count=0; int r=0; if (elvis_next(0) || elvis_next(2)) r=5; else r=1; return r+elvis_count*10; }));
+  EXPECT(3, ({ int r=0; if (!(0 || 0)) r=3; return r; }));
+  EXPECT(4, ({ int r=0; if (!(1 && 0) && !0) r=4; return r; }));
+  EXPECT(3, ({ int a[4]; a[0]=1; a[1]=2; a[2]=1; a[3]=5; return skip_ones_twos(a, 4); }));
+  EXPECT(2, ({ int a[4]; a[0]=1; a[1]=2; a[2]=1; a[3]=5; return skip_ones_twos(a, 2); }));
+  EXPECT(0, ({ int a[1]; a[0]=3; return skip_ones_twos(a, 1); }));
+  EXPECT(6, ({ int i=0; do { i++; } while (i < 3 || !(i > 5)); return i; }));
+  EXPECT(1, ({ int i=0; do { i++; } while (i > 3 && i < 5); return i; }));
+  EXPECT(1, ({ int x=1; int y=0; return x && !y ? 1 : 2; }));
+  EXPECT(2, ({ int x=1; int y=0; return x && y ? 1 : 2; }));
+  EXPECT(1, ({ int x=0; int y=3; return x || y ? 1 : 2; }));
+  EXPECT(10, ({ int s=0; for (int i=0; i<10 && !(i==5 && 0); i++) s++; return s; }));
+  EXPECT(1, 0 || 2);
+  EXPECT(0, 0 || 0);
+  EXPECT(1, 3 && 2);
+  EXPECT(3, 0 ?: 0 ?: 3);
 
   EXPECT(3, (1, 2, 3));
 
@@ -121,6 +578,19 @@ int main() {
   EXPECT(5, 6 ^ 3);
   EXPECT(2, 6 & 3);
   EXPECT(0, 6 & 0);
+  EXPECT(6, (6 & 3) | (1 ^ 5));
+  EXPECT(3, 1 | 2 ^ 3 & 4);
+  EXPECT(1, 3 == 3 & 1);
+  EXPECT(3, 7 & 2 + 1);
+  EXPECT(0, 1 ^ 1 && 1);
+  EXPECT(1, 2 | 1 || 0);
+  EXPECT(6, ({ int x=6; return x & *&x; }));
+  EXPECT(4, ({ char c=3; return sizeof(c & c); }));
+  EXPECT(4, ({ char c=3; return sizeof(c | c); }));
+  EXPECT(4, ({ char c=3; return sizeof(c ^ c); }));
+  EXPECT(4, ({ char c=3; return sizeof(c == c); }));
+  EXPECT(4, ({ char c=3; return sizeof(c && c); }));
+  EXPECT(4, ({ int *p=0; return sizeof(p < p); }));
 
   EXPECT(45, ({ int x=0; int y=0; do { y=y+x; x=x+1; } while (x < 10); return y; }));
 
@@ -130,10 +600,16 @@ int main() {
   EXPECT(60, ({ int sum=0; int i; for (i=10; i<15; i=i+1) sum = sum + i; return sum;}));
   EXPECT(89, ({ int i=1; int j=1; for (int k=0; k<10; k=k+1) { int m=i+j; i=j; j=m; } return i;}));
   EXPECT(1, ({ int i=1; for (int i = 5; i < 10; i++); return i; }));
+  EXPECT(13, ({ int s=0; for (int i=0; i<3; i++) s=s+i; for (int i=0; i<5; i++) s=s+i; return s; }));
+  EXPECT(7, ({ for (int i=0; i<3; i++); int i=7; return i; }));
   EXPECT(5, ({ int i=0; for (0; i < 10; i++) if (i==5) break; return i; }));
   EXPECT(10, ({ int i=0; for (;;) { i++; if (i==10) break; } return i; }));
 
   EXPECT(45, ({ int i=0; int j=0; while (i<10) { j=j+i; i=i+1; } return j;}));
+  EXPECT(10, ({ int i=0; while (i<10) i++; return i; }));
+  EXPECT(3, ({ int i=3; while (0) i++; return i; }));
+  EXPECT(7, ({ int i=7; while (i<5) i=0; return i; }));
+  EXPECT(30, ({ int n=0; for (int i=0; i<3; i++) { int j=0; while (j<i*5) { j++; n++; } } return n*2; }));
 
   EXPECT(3, ({ int ary[2]; *ary=1; *(ary+1)=2; return *ary + *(ary+1);}));
   EXPECT(5, ({ int x; int *p = &x; x = 5; return *p;}));
@@ -147,6 +623,17 @@ int main() {
   EXPECT(5, ({ int x; int *p = &x; x = 5; return p[0];}));
   EXPECT(1, ({ int ary[2]; ary[0]=1; ary[1]=2; int *p=ary; return *p++;}));
   EXPECT(2, ({ int ary[2]; ary[0]=1; ary[1]=2; int *p=ary; return *++p;}));
+  EXPECT(20, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i * 10; int *p = a; p += 2; return *p; }));
+  EXPECT(30, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i * 10; int *p = a + 2; p++; return *p; }));
+  EXPECT(40, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i * 10; int *p = a + 3; return *++p; }));
+  EXPECT(10, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i * 10; int *p = a + 4; p -= 3; return *p; }));
+  EXPECT(0, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i * 10; int *p = a + 1; p--; return *p; }));
+  EXPECT(50, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i * 10; int *p = a; long n = 5; p += n; return *p; }));
+  EXPECT(123, ({ int a[6]; for (int i = 0; i < 6; i++) a[i] = i; int *p = a; int s = 0; s = s * 10 + *(p += 1); s = s * 10 + *++p; p++; s = s * 10 + *p; return s; }));
+  EXPECT(4, ({ int a[6]; int *p = a; int *q = p++; return (long)p - (long)q; }));
+  EXPECT(8, ({ long a[4]; long *p = a + 3; p -= 1; p--; return (long)p - (long)a; }));
+  EXPECT(24, ({ int a[3][2]; int (*p)[2] = a; p += 3; return (long)p - (long)a; }));
+  EXPECT('d', ({ char *c = "abcdef"; c += 4; c--; return *c; }));
 
   EXPECT(1, ({ char x; return sizeof x; }));
   EXPECT(4, ({ int x; return sizeof(x); }));
@@ -163,12 +650,34 @@ int main() {
   EXPECT(5, ({ char x = 5; return x; }));
   EXPECT(42, ({ int x = 0; char *p = &x; p[0] = 42; return x; }));
 
+  EXPECT(1, 'A' == 65 && '\n' == 10);
+  EXPECT(0, '\0');
+  EXPECT(65, '\101');
+  EXPECT(65, '\x41');
+  EXPECT(9, '\t');
+  EXPECT(13, '\r');
+  EXPECT(92, '\\');
+  EXPECT(39, '\'');
+  EXPECT(34, '\"');
+  EXPECT(-1, '\xff');
+
   EXPECT('a', ({ char *p = "abc"; return p[0]; }));
   EXPECT('b', ({ char *p = "abc"; return p[1]; }));
   EXPECT('c', ({ char *p = "abc"; return p[2]; }));
   EXPECT(0, ({ char *p = "abc"; return p[3]; }));
   EXPECT(7, sizeof("abc" "def"));
   EXPECT(9, sizeof("ab\0c" "\0def"));
+  EXPECT(5, sizeof("a\tb\n"));
+  EXPECT(9, "a\tb\n"[1]);
+  EXPECT(10, "a\tb\n"[3]);
+  EXPECT(0, "a\tb\n"[4]);
+  EXPECT(4, sizeof("\x41\102\\"));
+  EXPECT(66, "\x41\102\\"[1]);
+  EXPECT(92, "\x41\102\\"[2]);
+  EXPECT(34, "\"'"[0]);
+  EXPECT(39, "\"\'"[1]);
+  EXPECT(-1, "\377"[0]);
+  EXPECT(49, "\0001"[1]);
 
   EXPECT(1, ({ int x = 1; { int x = 2; } return x; }));
 
@@ -177,6 +686,41 @@ int main() {
   EXPECT(20, sizeof(var2));
   EXPECT(15, ({ var2[0] = 5; var2[4] = 10; return var2[0] + var2[4]; }));
   EXPECT(5, global_arr[0]);
+  EXPECT(141, keeps_rbx(100, 20, 3));
+  EXPECT(2, odd_frame());
+  EXPECT(152, big_frame());
+
+  EXPECT(-1, call_typedef());
+  EXPECT(9, apply(compare, 10, 1));
+  EXPECT(11, apply(plus, 10, 1));
+  EXPECT(4, apply_deref(compare, 7, 3));
+  EXPECT(12, apply_twice(plus, 4));
+  EXPECT(5, pick_cmp(1)(2, 3));
+  EXPECT(-1, pick_cmp(0)(2, 3));
+  EXPECT(7, call_table(0));
+  EXPECT(13, call_table(1));
+  EXPECT(5, call_member());
+  EXPECT(4, fcall(fhalf, 8));
+  EXPECT(8, sizeof(cmp_fn));
+  EXPECT(1, ({ cmp_fn f = compare; return f == compare; }));
+  EXPECT(1, ({ cmp_fn f = compare; return f == &compare; }));
+  EXPECT(0, ({ cmp_fn f = compare; return f == plus; }));
+  EXPECT(3, ({ last_cmp = plus; return last_cmp(1, 2); }));
+  EXPECT(-2, ({ int (*g)(int, int) = compare; return g(1, 3); }));
+  EXPECT(15, ({ int (*p)(int,int,int,int,int,int) = add; return p(0,1,2,3,4,5); }));
+  EXPECT(8, table_at(1));
+  EXPECT(0, narrow_if());
+  EXPECT(0, narrow_and());
+  EXPECT(0, narrow_or());
+  EXPECT(0, narrow_cond());
+  EXPECT(1, wide_if());
+  EXPECT(1, wide_ptr());
+  EXPECT(9, *table_next(1));
+  EXPECT(7, later_set());
+  EXPECT(20, later_size());
+  EXPECT(7, later_sum());
+  EXPECT(3, die_unless(1));
+  EXPECT(4, die_or_set(1));
 
   EXPECT(8, ({ return 3 + ({ return 5; }); }));
 
@@ -184,11 +728,19 @@ int main() {
 
   EXPECT(4, ({ struct { int a; } x; return sizeof(x); }));
   EXPECT(8, ({ struct { char a; int b; } x; return sizeof(x); }));
+  EXPECT(7, ({ struct { int a; int b; int c; } x; x.c = 7; x; return x.c; }));
+  EXPECT(7, ({ struct { int a; int b; int c; } x; x.c = 7; 0 ? x : x; return x.c; }));
   EXPECT(12, ({ struct { char a; char b; int c; char d; } x; return sizeof(x); }));
   EXPECT(3, ({ struct { int a; } x; x.a=3; return x.a; }));
   EXPECT(8, ({ struct { char a; int b; } x; x.a=3; x.b=5; return x.a+x.b; }));
   EXPECT(8, ({ struct { char a; int b; } x; struct { char a; int b; } *p = &x; x.a=3; x.b=5; return p->a+p->b; }));
   EXPECT(8, ({ struct tag { char a; int b; } x; struct tag *p = &x; x.a=3; x.b=5; return p->a+p->b; }));
+  EXPECT(57, ({ struct pt { int x; int y; } s; struct pt *p = &s; p->x = 5; p->y = 7; return s.x*10+s.y; }));
+  EXPECT(78, ({ struct pt { int x; int y; } s; struct pt *p = &s; p->x = 5; p->y = 7; p->x += 2; p->y++; return p->x*10+p->y; }));
+  EXPECT(9, ({ struct pt { int x; int y; } s; struct pt *p = &s; int *q = &p->y; *q = 9; return s.y; }));
+  EXPECT(4, ({ struct { int a[3]; int b; } s; struct { int a[3]; int b; } *p = &s; p->a[1] = 4; return s.a[1]; }));
+  EXPECT(10, ({ struct in { int x; } b; struct out { int y; struct in *in; } a; a.in = &b; b.x = 5; struct out *p = &a; p->in->x = p->in->x * 2; return b.x; }));
+  EXPECT(3, ({ struct pt { char c; int y; } s; struct pt *p = &s; p->c = 3; return (*p).c; }));
   EXPECT(48, ({ struct { struct { int b; int c[5]; } a[2]; } x; return sizeof(x); }));
 
   EXPECT(8, ({
@@ -218,9 +770,122 @@ int main() {
   EXPECT(1, ({ int i=5; i&=3; return i; }));
   EXPECT(6, ({ int i=5; i^=3; return i; }));
   EXPECT(7, ({ int i=5; i|=3; return i; }));
+  EXPECT(3, ({ int a[4]; a[0]=1; a[1]=2; a[2]=3; int *p=a; p+=2; return *p; }));
+  EXPECT(2, ({ int a[4]; a[0]=1; a[1]=2; a[2]=3; int *p=a+2; p-=1; return *p; }));
+  EXPECT(4, ({ int a[4]; a[3]=4; int *p=a; int n=3; p+=n; return *p; }));
+  EXPECT(9, ({ char s[4]; s[3]=9; char *p=s; p+=3; return *p; }));
+  EXPECT(11, ({ int a[2]; a[0]=1; a[1]=2; int *p=a; *p++ += 10; return a[0]; }));
+  EXPECT(2, ({ int a[2]; a[0]=1; a[1]=2; int *p=a; *p++ += 10; return *p; }));
+  EXPECT(51, ({ int a[2]; a[0]=1; a[1]=2; int i=0; a[i++] *= 5; return a[0]*10+i; }));
+  EXPECT(65, ({ int x=1; int y=2; x += y += 3; return x*10+y; }));
+  EXPECT(6, ({ int x=2; int y=3; x *= y; return x; }));
 
   EXPECT(11, ({ int x[3] = {10, 11, 12}; return x[1]; }));
+  EXPECT(3, (dirty_stack(), zero_init_sum()));
+  EXPECT(0, ({ char x[5] = {1}; return x[4]; }));
+  EXPECT(3, ({ int x[] = {1, 2, 3,}; return sizeof(x) / sizeof(x[0]); }));
+  EXPECT(3, ({ int x[] = {1, 2, 3}; return sizeof(x) / sizeof(x[0]); }));
+  EXPECT(1, ({ char x[] = {5,}; return sizeof(x); }));
+  EXPECT(6, ({ int x[] = {1, 2, 3,}; return x[0] + x[1] + x[2]; }));
+  EXPECT(0, ({ int x[4] = {1, 2,}; return x[2] + x[3]; }));
+
+  EXPECT(24, ({ struct t { int a; int b[4]; int c; } x; return sizeof(x); }));
+  EXPECT(15, ({ struct t { int a; int b[4]; int c; } x; struct t y; x.a=1; x.b[3]=5; x.c=9; y=x; return y.a+y.b[3]+y.c; }));
+  EXPECT(9, ({ struct t { int a; int b[4]; int c; } x; x.c=9; struct t y = x; char *p = &y; return p[20]; }));
+  EXPECT(3, ({ struct { int a; int b; } x[2]; x[0].b=3; x[1]=x[0]; return x[1].b; }));
+
+  EXPECT('h', ({ char a[8]; __builtin_memset(a, 'x', 8); __builtin_memcpy(a, "hi", 2); return a[0]; }));
+  EXPECT('i', ({ char a[8]; __builtin_memset(a, 'x', 8); __builtin_memcpy(a, "hi", 2); return a[1]; }));
+  EXPECT('x', ({ char a[8]; __builtin_memset(a, 'x', 8); __builtin_memcpy(a, "hi", 2); return a[7]; }));
+  EXPECT(7, ({ int a[100]; int b[100]; for (int i = 0; i < 100; i++) a[i] = i; __builtin_memcpy(b, a, 400); return b[7]; }));
+  EXPECT(9, ({ int a[50]; int b[50]; a[49] = 9; __builtin_memcpy(b, a, 200); return b[49]; }));
+  EXPECT(0, ({ int a[100]; int n = 400; a[99] = 1; __builtin_memset(a, 0, n); return a[99]; }));
+
+  EXPECT(5, mylen("hello"));
+  EXPECT(0, mylen(""));
+  EXPECT(1, ({ char s[4]; s[0]='a'; s[1]=0; s[2]='b'; s[3]='c'; return mylen(s); }));
+  EXPECT(5, ({ char b[8]; mycpy(b, "hello"); return mylen(b); }));
+  EXPECT('o', ({ char b[8]; mycpy(b, "hello"); return b[4]; }));
+  EXPECT(0, ({ char b[8]; __builtin_memset(b, 'x', 8); mycpy(b, "hi"); return b[2]; }));
+  EXPECT('x', ({ char b[8]; __builtin_memset(b, 'x', 8); mycpy(b, "hi"); return b[3]; }));
+  EXPECT(3, ({ char b[8]; return mylen(mycpy(b, "abc")); }));
+  EXPECT(6, mylen("héllo"));
+  EXPECT(4, mylen("😀"));
+  EXPECT(5, sizeof("😀"));
+  EXPECT(9, mylen("日本" "語"));
+  EXPECT(0xa9, ({ char *s = "é"; return s[1] & 255; }));
+  EXPECT(3, mylen("\xc3\xa9" "a"));
+  EXPECT(8, größe(4));
+
+  EXPECT(31, swap_if(1));
+  EXPECT(1002, swap_if(0));
+  EXPECT(7, commented_args());
+  EXPECT(75, spliced());
+  EXPECT(10, undef_before());
+  EXPECT(3, undef_after());
+  EXPECT(42, guarded());
+  EXPECT(1, guard_nested());
+  EXPECT(1, guard_seen());
+  EXPECT(1, included_once());
+  EXPECT(1, if_taken());
+  EXPECT(0, strcmp(main_file(), "test/test.c"));
+  EXPECT(0, strcmp(guard_file(), "test/guard.inc"));
+  EXPECT(0, strcmp(macro_file(), "test/test.c"));
+  EXPECT(12, sizeof(__FILE__));
+  EXPECT(1, stdc());
+  EXPECT(12, date_len());
+  EXPECT(9, time_len());
+  EXPECT(2, chain_one());
+  EXPECT(3, chain_two());
+  EXPECT(1, chain_three());
+  EXPECT(100, pick_macro());
+  EXPECT(7, pasted_call());
+  EXPECT(15, pasted_var());
+  EXPECT(7, pasted_empty());
+  EXPECT(7, va_none());
+  EXPECT(10, va_one());
+  EXPECT(8, va_three());
+  EXPECT(6, va_only());
+  EXPECT(3, nested_four());
+  EXPECT(4, nested_call());
+  EXPECT(25, nested_args());
+  EXPECT(64, table_len());
+  EXPECT(33, half_table_len());
+  EXPECT(64, TABLE_SIZE);
+  EXPECT(5, global_init);
+  EXPECT(-1, global_char);
+  EXPECT('x', static_first("xy"));
+  EXPECT(6, hidden_const(3));
+  EXPECT(1, stringized_args());
+  EXPECT(1, stringized_literals());
+  EXPECT(3, pick_func());
+  EXPECT(8, pick_var());
+  EXPECT(34, digraphs());
+  EXPECT(2, mylen(digraph_str()));
+  EXPECT(':', digraph_str()[1]);
+  EXPECT(3, no_params());
+  EXPECT(5, unspecified(8, 3));
+  EXPECT(3, /* /* */ 3);
+  EXPECT(5, 5 /**/ /* // */);
+  EXPECT(1, assert_if(1));
+  EXPECT(2, assert_if(0));
+  EXPECT(18, spill_deep(1, 2));
+  EXPECT(24, spill_branch(1, 2));
+  EXPECT(8, spill_branch(0, 2));
+  EXPECT(49, spill_loop(1, 2));
+  EXPECT(107, frame_mix(1, 2, 3, 4, 5, 6, 7, 8));
+  EXPECT(6, vararg_sum(3, 1, 2, 3));
+  EXPECT(55, vararg_sum(10, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10));
+  EXPECT(186, vararg_mixed(6, 1.5, 1, 2.0, 3, 4.0, 5, 6.0, 7, 8.0, 9, 10.0, 11, 12.5));
+
+  assert(1);
+  assert(3 == 1 + 2);
+  assert(one() && !0);
 
   printf("OK\n");
   return 0;
  }
+
+int unspecified(int a, int b) { return a - b; }
+int is_odd2(int n) { if (n == 0) return 0; return is_even2(n - 1); }
+int is_even2(int n) { if (n == 0) return 1; return is_odd2(n - 1); }
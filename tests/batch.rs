@@ -0,0 +1,138 @@
+// compile_many compiles each input on its own: an error in one leaves
+// the others alone, and each comes out as it would by itself, whatever
+// else the process compiles at the same time.
+
+extern crate r9cc;
+
+use r9cc::symbol::Symbol;
+use r9cc::{compile_many, CompileError, Options, SourceInput};
+
+use std::fs;
+use std::process::Command;
+use std::thread;
+
+const FIRST: &str = "int x = 3;
+int add(int a, int b) { return a + b; }
+int main() { return add(x, 4); }";
+
+const BROKEN: &str = "int main() { return 1 + ; }";
+
+const LAST: &str =
+    "int main() { char *s = \"hello\"; int n = 0; while (s[n]) n++; return n * 10; }";
+
+fn text(name: &str, src: &str) -> SourceInput {
+    SourceInput::Text(name.to_string(), src.to_string())
+}
+
+// Assembles and runs the program, and returns its exit status.
+fn run(name: &str, asm: &[u8]) -> i32 {
+    let dir = std::env::temp_dir();
+    let s = dir.join(format!("r9cc-batch-{}.s", name));
+    let exe = dir.join(format!("r9cc-batch-{}", name));
+    fs::write(&s, asm).unwrap();
+    let ok = Command::new("gcc")
+        .arg("-static")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&s)
+        .status()
+        .unwrap()
+        .success();
+    assert!(ok, "gcc failed on {}", name);
+    Command::new(&exe).status().unwrap().code().unwrap()
+}
+
+#[test]
+fn error_in_one_file_does_not_stop_the_others() {
+    let inputs = [
+        text("first.c", FIRST),
+        text("broken.c", BROKEN),
+        text("last.c", LAST),
+    ];
+    let mut results = compile_many(&inputs, &Options::default());
+    assert_eq!(results.len(), 3);
+
+    match results[1] {
+        Err(CompileError::Fatal(ref e)) => {
+            assert_eq!(e.msg, "number expected");
+            assert_eq!((e.filename.as_str(), e.line, e.col), ("broken.c", 1, 25));
+            assert!(e.to_string().contains("return 1 + ; }"), "{}", e);
+        }
+        ref r => panic!("expected an error for broken.c, got {:?}", r),
+    }
+
+    let last = results.pop().unwrap().unwrap();
+    let first = results.remove(0).unwrap();
+    assert_eq!(run("first", &first.asm), 7);
+    assert_eq!(run("last", &last.asm), 50);
+    assert_eq!(first.stats.funcs.len(), 2);
+
+    // The same as compiling it alone, labels and all.
+    let alone = compile_many(&[text("last.c", LAST)], &Options::default());
+    assert_eq!(alone[0].as_ref().unwrap().asm, last.asm);
+}
+
+#[test]
+fn options_apply_to_every_file() {
+    let opts = Options {
+        macros: vec![("N".to_string(), Some("5".to_string()))],
+        optimize: true,
+        ..Options::default()
+    };
+    let inputs = [
+        text("a.c", "int main() { return N; }"),
+        text("b.c", "int main() { return N * 2; }"),
+    ];
+    let results = compile_many(&inputs, &opts);
+    assert_eq!(run("a", &results[0].as_ref().unwrap().asm), 5);
+    assert_eq!(run("b", &results[1].as_ref().unwrap().asm), 10);
+}
+
+#[test]
+fn warnings_come_back_with_the_output() {
+    let inputs = [
+        text("warn.c", "int main() { int x; return x; }"),
+        text("quiet.c", "int main() { return 0; }"),
+    ];
+    let results = compile_many(&inputs, &Options::default());
+    let warnings = &results[0].as_ref().unwrap().warnings;
+    assert_eq!(warnings.len(), 1);
+    assert!(
+        warnings[0].starts_with("warning: warn.c:1:28: variable 'x' is uninitialized"),
+        "{}",
+        warnings[0]
+    );
+    assert!(results[1].as_ref().unwrap().warnings.is_empty());
+}
+
+#[test]
+fn symbols_outlive_compilations() {
+    let kept = Symbol::intern("kept_across_inputs");
+    let inputs = [text(
+        "a.c",
+        "int kept_across_inputs; int main() { return 0; }",
+    )];
+    compile_many(&inputs, &Options::default());
+    compile_many(&inputs, &Options::default());
+    assert_eq!(kept.to_string(), "kept_across_inputs");
+    assert_eq!(Symbol::intern("kept_across_inputs"), kept);
+}
+
+#[test]
+fn threads_compile_at_the_same_time() {
+    let alone = compile_many(&[text("last.c", LAST)], &Options::default());
+    let alone = &alone[0].as_ref().unwrap().asm;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                let inputs = [text("broken.c", BROKEN), text("last.c", LAST)];
+                compile_many(&inputs, &Options::default())
+            })
+        })
+        .collect();
+    for t in threads {
+        let results = t.join().unwrap();
+        assert!(matches!(results[0], Err(CompileError::Fatal(_))));
+        assert_eq!(&results[1].as_ref().unwrap().asm, alone);
+    }
+}
@@ -0,0 +1,28 @@
+// __COUNTER__ belongs to the Preprocessor, so each compilation counts
+// from 0 again, even in the same process.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::tokenize_source;
+use r9cc::TokenType;
+
+fn counters(ctx: &mut Preprocessor) -> Vec<i64> {
+    let src = "#define TWICE __COUNTER__ __COUNTER__\nTWICE\n";
+    tokenize_source("counter.c".into(), src, ctx)
+        .unwrap()
+        .into_iter()
+        .filter_map(|t| match t.ty {
+            TokenType::Num(n, ..) => Some(n),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn counter_is_per_preprocessor() {
+    let mut ctx = Preprocessor::new();
+    assert_eq!(counters(&mut ctx), [0, 1]);
+    assert_eq!(counters(&mut ctx), [2, 3]);
+    assert_eq!(counters(&mut Preprocessor::new()), [0, 1]);
+}
@@ -0,0 +1,157 @@
+// Runs every file in tests/crashes/ through r9cc::compile and checks
+// that it comes back, with assembly or with an error, instead of
+// panicking, hanging or eating all memory. Run with `cargo test`.
+//
+// Each file is an input that once crashed the compiler, cut down to
+// what still triggers the bug. When someone reports an internal
+// compiler error, minimize their input, add it here as
+// tests/crashes/<what-it-does>.c once the fix is in, and say in its
+// name what used to go wrong, e.g. unclosed-string.c. Inputs that
+// still crash belong in an issue, not here.
+//
+// A case that must be rejected says how on its first line, e.g.
+// `// expect: 2:6: error: Semicolon expected`, and the first line of
+// the error has to be that, after the file name. Other cases may
+// compile or fail, as long as they do it cleanly.
+//
+// Each case runs in a child process, this test run again for that one
+// file, so that a case that hangs can be killed and one that runs out
+// of memory takes only itself down.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10); // per case
+const MEMORY_LIMIT: usize = 512 << 20; // in bytes, live at once
+
+// Refuses allocations past MEMORY_LIMIT, which aborts the case instead
+// of letting it take the machine down with it.
+struct Limited;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Limited {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size() > MEMORY_LIMIT {
+            LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+            return std::ptr::null_mut();
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Limited = Limited;
+
+// Set in the child process to the case it compiles.
+const CASE_VAR: &str = "R9CC_CRASH_CASE";
+
+// What became of a case, as the child prints it.
+const OUTCOME: &str = "outcome: ";
+
+// In the child: compiles the case and prints the outcome.
+fn run_case(path: String) {
+    let mut ctx = Preprocessor::new();
+    let outcome = match r9cc::compile(path, &mut ctx) {
+        Ok(_) => "ok".to_string(),
+        Err(e) => e.to_string().lines().next().unwrap_or_default().to_string(),
+    };
+    println!("{}{}", OUTCOME, outcome);
+}
+
+// Runs the case in a child process, and returns its outcome, or why
+// there is none.
+fn spawn_case(path: &str) -> Result<String, String> {
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(["crashes", "--exact", "--nocapture", "--test-threads=1"])
+        .env(CASE_VAR, path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot run: {}", e))?;
+    // Read as the child writes, so that it never waits on a full pipe.
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let stdout = thread::spawn(move || {
+        let mut s = String::new();
+        stdout.read_to_string(&mut s).ok();
+        s
+    });
+    let stderr = thread::spawn(move || {
+        let mut s = String::new();
+        stderr.read_to_string(&mut s).ok();
+        s
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format!("still running after {:?}", TIMEOUT));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let stdout = stdout.join().unwrap();
+    let stderr = stderr.join().unwrap();
+    // The test harness may have begun the line already.
+    let outcome = stdout.lines().find_map(|l| Some(l.split_once(OUTCOME)?.1));
+    match outcome {
+        Some(outcome) if status.success() => Ok(outcome.to_string()),
+        _ => Err(format!("crashed ({}):\n{}", status, stderr.trim_end())),
+    }
+}
+
+// The outcome the case asks for on its first line, if any.
+fn expected(path: &str) -> Option<String> {
+    let src = fs::read(path).unwrap();
+    let src = String::from_utf8_lossy(&src);
+    let line = src.lines().next()?.strip_prefix("// expect: ")?;
+    Some(format!("{}:{}", path, line))
+}
+
+#[test]
+fn crashes() {
+    if let Ok(path) = env::var(CASE_VAR) {
+        return run_case(path);
+    }
+
+    let mut paths: Vec<_> = fs::read_dir("tests/crashes")
+        .expect("cannot read tests/crashes")
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no cases in tests/crashes");
+
+    let mut failed = vec![];
+    for path in paths {
+        let path = path.to_string_lossy().into_owned();
+        match (spawn_case(&path), expected(&path)) {
+            (Err(e), _) => failed.push(format!("{}: {}", path, e)),
+            (Ok(outcome), Some(expected)) if outcome != expected => failed.push(format!(
+                "{}: expected `{}`, got `{}`",
+                path, expected, outcome
+            )),
+            _ => (),
+        }
+    }
+    assert!(failed.is_empty(), "\n{}", failed.join("\n"));
+}
@@ -0,0 +1,2 @@
+// expect: 2:14: error: lvalue required
+int main() { 1 = 2; return 0; }
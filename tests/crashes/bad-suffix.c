@@ -0,0 +1 @@
+int x = 1lul;
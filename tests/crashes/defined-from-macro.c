@@ -0,0 +1,6 @@
+#define HAS(x) defined(x)
+#define HAS_FOO defined FOO
+#define FOO
+#if HAS(FOO) && HAS_FOO && !HAS(BAR)
+int main() { return 0; }
+#endif
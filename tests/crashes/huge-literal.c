@@ -0,0 +1 @@
+int x = 99999999999999999999;
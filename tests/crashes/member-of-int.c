@@ -0,0 +1,2 @@
+// expect: 2:22: error: struct expected before '.'
+int main() { int a; a.x = 1; return 0; }
@@ -0,0 +1,2 @@
+// expect: 2:12: error: typename expected
+struct S { x; };
@@ -0,0 +1,2 @@
+// expect: 2:6: error: Semicolon expected
+int x
\ No newline at end of file
@@ -0,0 +1,7 @@
+int f(int x) { return x; }
+int g(int x) { return x; }
+#define A B
+#define B A
+#define f(x) f(x + 1)
+#define g(x) f(x) + g(x)
+int main() { int A = 1; return f(A) + g(A); }
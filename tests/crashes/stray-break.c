@@ -0,0 +1,2 @@
+// expect: 2:14: error: 'break' statement not in loop
+int main() { break; }
@@ -0,0 +1 @@
+int main() { struct { int a; int b; int c; } x; x; return 0; }
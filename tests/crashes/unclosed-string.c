@@ -0,0 +1 @@
+int main() { char *p = "abc; return 0; }
@@ -0,0 +1,58 @@
+// Bad directives and macro calls come back from tokenize_source as
+// errors located at the offending token, instead of panicking.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::{tokenize_source, LexError};
+
+fn preprocess(src: &str) -> Result<usize, LexError> {
+    let mut ctx = Preprocessor::new();
+    tokenize_source("directives.c".into(), src, &mut ctx).map(|v| v.len())
+}
+
+fn error(src: &str) -> String {
+    match preprocess(src) {
+        Err(e) => e.to_string(),
+        Ok(_) => panic!("{:?} preprocessed without an error", src),
+    }
+}
+
+#[test]
+fn unknown_directive() {
+    let e = error("int x;\n#foo\n");
+    assert!(e.contains("unknown directive"), "{}", e);
+    assert!(e.contains("directives.c:2:1"), "{}", e);
+}
+
+#[test]
+fn define_without_name() {
+    let e = error("#define\n");
+    assert!(e.contains("macro name expected"), "{}", e);
+    let e = error("#define");
+    assert!(e.contains("macro name expected"), "{}", e);
+}
+
+#[test]
+fn include_without_filename() {
+    let e = error("#include 3\n");
+    assert!(e.contains("\"filename\" or <filename> expected"), "{}", e);
+    assert!(e.contains("directives.c:1:10"), "{}", e);
+}
+
+#[test]
+fn unterminated_macro_call() {
+    let e = error("#define f(x) x\nf(1, \n");
+    assert!(
+        e.contains("unterminated argument list invoking macro 'f'"),
+        "{}",
+        e
+    );
+}
+
+#[test]
+fn empty_parameter_list() {
+    assert_eq!(preprocess("#define p() int\np() x;\n").unwrap(), 3);
+    let e = error("#define p() int\np(1) x;\n");
+    assert!(e.contains("number of parameter does not match"), "{}", e);
+}
@@ -0,0 +1,86 @@
+// #include through an in-memory FileLoader: one file reached through
+// different separators, spellings and letter cases is found, and read
+// once under #pragma once. The bundled headers need no files at all.
+
+extern crate r9cc;
+
+use r9cc::loader::MemoryLoader;
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::{tokenize_source, LexError};
+
+fn preprocess(loader: MemoryLoader, src: &str) -> Result<Vec<String>, LexError> {
+    let mut ctx = Preprocessor::new();
+    ctx.set_loader(Box::new(loader));
+    let tokens = tokenize_source("main.c".into(), src, &mut ctx)?;
+    Ok(tokens.iter().map(|t| t.tokstr()).collect())
+}
+
+#[test]
+fn mixed_separators() {
+    let mut loader = MemoryLoader::new();
+    loader.add("sub/defs.h", "int defs;\n");
+    let tokens = preprocess(loader, "#include \"sub\\defs.h\"\n").unwrap();
+    assert_eq!(tokens, ["int", "defs", ";"]);
+}
+
+#[test]
+fn header_added_with_backslashes() {
+    let mut loader = MemoryLoader::new();
+    loader.add("sub\\defs.h", "int defs;\n");
+    let tokens = preprocess(loader, "#include \"sub/defs.h\"\n").unwrap();
+    assert_eq!(tokens, ["int", "defs", ";"]);
+}
+
+#[test]
+fn pragma_once_across_spellings() {
+    let mut loader = MemoryLoader::new();
+    loader.add("once.h", "#pragma once\nint once;\n");
+    let src = "#include \"once.h\"\n\
+               #include \"./once.h\"\n\
+               #include \"sub/../once.h\"\n\
+               #include \"sub\\..\\once.h\"\n\
+               #include \"ONCE.H\"\n";
+    let tokens = preprocess(loader, src).unwrap();
+    assert_eq!(tokens, ["int", "once", ";"]);
+}
+
+#[test]
+fn case_insensitive_directory() {
+    let mut loader = MemoryLoader::new();
+    loader.add("Sub/Defs.h", "int defs;\n");
+    let tokens = preprocess(loader, "#include \"sub/DEFS.h\"\n").unwrap();
+    assert_eq!(tokens, ["int", "defs", ";"]);
+}
+
+#[test]
+fn include_dir() {
+    let mut loader = MemoryLoader::new();
+    loader.add("/inc/lib.h", "int lib;\n");
+    let mut ctx = Preprocessor::new();
+    ctx.set_loader(Box::new(loader));
+    ctx.add_include_dir("/inc");
+    let tokens = tokenize_source("main.c".into(), "#include <lib.h>\n", &mut ctx).unwrap();
+    assert_eq!(tokens.len(), 3);
+}
+
+#[test]
+fn missing_file() {
+    let e = preprocess(MemoryLoader::new(), "#include \"nope.h\"\n").unwrap_err();
+    let e = e.to_string();
+    assert!(e.contains("'nope.h' file not found"), "{}", e);
+}
+
+#[test]
+fn bundled_headers_without_a_disk() {
+    let src = "#include <stdbool.h>\n#include <stddef.h>\nbool ok = true;\n";
+    let tokens = preprocess(MemoryLoader::new(), src).unwrap();
+    assert_eq!(tokens[tokens.len() - 5..], ["int", "ok", "=", "1", ";"]);
+}
+
+#[test]
+fn header_on_disk_comes_before_bundled_one() {
+    let mut loader = MemoryLoader::new();
+    loader.add("stdbool.h", "int mine;\n");
+    let tokens = preprocess(loader, "#include <stdbool.h>\n").unwrap();
+    assert_eq!(tokens, ["int", "mine", ";"]);
+}
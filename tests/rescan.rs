@@ -0,0 +1,57 @@
+// Macro expansions are read again for more macros, and each token keeps
+// the set of macros that produced it: a macro is not expanded inside its
+// own expansion, directly or through others, so self-reference ends.
+
+extern crate r9cc;
+
+use r9cc::preprocess::Preprocessor;
+use r9cc::token::tokenize_source;
+
+fn expand(src: &str) -> String {
+    let mut ctx = Preprocessor::new();
+    let tokens = tokenize_source("rescan.c".into(), src, &mut ctx).unwrap();
+    let v: Vec<String> = tokens.iter().map(|t| t.tokstr()).collect();
+    v.join(" ")
+}
+
+#[test]
+fn direct_self_reference() {
+    assert_eq!(expand("#define FOO FOO + 1\nFOO FOO\n"), "FOO + 1 FOO + 1");
+}
+
+#[test]
+fn mutual_self_reference() {
+    assert_eq!(expand("#define A B\n#define B A\nA B\n"), "A B");
+    let src = "#define A B C\n#define B A\n#define C B\nA\n";
+    assert_eq!(expand(src), "A A");
+}
+
+#[test]
+fn function_like_self_reference() {
+    let src = "#define F(x) F(x) + G(x)\n#define G(x) F(x)\nF(1) G(2)\n";
+    assert_eq!(expand(src), "F ( 1 ) + F ( 1 ) F ( 2 ) + G ( 2 )");
+}
+
+#[test]
+fn call_formed_by_an_expansion() {
+    let src = "#define f(x) x + 1\n#define g f\n#define h g(2)\ng(1) h\n";
+    assert_eq!(expand(src), "1 + 1 2 + 1");
+}
+
+#[test]
+fn painted_name_stays_unexpanded_as_an_argument() {
+    // The inner FOO came out of FOO's own expansion, so it stays FOO
+    // even when it is later passed through another macro.
+    let src = "#define ID(x) x\n#define FOO ID(FOO)\nFOO\n";
+    assert_eq!(expand(src), "FOO");
+}
+
+#[test]
+fn long_chain() {
+    let mut src = String::new();
+    for i in 0..1000 {
+        src.push_str(&format!("#define M{} M{}\n", i, i + 1));
+    }
+    src.push_str("#define M1000 M0\nM0\n");
+    assert_eq!(expand(&src), "M0");
+}